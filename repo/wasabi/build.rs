@@ -0,0 +1,14 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // The kernel is placed at its link address by the loader, so it is
+    // linked at boot_info::KERNEL_IMAGE_BASE with page aligned segments.
+    if std::env::var("TARGET").as_deref() == Ok("x86_64-unknown-none") {
+        for arg in [
+            "--image-base=0xFFFFFFFF80000000",
+            "--entry=kernel_main",
+            "-zseparate-loadable-segments",
+        ] {
+            println!("cargo:rustc-link-arg-bin=kernel={arg}");
+        }
+    }
+}
@@ -0,0 +1,33 @@
+#!/bin/bash -e
+
+# カーネルの ELF の .wasabi_symbols セクションに関数のシンボルテーブルを書き込む
+# (形式は src/backtrace.rs を参照)
+KERNEL="$1"
+if [[ ! -f "$KERNEL" ]]; then
+  echo "Error: kernel not found at path '$KERNEL'"
+  exit 1
+fi
+SECTION=.wasabi_symbols
+
+WORK_DIR="$(mktemp -d)"
+trap 'rm -rf "$WORK_DIR"' EXIT
+
+# 予約されているセクションのサイズを取得する
+objcopy --dump-section ${SECTION}="$WORK_DIR/old" "$KERNEL" "$WORK_DIR/kernel"
+SECTION_SIZE=$(stat -c %s "$WORK_DIR/old")
+
+# "アドレス サイズ 名前" の行を並べる (名前末尾のハッシュは除く)
+echo "wasabi-symbols" > "$WORK_DIR/symbols"
+nm -n -S --defined-only -C "$KERNEL" \
+  | awk 'NF >= 4 && ($3 == "T" || $3 == "t") { addr = $1; size = $2; sub(/^[^ ]+ [^ ]+ [^ ]+ /, ""); print addr, size, $0 }' \
+  | sed -E 's/::h[0-9a-f]{16}$//' \
+  >> "$WORK_DIR/symbols"
+
+# 末尾に NUL が少なくとも 1 バイト必要
+TABLE_SIZE=$(stat -c %s "$WORK_DIR/symbols")
+if (( TABLE_SIZE >= SECTION_SIZE )); then
+  echo "Error: symbol table is too large (${TABLE_SIZE} bytes, ${SECTION_SIZE} bytes reserved)"
+  exit 1
+fi
+truncate -s "$SECTION_SIZE" "$WORK_DIR/symbols"
+objcopy --update-section ${SECTION}="$WORK_DIR/symbols" "$KERNEL"
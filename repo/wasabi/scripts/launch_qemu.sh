@@ -15,10 +15,29 @@ fi
 rm -rf mnt
 mkdir -p mnt/EFI/BOOT/
 cp ${PATH_TO_EFI} mnt/EFI/BOOT/BOOTX64.EFI
+
+# BOOTX64.EFI は EFI/wasabi/kernel.elf を読み込んで起動する
+if [[ "$PATH_TO_EFI" == */release/* ]]; then
+  KERNEL_PROFILE=release
+  cargo build --bin kernel --features kernel --target x86_64-unknown-none --release
+  cargo build --bin hello --features app --target x86_64-unknown-none --release
+else
+  KERNEL_PROFILE=debug
+  cargo build --bin kernel --features kernel --target x86_64-unknown-none
+  cargo build --bin hello --features app --target x86_64-unknown-none
+fi
+mkdir -p mnt/EFI/wasabi/
+cp target/x86_64-unknown-none/${KERNEL_PROFILE}/kernel mnt/EFI/wasabi/kernel.elf
+bash scripts/embed_symbols.sh mnt/EFI/wasabi/kernel.elf
+
+# EFI/wasabi/apps/ 以下のファイルはカーネルがユーザープログラムとして実行する
+mkdir -p mnt/EFI/wasabi/apps/
+cp target/x86_64-unknown-none/${KERNEL_PROFILE}/hello mnt/EFI/wasabi/apps/hello
 set +e
 mkdir -p log
 qemu-system-x86_64 \
   -m 4G \
+  -smp 4 \
   -bios third_party/ovmf/RELEASEX64_OVMF.fd \
   -machine q35 \
   -drive format=raw,file=fat:rw:mnt \
@@ -1,7 +1,15 @@
+extern crate alloc;
+
+use crate::addr::PhysAddr;
+use crate::addr::VirtAddr;
 use crate::hpet::HpetRegisters;
 use crate::result::Result;
+use crate::slice::Sliceable;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::fmt;
 use core::mem::size_of;
+use core::slice;
 
 #[repr(packed)]
 #[derive(Clone, Copy, Debug)]
@@ -23,6 +31,33 @@ impl SystemDescriptionTableHeader {
     }
 }
 
+unsafe impl Sliceable for SystemDescriptionTableHeader {}
+
+fn copy_table_to_heap(header: &SystemDescriptionTableHeader) -> &'static [u8] {
+    // SAFETY: a table is header.length bytes long including the header.
+    let table = unsafe {
+        slice::from_raw_parts(
+            header as *const SystemDescriptionTableHeader as *const u8,
+            header.length as usize,
+        )
+    };
+    Box::leak(Box::<[u8]>::from(table))
+}
+
+/// Tables refer to each other with physical addresses, and the heap is in
+/// the direct map.
+fn phys_addr_of(table: &[u8]) -> u64 {
+    VirtAddr::from_ptr(table.as_ptr()).to_phys().as_u64()
+}
+
+/// Updates the checksum field so that the sum of all bytes becomes 0.
+fn update_checksum(table: &mut [u8]) {
+    const CHECKSUM_OFFSET: usize = 9;
+    table[CHECKSUM_OFFSET] = 0;
+    let sum = table.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    table[CHECKSUM_OFFSET] = 0u8.wrapping_sub(sum);
+}
+
 struct XsdtIterator<'a> {
     table: &'a Xsdt,
     index: usize,
@@ -44,7 +79,10 @@ impl<'a> Iterator for XsdtIterator<'a> {
         } else {
             self.index += 1;
             Some(unsafe {
-                &*(self.table.entry(self.index - 1) as *const SystemDescriptionTableHeader)
+                &*self
+                    .table
+                    .entry(self.index - 1)
+                    .as_mut_ptr::<SystemDescriptionTableHeader>()
             })
         }
     }
@@ -64,12 +102,15 @@ impl Xsdt {
         size_of::<Self>()
     }
     fn num_of_entries(&self) -> usize {
-        (self.header.length as usize - self.header_size()) / size_of::<*const u8>()
+        (self.header.length as usize - self.header_size()) / size_of::<u64>()
     }
-    unsafe fn entry(&self, index: usize) -> *const u8 {
-        ((self as *const Self as *const u8).add(self.header_size()) as *const *const u8)
-            .add(index)
-            .read_unaligned()
+    /// Returns the physical address of the index-th table.
+    unsafe fn entry(&self, index: usize) -> PhysAddr {
+        PhysAddr::new(
+            ((self as *const Self as *const u8).add(self.header_size()) as *const u64)
+                .add(index)
+                .read_unaligned(),
+        )
     }
     fn iter(&self) -> XsdtIterator {
         XsdtIterator::new(self)
@@ -97,9 +138,9 @@ pub struct GenericAddress {
 }
 const _: () = assert!(size_of::<GenericAddress>() == 12);
 impl GenericAddress {
-    pub fn address_in_memory_space(&self) -> Result<usize> {
+    pub fn address_in_memory_space(&self) -> Result<PhysAddr> {
         if self.address_space_id == 0 {
-            Ok(self.address as usize)
+            Ok(PhysAddr::new(self.address))
         } else {
             Err("ACPI Generic Address is not in system memory space")
         }
@@ -122,7 +163,7 @@ impl AcpiHpetDescriptor {
         unsafe {
             self.address
                 .address_in_memory_space()
-                .map(|addr| &mut *(addr as *mut HpetRegisters))
+                .map(|addr| &mut *addr.as_mut_ptr::<HpetRegisters>())
         }
     }
 }
@@ -141,7 +182,34 @@ pub struct AcpiRsdpStruct {
 }
 impl AcpiRsdpStruct {
     fn xsdt(&self) -> &Xsdt {
-        unsafe { &*(self.xsdt as *const Xsdt) }
+        unsafe { &*PhysAddr::new(self.xsdt).as_mut_ptr::<Xsdt>() }
+    }
+    /// Copies the RSDP, the XSDT and the tables listed in the XSDT to the
+    /// heap so that ACPI_RECLAIM_MEMORY can be reused. Tables referenced only
+    /// from other tables (e.g. DSDT from FADT) are not copied.
+    pub fn copy_to_heap(&self) -> &'static AcpiRsdpStruct {
+        let xsdt = self.xsdt();
+        let entries: Vec<u64> = xsdt
+            .iter()
+            .map(|t| phys_addr_of(copy_table_to_heap(t)))
+            .collect();
+        let header = xsdt.header.as_slice();
+        let mut new_xsdt = Vec::with_capacity(header.len() + entries.len() * size_of::<u64>());
+        new_xsdt.extend_from_slice(header);
+        for e in entries {
+            new_xsdt.extend_from_slice(&e.to_le_bytes());
+        }
+        update_checksum(&mut new_xsdt);
+        let new_xsdt = Box::leak(new_xsdt.into_boxed_slice());
+        Box::leak(Box::new(AcpiRsdpStruct {
+            signature: self.signature,
+            checksum: self.checksum,
+            oem_id: self.oem_id,
+            revision: self.revision,
+            rsdt_address: 0,
+            length: self.length,
+            xsdt: phys_addr_of(new_xsdt),
+        }))
     }
     pub fn hpet(&self) -> Option<&AcpiHpetDescriptor> {
         let xsdt = self.xsdt();
@@ -151,6 +219,153 @@ impl AcpiRsdpStruct {
         let xsdt = self.xsdt();
         xsdt.find_table(b"MCFG").map(AcpiMcfgDescriptor::new)
     }
+    pub fn madt(&self) -> Option<&AcpiMadt> {
+        let xsdt = self.xsdt();
+        xsdt.find_table(b"APIC").map(AcpiMadt::new)
+    }
+}
+
+// 5.2.12 Multiple APIC Description Table (MADT)
+#[repr(C, packed)]
+pub struct AcpiMadt {
+    header: SystemDescriptionTableHeader,
+    local_apic_address: u32,
+    flags: u32,
+    // Followed by the interrupt controller structures
+}
+impl AcpiTable for AcpiMadt {
+    const SIGNATURE: &'static [u8; 4] = b"APIC";
+    type Table = Self;
+}
+const _: () = assert!(size_of::<AcpiMadt>() == 44);
+const MADT_FLAGS_PCAT_COMPAT: u32 = 1;
+impl AcpiMadt {
+    fn body(&self) -> &[u8] {
+        // SAFETY: the table is header.length bytes long.
+        unsafe {
+            slice::from_raw_parts(
+                (self as *const Self as *const u8).add(size_of::<Self>()),
+                self.header.length as usize - size_of::<Self>(),
+            )
+        }
+    }
+    pub fn entries(&self) -> MadtIterator {
+        MadtIterator { body: self.body() }
+    }
+    /// Physical address of the local APIC registers, which can be
+    /// overridden with a 64-bit address by an entry.
+    pub fn local_apic_address(&self) -> PhysAddr {
+        self.entries()
+            .find_map(|e| match e {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or(PhysAddr::new(self.local_apic_address as u64))
+    }
+    /// True if the legacy 8259 PICs are also installed.
+    pub fn has_8259_pics(&self) -> bool {
+        self.flags & MADT_FLAGS_PCAT_COMPAT != 0
+    }
+}
+
+/// Polarity and trigger mode of an interrupt (MPS INTI flags)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MpsIntiFlags(u16);
+impl MpsIntiFlags {
+    /// Returns Some(true) for active low, or None if it conforms to the
+    /// bus (active high for ISA).
+    pub fn is_active_low(&self) -> Option<bool> {
+        match self.0 & 0b11 {
+            0b01 => Some(false),
+            0b11 => Some(true),
+            _ => None,
+        }
+    }
+    /// Returns Some(true) for level triggered, or None if it conforms to
+    /// the bus (edge triggered for ISA).
+    pub fn is_level_triggered(&self) -> Option<bool> {
+        match (self.0 >> 2) & 0b11 {
+            0b01 => Some(false),
+            0b11 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_uid: u32,
+        apic_id: u32,
+        enabled: bool,
+    },
+    IoApic {
+        id: u8,
+        address: PhysAddr,
+        gsi_base: u32,
+    },
+    InterruptSourceOverride {
+        irq: u8,
+        gsi: u32,
+        flags: MpsIntiFlags,
+    },
+    LocalApicAddressOverride {
+        address: PhysAddr,
+    },
+    Other {
+        entry_type: u8,
+    },
+}
+
+pub struct MadtIterator<'a> {
+    body: &'a [u8],
+}
+impl<'a> Iterator for MadtIterator<'a> {
+    type Item = MadtEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry_type = *self.body.first()?;
+        let len = *self.body.get(1)? as usize;
+        if len < 2 || len > self.body.len() {
+            return None;
+        }
+        let e = &self.body[..len];
+        self.body = &self.body[len..];
+        let u16_at = |i: usize| e.get(i..i + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let u32_at = |i: usize| {
+            e.get(i..i + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let u64_at = |i: usize| Some(u32_at(i)? as u64 | (u32_at(i + 4)? as u64) << 32);
+        const FLAGS_ENABLED: u32 = 1;
+        const FLAGS_ONLINE_CAPABLE: u32 = 2;
+        let entry = match entry_type {
+            0 => MadtEntry::LocalApic {
+                processor_uid: *e.get(2)? as u32,
+                apic_id: *e.get(3)? as u32,
+                enabled: u32_at(4)? & (FLAGS_ENABLED | FLAGS_ONLINE_CAPABLE) == FLAGS_ENABLED,
+            },
+            1 => MadtEntry::IoApic {
+                id: *e.get(2)?,
+                address: PhysAddr::new(u32_at(4)? as u64),
+                gsi_base: u32_at(8)?,
+            },
+            2 => MadtEntry::InterruptSourceOverride {
+                irq: *e.get(3)?,
+                gsi: u32_at(4)?,
+                flags: MpsIntiFlags(u16_at(8)?),
+            },
+            5 => MadtEntry::LocalApicAddressOverride {
+                address: PhysAddr::new(u64_at(4)?),
+            },
+            9 => MadtEntry::LocalApic {
+                processor_uid: u32_at(12)?,
+                apic_id: u32_at(4)?,
+                enabled: u32_at(8)? & (FLAGS_ENABLED | FLAGS_ONLINE_CAPABLE) == FLAGS_ENABLED,
+            },
+            entry_type => MadtEntry::Other { entry_type },
+        };
+        Some(entry)
+    }
 }
 
 #[repr(C, packed)]
@@ -196,8 +411,8 @@ pub struct EcamEntry {
     _reserved: u32,
 }
 impl EcamEntry {
-    pub fn base_address(&self) -> u64 {
-        self.ecm_base_addr
+    pub fn base_address(&self) -> PhysAddr {
+        PhysAddr::new(self.ecm_base_addr)
     }
 }
 impl fmt::Display for EcamEntry {
@@ -213,3 +428,91 @@ impl fmt::Display for EcamEntry {
         )
     }
 }
+
+#[cfg(test)]
+fn build_test_table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut table = Vec::new();
+    table.extend_from_slice(signature);
+    table.extend_from_slice(&((36 + body.len()) as u32).to_le_bytes());
+    table.resize(36, 0);
+    table.extend_from_slice(body);
+    update_checksum(&mut table);
+    table
+}
+
+#[test_case]
+fn acpi_tables_are_copied_to_heap() {
+    let mut mcfg_body = [0u8; 8 + 16];
+    mcfg_body[8..16].copy_from_slice(&0xB000_0000u64.to_le_bytes());
+    let mcfg = build_test_table(b"MCFG", &mcfg_body);
+    let xsdt = build_test_table(b"XSDT", &phys_addr_of(&mcfg).to_le_bytes());
+    let rsdp = AcpiRsdpStruct {
+        signature: *b"RSD PTR ",
+        checksum: 0,
+        oem_id: *b"WASABI",
+        revision: 2,
+        rsdt_address: 0,
+        length: 36,
+        xsdt: phys_addr_of(&xsdt),
+    };
+    let copied = rsdp.copy_to_heap();
+    assert_ne!(copied.xsdt, rsdp.xsdt);
+    let copied_xsdt = copied.xsdt();
+    assert_eq!(copied_xsdt.num_of_entries(), 1);
+    let xsdt_bytes =
+        unsafe { slice::from_raw_parts(copied_xsdt as *const Xsdt as *const u8, 36 + 8) };
+    assert_eq!(xsdt_bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b)), 0);
+    let copied_mcfg = copied.mcfg().unwrap();
+    assert_ne!(
+        copied_mcfg as *const AcpiMcfgDescriptor as u64,
+        mcfg.as_ptr() as u64
+    );
+    assert_eq!(copied_mcfg.num_of_entries(), 1);
+    assert_eq!(
+        copied_mcfg.entry(0).unwrap().base_address(),
+        PhysAddr::new(0xB000_0000)
+    );
+    assert!(copied.hpet().is_none());
+}
+#[test_case]
+fn madt_entries_are_parsed() {
+    let mut body = Vec::new();
+    body.extend_from_slice(&0xFEE0_0000u32.to_le_bytes());
+    body.extend_from_slice(&MADT_FLAGS_PCAT_COMPAT.to_le_bytes());
+    // Local APIC: uid 0, id 1, enabled
+    body.extend_from_slice(&[0, 8, 0, 1, 1, 0, 0, 0]);
+    // I/O APIC: id 2 at 0xFEC00000, GSI base 0
+    body.extend_from_slice(&[1, 12, 2, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+    // IRQ 0 is GSI 2
+    body.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+    // IRQ 9 is GSI 9, active low and level triggered
+    body.extend_from_slice(&[2, 10, 0, 9, 9, 0, 0, 0, 0x0F, 0]);
+    let table = build_test_table(b"APIC", &body);
+    let madt = AcpiMadt::new(unsafe { &*(table.as_ptr() as *const SystemDescriptionTableHeader) });
+    assert!(madt.has_8259_pics());
+    assert_eq!(madt.local_apic_address(), PhysAddr::new(0xFEE0_0000));
+    let entries: Vec<MadtEntry> = madt.entries().collect();
+    assert_eq!(entries.len(), 4);
+    assert_eq!(
+        entries[0],
+        MadtEntry::LocalApic {
+            processor_uid: 0,
+            apic_id: 1,
+            enabled: true
+        }
+    );
+    assert_eq!(
+        entries[1],
+        MadtEntry::IoApic {
+            id: 2,
+            address: PhysAddr::new(0xFEC0_0000),
+            gsi_base: 0
+        }
+    );
+    let MadtEntry::InterruptSourceOverride { irq, gsi, flags } = entries[3] else {
+        panic!("Unexpected entry: {:?}", entries[3]);
+    };
+    assert_eq!((irq, gsi), (9, 9));
+    assert_eq!(flags.is_active_low(), Some(true));
+    assert_eq!(flags.is_level_triggered(), Some(true));
+}
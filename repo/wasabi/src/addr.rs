@@ -0,0 +1,130 @@
+//! Physical and virtual addresses
+//!
+//! Addresses that devices and page tables see are PhysAddr, and addresses
+//! that the CPU can dereference are VirtAddr. All the physical memory is
+//! visible through the direct map, which starts at the physical map offset
+//! in the virtual address space. The offset is 0 (identity mapping) while
+//! we run on the page table made by the firmware.
+
+use core::fmt;
+use core::ops::Add;
+use core::ops::Sub;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+static PHYS_MAP_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the virtual address where the physical address 0 is mapped.
+pub fn phys_map_offset() -> u64 {
+    PHYS_MAP_OFFSET.load(Ordering::Relaxed)
+}
+
+/// # Safety
+/// All the physical memory should be mapped at offset in the current page
+/// table, and no one should hold pointers derived with the previous offset.
+pub unsafe fn set_phys_map_offset(offset: u64) {
+    PHYS_MAP_OFFSET.store(offset, Ordering::SeqCst)
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct PhysAddr(u64);
+impl PhysAddr {
+    pub const fn new(addr: u64) -> Self {
+        Self(addr)
+    }
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+    /// Returns the address in the direct map that points to this address.
+    pub fn to_virt(self) -> VirtAddr {
+        VirtAddr(self.0 + phys_map_offset())
+    }
+    /// Returns a pointer to this address through the direct map.
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.to_virt().as_mut_ptr()
+    }
+}
+impl Add<u64> for PhysAddr {
+    type Output = Self;
+    fn add(self, rhs: u64) -> Self {
+        Self(self.0 + rhs)
+    }
+}
+impl Sub for PhysAddr {
+    type Output = u64;
+    fn sub(self, rhs: Self) -> u64 {
+        self.0 - rhs.0
+    }
+}
+impl fmt::Debug for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PhysAddr({:#X})", self.0)
+    }
+}
+impl fmt::UpperHex for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.0, f)
+    }
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct VirtAddr(u64);
+impl VirtAddr {
+    pub const fn new(addr: u64) -> Self {
+        Self(addr)
+    }
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        Self(ptr as u64)
+    }
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+    /// Returns the physical address for an address in the direct map,
+    /// such as the ones in the heap. Use PML4::virt_to_phys() for the other
+    /// addresses.
+    pub fn to_phys(self) -> PhysAddr {
+        PhysAddr(self.0 - phys_map_offset())
+    }
+}
+impl Add<u64> for VirtAddr {
+    type Output = Self;
+    fn add(self, rhs: u64) -> Self {
+        Self(self.0 + rhs)
+    }
+}
+impl Sub for VirtAddr {
+    type Output = u64;
+    fn sub(self, rhs: Self) -> u64 {
+        self.0 - rhs.0
+    }
+}
+impl fmt::Debug for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VirtAddr({:#X})", self.0)
+    }
+}
+impl fmt::UpperHex for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.0, f)
+    }
+}
+
+#[test_case]
+fn addresses_are_translated_with_the_direct_map() {
+    let offset = phys_map_offset();
+    let phys = PhysAddr::new(0x1234_5000);
+    assert_eq!(phys.to_virt().as_u64(), 0x1234_5000 + offset);
+    assert_eq!(phys.to_virt().to_phys(), phys);
+    assert_eq!((phys + 0x10) - phys, 0x10);
+    let value = 0u64;
+    let virt = VirtAddr::from_ptr(&value);
+    assert_eq!(
+        virt.to_phys().as_mut_ptr::<u64>() as *const u64,
+        &value as *const u64
+    );
+}
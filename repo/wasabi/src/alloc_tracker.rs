@@ -0,0 +1,236 @@
+//! Debug mode of the allocator to find leaks
+//!
+//! While tracking is enabled, every allocation made through the global
+//! allocator is recorded with its size and the return addresses of its
+//! callers, until it is freed. A Location is not available here since
+//! GlobalAlloc is called through the alloc crate, so the call stack is
+//! recorded by following the frame pointers instead. The frames in the
+//! allocator and the alloc crate are skipped with the symbol table when
+//! the records are dumped, to show where the allocation was made.
+//!
+//! The records are kept in a fixed-size table so that tracking does not
+//! allocate by itself.
+
+use crate::backtrace::symbol_table;
+use crate::backtrace::StackFrames;
+use crate::backtrace::SymbolTable;
+use crate::info;
+use crate::mutex::SpinLock;
+use crate::x86::read_rbp;
+use core::alloc::Layout;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+const MAX_RECORDS: usize = 1024;
+/// Enough to reach the caller of Vec or Box through the alloc crate, even
+/// in debug builds where nothing is inlined
+const NUM_CALLERS: usize = 24;
+/// Frames of the functions with these prefixes are in the path from an
+/// allocation to the global allocator.
+const ALLOCATOR_FUNCTIONS: [&str; 7] = [
+    "wasabi::alloc_tracker::",
+    "wasabi::x86::read_rbp",
+    "<wasabi::allocator::",
+    "__rust_",
+    "__rg_",
+    "alloc::",
+    "<alloc::",
+];
+/// Number of the frames printed for each allocation
+const NUM_DUMPED_FRAMES: usize = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AllocRecord {
+    pub ptr: usize,
+    pub size: usize,
+    /// Return addresses, starting from the frames in the allocator. The
+    /// rest are 0 if the stack is shallower.
+    pub callers: [u64; NUM_CALLERS],
+}
+impl AllocRecord {
+    /// Iterates the functions of the callers with the offsets of the
+    /// return addresses, from the one that made the allocation.
+    pub fn call_stack<'a>(
+        &'a self,
+        symbols: SymbolTable<'a>,
+    ) -> impl Iterator<Item = (&'a str, u64)> + 'a {
+        self.callers
+            .iter()
+            .take_while(|ret| **ret != 0)
+            // Look up the call instruction, as in the backtraces.
+            .map(move |ret| (symbols.lookup(ret - 1), *ret))
+            .skip_while(|(symbol, _)| {
+                symbol.is_some_and(|(name, _)| {
+                    ALLOCATOR_FUNCTIONS.iter().any(|f| name.starts_with(f))
+                })
+            })
+            .map(|(symbol, ret)| symbol.map_or(("?", ret), |(name, offset)| (name, offset + 1)))
+    }
+}
+
+struct AllocTracker {
+    records: [Option<AllocRecord>; MAX_RECORDS],
+    num_dropped: usize,
+}
+
+static TRACKER: SpinLock<AllocTracker> = SpinLock::new(AllocTracker {
+    records: [None; MAX_RECORDS],
+    num_dropped: 0,
+});
+static IS_TRACKING: AtomicBool = AtomicBool::new(false);
+
+/// Starts recording allocations. The records of the previous run are
+/// cleared.
+pub fn start_tracking_allocations() {
+    let mut tracker = TRACKER.lock();
+    tracker.records.fill(None);
+    tracker.num_dropped = 0;
+    IS_TRACKING.store(true, Ordering::SeqCst);
+}
+
+/// Stops recording allocations. Frees of the recorded allocations are
+/// not tracked anymore either, so call this after the code to check has
+/// released everything it should.
+pub fn stop_tracking_allocations() {
+    IS_TRACKING.store(false, Ordering::SeqCst);
+}
+
+pub fn is_tracking_allocations() -> bool {
+    IS_TRACKING.load(Ordering::Relaxed)
+}
+
+pub(crate) fn on_alloc(ptr: *mut u8, layout: Layout) {
+    if ptr.is_null() || !is_tracking_allocations() {
+        return;
+    }
+    let record = AllocRecord {
+        ptr: ptr as usize,
+        size: layout.size(),
+        callers: collect_callers(),
+    };
+    let mut tracker = TRACKER.lock();
+    match tracker.records.iter_mut().find(|r| r.is_none()) {
+        Some(slot) => *slot = Some(record),
+        None => tracker.num_dropped += 1,
+    }
+}
+
+pub(crate) fn on_dealloc(ptr: *mut u8) {
+    if !is_tracking_allocations() {
+        return;
+    }
+    let mut tracker = TRACKER.lock();
+    if let Some(slot) = tracker
+        .records
+        .iter_mut()
+        .find(|r| r.is_some_and(|r| r.ptr == ptr as usize))
+    {
+        *slot = None;
+    }
+}
+
+/// Calls f for each allocation that is recorded and not freed yet.
+pub fn for_each_outstanding_allocation(mut f: impl FnMut(&AllocRecord)) {
+    for i in 0..MAX_RECORDS {
+        // Copy the record out so that f can allocate.
+        let record = TRACKER.lock().records[i];
+        if let Some(record) = record {
+            f(&record)
+        }
+    }
+}
+
+pub fn num_outstanding_allocations() -> usize {
+    TRACKER.lock().records.iter().flatten().count()
+}
+
+pub fn dump_outstanding_allocations() {
+    let num_dropped = TRACKER.lock().num_dropped;
+    let symbols = symbol_table();
+    let mut count = 0;
+    let mut bytes = 0;
+    for_each_outstanding_allocation(|r| {
+        if let Some(symbols) = symbols {
+            info!("outstanding: {:#X} {} bytes, allocated at:", r.ptr, r.size);
+            for (name, offset) in r.call_stack(symbols).take(NUM_DUMPED_FRAMES) {
+                info!("  {name}+{offset:#X}");
+            }
+        } else {
+            info!(
+                "outstanding: {:#X} {} bytes, callers: {:X?}",
+                r.ptr, r.size, r.callers
+            );
+        }
+        count += 1;
+        bytes += r.size;
+    });
+    info!("{count} outstanding allocations, {bytes} bytes in total");
+    if num_dropped > 0 {
+        info!("{num_dropped} allocations were not recorded since the table was full");
+    }
+}
+
+fn collect_callers() -> [u64; NUM_CALLERS] {
+    let mut callers = [0; NUM_CALLERS];
+    for (caller, ret) in callers.iter_mut().zip(StackFrames::from_rbp(read_rbp())) {
+        *caller = ret;
+    }
+    callers
+}
+
+#[test_case]
+fn outstanding_allocations_are_tracked() {
+    extern crate alloc;
+    use alloc::boxed::Box;
+    // The allocation is made right at the start of this, to find the
+    // return address in it.
+    #[inline(never)]
+    fn leak() -> &'static mut [u8; 100] {
+        Box::leak(Box::new([0u8; 100]))
+    }
+    start_tracking_allocations();
+    let leaked = leak();
+    let freed = Box::new([0u64; 3]);
+    let leaked_ptr = leaked.as_ptr() as usize;
+    let freed_ptr = freed.as_ptr() as usize;
+    drop(freed);
+    stop_tracking_allocations();
+    let leak_fn = leak as usize as u64;
+    let mut found_leaked = false;
+    for_each_outstanding_allocation(|r| {
+        assert_ne!(r.ptr, freed_ptr);
+        if r.ptr == leaked_ptr {
+            assert_eq!(r.size, 100);
+            assert!(r
+                .callers
+                .iter()
+                .any(|ret| (leak_fn + 1..leak_fn + 0x100).contains(ret)));
+            found_leaked = true;
+        }
+    });
+    assert!(found_leaked);
+}
+
+#[test_case]
+fn allocator_frames_are_skipped() {
+    let data = b"wasabi-symbols\n\
+        1000 100 wasabi::alloc_tracker::on_alloc\n\
+        1100 100 <wasabi::allocator::BuddySlabAllocator as core::alloc::global::GlobalAlloc>::alloc\n\
+        1200 100 alloc::alloc::exchange_malloc\n\
+        1300 100 wasabi::f\n\
+        1400 100 alloc::boxed::Box<T>::new\n\0";
+    let symbols = SymbolTable::new(data).unwrap();
+    let mut callers = [0; NUM_CALLERS];
+    callers[..6].copy_from_slice(&[0x1010, 0x1120, 0x1230, 0x1340, 0x1450, 0x2000]);
+    let record = AllocRecord {
+        ptr: 0,
+        size: 0,
+        callers,
+    };
+    // Only the frames before the caller are skipped.
+    assert!(record.call_stack(symbols).eq([
+        ("wasabi::f", 0x40),
+        ("alloc::boxed::Box<T>::new", 0x50),
+        ("?", 0x2000)
+    ]));
+}
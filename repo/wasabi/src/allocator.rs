@@ -1,18 +1,22 @@
 extern crate alloc;
 
+use crate::addr::PhysAddr;
+use crate::addr::VirtAddr;
+use crate::alloc_tracker;
+use crate::error;
+use crate::mutex::SpinLock;
+use crate::percpu::this_cpu;
 use crate::result::Result;
-use crate::uefi::EfiMemoryDescriptor;
 use crate::uefi::EfiMemoryType;
 use crate::uefi::MemoryMapHolder;
+use crate::x86::InterruptGuard;
+use crate::x86::PAGE_SIZE;
 use alloc::alloc::GlobalAlloc;
 use alloc::alloc::Layout;
-use alloc::boxed::Box;
-use core::borrow::BorrowMut;
-use core::cell::RefCell;
 use core::cmp::max;
 use core::fmt;
 use core::mem::size_of;
-use core::ops::DerefMut;
+use core::ops::Range;
 use core::ptr::null_mut;
 
 pub fn round_up_to_nearest_pow2(v: usize) -> Result<usize> {
@@ -35,192 +39,788 @@ fn round_up_to_nearest_pow2_tests() {
     assert_eq!(round_up_to_nearest_pow2(9), Ok(16));
 }
 
-/// Vertical bar `|` represents the chunk that has a Header
-/// before: |-- prev -------|---- self ---------------
-/// align:  |--------|-------|-------|-------|-------|
-/// after:  |---------------||-------|----------------
+pub const LAYOUT_PAGE_4K: Layout = unsafe { Layout::from_size_align_unchecked(4096, 4096) };
+
+/// A block of order N is 2^N pages long and its physical address is aligned
+/// to its size, so the buddy of a block is found by flipping bit N of its
+/// page frame number.
+const MAX_ORDER: usize = 18;
 
-struct Header {
-    next_header: Option<Box<Header>>,
-    size: usize,
-    is_allocated: bool,
-    _reserved: usize,
+fn order_for_pages(num_pages: usize) -> Option<usize> {
+    let order = round_up_to_nearest_pow2(num_pages).ok()?.trailing_zeros() as usize;
+    (order <= MAX_ORDER).then_some(order)
 }
-const HEADER_SIZE: usize = size_of::<Header>();
-#[allow(clippy::assertions_on_constants)]
-const _: () = assert!(HEADER_SIZE == 32);
-// Size of Header should be power of 2
-const _: () = assert!(HEADER_SIZE.count_ones() == 1);
-pub const LAYOUT_PAGE_4K: Layout = unsafe { Layout::from_size_align_unchecked(4096, 4096) };
-impl Header {
-    fn can_provide(&self, size: usize, align: usize) -> bool {
-        // This check is rough - actual size needed may be smaller.
-        // HEADER_SIZE * 2 => one for allocated region, another for padding.
-        self.size >= size + HEADER_SIZE * 2 + align
-    }
-    fn is_allocated(&self) -> bool {
-        self.is_allocated
-    }
-    fn end_addr(&self) -> usize {
-        self as *const Header as usize + self.size
-    }
-    unsafe fn new_from_addr(addr: usize) -> Box<Header> {
-        let header = addr as *mut Header;
-        header.write(Header {
-            next_header: None,
-            size: 0,
-            is_allocated: false,
-            _reserved: 0,
+#[test_case]
+fn order_for_pages_tests() {
+    assert_eq!(order_for_pages(1), Some(0));
+    assert_eq!(order_for_pages(2), Some(1));
+    assert_eq!(order_for_pages(3), Some(2));
+    assert_eq!(order_for_pages(512), Some(9));
+    assert_eq!(order_for_pages(513), Some(10));
+    assert_eq!(order_for_pages(1 << MAX_ORDER), Some(MAX_ORDER));
+    assert_eq!(order_for_pages((1 << MAX_ORDER) + 1), None);
+}
+
+// Page frame numbers are physical, and the pages are accessed through the
+// direct map.
+fn pfn_to_ptr(pfn: usize) -> *mut u8 {
+    PhysAddr::new((pfn * PAGE_SIZE) as u64).as_mut_ptr()
+}
+fn ptr_to_pfn(ptr: *mut u8) -> usize {
+    (VirtAddr::from_ptr(ptr).to_phys().as_u64() / PAGE_SIZE as u64) as usize
+}
+
+/// Placed at the head of every free block to link it into the free list of
+/// its order.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+// page_state[pfn - first_pfn] holds PAGE_STATE_FREE_HEAD | order if a free
+// block of the order starts at the page, and 0 otherwise.
+const PAGE_STATE_FREE_HEAD: u8 = 0x80;
+
+/// A contiguous range of physical pages managed by the buddy allocator.
+///
+/// The Zone itself and its page_state array live in the first pages of the
+/// range, so no memory is needed other than the range itself.
+struct Zone {
+    next: *mut Zone,
+    first_pfn: usize,
+    end_pfn: usize,
+    free_pages: usize,
+    page_state: *mut u8,
+    free_lists: [*mut FreeBlock; MAX_ORDER + 1],
+}
+impl Zone {
+    /// # Safety
+    /// [start_pfn, end_pfn) should be unused memory that is accessible
+    /// and owned by nobody else.
+    unsafe fn new_in_range(start_pfn: usize, end_pfn: usize) -> Option<*mut Zone> {
+        let num_pages = end_pfn.checked_sub(start_pfn)?;
+        let meta_pages = (size_of::<Zone>() + num_pages + PAGE_SIZE - 1) / PAGE_SIZE;
+        if num_pages <= meta_pages {
+            return None;
+        }
+        let zone = pfn_to_ptr(start_pfn) as *mut Zone;
+        let page_state = (zone as *mut u8).add(size_of::<Zone>());
+        page_state.write_bytes(0, num_pages);
+        zone.write(Zone {
+            next: null_mut(),
+            first_pfn: start_pfn + meta_pages,
+            end_pfn,
+            free_pages: 0,
+            page_state,
+            free_lists: [null_mut(); MAX_ORDER + 1],
         });
-        Box::from_raw(addr as *mut Header)
-    }
-    unsafe fn from_allocated_region(addr: *mut u8) -> Box<Header> {
-        let header = addr.sub(HEADER_SIZE) as *mut Header;
-        Box::from_raw(header)
-    }
-    //
-    // Note: std::alloc::Layout doc says:
-    // > All layouts have an associated size and a power-of-two alignment.
-    fn provide(&mut self, size: usize, align: usize) -> Option<*mut u8> {
-        let size = max(round_up_to_nearest_pow2(size).ok()?, HEADER_SIZE);
-        let align = max(align, HEADER_SIZE);
-        if self.is_allocated() || !self.can_provide(size, align) {
-            None
+        (*zone).free_range(start_pfn + meta_pages, end_pfn - start_pfn - meta_pages);
+        Some(zone)
+    }
+    fn contains(&self, pfn: usize) -> bool {
+        (self.first_pfn..self.end_pfn).contains(&pfn)
+    }
+    fn state(&mut self, pfn: usize) -> &mut u8 {
+        assert!(self.contains(pfn));
+        unsafe { &mut *self.page_state.add(pfn - self.first_pfn) }
+    }
+    fn push_free(&mut self, pfn: usize, order: usize) {
+        let block = pfn_to_ptr(pfn) as *mut FreeBlock;
+        let head = self.free_lists[order];
+        unsafe {
+            block.write(FreeBlock {
+                next: head,
+                prev: null_mut(),
+            });
+            if let Some(head) = head.as_mut() {
+                head.prev = block;
+            }
+        }
+        self.free_lists[order] = block;
+        *self.state(pfn) = PAGE_STATE_FREE_HEAD | order as u8;
+        self.free_pages += 1 << order;
+    }
+    fn remove_free(&mut self, pfn: usize, order: usize) {
+        let block = pfn_to_ptr(pfn) as *mut FreeBlock;
+        unsafe {
+            let FreeBlock { next, prev } = block.read();
+            if let Some(next) = next.as_mut() {
+                next.prev = prev;
+            }
+            match prev.as_mut() {
+                Some(prev) => prev.next = next,
+                None => self.free_lists[order] = next,
+            }
+        }
+        *self.state(pfn) = 0;
+        self.free_pages -= 1 << order;
+    }
+    fn is_free_head(&mut self, pfn: usize, order: usize) -> bool {
+        self.contains(pfn)
+            && pfn + (1 << order) <= self.end_pfn
+            && *self.state(pfn) == PAGE_STATE_FREE_HEAD | order as u8
+    }
+    /// Takes a block of the given order that ends at or below end_pfn_limit
+    /// from this zone, splitting a larger block if needed.
+    fn take_block(
+        &mut self,
+        order: usize,
+        from_order: usize,
+        end_pfn_limit: usize,
+    ) -> Option<usize> {
+        let mut block = self.free_lists[from_order];
+        let pfn = loop {
+            let b = unsafe { block.as_ref()? };
+            let pfn = ptr_to_pfn(block as *mut u8);
+            if pfn + (1 << order) <= end_pfn_limit {
+                break pfn;
+            }
+            block = b.next;
+        };
+        self.remove_free(pfn, from_order);
+        let mut split_order = from_order;
+        while split_order > order {
+            split_order -= 1;
+            self.push_free(pfn + (1 << split_order), split_order);
+        }
+        Some(pfn)
+    }
+    /// Returns a block to the free lists, merging it with its buddies as
+    /// long as they are free as well.
+    fn free_block(&mut self, mut pfn: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = pfn ^ (1 << order);
+            if !self.is_free_head(buddy, order) {
+                break;
+            }
+            self.remove_free(buddy, order);
+            pfn &= !(1 << order);
+            order += 1;
+        }
+        self.push_free(pfn, order);
+    }
+    /// Frees an arbitrary run of pages by splitting it into naturally
+    /// aligned blocks.
+    fn free_range(&mut self, mut pfn: usize, mut num_pages: usize) {
+        while num_pages > 0 {
+            let order = [
+                pfn.trailing_zeros() as usize,
+                (usize::BITS - 1 - num_pages.leading_zeros()) as usize,
+                MAX_ORDER,
+            ]
+            .into_iter()
+            .min()
+            .unwrap_or(0);
+            self.free_block(pfn, order);
+            pfn += 1 << order;
+            num_pages -= 1 << order;
+        }
+    }
+}
+
+/// Sizes of the objects served from slabs. Objects of a class are aligned
+/// to the largest power of two that divides the size.
+const SIZE_CLASSES: [usize; 14] = [
+    16, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024, 1536, 2048,
+];
+const NUM_SIZE_CLASSES: usize = SIZE_CLASSES.len();
+
+fn size_class_for(layout: Layout) -> Option<usize> {
+    let size = max(layout.size(), 1);
+    SIZE_CLASSES
+        .iter()
+        .position(|&c| c >= size && 1 << c.trailing_zeros() >= layout.align())
+}
+#[test_case]
+fn size_class_for_tests() {
+    let class_size = |size, align| {
+        size_class_for(Layout::from_size_align(size, align).unwrap()).map(|i| SIZE_CLASSES[i])
+    };
+    assert_eq!(class_size(0, 1), Some(16));
+    assert_eq!(class_size(1, 1), Some(16));
+    assert_eq!(class_size(17, 8), Some(32));
+    assert_eq!(class_size(33, 16), Some(48));
+    assert_eq!(class_size(33, 32), Some(64));
+    assert_eq!(class_size(100, 8), Some(128));
+    assert_eq!(class_size(3, 64), Some(64));
+    assert_eq!(class_size(600, 256), Some(768));
+    assert_eq!(class_size(2048, 2048), Some(2048));
+    assert_eq!(class_size(2049, 8), None);
+    assert_eq!(class_size(1, 4096), None);
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Header of a slab, placed at its first bytes. A slab is a buddy block
+/// that is sliced into the objects of one size class.
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    free_objects: *mut FreeObject,
+    num_free: usize,
+}
+
+#[derive(Clone, Copy)]
+struct SizeClassCache {
+    object_size: usize,
+    slab_order: usize,
+    // Slabs that have at least one free object, except `empty`.
+    partial: *mut Slab,
+    // At most one completely free slab is kept to avoid returning and
+    // splitting buddy blocks repeatedly on alloc/free cycles.
+    empty: *mut Slab,
+    num_slabs: usize,
+    num_free_objects: usize,
+}
+impl SizeClassCache {
+    const fn new(object_size: usize) -> Self {
+        let mut slab_order = 0;
+        while (PAGE_SIZE << slab_order) < object_size * 16 {
+            slab_order += 1;
+        }
+        Self {
+            object_size,
+            slab_order,
+            partial: null_mut(),
+            empty: null_mut(),
+            num_slabs: 0,
+            num_free_objects: 0,
+        }
+    }
+    fn slab_bytes(&self) -> usize {
+        PAGE_SIZE << self.slab_order
+    }
+    fn first_object_offset(&self) -> usize {
+        let align = 1 << self.object_size.trailing_zeros();
+        (size_of::<Slab>() + align - 1) & !(align - 1)
+    }
+    fn objects_per_slab(&self) -> usize {
+        (self.slab_bytes() - self.first_object_offset()) / self.object_size
+    }
+    fn slab_of(&self, object: *mut u8) -> *mut Slab {
+        (object as usize & !(self.slab_bytes() - 1)) as *mut Slab
+    }
+    /// # Safety
+    /// block should be an unused block of slab_order.
+    unsafe fn init_slab(&self, block: *mut u8) -> *mut Slab {
+        let slab = block as *mut Slab;
+        let mut free_objects = null_mut::<FreeObject>();
+        for i in (0..self.objects_per_slab()).rev() {
+            let object = block.add(self.first_object_offset() + i * self.object_size);
+            let object = object as *mut FreeObject;
+            object.write(FreeObject { next: free_objects });
+            free_objects = object;
+        }
+        slab.write(Slab {
+            next: null_mut(),
+            prev: null_mut(),
+            free_objects,
+            num_free: self.objects_per_slab(),
+        });
+        slab
+    }
+    fn link_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = null_mut();
+            (*slab).next = self.partial;
+            if let Some(head) = self.partial.as_mut() {
+                head.prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+    fn unlink_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            let Slab { next, prev, .. } = slab.read();
+            if let Some(next) = next.as_mut() {
+                next.prev = prev;
+            }
+            match prev.as_mut() {
+                Some(prev) => prev.next = next,
+                None => self.partial = next,
+            }
+        }
+    }
+}
+
+struct Heap {
+    // Sorted by address
+    zones: *mut Zone,
+    caches: [SizeClassCache; NUM_SIZE_CLASSES],
+}
+impl Heap {
+    const fn new() -> Self {
+        let mut caches = [SizeClassCache::new(0); NUM_SIZE_CLASSES];
+        let mut i = 0;
+        while i < NUM_SIZE_CLASSES {
+            caches[i] = SizeClassCache::new(SIZE_CLASSES[i]);
+            i += 1;
+        }
+        Self {
+            zones: null_mut(),
+            caches,
+        }
+    }
+    fn zones(&self) -> ZoneIterator {
+        ZoneIterator { next: self.zones }
+    }
+    fn zone_of(&self, pfn: usize) -> Option<&'static mut Zone> {
+        self.zones().find(|z| z.contains(pfn))
+    }
+    fn add_zone(&mut self, zone: *mut Zone) {
+        let mut link = &mut self.zones;
+        unsafe {
+            while let Some(z) = link.as_mut() {
+                if z.first_pfn > (*zone).first_pfn {
+                    break;
+                }
+                link = &mut z.next;
+            }
+            (*zone).next = *link;
+        }
+        *link = zone;
+    }
+    fn alloc_block(&mut self, order: usize, end_pfn_limit: usize) -> Option<usize> {
+        // Prefer splitting the smallest block available in any zone to keep
+        // larger blocks intact.
+        for from_order in order..=MAX_ORDER {
+            for zone in self.zones().take_while(|z| z.first_pfn < end_pfn_limit) {
+                if let Some(pfn) = zone.take_block(order, from_order, end_pfn_limit) {
+                    return Some(pfn);
+                }
+            }
+        }
+        None
+    }
+    fn alloc_pages(
+        &mut self,
+        num_pages: usize,
+        align_pages: usize,
+        end_pfn_limit: usize,
+    ) -> *mut u8 {
+        let Some(order) = order_for_pages(max(num_pages, align_pages)) else {
+            return null_mut();
+        };
+        let Some(pfn) = self.alloc_block(order, end_pfn_limit) else {
+            return null_mut();
+        };
+        // Give back the tail of the block that is not needed.
+        if let Some(zone) = self.zone_of(pfn) {
+            zone.free_range(pfn + num_pages, (1 << order) - num_pages);
+        }
+        pfn_to_ptr(pfn)
+    }
+    fn free_pages(&mut self, ptr: *mut u8, num_pages: usize) {
+        let pfn = ptr_to_pfn(ptr);
+        self.zone_of(pfn)
+            .expect("Freeing pages that are not in any zone")
+            .free_range(pfn, num_pages);
+    }
+    fn alloc_object(&mut self, class: usize) -> *mut u8 {
+        let cache = &mut self.caches[class];
+        let slab = if !cache.partial.is_null() {
+            cache.partial
+        } else if !cache.empty.is_null() {
+            let slab = cache.empty;
+            cache.empty = null_mut();
+            cache.link_partial(slab);
+            slab
         } else {
-            // Each char represents 32-byte chunks.
-            //
-            // |-----|----------------- self ---------|----------
-            // |-----|----------------------          |----------
-            //                                        ^ self.end_addr()
-            //                              |-------|-
-            //                              ^ header_for_allocated
-            //                               ^ allocated_addr
-            //                                      ^ header_for_padding
-            //                                      ^
-            // header_for_allocated.end_addr() self has enough space
-            // to allocate the requested object.
-
-            // Make a Header for the allocated object
-            let mut size_used = 0;
-            let allocated_addr = (self.end_addr() - size) & !(align - 1);
-            let mut header_for_allocated =
-                unsafe { Self::new_from_addr(allocated_addr - HEADER_SIZE) };
-            header_for_allocated.is_allocated = true;
-            header_for_allocated.size = size + HEADER_SIZE;
-            size_used += header_for_allocated.size;
-            header_for_allocated.next_header = self.next_header.take();
-            if header_for_allocated.end_addr() != self.end_addr() {
-                // Make a Header for padding
-                let mut header_for_padding =
-                    unsafe { Self::new_from_addr(header_for_allocated.end_addr()) };
-                header_for_padding.is_allocated = false;
-                header_for_padding.size = self.end_addr() - header_for_allocated.end_addr();
-                size_used += header_for_padding.size;
-                header_for_padding.next_header = header_for_allocated.next_header.take();
-                header_for_allocated.next_header = Some(header_for_padding);
+            let slab_order = cache.slab_order;
+            let Some(pfn) = self.alloc_block(slab_order, usize::MAX) else {
+                return null_mut();
+            };
+            let cache = &mut self.caches[class];
+            let slab = unsafe { cache.init_slab(pfn_to_ptr(pfn)) };
+            cache.num_slabs += 1;
+            cache.num_free_objects += cache.objects_per_slab();
+            cache.link_partial(slab);
+            slab
+        };
+        let cache = &mut self.caches[class];
+        let slab = unsafe { &mut *slab };
+        let object = slab.free_objects;
+        slab.free_objects = unsafe { (*object).next };
+        slab.num_free -= 1;
+        cache.num_free_objects -= 1;
+        if slab.num_free == 0 {
+            cache.unlink_partial(slab);
+        }
+        object as *mut u8
+    }
+    fn free_object(&mut self, class: usize, object: *mut u8) {
+        let cache = &mut self.caches[class];
+        let slab = unsafe { &mut *cache.slab_of(object) };
+        let object = object as *mut FreeObject;
+        unsafe {
+            object.write(FreeObject {
+                next: slab.free_objects,
+            })
+        };
+        slab.free_objects = object;
+        slab.num_free += 1;
+        cache.num_free_objects += 1;
+        if slab.num_free == 1 {
+            cache.link_partial(slab);
+        }
+        if slab.num_free == cache.objects_per_slab() {
+            cache.unlink_partial(slab);
+            let released = core::mem::replace(&mut cache.empty, slab);
+            if !released.is_null() {
+                self.release_slab(class, released);
+            }
+        }
+    }
+    fn release_slab(&mut self, class: usize, slab: *mut Slab) {
+        let cache = &mut self.caches[class];
+        cache.num_slabs -= 1;
+        cache.num_free_objects -= cache.objects_per_slab();
+        let slab_order = cache.slab_order;
+        let pfn = ptr_to_pfn(slab as *mut u8);
+        self.zone_of(pfn)
+            .expect("Slab is not in any zone")
+            .free_block(pfn, slab_order);
+    }
+    fn shrink(&mut self) {
+        for class in 0..NUM_SIZE_CLASSES {
+            let slab = core::mem::replace(&mut self.caches[class].empty, null_mut());
+            if !slab.is_null() {
+                self.release_slab(class, slab);
+            }
+        }
+    }
+    fn num_free_pages(&self) -> usize {
+        self.zones().map(|z| z.free_pages).sum()
+    }
+    fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats::default();
+        for (s, cache) in stats.size_classes.iter_mut().zip(self.caches.iter()) {
+            *s = SizeClassStats {
+                object_size: cache.object_size,
+                num_slabs: cache.num_slabs,
+                num_objects_in_use: cache.num_slabs * cache.objects_per_slab()
+                    - cache.num_free_objects,
+                num_free_objects: cache.num_free_objects,
+            };
+        }
+        for zone in self.zones() {
+            stats.num_zones += 1;
+            stats.total_pages += zone.end_pfn - zone.first_pfn;
+            stats.free_pages += zone.free_pages;
+            if let Some(order) = zone.free_lists.iter().rposition(|b| !b.is_null()) {
+                stats.largest_free_block_pages = max(stats.largest_free_block_pages, 1 << order);
+            }
+        }
+        stats
+    }
+}
+
+struct ZoneIterator {
+    next: *mut Zone,
+}
+impl Iterator for ZoneIterator {
+    type Item = &'static mut Zone;
+    fn next(&mut self) -> Option<Self::Item> {
+        let zone = unsafe { self.next.as_mut()? };
+        self.next = zone.next;
+        Some(zone)
+    }
+}
+
+const CPU_CACHE_CAPACITY: usize = 16;
+
+/// Free objects kept by a CPU so that most of the small allocations and
+/// frees do not need to take the heap lock. Each CPU has one in its
+/// CpuData.
+pub(crate) struct CpuCache {
+    objects: [[*mut u8; CPU_CACHE_CAPACITY]; NUM_SIZE_CLASSES],
+    num_objects: [usize; NUM_SIZE_CLASSES],
+}
+impl CpuCache {
+    pub(crate) const fn new() -> Self {
+        Self {
+            objects: [[null_mut(); CPU_CACHE_CAPACITY]; NUM_SIZE_CLASSES],
+            num_objects: [0; NUM_SIZE_CLASSES],
+        }
+    }
+    fn pop(&mut self, class: usize) -> Option<*mut u8> {
+        let n = self.num_objects[class].checked_sub(1)?;
+        self.num_objects[class] = n;
+        Some(self.objects[class][n])
+    }
+    fn push(&mut self, class: usize, object: *mut u8) -> bool {
+        let n = self.num_objects[class];
+        if n >= CPU_CACHE_CAPACITY {
+            return false;
+        }
+        self.objects[class][n] = object;
+        self.num_objects[class] = n + 1;
+        true
+    }
+    /// Moves the cached objects of the class to the heap until at most
+    /// `keep` objects are left.
+    fn flush(&mut self, heap: &mut Heap, class: usize, keep: usize) {
+        while self.num_objects[class] > keep {
+            if let Some(object) = self.pop(class) {
+                heap.free_object(class, object);
             }
-            // Shrink self
-            assert!(self.size >= size_used + HEADER_SIZE);
-            self.size -= size_used;
-            self.next_header = Some(header_for_allocated);
-            Some(allocated_addr as *mut u8)
         }
     }
 }
-impl Drop for Header {
-    fn drop(&mut self) {
-        panic!("Header should not be dropped!");
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    pub object_size: usize,
+    pub num_slabs: usize,
+    /// Includes the objects kept in the per-CPU caches.
+    pub num_objects_in_use: usize,
+    pub num_free_objects: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub num_zones: usize,
+    pub total_pages: usize,
+    pub free_pages: usize,
+    pub largest_free_block_pages: usize,
+    pub size_classes: [SizeClassStats; NUM_SIZE_CLASSES],
+}
+impl HeapStats {
+    pub fn free_bytes(&self) -> usize {
+        self.free_pages * PAGE_SIZE
+    }
+    /// Bytes used by page allocations and slab objects. Free objects in
+    /// slabs are not counted as used.
+    pub fn used_bytes(&self) -> usize {
+        let free_object_bytes: usize = self
+            .size_classes
+            .iter()
+            .map(|c| c.num_free_objects * c.object_size)
+            .sum();
+        (self.total_pages - self.free_pages) * PAGE_SIZE - free_object_bytes
+    }
+    /// How much of the free memory is not in the largest free block,
+    /// from 0 (not fragmented at all) to 100.
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.free_pages == 0 {
+            return 0;
+        }
+        100 - self.largest_free_block_pages * 100 / self.free_pages
     }
 }
-impl fmt::Debug for Header {
+impl fmt::Display for HeapStats {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} KiB used, {} KiB free in {} zones ({} / {} pages free)",
+            self.used_bytes() / 1024,
+            self.free_bytes() / 1024,
+            self.num_zones,
+            self.free_pages,
+            self.total_pages
+        )?;
         write!(
             f,
-            "Header @ {:#018X} {{ size: {:#018X}, is_allocated: {} }}",
-            self as *const Header as usize,
-            self.size,
-            self.is_allocated()
-        )
+            "largest free block: {} pages, fragmentation: {}%",
+            self.largest_free_block_pages,
+            self.fragmentation_percent()
+        )?;
+        for c in self.size_classes.iter().filter(|c| c.num_slabs > 0) {
+            write!(
+                f,
+                "\n  {:4} bytes: {:6} in use, {:6} free in {} slabs",
+                c.object_size, c.num_objects_in_use, c.num_free_objects, c.num_slabs
+            )?;
+        }
+        Ok(())
     }
 }
 
-pub struct FirstFitAllocator {
-    first_header: RefCell<Option<Box<Header>>>,
+/// Buddy allocator for page-sized blocks with slab caches for small
+/// objects on top of it.
+///
+/// The heap is protected by a SpinLock so that it can be used from
+/// interrupt handlers and from any CPU. Small objects are served from a
+/// per-CPU cache first, which only needs the interrupts to be disabled.
+pub struct BuddySlabAllocator {
+    heap: SpinLock<Heap>,
 }
 
 #[global_allocator]
-pub static ALLOCATOR: FirstFitAllocator = FirstFitAllocator {
-    first_header: RefCell::new(None),
+pub static ALLOCATOR: BuddySlabAllocator = BuddySlabAllocator {
+    heap: SpinLock::new(Heap::new()),
 };
 
-unsafe impl Sync for FirstFitAllocator {}
+// SAFETY: Heap is only accessed with the lock held.
+unsafe impl Send for Heap {}
 
-unsafe impl GlobalAlloc for FirstFitAllocator {
+unsafe impl GlobalAlloc for BuddySlabAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.alloc_with_options(layout)
+        let ptr = self.alloc_with_options(layout);
+        alloc_tracker::on_alloc(ptr, layout);
+        ptr
     }
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        let mut region = Header::from_allocated_region(ptr);
-        region.is_allocated = false;
-        Box::leak(region);
-        // region is leaked here to avoid dropping the free info on the memory.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        alloc_tracker::on_dealloc(ptr);
+        match size_class_for(layout) {
+            Some(class) => self.free_object(class, ptr),
+            None => self
+                .heap
+                .lock()
+                .free_pages(ptr, Self::num_pages_for(layout)),
+        }
     }
 }
 
-impl FirstFitAllocator {
-    pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
-        let mut header = self.first_header.borrow_mut();
-        let mut header = header.deref_mut();
-        loop {
-            match header {
-                Some(e) => match e.provide(layout.size(), layout.align()) {
-                    Some(p) => break p,
-                    None => {
-                        header = e.next_header.borrow_mut();
-                        continue;
-                    }
-                },
-                None => {
-                    break null_mut::<u8>();
-                }
+impl BuddySlabAllocator {
+    fn num_pages_for(layout: Layout) -> usize {
+        (layout.size() + PAGE_SIZE - 1) / PAGE_SIZE
+    }
+    /// Returns the cache of the current CPU. The interrupts should be kept
+    /// disabled while the cache is used.
+    fn cpu_cache(_interrupts: &InterruptGuard) -> &'static mut CpuCache {
+        // SAFETY: only the current CPU accesses the cache and it can not be
+        // interrupted while holding the guard.
+        unsafe { &mut *this_cpu().allocator_cache() }
+    }
+    fn alloc_object(&self, class: usize) -> *mut u8 {
+        let interrupts = InterruptGuard::new();
+        let cache = Self::cpu_cache(&interrupts);
+        if let Some(object) = cache.pop(class) {
+            return object;
+        }
+        // Refill the half of the cache so that the next allocations are
+        // served without taking the lock.
+        let mut heap = self.heap.lock();
+        for _ in 0..CPU_CACHE_CAPACITY / 2 {
+            let object = heap.alloc_object(class);
+            if object.is_null() {
+                break;
             }
+            cache.push(class, object);
+        }
+        cache.pop(class).unwrap_or(null_mut())
+    }
+    fn free_object(&self, class: usize, object: *mut u8) {
+        let interrupts = InterruptGuard::new();
+        let cache = Self::cpu_cache(&interrupts);
+        if cache.push(class, object) {
+            return;
+        }
+        let mut heap = self.heap.lock();
+        cache.flush(&mut heap, class, CPU_CACHE_CAPACITY / 2);
+        cache.push(class, object);
+    }
+    pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
+        match size_class_for(layout) {
+            Some(class) => self.alloc_object(class),
+            None => self.heap.lock().alloc_pages(
+                Self::num_pages_for(layout),
+                max(layout.align() / PAGE_SIZE, 1),
+                usize::MAX,
+            ),
         }
     }
+    /// Allocates physically contiguous pages that end at or below
+    /// phys_limit. align should be a power of two.
+    pub fn alloc_pages(&self, num_pages: usize, align: usize, phys_limit: u64) -> *mut u8 {
+        let end_pfn_limit = usize::try_from(phys_limit / PAGE_SIZE as u64).unwrap_or(usize::MAX);
+        self.heap
+            .lock()
+            .alloc_pages(num_pages, max(align / PAGE_SIZE, 1), end_pfn_limit)
+    }
+    /// # Safety
+    /// ptr and num_pages should be the ones passed to / returned from
+    /// alloc_pages and the pages should not be used anymore.
+    pub unsafe fn free_pages(&self, ptr: *mut u8, num_pages: usize) {
+        self.heap.lock().free_pages(ptr, num_pages)
+    }
+    /// Returns the objects cached by the current CPU and the slabs cached
+    /// for reuse back to the buddy allocator. The caches of the other CPUs
+    /// are kept as is.
+    pub fn shrink(&self) {
+        let interrupts = InterruptGuard::new();
+        let mut heap = self.heap.lock();
+        let cache = Self::cpu_cache(&interrupts);
+        for class in 0..NUM_SIZE_CLASSES {
+            cache.flush(&mut heap, class, 0);
+        }
+        heap.shrink()
+    }
+    pub fn num_free_pages(&self) -> usize {
+        self.heap.lock().num_free_pages()
+    }
+    pub fn stats(&self) -> HeapStats {
+        self.heap.lock().stats()
+    }
+    /// Returns true if the page at ptr is managed by this allocator.
+    pub fn manages(&self, ptr: *const u8) -> bool {
+        self.heap
+            .lock()
+            .zone_of(ptr_to_pfn(ptr as *mut u8))
+            .is_some()
+    }
     pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) {
+        // SAFETY: CONVENTIONAL_MEMORY is not used by anyone.
+        unsafe { self.add_free_from_mmap(memory_map, &[EfiMemoryType::CONVENTIONAL_MEMORY]) };
+    }
+    /// Adds the regions of the given types as free memory.
+    /// Adjacent regions are merged into one zone.
+    ///
+    /// # Safety
+    /// Nothing in the regions of the given types should be used anymore,
+    /// and this should not be called twice for the same type.
+    pub unsafe fn add_free_from_mmap(&self, memory_map: &MemoryMapHolder, types: &[EfiMemoryType]) {
+        let mut pending: Option<Range<usize>> = None;
         for e in memory_map.iter() {
-            if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
+            if !types.contains(&e.memory_type()) {
                 continue;
             }
-            self.add_free_from_descriptor(e);
+            let start_pfn = e.physical_start() as usize / PAGE_SIZE;
+            let end_pfn = start_pfn + e.number_of_pages() as usize;
+            pending = match pending {
+                Some(range) if range.end == start_pfn => Some(range.start..end_pfn),
+                Some(range) => {
+                    self.add_free_range(range);
+                    Some(start_pfn..end_pfn)
+                }
+                None => Some(start_pfn..end_pfn),
+            }
+        }
+        if let Some(range) = pending {
+            self.add_free_range(range);
         }
     }
-    fn add_free_from_descriptor(&self, desc: &EfiMemoryDescriptor) {
-        let mut start_addr = desc.physical_start() as usize;
-        let mut size = desc.number_of_pages() as usize * 4096;
+    /// Adds num_pages pages from phys_addr as free memory.
+    ///
+    /// # Safety
+    /// The pages should not be used by anyone else.
+    pub unsafe fn add_free_region(&self, phys_addr: PhysAddr, num_pages: usize) {
+        let start_pfn = (phys_addr.as_u64() / PAGE_SIZE as u64) as usize;
+        self.add_free_range(start_pfn..start_pfn + num_pages)
+    }
+    fn add_free_range(&self, pfn_range: Range<usize>) {
         // Make sure the allocator does not include the address 0 as a free
         // area.
-        if start_addr == 0 {
-            start_addr += 4096;
-            size = size.saturating_sub(4096);
-        }
-        if size <= 4096 {
-            return;
+        let start_pfn = max(pfn_range.start, 1);
+        if let Some(zone) = unsafe { Zone::new_in_range(start_pfn, pfn_range.end) } {
+            self.heap.lock().add_zone(zone);
         }
-        let mut header = unsafe { Header::new_from_addr(start_addr) };
-        header.next_header = None;
-        header.is_allocated = false;
-        header.size = size;
-        let mut first_header = self.first_header.borrow_mut();
-        let prev_last = first_header.replace(header);
-        drop(first_header);
-        let mut header = self.first_header.borrow_mut();
-        header.as_mut().unwrap().next_header = prev_last;
-        // It's okay not to be sorted the headers at this point
-        // since all the regions written in memory maps are not contiguous
-        // so that they can't be merged anyway
     }
 }
 
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    // Do not take the lock as the error may happen while it is held.
+    match ALLOCATOR.heap.try_lock() {
+        Some(heap) => error!("Heap: {}", heap.stats()),
+        None => error!("Heap: locked"),
+    }
+    panic!("Failed to allocate {layout:?}")
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -356,4 +956,134 @@ mod test {
             }
         }
     }
+
+    #[test_case]
+    fn freed_pages_are_coalesced() {
+        ALLOCATOR.shrink();
+        let free_pages_before = ALLOCATOR.num_free_pages();
+        let layout = Layout::from_size_align(3 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let mut pointers = [null_mut::<u8>(); 64];
+        for p in pointers.iter_mut() {
+            *p = ALLOCATOR.alloc_with_options(layout);
+            assert!(!p.is_null());
+        }
+        assert_eq!(
+            ALLOCATOR.num_free_pages(),
+            free_pages_before - pointers.len() * 3
+        );
+        for p in pointers
+            .iter()
+            .step_by(2)
+            .chain(pointers.iter().skip(1).step_by(2))
+        {
+            unsafe { ALLOCATOR.dealloc(*p, layout) }
+        }
+        assert_eq!(ALLOCATOR.num_free_pages(), free_pages_before);
+        // All the freed pages should be merged back so that a block as large
+        // as all of them can be allocated again.
+        let large = Layout::from_size_align(pointers.len() * 3 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let p = ALLOCATOR.alloc_with_options(large);
+        assert!(!p.is_null());
+        unsafe { ALLOCATOR.dealloc(p, large) }
+    }
+
+    #[test_case]
+    fn cpu_cache_is_returned_by_shrink() {
+        ALLOCATOR.shrink();
+        let free_pages_before = ALLOCATOR.num_free_pages();
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let mut pointers = [null_mut::<u8>(); 3 * CPU_CACHE_CAPACITY];
+        for p in pointers.iter_mut() {
+            *p = ALLOCATOR.alloc_with_options(layout);
+            assert!(!p.is_null());
+        }
+        for p in pointers {
+            unsafe { ALLOCATOR.dealloc(p, layout) }
+        }
+        // The last freed object should be reused from the cache.
+        let p = ALLOCATOR.alloc_with_options(layout);
+        assert_eq!(p, pointers[pointers.len() - 1]);
+        unsafe { ALLOCATOR.dealloc(p, layout) }
+        ALLOCATOR.shrink();
+        assert_eq!(ALLOCATOR.num_free_pages(), free_pages_before);
+        let stats = ALLOCATOR.stats();
+        assert_eq!(stats.free_pages, free_pages_before);
+        assert!(stats.largest_free_block_pages <= stats.free_pages);
+    }
+
+    #[test_case]
+    fn heap_stats_reflect_allocations() {
+        ALLOCATOR.shrink();
+        let before = ALLOCATOR.stats();
+        let layout = Layout::from_size_align(8 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let p = ALLOCATOR.alloc_with_options(layout);
+        assert!(!p.is_null());
+        let after = ALLOCATOR.stats();
+        assert_eq!(after.free_pages, before.free_pages - 8);
+        assert_eq!(after.used_bytes(), before.used_bytes() + layout.size());
+        assert!(after.largest_free_block_pages <= after.free_pages);
+        assert!(after.fragmentation_percent() <= 100);
+        for c in after.size_classes {
+            let cache = SizeClassCache::new(c.object_size);
+            assert_eq!(
+                c.num_objects_in_use + c.num_free_objects,
+                c.num_slabs * cache.objects_per_slab()
+            );
+        }
+        unsafe { ALLOCATOR.dealloc(p, layout) }
+        assert_eq!(ALLOCATOR.stats().free_pages, before.free_pages);
+    }
+
+    // Enable the "soak" feature to keep the heap busy for hours.
+    const SOAK_ITERATIONS: usize = if cfg!(feature = "soak") {
+        1 << 36
+    } else {
+        1 << 16
+    };
+
+    #[test_case]
+    fn alloc_free_cycles_do_not_fragment_heap() {
+        let mut rng = 0x2545_f491_4f6c_dd1du64;
+        let mut next_random = move || {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng as usize
+        };
+        const NUM_SLOTS: usize = 256;
+        let mut slots = [(null_mut::<u8>(), LAYOUT_PAGE_4K); NUM_SLOTS];
+        ALLOCATOR.shrink();
+        let free_pages_before = ALLOCATOR.num_free_pages();
+        for _ in 0..SOAK_ITERATIONS {
+            let i = next_random() % NUM_SLOTS;
+            let (ptr, layout) = slots[i];
+            if ptr.is_null() {
+                let size = match next_random() % 8 {
+                    0 => next_random() % (64 * PAGE_SIZE) + 1,
+                    1..=2 => next_random() % (2 * PAGE_SIZE) + 1,
+                    _ => next_random() % 256 + 1,
+                };
+                let align = 1 << (next_random() % 13);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = ALLOCATOR.alloc_with_options(layout);
+                assert!(!ptr.is_null());
+                assert!(ptr as usize % align == 0);
+                unsafe { ptr.write_bytes(i as u8, size) };
+                slots[i] = (ptr, layout);
+            } else {
+                for k in 0..layout.size() {
+                    assert!(unsafe { *ptr.add(k) } == i as u8);
+                }
+                unsafe { ALLOCATOR.dealloc(ptr, layout) };
+                slots[i].0 = null_mut();
+            }
+        }
+        for (ptr, layout) in slots {
+            if !ptr.is_null() {
+                unsafe { ALLOCATOR.dealloc(ptr, layout) };
+            }
+        }
+        ALLOCATOR.shrink();
+        assert_eq!(ALLOCATOR.num_free_pages(), free_pages_before);
+    }
 }
@@ -0,0 +1,449 @@
+//! Interrupt controllers: the local APIC of each CPU and the I/O APICs
+//!
+//! The legacy 8259 PICs are remapped away from the exception vectors and
+//! masked, and ISA IRQs are delivered through the I/O APICs instead. The
+//! local APIC is used in x2APIC mode (MSRs) if the CPU supports it, and in
+//! xAPIC mode (MMIO) otherwise.
+//! c.f. SDM Vol.3: 11 Advanced Programmable Interrupt Controller (APIC)
+//! and the 82093AA I/O APIC datasheet.
+
+extern crate alloc;
+
+use crate::acpi::AcpiRsdpStruct;
+use crate::acpi::MadtEntry;
+use crate::acpi::MpsIntiFlags;
+use crate::addr::PhysAddr;
+use crate::info;
+use crate::mutex::SpinLock;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::read_msr;
+use crate::x86::write_io_port_u8;
+use crate::x86::write_msr;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::ops::Range;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+/// ISA IRQ n is delivered to vector ISA_IRQ_BASE_VECTOR + n.
+pub const ISA_IRQ_BASE_VECTOR: u8 = 0x20;
+pub const IRQ_TIMER: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_COM1: u8 = 4;
+/// Spurious interrupts of the local APIC, which should not be EOI-ed.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const MSR_IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const MSR_X2APIC_REGS: u32 = 0x800;
+
+// Offsets of the xAPIC registers. The x2APIC MSR of a register is at
+// MSR_X2APIC_REGS + offset / 16.
+const REG_ID: u64 = 0x20;
+const REG_VERSION: u64 = 0x30;
+const REG_TPR: u64 = 0x80;
+const REG_EOI: u64 = 0xB0;
+const REG_SVR: u64 = 0xF0;
+const REG_ESR: u64 = 0x280;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_LVT_LINT0: u64 = 0x350;
+const REG_LVT_LINT1: u64 = 0x360;
+const REG_LVT_ERROR: u64 = 0x370;
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+fn supports_x2apic() -> bool {
+    // SAFETY: CPUID leaf 1 is always available on x86_64.
+    unsafe { __cpuid(1) }.ecx & (1 << 21) != 0
+}
+
+// Virtual address of the xAPIC registers, or 0 in x2APIC mode.
+static XAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// The local APIC of the current CPU
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    xapic_base: Option<u64>,
+}
+impl LocalApic {
+    /// Should be called after init_apic().
+    pub fn current() -> Self {
+        let base = XAPIC_BASE.load(Ordering::Relaxed);
+        Self {
+            xapic_base: (base != 0).then_some(base),
+        }
+    }
+    pub fn is_x2apic(&self) -> bool {
+        self.xapic_base.is_none()
+    }
+    fn read(&self, reg: u64) -> u32 {
+        match self.xapic_base {
+            // SAFETY: the registers are mapped as uncacheable in init_apic().
+            Some(base) => unsafe { read_volatile((base + reg) as *const u32) },
+            None => read_msr(MSR_X2APIC_REGS + (reg / 16) as u32) as u32,
+        }
+    }
+    /// # Safety
+    /// Writing registers can change how interrupts are delivered.
+    unsafe fn write(&self, reg: u64, value: u32) {
+        match self.xapic_base {
+            Some(base) => write_volatile((base + reg) as *mut u32, value),
+            None => write_msr(MSR_X2APIC_REGS + (reg / 16) as u32, value as u64),
+        }
+    }
+    pub fn id(&self) -> u32 {
+        let id = self.read(REG_ID);
+        if self.is_x2apic() {
+            id
+        } else {
+            id >> 24
+        }
+    }
+    pub fn version(&self) -> u32 {
+        self.read(REG_VERSION) & 0xFF
+    }
+    /// Signals the end of the interrupt being handled.
+    pub fn eoi(&self) {
+        // SAFETY: writing 0 to EOI only completes the current interrupt.
+        unsafe { self.write(REG_EOI, 0) }
+    }
+    /// Sends an inter-processor interrupt to the CPU whose local APIC ID
+    /// is dest_apic_id, and waits until it is accepted.
+    ///
+    /// # Safety
+    /// IPIs can reset or start other CPUs, depending on the command.
+    unsafe fn send_ipi(&self, dest_apic_id: u32, command: u32) {
+        match self.xapic_base {
+            // Writing the low half sends the IPI.
+            Some(_) => {
+                self.write(REG_ICR_HIGH, dest_apic_id << 24);
+                self.write(REG_ICR_LOW, command);
+                while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    busy_loop_hint();
+                }
+            }
+            // The ICR is a single 64-bit MSR in x2APIC mode, and the
+            // delivery status is not there anymore.
+            None => write_msr(
+                MSR_X2APIC_REGS + (REG_ICR_LOW / 16) as u32,
+                (dest_apic_id as u64) << 32 | command as u64,
+            ),
+        }
+    }
+    /// Sends an INIT IPI, which resets the CPU into the wait-for-SIPI
+    /// state.
+    ///
+    /// # Safety
+    /// The CPU loses everything it was running.
+    pub unsafe fn send_init(&self, dest_apic_id: u32) {
+        self.send_ipi(dest_apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT)
+    }
+    /// Sends a startup IPI, which makes a CPU waiting for it start in real
+    /// mode at page * 0x1000.
+    ///
+    /// # Safety
+    /// The page should have the code to start the CPU.
+    pub unsafe fn send_startup(&self, dest_apic_id: u32, page: u8) {
+        self.send_ipi(
+            dest_apic_id,
+            ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32,
+        )
+    }
+    /// Enables the local APIC of the current CPU with all the local
+    /// interrupts (timer, LINT0/1 and errors) masked.
+    ///
+    /// # Safety
+    /// Interrupts may be delivered once this returns.
+    unsafe fn enable(&self) {
+        let mut base = read_msr(MSR_IA32_APIC_BASE) | APIC_BASE_GLOBAL_ENABLE;
+        if self.is_x2apic() {
+            base |= APIC_BASE_X2APIC_ENABLE;
+        }
+        write_msr(MSR_IA32_APIC_BASE, base);
+        self.write(REG_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+        for lvt in [REG_LVT_TIMER, REG_LVT_LINT0, REG_LVT_LINT1, REG_LVT_ERROR] {
+            self.write(lvt, LVT_MASKED);
+        }
+        // Clear the errors, which needs back-to-back writes.
+        self.write(REG_ESR, 0);
+        self.write(REG_ESR, 0);
+        // Accept interrupts of all priorities.
+        self.write(REG_TPR, 0);
+        self.eoi();
+    }
+}
+
+/// Enables the local APIC of the calling CPU. This is done by init_apic()
+/// for the boot CPU, and should be done by each of the other CPUs.
+pub fn init_local_apic() {
+    let lapic = LocalApic::current();
+    // SAFETY: all the local interrupts are masked.
+    unsafe { lapic.enable() };
+}
+
+/// Moves the 8259 PICs to vectors 0xF0-0xFF, so that a stray IRQ raised
+/// before masking does not look like an exception, then masks them all.
+pub fn disable_legacy_pics() {
+    const PIC1_COMMAND: u16 = 0x20;
+    const PIC1_DATA: u16 = 0x21;
+    const PIC2_COMMAND: u16 = 0xA0;
+    const PIC2_DATA: u16 = 0xA1;
+    const ICW1_INIT_WITH_ICW4: u8 = 0x11;
+    const ICW4_8086_MODE: u8 = 0x01;
+    write_io_port_u8(PIC1_COMMAND, ICW1_INIT_WITH_ICW4);
+    write_io_port_u8(PIC2_COMMAND, ICW1_INIT_WITH_ICW4);
+    // ICW2: vector offsets
+    write_io_port_u8(PIC1_DATA, 0xF0);
+    write_io_port_u8(PIC2_DATA, 0xF8);
+    // ICW3: the slave is on IRQ2 of the master
+    write_io_port_u8(PIC1_DATA, 1 << 2);
+    write_io_port_u8(PIC2_DATA, 2);
+    write_io_port_u8(PIC1_DATA, ICW4_8086_MODE);
+    write_io_port_u8(PIC2_DATA, ICW4_8086_MODE);
+    write_io_port_u8(PIC1_DATA, 0xFF);
+    write_io_port_u8(PIC2_DATA, 0xFF);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+const REDIR_POLARITY_LOW: u64 = 1 << 13;
+const REDIR_TRIGGER_LEVEL: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+
+/// Builds a redirection entry that delivers a fixed interrupt to the
+/// local APIC with dest_apic_id in physical destination mode.
+fn redirection_entry(
+    vector: u8,
+    dest_apic_id: u8,
+    trigger: TriggerMode,
+    polarity: Polarity,
+) -> u64 {
+    let mut entry = vector as u64 | (dest_apic_id as u64) << 56;
+    if trigger == TriggerMode::Level {
+        entry |= REDIR_TRIGGER_LEVEL;
+    }
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIR_POLARITY_LOW;
+    }
+    entry
+}
+
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_REDIRECTION_TABLE: u32 = 0x10;
+
+struct IoApic {
+    id: u8,
+    // Virtual address of IOREGSEL. IOWIN is at +0x10.
+    base: u64,
+    gsi_base: u32,
+    num_entries: u32,
+}
+impl IoApic {
+    /// # Safety
+    /// phys_addr should point to the registers of an I/O APIC.
+    unsafe fn new(id: u8, phys_addr: PhysAddr, gsi_base: u32) -> Result<Self> {
+        // MMIO is uncacheable in the direct map.
+        let base = phys_addr.to_virt().as_u64();
+        let mut this = Self {
+            id,
+            base,
+            gsi_base,
+            num_entries: 0,
+        };
+        this.num_entries = ((this.read(IOAPIC_REG_VERSION) >> 16) & 0xFF) + 1;
+        for i in 0..this.num_entries {
+            this.write_redirection(i, REDIR_MASKED);
+        }
+        Ok(this)
+    }
+    fn gsi_range(&self) -> Range<u32> {
+        self.gsi_base..self.gsi_base + self.num_entries
+    }
+    fn read(&self, reg: u32) -> u32 {
+        // SAFETY: the registers are mapped in new(), and the accesses are
+        // serialized by IRQ_ROUTING.
+        unsafe {
+            write_volatile(self.base as *mut u32, reg);
+            read_volatile((self.base + 0x10) as *const u32)
+        }
+    }
+    fn write(&mut self, reg: u32, value: u32) {
+        // SAFETY: same as read().
+        unsafe {
+            write_volatile(self.base as *mut u32, reg);
+            write_volatile((self.base + 0x10) as *mut u32, value);
+        }
+    }
+    fn write_redirection(&mut self, index: u32, entry: u64) {
+        let reg = IOAPIC_REG_REDIRECTION_TABLE + index * 2;
+        // Mask first so that a half-written entry never fires.
+        self.write(reg, REDIR_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+struct IsaOverride {
+    irq: u8,
+    gsi: u32,
+    flags: MpsIntiFlags,
+}
+
+struct IrqRouting {
+    io_apics: Vec<IoApic>,
+    isa_overrides: Vec<IsaOverride>,
+}
+impl IrqRouting {
+    fn io_apic_for(&mut self, gsi: u32) -> Result<(&mut IoApic, u32)> {
+        self.io_apics
+            .iter_mut()
+            .find(|a| a.gsi_range().contains(&gsi))
+            .map(|a| {
+                let index = gsi - a.gsi_base;
+                (a, index)
+            })
+            .ok_or("No I/O APIC handles the GSI")
+    }
+}
+
+static IRQ_ROUTING: SpinLock<IrqRouting> = SpinLock::new(IrqRouting {
+    io_apics: Vec::new(),
+    isa_overrides: Vec::new(),
+});
+
+/// Delivers the global system interrupt gsi to vector on the CPU whose
+/// local APIC ID is dest_apic_id.
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    dest_apic_id: u32,
+    trigger: TriggerMode,
+    polarity: Polarity,
+) -> Result<()> {
+    if vector < 0x20 {
+        return Err("Vectors below 0x20 are reserved for exceptions");
+    }
+    let dest_apic_id = u8::try_from(dest_apic_id).or(Err("APIC ID is too large for I/O APIC"))?;
+    let mut routing = IRQ_ROUTING.lock();
+    let (io_apic, index) = routing.io_apic_for(gsi)?;
+    io_apic.write_redirection(
+        index,
+        redirection_entry(vector, dest_apic_id, trigger, polarity),
+    );
+    Ok(())
+}
+
+pub fn mask_gsi(gsi: u32) -> Result<()> {
+    let mut routing = IRQ_ROUTING.lock();
+    let (io_apic, index) = routing.io_apic_for(gsi)?;
+    io_apic.write_redirection(index, REDIR_MASKED);
+    Ok(())
+}
+
+/// Delivers the ISA IRQ to ISA_IRQ_BASE_VECTOR + irq on the current CPU,
+/// following the overrides in the MADT. Returns the vector.
+pub fn route_isa_irq(irq: u8) -> Result<u8> {
+    if irq >= 16 {
+        return Err("ISA IRQ should be less than 16");
+    }
+    // ISA interrupts are edge triggered and active high unless overridden.
+    let (gsi, trigger, polarity) = IRQ_ROUTING
+        .lock()
+        .isa_overrides
+        .iter()
+        .find(|o| o.irq == irq)
+        .map(|o| {
+            let trigger = match o.flags.is_level_triggered() {
+                Some(true) => TriggerMode::Level,
+                _ => TriggerMode::Edge,
+            };
+            let polarity = match o.flags.is_active_low() {
+                Some(true) => Polarity::ActiveLow,
+                _ => Polarity::ActiveHigh,
+            };
+            (o.gsi, trigger, polarity)
+        })
+        .unwrap_or((irq as u32, TriggerMode::Edge, Polarity::ActiveHigh));
+    let vector = ISA_IRQ_BASE_VECTOR + irq;
+    route_gsi(gsi, vector, LocalApic::current().id(), trigger, polarity)?;
+    Ok(vector)
+}
+
+/// Disables the 8259 PICs, enables the local APIC of the boot CPU and
+/// masks all the inputs of the I/O APICs listed in the MADT.
+pub fn init_apic(acpi: &AcpiRsdpStruct) -> Result<()> {
+    let madt = acpi.madt().ok_or("MADT not found")?;
+    if madt.has_8259_pics() {
+        disable_legacy_pics();
+    }
+    if !supports_x2apic() {
+        let base = madt.local_apic_address().to_virt().as_u64();
+        XAPIC_BASE.store(base, Ordering::Relaxed);
+    }
+    init_local_apic();
+    let lapic = LocalApic::current();
+    info!(
+        "Local APIC: id = {}, version = {:#04X}, x2APIC: {}",
+        lapic.id(),
+        lapic.version(),
+        lapic.is_x2apic()
+    );
+    let mut routing = IRQ_ROUTING.lock();
+    for e in madt.entries() {
+        match e {
+            MadtEntry::IoApic {
+                id,
+                address,
+                gsi_base,
+            } => {
+                // SAFETY: the address is given by the firmware.
+                let io_apic = unsafe { IoApic::new(id, address, gsi_base)? };
+                info!(
+                    "I/O APIC {}: GSI {:?} at {address:#X}",
+                    io_apic.id,
+                    io_apic.gsi_range()
+                );
+                routing.io_apics.push(io_apic);
+            }
+            MadtEntry::InterruptSourceOverride { irq, gsi, flags } => {
+                info!("ISA IRQ {irq} is GSI {gsi} ({flags:?})");
+                routing.isa_overrides.push(IsaOverride { irq, gsi, flags });
+            }
+            _ => {}
+        }
+    }
+    if routing.io_apics.is_empty() {
+        return Err("No I/O APIC found");
+    }
+    Ok(())
+}
+
+#[test_case]
+fn redirection_entries_are_encoded() {
+    let entry = redirection_entry(0x21, 3, TriggerMode::Edge, Polarity::ActiveHigh);
+    assert_eq!(entry, 0x0300_0000_0000_0021);
+    let entry = redirection_entry(0x29, 0, TriggerMode::Level, Polarity::ActiveLow);
+    assert_eq!(entry, 0x29 | REDIR_TRIGGER_LEVEL | REDIR_POLARITY_LOW);
+    assert_eq!(entry & REDIR_MASKED, 0);
+}
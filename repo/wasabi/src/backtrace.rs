@@ -0,0 +1,215 @@
+//! Stack backtraces
+//!
+//! Everything is built with frame pointers (see .cargo/config.toml), so
+//! [rbp] of each frame is the saved RBP of the caller and [rbp + 8] is the
+//! return address. The chain ends with RBP = 0 at the bottom of the kernel
+//! stacks, but a corrupted frame can point anywhere, so every frame is
+//! checked before it is read.
+//!
+//! Addresses are printed relative to the image base so that they can be
+//! looked up in the binary. The kernel also has a symbol table, which
+//! scripts/embed_symbols.sh writes into its .wasabi_symbols section after
+//! linking. The table is a text starting with SYMBOL_TABLE_HEADER, followed
+//! by lines of "address size name" in hex, and ends with a NUL byte.
+
+use crate::error;
+use crate::stack::find_stack;
+use crate::x86::read_rbp;
+use crate::x86::take_current_page_table;
+use core::fmt;
+use core::str;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+/// Size of the .wasabi_symbols section reserved in the kernel
+pub const SYMBOL_TABLE_SIZE: usize = 1024 * 1024;
+const SYMBOL_TABLE_HEADER: &str = "wasabi-symbols\n";
+const MAX_FRAMES: usize = 32;
+/// A caller frame further than this is considered broken.
+const MAX_FRAME_SIZE: u64 = 0x10_0000;
+
+static IMAGE_BASE: AtomicU64 = AtomicU64::new(0);
+static SYMBOL_TABLE: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+static SYMBOL_TABLE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_image_base(image_base: u64) {
+    IMAGE_BASE.store(image_base, Ordering::SeqCst);
+}
+/// Address where the running binary is loaded
+pub fn image_base() -> u64 {
+    IMAGE_BASE.load(Ordering::SeqCst)
+}
+
+/// Uses data as the symbol table. It is ignored if nothing has been
+/// embedded there.
+pub fn set_symbol_table(data: &'static [u8]) {
+    SYMBOL_TABLE_LEN.store(data.len(), Ordering::SeqCst);
+    SYMBOL_TABLE.store(data.as_ptr() as *mut u8, Ordering::SeqCst);
+}
+pub fn symbol_table() -> Option<SymbolTable<'static>> {
+    let ptr = SYMBOL_TABLE.load(Ordering::SeqCst);
+    if ptr.is_null() {
+        return None;
+    }
+    // SAFETY: ptr and the length are from the 'static slice given to
+    // set_symbol_table().
+    let data = unsafe { core::slice::from_raw_parts(ptr, SYMBOL_TABLE_LEN.load(Ordering::SeqCst)) };
+    SymbolTable::new(data)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a> {
+    lines: &'a str,
+}
+impl<'a> SymbolTable<'a> {
+    /// Returns None if data does not have a table
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+        let lines = str::from_utf8(&data[..len]).ok()?;
+        let lines = lines.strip_prefix(SYMBOL_TABLE_HEADER)?;
+        Some(Self { lines })
+    }
+    /// Returns the name of the function that contains addr, and the offset
+    /// of addr from its start.
+    pub fn lookup(&self, addr: u64) -> Option<(&'a str, u64)> {
+        self.lines.lines().find_map(|line| {
+            let (start, line) = line.split_once(' ')?;
+            let (size, name) = line.split_once(' ')?;
+            let start = u64::from_str_radix(start, 16).ok()?;
+            let size = u64::from_str_radix(size, 16).ok()?;
+            (start..start + size)
+                .contains(&addr)
+                .then_some((name, addr - start))
+        })
+    }
+}
+
+/// Returns true if the 16 bytes at rbp can be read as a frame.
+fn is_valid_frame(rbp: u64) -> bool {
+    if rbp == 0 || rbp % 8 != 0 {
+        return false;
+    }
+    [rbp, rbp + 8].iter().all(|addr| {
+        // Registered stacks are mapped except for their guard pages, so
+        // check the page table only for the others, e.g. the stack of
+        // the firmware.
+        find_stack(*addr).is_some() || {
+            // SAFETY: the table is only read here.
+            let table = unsafe { take_current_page_table() };
+            table.translate(*addr).is_some()
+        }
+    })
+}
+
+/// Iterates the return addresses by following the saved RBPs
+pub struct StackFrames {
+    rbp: u64,
+    depth: usize,
+}
+impl StackFrames {
+    pub fn from_rbp(rbp: u64) -> Self {
+        Self { rbp, depth: 0 }
+    }
+}
+impl Iterator for StackFrames {
+    type Item = u64;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.depth >= MAX_FRAMES || !is_valid_frame(self.rbp) {
+            return None;
+        }
+        // SAFETY: the frame is checked to be mapped above.
+        let (next_rbp, ret) = unsafe {
+            let frame = self.rbp as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if ret == 0 {
+            return None;
+        }
+        // Callers are at higher addresses on the same stack.
+        self.rbp = if next_rbp > self.rbp && next_rbp - self.rbp <= MAX_FRAME_SIZE {
+            next_rbp
+        } else {
+            0
+        };
+        self.depth += 1;
+        Some(ret)
+    }
+}
+
+/// Return addresses are looked up at the byte before them, which is in
+/// the call instruction, as they can be the start of the next function if
+/// the callee does not return.
+fn describe_frame(
+    index: usize,
+    addr: u64,
+    is_return_address: bool,
+    symbols: Option<SymbolTable>,
+    f: &mut impl FnMut(fmt::Arguments),
+) {
+    let relative = addr.wrapping_sub(image_base());
+    let call_site = addr - is_return_address as u64;
+    match symbols.and_then(|s| s.lookup(call_site)) {
+        Some((name, offset)) => {
+            let offset = offset + (addr - call_site);
+            f(format_args!(
+                "#{index:<2} {addr:#018X} (image_base + {relative:#X}) {name}+{offset:#X}"
+            ))
+        }
+        None => f(format_args!(
+            "#{index:<2} {addr:#018X} (image_base + {relative:#X})"
+        )),
+    }
+}
+
+/// Calls f with a line for each frame, starting from rip if given and then
+/// the return addresses found from rbp.
+pub fn for_each_frame(rip: Option<u64>, rbp: u64, mut f: impl FnMut(fmt::Arguments)) {
+    let symbols = symbol_table();
+    if let Some(rip) = rip {
+        describe_frame(0, rip, false, symbols, &mut f);
+    }
+    let first = rip.is_some() as usize;
+    for (i, ret) in StackFrames::from_rbp(rbp).enumerate() {
+        describe_frame(first + i, ret, true, symbols, &mut f);
+    }
+}
+
+/// Prints the backtrace of a context stopped at rip, e.g. by an exception.
+pub fn print_backtrace_from(rip: u64, rbp: u64) {
+    error!("Backtrace:");
+    for_each_frame(Some(rip), rbp, |frame| error!("  {frame}"));
+}
+
+/// Prints the backtrace of the caller.
+#[inline(never)]
+pub fn print_backtrace() {
+    error!("Backtrace:");
+    for_each_frame(None, read_rbp(), |frame| error!("  {frame}"));
+}
+
+#[test_case]
+fn symbols_are_looked_up() {
+    let data = b"wasabi-symbols\nffffffff80001000 20 wasabi::f\nffffffff80001020 8 g\n\0\0";
+    let table = SymbolTable::new(data).unwrap();
+    assert_eq!(table.lookup(0xffffffff80001000), Some(("wasabi::f", 0)));
+    assert_eq!(table.lookup(0xffffffff8000101f), Some(("wasabi::f", 0x1f)));
+    assert_eq!(table.lookup(0xffffffff80001024), Some(("g", 4)));
+    assert_eq!(table.lookup(0xffffffff80001028), None);
+    assert!(SymbolTable::new(&[0; 16]).is_none());
+}
+
+#[test_case]
+fn stack_frames_are_followed() {
+    let mut frames = [0u64; 4];
+    let base = frames.as_ptr() as u64;
+    frames[0] = base + 16;
+    frames[1] = 0x1111;
+    // A frame going back to a lower address ends the walk.
+    frames[2] = base;
+    frames[3] = 0x2222;
+    assert!(StackFrames::from_rbp(base).eq([0x1111, 0x2222]));
+    assert_eq!(StackFrames::from_rbp(base + 4).count(), 0);
+    assert!(StackFrames::from_rbp(read_rbp()).next().is_some());
+}
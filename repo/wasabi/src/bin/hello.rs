@@ -0,0 +1,68 @@
+//! A user program that prints its arguments and exits. The loader reads it
+//! from EFI/wasabi/apps/ on the boot volume and the kernel runs it.
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::arch::global_asm;
+use core::ffi::CStr;
+use core::panic::PanicInfo;
+use syscall_abi::SYSCALL_EXIT;
+use syscall_abi::SYSCALL_WRITE_CONSOLE;
+
+// Only the ABI is shared with the kernel, so that this does not link it.
+#[allow(dead_code)]
+#[path = "../syscall_abi.rs"]
+mod syscall_abi;
+
+// rsp points to argc and is 16 byte aligned, which the call below keeps.
+global_asm!(
+    ".global _start",
+    "_start:",
+    "mov rdi, rsp",
+    "call {main}",
+    "ud2",
+    main = sym main,
+);
+
+fn syscall(number: u64, arg0: u64, arg1: u64) -> u64 {
+    let ret;
+    // SAFETY: the kernel only touches the memory passed to it.
+    unsafe {
+        asm!("syscall",
+            inlateout("rax") number => ret,
+            in("rdi") arg0,
+            in("rsi") arg1,
+            out("rcx") _,
+            out("r11") _,
+            options(nostack))
+    }
+    ret
+}
+fn print(s: &[u8]) {
+    syscall(SYSCALL_WRITE_CONSOLE, s.as_ptr() as u64, s.len() as u64);
+}
+fn exit(code: u64) -> ! {
+    syscall(SYSCALL_EXIT, code, 0);
+    unreachable!()
+}
+
+extern "sysv64" fn main(stack: *const u64) -> ! {
+    // SAFETY: the kernel puts argc and the argv pointers on the stack.
+    let argc = unsafe { *stack } as usize;
+    print(b"Hello from user mode!\n");
+    for i in 0..argc {
+        // SAFETY: same as above, and each argument ends with NUL.
+        let arg = unsafe { CStr::from_ptr(*stack.add(1 + i) as *const i8) };
+        print(b"arg: ");
+        print(arg.to_bytes());
+        print(b"\n");
+    }
+    exit(0)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    exit(1)
+}
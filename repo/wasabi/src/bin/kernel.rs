@@ -0,0 +1,185 @@
+#![no_std]
+#![no_main]
+#![feature(sync_unsafe_cell)]
+
+use core::cell::SyncUnsafeCell;
+use core::panic::PanicInfo;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+use wasabi::acpi::AcpiRsdpStruct;
+use wasabi::addr::set_phys_map_offset;
+use wasabi::allocator::ALLOCATOR;
+use wasabi::backtrace::print_backtrace;
+use wasabi::backtrace::set_image_base;
+use wasabi::backtrace::set_symbol_table;
+use wasabi::backtrace::SYMBOL_TABLE_SIZE;
+use wasabi::boot_info::BootApp;
+use wasabi::boot_info::BootInfo;
+use wasabi::boot_info::KernelEntry;
+use wasabi::boot_info::KERNEL_IMAGE_BASE;
+use wasabi::error;
+use wasabi::executor::sleep;
+use wasabi::executor::spawn_global;
+use wasabi::executor::spawn_shared;
+use wasabi::executor::start_global_executor;
+use wasabi::hpet::global_timestamp;
+use wasabi::info;
+use wasabi::init::init_allocator;
+use wasabi::init::init_display;
+use wasabi::init::init_hpet;
+use wasabi::init::init_interrupts;
+use wasabi::init::init_paging;
+use wasabi::init::init_pci;
+use wasabi::init::reclaim_boot_memory;
+use wasabi::panic_screen::show_panic_screen;
+use wasabi::percpu::this_cpu;
+use wasabi::print::set_global_vram;
+use wasabi::process::Process;
+use wasabi::qemu::exit_qemu;
+use wasabi::qemu::QemuExitCode;
+use wasabi::serial::SerialPort;
+use wasabi::smp::init_smp;
+use wasabi::stack::KernelStack;
+use wasabi::uefi::MemoryMapHolder;
+use wasabi::user::init_syscall;
+use wasabi::x86::hlt;
+use wasabi::x86::init_exceptions;
+use wasabi::x86::InterruptGuard;
+
+const BOOT_STACK_SIZE: usize = 1024 * 1024;
+
+const _: KernelEntry = kernel_main;
+
+static HALT_ON_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Filled by scripts/embed_symbols.sh after linking. This is never written
+/// at runtime, but is not a plain static so that the zeros here are not
+/// assumed by the compiler.
+#[used]
+#[link_section = ".wasabi_symbols"]
+static SYMBOL_TABLE: SyncUnsafeCell<[u8; SYMBOL_TABLE_SIZE]> =
+    SyncUnsafeCell::new([0; SYMBOL_TABLE_SIZE]);
+
+#[no_mangle]
+extern "sysv64" fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    // SAFETY: the loader maps all the physical memory at the offset.
+    unsafe { set_phys_map_offset(boot_info.phys_map_offset()) };
+    assert!(boot_info.is_valid(), "Boot info is broken");
+    set_image_base(KERNEL_IMAGE_BASE);
+    // SAFETY: SYMBOL_TABLE is never written at runtime.
+    set_symbol_table(unsafe { &*SYMBOL_TABLE.get() });
+    info!("Booting WasabiOS kernel...");
+    info!("cmdline: {:?}", boot_info.cmdline());
+    // Keep the panic screen instead of exiting QEMU, to read it on the
+    // display.
+    if boot_info
+        .cmdline()
+        .split_whitespace()
+        .any(|arg| arg == "panic=halt")
+    {
+        HALT_ON_PANIC.store(true, Ordering::SeqCst);
+    }
+    let mut vram = boot_info.vram();
+    init_display(&mut vram);
+    set_global_vram(vram);
+    // SAFETY: the phys map offset is set above.
+    let (memory_map, acpi) = unsafe { (boot_info.memory_map(), boot_info.acpi_rsdp()) };
+    ALLOCATOR.init_with_mmap(memory_map);
+    init_allocator(memory_map);
+    let (gdt, _idt) = init_exceptions();
+    init_syscall(&gdt);
+    init_paging(memory_map, &vram, boot_info.kernel_segments());
+    // Leave the stack given by the loader, which has no guard page.
+    let boot_stack =
+        KernelStack::alloc("boot", BOOT_STACK_SIZE).expect("Failed to alloc boot stack");
+    let apps = boot_info.apps();
+    boot_stack.switch_to(move || run_kernel(acpi, memory_map, apps))
+}
+
+fn run_kernel(
+    acpi: &'static AcpiRsdpStruct,
+    memory_map: &'static MemoryMapHolder,
+    apps: &'static [BootApp],
+) -> ! {
+    let acpi = acpi.copy_to_heap();
+    // SAFETY: the loader does not leave anything needed in boot memory, the
+    // ACPI tables are copied and everything else came from the allocator.
+    unsafe { reclaim_boot_memory(memory_map) };
+    init_interrupts(acpi);
+    init_hpet(acpi);
+    init_pci(acpi);
+    if let Err(e) = init_smp(acpi) {
+        error!("Failed to start the other CPUs: {e}");
+    }
+    for app in apps {
+        spawn_shared(async move {
+            // SAFETY: the phys map offset is set, and the loader placed the
+            // apps in LOADER_DATA, which is never reclaimed.
+            let file = unsafe { app.data() };
+            let mut process = Process::load(app.name(), file, &[app.name()], &[])?;
+            info!("Running {} on CPU {}", process.name(), this_cpu().id());
+            let exit = process.run().await?;
+            info!("{} stopped: {exit:?}", process.name());
+            Ok(())
+        });
+    }
+    let t0 = global_timestamp();
+    let task1 = async move {
+        for i in 100..=103 {
+            info!(
+                "{i} hpet.main_counter = {:?} on CPU {}",
+                global_timestamp() - t0,
+                this_cpu().id()
+            );
+            sleep(Duration::from_secs(1)).await;
+        }
+        Ok(())
+    };
+    let task2 = async move {
+        for i in 200..=203 {
+            info!(
+                "{i} hpet.main_counter = {:?} on CPU {}",
+                global_timestamp() - t0,
+                this_cpu().id()
+            );
+            sleep(Duration::from_secs(2)).await;
+        }
+        Ok(())
+    };
+    let serial_task = async {
+        let sp = SerialPort::default();
+        if let Err(e) = sp.loopback_test() {
+            error!("{e:?}");
+            return Err("serial: loopback test failed");
+        }
+        info!("Started to monitor serial port");
+        loop {
+            if let Some(v) = sp.try_read() {
+                let c = char::from_u32(v as u32);
+                info!("serial input: {v:#04X} = {c:?}");
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    };
+    spawn_shared(task1);
+    spawn_shared(task2);
+    spawn_global(serial_task);
+    start_global_executor()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // Take over the screen first, so that the logs below go to the serial
+    // port only, without waiting for the VRAM writer.
+    show_panic_screen(info);
+    error!("PANIC: {info:?}");
+    print_backtrace();
+    if HALT_ON_PANIC.load(Ordering::SeqCst) {
+        let _interrupts = InterruptGuard::new();
+        loop {
+            hlt();
+        }
+    }
+    exit_qemu(QemuExitCode::Fail);
+}
@@ -0,0 +1,169 @@
+//! Information handed over from the loader to the kernel
+//!
+//! The loader maps all the physical memory at KERNEL_PHYS_MAP_OFFSET and
+//! the kernel image at its link address (KERNEL_IMAGE_BASE and above),
+//! then calls the entry of the kernel with a pointer to BootInfo through
+//! the direct map. Everything referred from BootInfo is in LOADER_DATA,
+//! which the kernel never reuses. Addresses in BootInfo are physical, so
+//! set the phys map offset first to access them.
+
+use crate::acpi::AcpiRsdpStruct;
+use crate::addr::PhysAddr;
+use crate::result::Result;
+use crate::uefi::MemoryMapHolder;
+use crate::uefi::VramBufferInfo;
+use crate::x86::PageAttr;
+use core::slice;
+use core::str;
+
+pub const KERNEL_PHYS_MAP_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+/// Should match with the image base given to the linker in build.rs.
+pub const KERNEL_IMAGE_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+pub const MAX_KERNEL_SEGMENTS: usize = 8;
+const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"WasabiBI");
+const MAX_CMDLINE_LEN: usize = 256;
+pub const MAX_BOOT_APPS: usize = 8;
+const MAX_APP_NAME_LEN: usize = 32;
+
+/// Signature of the entry point of the kernel
+pub type KernelEntry = extern "sysv64" fn(boot_info: &'static BootInfo) -> !;
+
+/// A part of the kernel image placed by the loader
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KernelSegment {
+    pub virt: u64,
+    pub phys: PhysAddr,
+    /// Size in bytes, rounded up to pages
+    pub size: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+impl KernelSegment {
+    pub fn attr(&self) -> Result<PageAttr> {
+        match (self.writable, self.executable) {
+            (false, true) => Ok(PageAttr::READ_ONLY_KERNEL),
+            (true, false) => Ok(PageAttr::READ_WRITE_KERNEL | PageAttr::NO_EXECUTE),
+            (false, false) => Ok(PageAttr::READ_ONLY_KERNEL | PageAttr::NO_EXECUTE),
+            (true, true) => Err("A segment is both writable and executable"),
+        }
+    }
+}
+
+/// A user program read from the boot volume by the loader
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BootApp {
+    name: [u8; MAX_APP_NAME_LEN],
+    name_len: usize,
+    phys: PhysAddr,
+    size: usize,
+}
+impl BootApp {
+    pub fn new(name: &str, phys: PhysAddr, size: usize) -> Result<Self> {
+        if name.len() > MAX_APP_NAME_LEN {
+            return Err("App name is too long");
+        }
+        let mut this = Self {
+            name: [0; MAX_APP_NAME_LEN],
+            name_len: name.len(),
+            phys,
+            size,
+        };
+        this.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(this)
+    }
+    /// File name without the directory
+    pub fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+    /// Contents of the file
+    ///
+    /// # Safety
+    /// The phys map offset should be set before calling this.
+    pub unsafe fn data(&self) -> &'static [u8] {
+        slice::from_raw_parts(self.phys.as_mut_ptr::<u8>(), self.size)
+    }
+}
+#[repr(C)]
+pub struct BootInfo {
+    magic: u64,
+    phys_map_offset: u64,
+    memory_map: PhysAddr,
+    vram: VramBufferInfo,
+    acpi_rsdp: PhysAddr,
+    kernel_segments: [KernelSegment; MAX_KERNEL_SEGMENTS],
+    num_kernel_segments: usize,
+    cmdline: [u8; MAX_CMDLINE_LEN],
+    cmdline_len: usize,
+    apps: [BootApp; MAX_BOOT_APPS],
+    num_apps: usize,
+}
+impl BootInfo {
+    pub fn new(
+        memory_map: PhysAddr,
+        vram: VramBufferInfo,
+        acpi_rsdp: PhysAddr,
+        kernel_segments: &[KernelSegment],
+        cmdline: &str,
+        apps: &[BootApp],
+    ) -> Result<Self> {
+        if kernel_segments.len() > MAX_KERNEL_SEGMENTS {
+            return Err("Too many kernel segments");
+        }
+        if cmdline.len() > MAX_CMDLINE_LEN {
+            return Err("Command line is too long");
+        }
+        if apps.len() > MAX_BOOT_APPS {
+            return Err("Too many apps");
+        }
+        let mut this = Self {
+            magic: BOOT_INFO_MAGIC,
+            phys_map_offset: KERNEL_PHYS_MAP_OFFSET,
+            memory_map,
+            vram,
+            acpi_rsdp,
+            kernel_segments: [KernelSegment::default(); MAX_KERNEL_SEGMENTS],
+            num_kernel_segments: kernel_segments.len(),
+            cmdline: [0; MAX_CMDLINE_LEN],
+            cmdline_len: cmdline.len(),
+            apps: [BootApp::default(); MAX_BOOT_APPS],
+            num_apps: apps.len(),
+        };
+        this.kernel_segments[..kernel_segments.len()].copy_from_slice(kernel_segments);
+        this.cmdline[..cmdline.len()].copy_from_slice(cmdline.as_bytes());
+        this.apps[..apps.len()].copy_from_slice(apps);
+        Ok(this)
+    }
+    pub fn is_valid(&self) -> bool {
+        self.magic == BOOT_INFO_MAGIC
+    }
+    /// Virtual address where the physical address 0 is mapped
+    pub fn phys_map_offset(&self) -> u64 {
+        self.phys_map_offset
+    }
+    /// The memory map at the time the boot services were exited
+    ///
+    /// # Safety
+    /// The phys map offset should be set before calling this.
+    pub unsafe fn memory_map(&self) -> &'static MemoryMapHolder {
+        &*self.memory_map.as_mut_ptr::<MemoryMapHolder>()
+    }
+    pub fn vram(&self) -> VramBufferInfo {
+        self.vram
+    }
+    /// # Safety
+    /// The phys map offset should be set before calling this.
+    pub unsafe fn acpi_rsdp(&self) -> &'static AcpiRsdpStruct {
+        &*self.acpi_rsdp.as_mut_ptr::<AcpiRsdpStruct>()
+    }
+    pub fn kernel_segments(&self) -> &[KernelSegment] {
+        &self.kernel_segments[..self.num_kernel_segments]
+    }
+    pub fn cmdline(&self) -> &str {
+        str::from_utf8(&self.cmdline[..self.cmdline_len]).unwrap_or("")
+    }
+    pub fn apps(&self) -> &[BootApp] {
+        &self.apps[..self.num_apps]
+    }
+}
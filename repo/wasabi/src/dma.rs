@@ -0,0 +1,253 @@
+//! Memory shared with devices
+//!
+//! A physically contiguous region below 4 GiB is reserved and mapped as
+//! uncacheable once, and DmaBox / DmaSlice are carved out of it. This
+//! keeps the page table untouched on each allocation, and the other heap
+//! objects are never made uncacheable by accident.
+
+use crate::addr::PhysAddr;
+use crate::frame::FrameConstraints;
+use crate::frame::PhysFrames;
+use crate::mutex::SpinLock;
+use crate::result::Result;
+use crate::x86::available_attr;
+use crate::x86::flush_cpu_caches;
+use crate::x86::with_current_page_table;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use core::cmp::max;
+use core::marker::PhantomData;
+use core::mem::align_of;
+use core::mem::size_of;
+use core::ops::Deref;
+use core::ops::DerefMut;
+use core::ops::Range;
+use core::pin::Pin;
+use core::ptr::drop_in_place;
+use core::slice;
+
+const DMA_POOL_SIZE: usize = 1024 * 1024;
+const DMA_BLOCK_SIZE: usize = 64;
+const NUM_DMA_BLOCKS: usize = DMA_POOL_SIZE / DMA_BLOCK_SIZE;
+// Buffers of this size or smaller do not cross this boundary, as required
+// by xHCI for TRB rings and data buffers (xHCI 1.2 Table 6-1).
+const DMA_BOUNDARY: usize = 64 * 1024;
+
+/// Manages the pool with a bitmap of 64-byte blocks.
+struct DmaPool {
+    phys_base: PhysAddr,
+    used: [u64; NUM_DMA_BLOCKS / 64],
+}
+impl DmaPool {
+    fn new(phys_base: PhysAddr) -> Self {
+        Self {
+            phys_base,
+            used: [0; NUM_DMA_BLOCKS / 64],
+        }
+    }
+    fn is_used(&self, block: usize) -> bool {
+        self.used[block / 64] & (1 << (block % 64)) != 0
+    }
+    fn set_used(&mut self, blocks: Range<usize>, used: bool) {
+        for block in blocks {
+            if used {
+                self.used[block / 64] |= 1 << (block % 64);
+            } else {
+                self.used[block / 64] &= !(1 << (block % 64));
+            }
+        }
+    }
+    fn alloc(&mut self, size: usize, align: usize) -> Option<PhysAddr> {
+        let size = max(size, 1);
+        let num_blocks = size.div_ceil(DMA_BLOCK_SIZE);
+        let step = max(align, DMA_BLOCK_SIZE) / DMA_BLOCK_SIZE;
+        let first_block = (0..NUM_DMA_BLOCKS)
+            .step_by(step)
+            .take_while(|start| start + num_blocks <= NUM_DMA_BLOCKS)
+            .find(|&start| {
+                let offset = start * DMA_BLOCK_SIZE;
+                let crosses_boundary = size <= DMA_BOUNDARY
+                    && offset / DMA_BOUNDARY != (offset + size - 1) / DMA_BOUNDARY;
+                !crosses_boundary && (start..start + num_blocks).all(|b| !self.is_used(b))
+            })?;
+        self.set_used(first_block..first_block + num_blocks, true);
+        Some(self.phys_base + (first_block * DMA_BLOCK_SIZE) as u64)
+    }
+    fn free(&mut self, phys_addr: PhysAddr, size: usize) {
+        let first_block = (phys_addr - self.phys_base) as usize / DMA_BLOCK_SIZE;
+        let num_blocks = max(size.div_ceil(DMA_BLOCK_SIZE), 1);
+        self.set_used(first_block..first_block + num_blocks, false);
+    }
+}
+
+static DMA_POOL: SpinLock<Option<DmaPool>> = SpinLock::new(None);
+
+fn reserve_pool(table: &mut PML4) -> Result<DmaPool> {
+    let frames = PhysFrames::alloc(
+        DMA_POOL_SIZE / PAGE_SIZE,
+        FrameConstraints::BELOW_4G.aligned(DMA_POOL_SIZE),
+    )?;
+    table.change_attr(
+        frames.phys_addr().to_virt().as_u64(),
+        frames.end_phys_addr().to_virt().as_u64(),
+        available_attr(PageAttr::READ_WRITE_IO | PageAttr::NO_EXECUTE | PageAttr::GLOBAL),
+    )?;
+    // Write back the lines cached while the frames were cacheable so that
+    // they will not overwrite what devices write later.
+    flush_cpu_caches();
+    Ok(DmaPool::new(frames.leak()))
+}
+
+/// Reserves the DMA pool and maps it as uncacheable in the table.
+/// If this is not called, the pool is reserved on the first allocation
+/// with the current page table.
+pub fn init_dma_pool(table: &mut PML4) -> Result<()> {
+    let mut pool = DMA_POOL.lock();
+    if pool.is_none() {
+        *pool = Some(reserve_pool(table)?);
+    }
+    Ok(())
+}
+
+fn alloc_dma(size: usize, align: usize) -> Result<PhysAddr> {
+    let mut pool = DMA_POOL.lock();
+    if pool.is_none() {
+        let mut reserved = Err("DMA pool is not reserved");
+        // SAFETY: only the attributes of the frames that we own are changed.
+        unsafe { with_current_page_table(|table| reserved = reserve_pool(table)) };
+        *pool = Some(reserved?);
+    }
+    pool.as_mut()
+        .and_then(|pool| pool.alloc(size, align))
+        .ok_or("DMA pool is exhausted")
+}
+
+fn free_dma(phys_addr: PhysAddr, size: usize) {
+    if let Some(pool) = DMA_POOL.lock().as_mut() {
+        pool.free(phys_addr, size)
+    }
+}
+
+/// An object shared with devices. The object never moves, and its
+/// physical address can be given to devices.
+pub struct DmaBox<T> {
+    phys_addr: PhysAddr,
+    _phantom: PhantomData<T>,
+}
+impl<T> DmaBox<T> {
+    pub fn new(value: T) -> Result<Self> {
+        let phys_addr = alloc_dma(size_of::<T>(), align_of::<T>())?;
+        let this = Self {
+            phys_addr,
+            _phantom: PhantomData,
+        };
+        // SAFETY: the memory is allocated for T above.
+        unsafe { this.as_mut_ptr().write(value) };
+        Ok(this)
+    }
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+    fn as_mut_ptr(&self) -> *mut T {
+        self.phys_addr.as_mut_ptr()
+    }
+    pub fn as_pin_mut(&mut self) -> Pin<&mut T> {
+        // SAFETY: the object is never moved until it is dropped.
+        unsafe { Pin::new_unchecked(&mut *self.as_mut_ptr()) }
+    }
+    /// # Safety
+    /// Same rules as Pin::get_unchecked_mut() applies.
+    pub unsafe fn get_unchecked_mut(&mut self) -> &mut T {
+        &mut *self.as_mut_ptr()
+    }
+}
+impl<T> AsRef<T> for DmaBox<T> {
+    fn as_ref(&self) -> &T {
+        // SAFETY: the object is initialized in new().
+        unsafe { &*self.as_mut_ptr() }
+    }
+}
+impl<T> Drop for DmaBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: the device should not touch the object anymore at this point.
+        unsafe { drop_in_place(self.as_mut_ptr()) };
+        free_dma(self.phys_addr, size_of::<T>());
+    }
+}
+
+/// A buffer shared with devices, such as the data buffer of a transfer.
+pub struct DmaSlice<T: Copy> {
+    phys_addr: PhysAddr,
+    len: usize,
+    _phantom: PhantomData<T>,
+}
+impl<T: Copy + Default> DmaSlice<T> {
+    /// Allocates a slice of len elements filled with T::default().
+    pub fn new(len: usize) -> Result<Self> {
+        let phys_addr = alloc_dma(size_of::<T>() * len, align_of::<T>())?;
+        let mut this = Self {
+            phys_addr,
+            len,
+            _phantom: PhantomData,
+        };
+        this.fill(T::default());
+        Ok(this)
+    }
+}
+impl<T: Copy> DmaSlice<T> {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+}
+impl<T: Copy> Deref for DmaSlice<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        // SAFETY: the elements are initialized in new().
+        unsafe { slice::from_raw_parts(self.phys_addr.as_mut_ptr(), self.len) }
+    }
+}
+impl<T: Copy> DerefMut for DmaSlice<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // SAFETY: same as deref().
+        unsafe { slice::from_raw_parts_mut(self.phys_addr.as_mut_ptr(), self.len) }
+    }
+}
+impl<T: Copy> Drop for DmaSlice<T> {
+    fn drop(&mut self) {
+        free_dma(self.phys_addr, size_of::<T>() * self.len);
+    }
+}
+
+#[test_case]
+fn dma_pool_alloc_and_free() {
+    let base = PhysAddr::new(0x100_0000);
+    let mut pool = DmaPool::new(base);
+    let a = pool.alloc(1, 1).unwrap();
+    assert_eq!(a, base);
+    let b = pool.alloc(4096, 4096).unwrap();
+    assert_eq!(b.as_u64() % 4096, 0);
+    let c = pool.alloc(100, 8).unwrap();
+    assert_eq!(c, a + 64);
+    // 0x10000-byte boundaries are not crossed.
+    let d = pool.alloc(0xF000, 64).unwrap() - base;
+    assert_eq!(d / 0x10000, (d + 0xF000 - 1) / 0x10000);
+    pool.free(c, 100);
+    assert_eq!(pool.alloc(128, 64), Some(c));
+    pool.free(b, 4096);
+    assert_eq!(pool.alloc(4096, 4096), Some(b));
+    assert!(pool.alloc(DMA_POOL_SIZE, 1).is_none());
+}
+#[test_case]
+fn dma_box_and_slice() {
+    #[repr(C, align(4096))]
+    struct Aligned([u64; 2]);
+    let boxed = DmaBox::new(Aligned([1, 2])).unwrap();
+    assert_eq!(boxed.phys_addr().as_u64() % 4096, 0);
+    assert_eq!(boxed.as_ref().0, [1, 2]);
+    let mut buf = DmaSlice::<u8>::new(100).unwrap();
+    assert!(buf.iter().all(|b| *b == 0));
+    buf[99] = 1;
+    assert_eq!(buf.len(), 100);
+    assert_ne!(buf.phys_addr(), boxed.phys_addr());
+}
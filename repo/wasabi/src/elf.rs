@@ -0,0 +1,305 @@
+extern crate alloc;
+
+use crate::result::Result;
+use crate::slice::Sliceable;
+use core::mem::size_of;
+use core::ops::Range;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_MACHINE_X86_64: u16 = 0x3E;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_TYPE_DYN: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+#[derive(Debug, Copy, Clone, Default)]
+#[allow(unused)]
+#[repr(packed)]
+struct ElfHeader {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+const _: () = assert!(size_of::<ElfHeader>() == 64);
+unsafe impl Sliceable for ElfHeader {}
+
+#[derive(Debug, Copy, Clone, Default)]
+#[allow(unused)]
+#[repr(packed)]
+pub struct ProgramHeader {
+    p_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+const _: () = assert!(size_of::<ProgramHeader>() == 56);
+unsafe impl Sliceable for ProgramHeader {}
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.p_type == PT_LOAD
+    }
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+    /// Range of the segment in memory. Bytes after the file contents are
+    /// filled with zero.
+    pub fn virt_range(&self) -> Range<u64> {
+        self.vaddr..self.vaddr + self.memsz
+    }
+    pub fn file_range(&self) -> Range<usize> {
+        self.offset as usize..(self.offset + self.filesz) as usize
+    }
+    /// Returns true if the ranges fit in the file and the address space.
+    fn is_valid(&self, file_len: usize) -> bool {
+        self.filesz <= self.memsz
+            && self.vaddr.checked_add(self.memsz).is_some()
+            && self
+                .offset
+                .checked_add(self.filesz)
+                .is_some_and(|end| end <= file_len as u64)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+#[repr(packed)]
+struct DynamicEntry {
+    tag: i64,
+    value: u64,
+}
+const _: () = assert!(size_of::<DynamicEntry>() == 16);
+unsafe impl Sliceable for DynamicEntry {}
+
+#[derive(Debug, Copy, Clone, Default)]
+#[repr(packed)]
+pub struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+const _: () = assert!(size_of::<Rela>() == 24);
+unsafe impl Sliceable for Rela {}
+impl Rela {
+    /// Virtual address to apply the relocation
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+    pub fn reloc_type(&self) -> u32 {
+        self.info as u32
+    }
+    pub fn addend(&self) -> i64 {
+        self.addend
+    }
+}
+
+/// An ELF64 executable for x86_64 as it is stored in a file
+pub struct ElfImage<'a> {
+    file: &'a [u8],
+    header: ElfHeader,
+}
+impl<'a> ElfImage<'a> {
+    pub fn parse(file: &'a [u8]) -> Result<Self> {
+        let header = ElfHeader::copy_from_slice(file)?;
+        if &header.ident[0..4] != ELF_MAGIC {
+            return Err("ELF magic not found");
+        }
+        if header.ident[4] != ELF_CLASS_64 || header.ident[5] != ELF_DATA_LSB {
+            return Err("Not a 64-bit little endian ELF");
+        }
+        if header.machine != ELF_MACHINE_X86_64 {
+            return Err("Not an x86_64 ELF");
+        }
+        if header.elf_type != ELF_TYPE_EXEC && header.elf_type != ELF_TYPE_DYN {
+            return Err("Not an executable ELF");
+        }
+        if header.phentsize as usize != size_of::<ProgramHeader>() {
+            return Err("Unexpected size of program headers");
+        }
+        let phdrs_end =
+            (header.phoff as usize).checked_add(header.phnum as usize * size_of::<ProgramHeader>());
+        if phdrs_end.map_or(true, |end| file.len() < end) {
+            return Err("Program headers are truncated");
+        }
+        let this = Self { file, header };
+        if this.segments().any(|s| !s.is_valid(file.len())) {
+            return Err("Segments are truncated or out of range");
+        }
+        Ok(this)
+    }
+    pub fn entry(&self) -> u64 {
+        self.header.entry
+    }
+    /// Returns true for ET_DYN, which can be loaded at any address by
+    /// applying the relocations.
+    pub fn is_position_independent(&self) -> bool {
+        self.header.elf_type == ELF_TYPE_DYN
+    }
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.header.phnum as usize).filter_map(|i| {
+            let offset = self.header.phoff as usize + i * size_of::<ProgramHeader>();
+            ProgramHeader::copy_from_slice(&self.file[offset..]).ok()
+        })
+    }
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        self.segments().filter(|s| s.is_load())
+    }
+    /// Contents of the segment in the file
+    pub fn segment_data(&self, segment: &ProgramHeader) -> &'a [u8] {
+        &self.file[segment.file_range()]
+    }
+    fn vaddr_to_file_offset(&self, vaddr: u64) -> Option<usize> {
+        self.load_segments()
+            .find(|s| (s.vaddr..s.vaddr + s.filesz).contains(&vaddr))
+            .map(|s| (s.offset + (vaddr - s.vaddr)) as usize)
+    }
+    /// Returns the relocations listed in the dynamic section, which a
+    /// position independent executable has.
+    pub fn relocations(&self) -> Result<impl Iterator<Item = Rela> + '_> {
+        let mut rela = None;
+        let mut rela_size = 0;
+        if let Some(dynamic) = self.segments().find(|s| s.p_type == PT_DYNAMIC) {
+            for entry in self
+                .segment_data(&dynamic)
+                .chunks_exact(size_of::<DynamicEntry>())
+            {
+                let DynamicEntry { tag, value } = DynamicEntry::copy_from_slice(entry)?;
+                match tag {
+                    DT_NULL => break,
+                    DT_RELA => rela = Some(value),
+                    DT_RELASZ => rela_size = value as usize,
+                    DT_RELAENT if value as usize != size_of::<Rela>() => {
+                        return Err("Unexpected size of relocation entries")
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let relocations = match rela {
+            Some(vaddr) => {
+                let offset = self
+                    .vaddr_to_file_offset(vaddr)
+                    .ok_or("Relocations are not in any segment")?;
+                offset
+                    .checked_add(rela_size)
+                    .and_then(|end| self.file.get(offset..end))
+                    .ok_or("Relocations are truncated")?
+            }
+            None => &[],
+        };
+        Ok(relocations
+            .chunks_exact(size_of::<Rela>())
+            .filter_map(|r| Rela::copy_from_slice(r).ok()))
+    }
+}
+
+/// Builds a position independent executable linked at base, with a text
+/// segment at base + 0x1000 and a data segment at base + 0x2200 that has
+/// a relocation at base + 0x2300.
+#[cfg(test)]
+pub(crate) fn build_test_elf(base: u64) -> [u8; 0x400] {
+    fn put(image: &mut [u8], offset: usize, data: &[u8]) {
+        image[offset..offset + data.len()].copy_from_slice(data)
+    }
+    // [offset, vaddr, filesz, memsz]
+    fn put_phdr(image: &mut [u8], index: usize, p_type: u32, flags: u32, phdr: [u64; 4]) {
+        let [offset, vaddr, filesz, memsz] = phdr;
+        let base = 0x40 + index * size_of::<ProgramHeader>();
+        put(image, base, &p_type.to_le_bytes());
+        put(image, base + 4, &flags.to_le_bytes());
+        for (i, v) in [offset, vaddr, vaddr, filesz, memsz, 8].iter().enumerate() {
+            put(image, base + 8 + i * 8, &v.to_le_bytes());
+        }
+    }
+    let mut image = [0u8; 0x400];
+    put(&mut image, 0, ELF_MAGIC);
+    image[4] = ELF_CLASS_64;
+    image[5] = ELF_DATA_LSB;
+    put(&mut image, 16, &ELF_TYPE_DYN.to_le_bytes());
+    put(&mut image, 18, &ELF_MACHINE_X86_64.to_le_bytes());
+    put(&mut image, 24, &(base + 0x1000).to_le_bytes());
+    put(&mut image, 32, &0x40u64.to_le_bytes());
+    put(&mut image, 54, &56u16.to_le_bytes());
+    put(&mut image, 56, &3u16.to_le_bytes());
+    let text = [0x100, base + 0x1000, 0x100, 0x100];
+    put_phdr(&mut image, 0, PT_LOAD, PF_X, text);
+    let data = [0x200, base + 0x2200, 0x100, 0x2000];
+    put_phdr(&mut image, 1, PT_LOAD, PF_W, data);
+    let dynamic = [0x200, base + 0x2200, 0x40, 0x40];
+    put_phdr(&mut image, 2, PT_DYNAMIC, PF_W, dynamic);
+    // The dynamic section points 1 relocation at 0x280 in the file.
+    put(&mut image, 0x200, &DT_RELA.to_le_bytes());
+    put(&mut image, 0x208, &(base + 0x2280).to_le_bytes());
+    put(&mut image, 0x210, &DT_RELASZ.to_le_bytes());
+    put(&mut image, 0x218, &24u64.to_le_bytes());
+    put(&mut image, 0x220, &DT_RELAENT.to_le_bytes());
+    put(&mut image, 0x228, &24u64.to_le_bytes());
+    put(&mut image, 0x280, &(base + 0x2300).to_le_bytes());
+    put(&mut image, 0x288, &(R_X86_64_RELATIVE as u64).to_le_bytes());
+    put(&mut image, 0x290, &(base + 0x1010).to_le_bytes());
+    image
+}
+
+#[test_case]
+fn parse_elf_segments() {
+    let image = build_test_elf(0xFFFF_FFFF_8000_0000);
+    let elf = ElfImage::parse(&image).unwrap();
+    assert_eq!(elf.entry(), 0xFFFF_FFFF_8000_1000);
+    let segments: alloc::vec::Vec<ProgramHeader> = elf.load_segments().collect();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(
+        segments[0].virt_range(),
+        0xFFFF_FFFF_8000_1000..0xFFFF_FFFF_8000_1100
+    );
+    assert!(segments[0].is_executable() && !segments[0].is_writable());
+    assert_eq!(
+        segments[1].virt_range(),
+        0xFFFF_FFFF_8000_2200..0xFFFF_FFFF_8000_4200
+    );
+    assert!(!segments[1].is_executable() && segments[1].is_writable());
+    assert_eq!(elf.segment_data(&segments[1]).len(), 0x100);
+    let relocations: alloc::vec::Vec<Rela> = elf.relocations().unwrap().collect();
+    assert_eq!(relocations.len(), 1);
+    assert_eq!(relocations[0].offset(), 0xFFFF_FFFF_8000_2300);
+    assert_eq!(relocations[0].reloc_type(), R_X86_64_RELATIVE);
+    assert_eq!(relocations[0].addend(), 0xFFFF_FFFF_8000_1010u64 as i64);
+}
+#[test_case]
+fn parse_broken_elf() {
+    let mut image = build_test_elf(0xFFFF_FFFF_8000_0000);
+    assert!(ElfImage::parse(&image[..0x80]).is_err());
+    image[18] = 0x28;
+    assert!(ElfImage::parse(&image).is_err());
+    image[18] = ELF_MACHINE_X86_64 as u8;
+    image[0] = 0;
+    assert!(ElfImage::parse(&image).is_err());
+}
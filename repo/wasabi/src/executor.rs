@@ -2,6 +2,7 @@ extern crate alloc;
 use crate::hpet::global_timestamp;
 use crate::info;
 use crate::mutex::Mutex;
+use crate::percpu::this_cpu;
 use crate::result::Result;
 use crate::x86::busy_loop_hint;
 use alloc::boxed::Box;
@@ -22,8 +23,7 @@ use core::time::Duration;
 
 struct Task<T> {
     future: Pin<Box<dyn Future<Output = Result<T>>>>,
-    created_at_file: &'static str,
-    created_at_line: u32,
+    created_at: &'static Location<'static>,
 }
 impl<T> Task<T> {
     #[track_caller]
@@ -32,8 +32,7 @@ impl<T> Task<T> {
             // Pin the task here to avoid invalidating the self references used
             // in  the future
             future: Box::pin(future),
-            created_at_file: Location::caller().file(),
-            created_at_line: Location::caller().line(),
+            created_at: Location::caller(),
         }
     }
     fn poll(&mut self, context: &mut Context) -> Poll<Result<T>> {
@@ -42,7 +41,7 @@ impl<T> Task<T> {
 }
 impl<T> Debug for Task<T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Task({}:{})", self.created_at_file, self.created_at_line)
+        write!(f, "Task({})", self.created_at)
     }
 }
 
@@ -72,40 +71,47 @@ pub fn block_on<T>(future: impl Future<Output = Result<T>> + 'static) -> Result<
     }
 }
 
+/// Polls the task once, and returns it if it is not completed yet.
+fn poll_once(mut task: Task<()>) -> Option<Task<()>> {
+    let waker = no_op_waker();
+    let mut context = Context::from_waker(&waker);
+    let cpu = this_cpu();
+    cpu.set_current_task(Some(task.created_at));
+    let result = task.poll(&mut context);
+    cpu.set_current_task(None);
+    match result {
+        Poll::Ready(result) => {
+            info!("Task completed: {:?}: {:?}", task, result);
+            None
+        }
+        Poll::Pending => Some(task),
+    }
+}
+
 pub struct Executor {
-    task_queue: Option<VecDeque<Task<()>>>,
+    task_queue: VecDeque<Task<()>>,
 }
 impl Executor {
     const fn new() -> Self {
-        Self { task_queue: None }
-    }
-    fn task_queue(&mut self) -> &mut VecDeque<Task<()>> {
-        if self.task_queue.is_none() {
-            self.task_queue = Some(VecDeque::new());
+        Self {
+            task_queue: VecDeque::new(),
         }
-        self.task_queue.as_mut().unwrap()
     }
     fn enqueue(&mut self, task: Task<()>) {
-        self.task_queue().push_back(task)
+        self.task_queue.push_back(task)
     }
-    fn run(executor: &Mutex<Option<Self>>) -> ! {
+    fn run(executor: &Mutex<Self>) -> ! {
         info!("Executor starts running...");
         loop {
-            let task = executor.lock().as_mut().map(|e| e.task_queue().pop_front());
-            if let Some(Some(mut task)) = task {
-                let waker = no_op_waker();
-                let mut context = Context::from_waker(&waker);
-                match task.poll(&mut context) {
-                    Poll::Ready(result) => {
-                        info!("Task completed: {:?}: {:?}", task, result);
-                    }
-                    Poll::Pending => {
-                        if let Some(e) = executor.lock().as_mut() {
-                            e.task_queue().push_back(task)
-                        }
-                    }
+            // Release the lock before polling so that the task can spawn
+            // other tasks.
+            let task = executor.lock().task_queue.pop_front();
+            if let Some(task) = task {
+                if let Some(task) = poll_once(task) {
+                    executor.lock().enqueue(task)
                 }
             }
+            run_shared_task();
         }
     }
 }
@@ -157,13 +163,61 @@ pub async fn sleep(duration: Duration) {
     TimeoutFuture::new(duration).await
 }
 
-static GLOBAL_EXECUTOR: Mutex<Option<Executor>> = Mutex::new(None);
+static GLOBAL_EXECUTOR: Mutex<Executor> = Mutex::new(Executor::new());
 #[track_caller]
 pub fn spawn_global(future: impl Future<Output = Result<()>> + 'static) {
     let task = Task::new(future);
-    GLOBAL_EXECUTOR.lock().get_or_insert_default().enqueue(task);
+    GLOBAL_EXECUTOR.lock().enqueue(task);
 }
 pub fn start_global_executor() -> ! {
     info!("Starting global executor loop");
     Executor::run(&GLOBAL_EXECUTOR);
 }
+
+/// A task that any CPU can poll
+struct SharedTask(Task<()>);
+// SAFETY: SharedTask is made only from Send futures in spawn_shared().
+unsafe impl Send for SharedTask {}
+
+static SHARED_TASKS: Mutex<VecDeque<SharedTask>> = Mutex::new(VecDeque::new());
+
+/// Spawns a task that can run on any CPU. The boot CPU polls them between
+/// the tasks of the global executor, and the other CPUs poll only them.
+#[track_caller]
+pub fn spawn_shared(future: impl Future<Output = Result<()>> + Send + 'static) {
+    let task = SharedTask(Task::new(future));
+    SHARED_TASKS.lock().push_back(task);
+}
+fn run_shared_task() {
+    let task = SHARED_TASKS.lock().pop_front();
+    if let Some(SharedTask(task)) = task {
+        if let Some(task) = poll_once(task) {
+            SHARED_TASKS.lock().push_back(SharedTask(task))
+        }
+    }
+}
+/// Runs the tasks spawned with spawn_shared() on the calling CPU.
+pub fn run_shared_tasks() -> ! {
+    loop {
+        run_shared_task();
+        busy_loop_hint();
+    }
+}
+
+#[test_case]
+fn shared_tasks_are_polled_until_completed() {
+    use alloc::sync::Arc;
+    let num_polls = Arc::new(core::sync::atomic::AtomicUsize::new(0));
+    let counter = num_polls.clone();
+    spawn_shared(async move {
+        counter.fetch_add(1, Ordering::SeqCst);
+        yield_execution().await;
+        counter.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    });
+    run_shared_task();
+    assert_eq!(num_polls.load(Ordering::SeqCst), 1);
+    run_shared_task();
+    assert_eq!(num_polls.load(Ordering::SeqCst), 2);
+    assert!(SHARED_TASKS.lock().is_empty());
+}
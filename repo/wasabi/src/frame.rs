@@ -0,0 +1,156 @@
+use crate::addr::PhysAddr;
+use crate::addr::VirtAddr;
+use crate::allocator::ALLOCATOR;
+use crate::result::Result;
+use crate::x86::PAGE_SIZE;
+use core::cmp::max;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ptr::write_bytes;
+
+/// Constraints on the physical placement of frames.
+/// Frames are always aligned to PAGE_SIZE at least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameConstraints {
+    align: usize,
+    phys_limit: u64,
+}
+impl FrameConstraints {
+    pub const ANY: Self = Self {
+        align: PAGE_SIZE,
+        phys_limit: u64::MAX,
+    };
+    /// For devices that can only do 32-bit DMA.
+    pub const BELOW_4G: Self = Self {
+        align: PAGE_SIZE,
+        phys_limit: 1 << 32,
+    };
+    pub const fn aligned(self, align: usize) -> Self {
+        Self {
+            align: if align > PAGE_SIZE { align } else { PAGE_SIZE },
+            ..self
+        }
+    }
+    /// The frames allocated will end at or below phys_limit.
+    pub const fn below(self, phys_limit: u64) -> Self {
+        Self { phys_limit, ..self }
+    }
+}
+
+/// Physically contiguous 4 KiB frames taken from the buddy allocator,
+/// which is seeded from the UEFI memory map. Use this instead of the heap
+/// when the physical placement matters (page tables, DMA buffers, stacks).
+/// The frames are returned on drop unless leaked.
+pub struct PhysFrames {
+    phys_addr: PhysAddr,
+    num_frames: usize,
+}
+impl PhysFrames {
+    pub fn alloc(num_frames: usize, constraints: FrameConstraints) -> Result<Self> {
+        if num_frames == 0 {
+            return Err("num_frames should not be zero");
+        }
+        if !constraints.align.is_power_of_two() {
+            return Err("align should be a power of two");
+        }
+        let ptr = ALLOCATOR.alloc_pages(num_frames, constraints.align, constraints.phys_limit);
+        if ptr.is_null() {
+            return Err("No frames available for the constraints");
+        }
+        Ok(Self {
+            phys_addr: VirtAddr::from_ptr(ptr).to_phys(),
+            num_frames,
+        })
+    }
+    pub fn alloc_zeroed(num_frames: usize, constraints: FrameConstraints) -> Result<Self> {
+        let frames = Self::alloc(num_frames, constraints)?;
+        // SAFETY: the frames are owned by us and in the direct map.
+        unsafe { write_bytes(frames.as_mut_ptr::<u8>(), 0, frames.size()) };
+        Ok(frames)
+    }
+    /// # Safety
+    /// phys_addr and num_frames should be the ones returned from leak()
+    /// and no one else should own the frames.
+    pub unsafe fn from_raw(phys_addr: PhysAddr, num_frames: usize) -> Self {
+        Self {
+            phys_addr,
+            num_frames,
+        }
+    }
+    /// Gives up the ownership of the frames and returns its physical address.
+    pub fn leak(self) -> PhysAddr {
+        ManuallyDrop::new(self).phys_addr
+    }
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+    pub fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+    pub fn size(&self) -> usize {
+        self.num_frames * PAGE_SIZE
+    }
+    pub fn end_phys_addr(&self) -> PhysAddr {
+        self.phys_addr + self.size() as u64
+    }
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.phys_addr.as_mut_ptr()
+    }
+}
+impl Drop for PhysFrames {
+    fn drop(&mut self) {
+        // SAFETY: the frames were allocated by ALLOCATOR.alloc_pages
+        // and we are the only owner of them.
+        unsafe { ALLOCATOR.free_pages(self.as_mut_ptr(), self.num_frames) }
+    }
+}
+impl fmt::Debug for PhysFrames {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PhysFrames {{ {:#X}-{:#X}, {} frames }}",
+            self.phys_addr,
+            self.end_phys_addr(),
+            self.num_frames
+        )
+    }
+}
+
+/// Returns true if the frame at phys_addr came from the frame allocator
+/// (as opposed to the firmware or the loaded image).
+pub fn is_managed(phys_addr: PhysAddr) -> bool {
+    ALLOCATOR.manages(phys_addr.as_mut_ptr())
+}
+
+pub fn num_pages_for(size: usize) -> usize {
+    max(size.div_ceil(PAGE_SIZE), 1)
+}
+
+#[test_case]
+fn frames_are_aligned() {
+    for align_shift in [12, 13, 16, 21] {
+        let align = 1 << align_shift;
+        let frames = PhysFrames::alloc(3, FrameConstraints::ANY.aligned(align)).unwrap();
+        assert_eq!(frames.phys_addr().as_u64() % align as u64, 0);
+        assert_eq!(frames.size(), 3 * PAGE_SIZE);
+    }
+}
+#[test_case]
+fn frames_are_below_limit() {
+    let frames = PhysFrames::alloc(16, FrameConstraints::BELOW_4G).unwrap();
+    assert!(frames.end_phys_addr().as_u64() <= 1 << 32);
+    let limit = frames.end_phys_addr().as_u64();
+    drop(frames);
+    let frames = PhysFrames::alloc(1, FrameConstraints::ANY.below(limit)).unwrap();
+    assert!(frames.end_phys_addr().as_u64() <= limit);
+    assert!(PhysFrames::alloc(1, FrameConstraints::ANY.below(PAGE_SIZE as u64)).is_err());
+}
+#[test_case]
+fn frames_are_zeroed_and_returned() {
+    let free_pages_before = ALLOCATOR.num_free_pages();
+    let frames = PhysFrames::alloc_zeroed(5, FrameConstraints::ANY).unwrap();
+    let bytes = unsafe { core::slice::from_raw_parts(frames.as_mut_ptr::<u8>(), frames.size()) };
+    assert!(bytes.iter().all(|b| *b == 0));
+    drop(frames);
+    assert_eq!(ALLOCATOR.num_free_pages(), free_pages_before);
+}
@@ -1,3 +1,4 @@
+use crate::once::Lazy;
 use crate::result::Result;
 use core::cmp::min;
 use core::fmt;
@@ -106,36 +107,33 @@ fn draw_line<T: Bitmap>(buf: &mut T, color: u32, x0: i64, y0: i64, x1: i64, y1:
     Ok(())
 }
 
-fn lookup_font(c: char) -> Option<[[char; 8]; 16]> {
+type Glyph = [[char; 8]; 16];
+
+fn parse_font() -> [Glyph; 256] {
     const FONT_SOURCE: &str = include_str!("./font.txt");
-    static mut FONT_CACHE: Option<[[[char; 8]; 16]; 256]> = None;
-    if let Ok(c) = u8::try_from(c) {
-        let font = unsafe {
-            FONT_CACHE.get_or_insert_with(|| {
-                let mut font = [[['*'; 8]; 16]; 256];
-                let mut fi = FONT_SOURCE.split('\n');
-                while let Some(line) = fi.next() {
-                    if let Some(line) = line.strip_prefix("0x") {
-                        if let Ok(idx) = u8::from_str_radix(line, 16) {
-                            let mut glyph = [['*'; 8]; 16];
-                            for (y, line) in fi.clone().take(16).enumerate() {
-                                for (x, c) in line.chars().enumerate() {
-                                    if let Some(e) = glyph[y].get_mut(x) {
-                                        *e = c;
-                                    }
-                                }
-                            }
-                            font[idx as usize] = glyph;
+    let mut font = [[['*'; 8]; 16]; 256];
+    let mut fi = FONT_SOURCE.split('\n');
+    while let Some(line) = fi.next() {
+        if let Some(line) = line.strip_prefix("0x") {
+            if let Ok(idx) = u8::from_str_radix(line, 16) {
+                let mut glyph = [['*'; 8]; 16];
+                for (y, line) in fi.clone().take(16).enumerate() {
+                    for (x, c) in line.chars().enumerate() {
+                        if let Some(e) = glyph[y].get_mut(x) {
+                            *e = c;
                         }
                     }
                 }
-                font
-            })
-        };
-        Some(font[c as usize])
-    } else {
-        None
+                font[idx as usize] = glyph;
+            }
+        }
     }
+    font
+}
+static FONT: Lazy<[Glyph; 256]> = Lazy::new(parse_font);
+
+fn lookup_font(c: char) -> Option<Glyph> {
+    u8::try_from(c).ok().map(|c| FONT[c as usize])
 }
 
 pub fn draw_font_fg<T: Bitmap>(buf: &mut T, x: i64, y: i64, color: u32, c: char) {
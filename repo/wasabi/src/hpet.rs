@@ -1,4 +1,5 @@
-use crate::mutex::Mutex;
+use crate::once::Once;
+use crate::result::Result;
 use core::mem::size_of;
 use core::ptr::read_volatile;
 use core::ptr::write_volatile;
@@ -7,11 +8,19 @@ use core::time::Duration;
 const TIMER_CONFIG_LEVEL_TRIGGER: u64 = 1 << 1;
 const TIMER_CONFIG_INT_ENABLE: u64 = 1 << 2;
 const TIMER_CONFIG_USE_PERIODIC_MODE: u64 = 1 << 3;
+const TIMER_CAP_PERIODIC: u64 = 1 << 4;
+const TIMER_CONFIG_SET_VALUE: u64 = 1 << 6;
+const TIMER_CONFIG_ROUTE_SHIFT: u64 = 9;
+const TIMER_CONFIG_ROUTE_MASK: u64 = 0b11111 << TIMER_CONFIG_ROUTE_SHIFT;
+
+/// Interval of the interrupts from timer 0, set up by init_hpet()
+pub const HPET_TICK_INTERVAL: Duration = Duration::from_millis(10);
 
 #[repr(C)]
 struct TimerRegister {
     configuration_and_capability: u64,
-    _reserved: [u64; 3],
+    comparator_value: u64,
+    _reserved: [u64; 2],
 }
 const _: () = assert!(size_of::<TimerRegister>() == 0x20);
 impl TimerRegister {
@@ -56,7 +65,7 @@ impl Hpet {
                 config &= !(TIMER_CONFIG_INT_ENABLE
                     | TIMER_CONFIG_USE_PERIODIC_MODE
                     | TIMER_CONFIG_LEVEL_TRIGGER
-                    | (0b11111 << 9));
+                    | TIMER_CONFIG_ROUTE_MASK);
                 timer.write_config(config);
             }
             write_volatile(&mut hpet.registers.main_counter_value, 0);
@@ -72,23 +81,66 @@ impl Hpet {
         let config = read_volatile(&self.registers.configuration) | 0b01;
         write_volatile(&mut self.registers.configuration, config);
     }
+    /// Bitmap of the I/O APIC inputs that timer 0 can interrupt
+    pub fn interrupt_routes(&self) -> u32 {
+        // SAFETY: the register is always readable.
+        let config =
+            unsafe { read_volatile(&self.registers.timers[0].configuration_and_capability) };
+        (config >> 32) as u32
+    }
+    /// Makes timer 0 raise an edge triggered interrupt on the I/O APIC
+    /// input gsi every interval. The GSI should be routed beforehand.
+    pub fn start_periodic_timer(&mut self, gsi: u32, interval: Duration) -> Result<()> {
+        if gsi >= 32 || self.interrupt_routes() & (1 << gsi) == 0 {
+            return Err("Timer 0 can not interrupt the GSI");
+        }
+        let ticks = (interval.as_nanos() * self.freq as u128 / 1_000_000_000) as u64;
+        if ticks == 0 {
+            return Err("Interval is too short");
+        }
+        // SAFETY: the register is always readable.
+        let config =
+            unsafe { read_volatile(&self.registers.timers[0].configuration_and_capability) };
+        if config & TIMER_CAP_PERIODIC == 0 {
+            return Err("Timer 0 does not support the periodic mode");
+        }
+        let now = self.main_counter();
+        // SAFETY: timer 0 is not used by anything else, and the counter is
+        // stopped while it is set up.
+        unsafe {
+            self.globally_disable();
+            let timer = &mut self.registers.timers[0];
+            timer.write_config(
+                (config & !(TIMER_CONFIG_ROUTE_MASK | TIMER_CONFIG_LEVEL_TRIGGER))
+                    | (gsi as u64) << TIMER_CONFIG_ROUTE_SHIFT
+                    | TIMER_CONFIG_INT_ENABLE
+                    | TIMER_CONFIG_USE_PERIODIC_MODE
+                    | TIMER_CONFIG_SET_VALUE,
+            );
+            // The first write sets the time of the first interrupt, and the
+            // second one, allowed by SET_VALUE, sets the period.
+            write_volatile(&mut timer.comparator_value, now + ticks);
+            write_volatile(&mut timer.comparator_value, ticks);
+            self.globally_enable();
+        }
+        Ok(())
+    }
     pub fn main_counter(&self) -> u64 {
         unsafe { read_volatile(&self.registers.main_counter_value) }
     }
     pub fn freq(&self) -> u64 {
         self.freq
     }
+    fn timestamp(&self) -> Duration {
+        let ns = self.main_counter() as u128 * 1_000_000_000 / self.freq() as u128;
+        Duration::from_nanos(ns as u64)
+    }
 }
-static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
+static HPET: Once<Hpet> = Once::new();
 pub fn set_global_hpet(hpet: Hpet) {
-    assert!(HPET.lock().is_none());
-    *HPET.lock() = Some(hpet);
+    HPET.set(hpet).expect("HPET is already set");
 }
+/// Time since the HPET was started. This does not take any locks.
 pub fn global_timestamp() -> Duration {
-    if let Some(hpet) = &*HPET.lock() {
-        let ns = hpet.main_counter() as u128 * 1_000_000_000 / hpet.freq() as u128;
-        Duration::from_nanos(ns as u64)
-    } else {
-        Duration::ZERO
-    }
+    HPET.get().map(Hpet::timestamp).unwrap_or(Duration::ZERO)
 }
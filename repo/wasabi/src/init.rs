@@ -1,14 +1,31 @@
 extern crate alloc;
 
 use crate::acpi::AcpiRsdpStruct;
+use crate::addr::phys_map_offset;
+use crate::addr::PhysAddr;
+use crate::addr::VirtAddr;
 use crate::allocator::ALLOCATOR;
+use crate::apic::init_apic;
+use crate::apic::route_gsi;
+use crate::apic::route_isa_irq;
+use crate::apic::LocalApic;
+use crate::apic::Polarity;
+use crate::apic::TriggerMode;
+use crate::apic::IRQ_COM1;
+use crate::apic::IRQ_KEYBOARD;
+use crate::boot_info::KernelSegment;
+use crate::dma::init_dma_pool;
 use crate::graphics::draw_test_pattern;
 use crate::graphics::fill_rect;
 use crate::graphics::Bitmap;
 use crate::hpet::set_global_hpet;
 use crate::hpet::Hpet;
+use crate::hpet::HPET_TICK_INTERVAL;
 use crate::info;
+use crate::interrupt::alloc_vector;
 use crate::pci::Pci;
+use crate::result::Result;
+use crate::stack::enable_guard_pages;
 use crate::uefi::exit_from_efi_boot_services;
 use crate::uefi::EfiHandle;
 use crate::uefi::EfiMemoryType;
@@ -16,12 +33,17 @@ use crate::uefi::EfiMemoryType::*;
 use crate::uefi::EfiSystemTable;
 use crate::uefi::MemoryMapHolder;
 use crate::uefi::VramBufferInfo;
+use crate::warn;
+use crate::x86::available_attr;
+use crate::x86::enable_interrupts;
+use crate::x86::init_kernel_protections;
+use crate::x86::init_page_attributes;
 use crate::x86::write_cr3;
 use crate::x86::PageAttr;
 use crate::x86::PAGE_SIZE;
 use crate::x86::PML4;
-use alloc::boxed::Box;
 use core::cmp::max;
+use core::ops::Range;
 
 pub fn init_basic_runtime(
     image_handle: EfiHandle,
@@ -33,12 +55,14 @@ pub fn init_basic_runtime(
     memory_map
 }
 
-pub fn init_paging(memory_map: &MemoryMapHolder) {
-    let mut table = PML4::new();
+/// Returns the end of the physical memory to be covered by the direct map.
+/// At least the first 4 GiB are covered, which include MMIO regions.
+pub fn end_of_memory(memory_map: &MemoryMapHolder) -> u64 {
     let mut end_of_mem = 0x1_0000_0000u64;
     for e in memory_map.iter() {
         match e.memory_type() {
-            CONVENTIONAL_MEMORY | LOADER_CODE | LOADER_DATA => {
+            CONVENTIONAL_MEMORY | LOADER_CODE | LOADER_DATA | BOOT_SERVICES_CODE
+            | BOOT_SERVICES_DATA | ACPI_RECLAIM_MEMORY => {
                 end_of_mem = max(
                     end_of_mem,
                     e.physical_start() + e.number_of_pages() * (PAGE_SIZE as u64),
@@ -47,27 +71,179 @@ pub fn init_paging(memory_map: &MemoryMapHolder) {
             _ => (),
         }
     }
+    end_of_mem
+}
+
+/// Returns whether the memory of the type is RAM, which can be cached.
+/// The others, and the holes in the memory map, may be MMIO.
+fn is_ram(memory_type: EfiMemoryType) -> bool {
+    matches!(
+        memory_type,
+        CONVENTIONAL_MEMORY
+            | LOADER_CODE
+            | LOADER_DATA
+            | BOOT_SERVICES_CODE
+            | BOOT_SERVICES_DATA
+            | RUNTIME_SERVICES_CODE
+            | RUNTIME_SERVICES_DATA
+            | ACPI_RECLAIM_MEMORY
+            | ACPI_MEMORY_NVS
+    )
+}
+
+/// Maps the physical memory to the direct map. MMIO registers should not
+/// be cached, so everything is uncacheable except the RAM in memory_map.
+fn map_physical_memory(table: &mut PML4, memory_map: &MemoryMapHolder) -> Result<()> {
+    let offset = phys_map_offset();
+    let attr = |attr| available_attr(attr | PageAttr::NO_EXECUTE | PageAttr::GLOBAL);
+    table.create_mapping(
+        offset,
+        offset + end_of_memory(memory_map),
+        PhysAddr::new(0),
+        attr(PageAttr::READ_WRITE_IO),
+    )?;
+    let mut map_ram = |ram: Range<u64>| {
+        table.create_mapping(
+            offset + ram.start,
+            offset + ram.end,
+            PhysAddr::new(ram.start),
+            attr(PageAttr::READ_WRITE_KERNEL),
+        )
+    };
+    // Adjacent entries are merged to use huge pages where possible.
+    let mut ram: Option<Range<u64>> = None;
+    for e in memory_map.iter().filter(|e| is_ram(e.memory_type())) {
+        let start = e.physical_start();
+        let end = start + e.number_of_pages() * PAGE_SIZE as u64;
+        match &mut ram {
+            Some(ram) if ram.end == start => ram.end = end,
+            _ => {
+                if let Some(ram) = ram.replace(start..end) {
+                    map_ram(ram)?;
+                }
+            }
+        }
+    }
+    if let Some(ram) = ram {
+        map_ram(ram)?;
+    }
+    Ok(())
+}
+
+pub fn init_paging(
+    memory_map: &MemoryMapHolder,
+    vram: &VramBufferInfo,
+    kernel_segments: &[KernelSegment],
+) {
+    init_page_attributes();
+    let table = PML4::new().expect("Failed to allocate PML4");
+    // The tables for user mode and the APs share the kernel space with this.
     table
-        .create_mapping(0, end_of_mem, 0, PageAttr::ReadWriteKernel)
-        .expect("Failed to create initial page mapping");
-    // Unmap page 0 to detect null ptr dereference
+        .pin_kernel_space()
+        .expect("Failed to populate the kernel space");
+    // There is no identity mapping, so null pointers are never mapped.
+    map_physical_memory(table, memory_map).expect("Failed to create the direct map");
+    map_kernel_image(table, kernel_segments).expect("Failed to map the kernel image");
+    let offset = phys_map_offset();
+    let vram = vram.phys_range();
+    let vram_start = vram.start & !(PAGE_SIZE as u64 - 1);
     table
-        .create_mapping(0, 4096, 0, PageAttr::NotPresent)
-        .expect("Failed to unmap page 0");
+        .create_mapping(
+            offset + vram_start,
+            offset + vram.end,
+            PhysAddr::new(vram_start),
+            available_attr(
+                PageAttr::READ_WRITE_KERNEL | PageAttr::WRITE_COMBINING | PageAttr::NO_EXECUTE,
+            ),
+        )
+        .expect("Failed to map the frame buffer");
+    enable_guard_pages(table).expect("Failed to unmap guard pages of kernel stacks");
+    init_dma_pool(table).expect("Failed to reserve the DMA pool");
     unsafe {
-        write_cr3(Box::into_raw(table));
+        write_cr3(VirtAddr::from_ptr(table).to_phys());
     }
+    init_kernel_protections();
 }
 
+fn map_kernel_image(table: &mut PML4, segments: &[KernelSegment]) -> Result<()> {
+    for s in segments {
+        let attr = s.attr()?;
+        table.create_mapping(
+            s.virt,
+            s.virt + s.size,
+            s.phys,
+            available_attr(attr | PageAttr::GLOBAL),
+        )?;
+        info!("{:#018X}-{:#018X} {:?}", s.virt, s.virt + s.size, attr);
+    }
+    Ok(())
+}
+
+/// Returns the memory used by the firmware during boot to the allocator.
+///
+/// # Safety
+/// Nothing in BOOT_SERVICES_CODE/DATA or ACPI_RECLAIM_MEMORY should be used
+/// anymore, i.e. we should be off the firmware stack and the ACPI tables
+/// should have been copied with AcpiRsdpStruct::copy_to_heap().
+pub unsafe fn reclaim_boot_memory(memory_map: &MemoryMapHolder) {
+    let free_pages_before = ALLOCATOR.num_free_pages();
+    ALLOCATOR.add_free_from_mmap(
+        memory_map,
+        &[BOOT_SERVICES_CODE, BOOT_SERVICES_DATA, ACPI_RECLAIM_MEMORY],
+    );
+    let reclaimed_pages = ALLOCATOR.num_free_pages() - free_pages_before;
+    let reclaimed_size_mib = reclaimed_pages * PAGE_SIZE / 1024 / 1024;
+    info!("Reclaimed {reclaimed_pages} pages = {reclaimed_size_mib} MiB of boot memory");
+}
+
+/// Starts the HPET, and makes it interrupt the current CPU every
+/// HPET_TICK_INTERVAL so that hlt does not wait forever. This should be
+/// called after init_interrupts().
 pub fn init_hpet(acpi: &AcpiRsdpStruct) {
     let hpet = acpi.hpet().expect("Failed to get HPET from ACPI");
     let hpet = hpet
         .base_address()
         .expect("Failed to get HPET base address");
     info!("HPET is at {hpet:#p}");
-    let hpet = Hpet::new(hpet);
+    let mut hpet = Hpet::new(hpet);
+    if let Err(e) = start_hpet_tick(&mut hpet) {
+        warn!("HPET does not interrupt: {e}");
+    }
     set_global_hpet(hpet);
 }
+fn start_hpet_tick(hpet: &mut Hpet) -> Result<()> {
+    // The lower GSIs are usually taken by the ISA IRQs.
+    let routes = hpet.interrupt_routes();
+    let gsi = (0..32)
+        .rev()
+        .find(|gsi| routes & (1 << gsi) != 0)
+        .ok_or("No I/O APIC input is available")?;
+    // The time is read from the main counter, so the tick itself has
+    // nothing to do.
+    let vector = alloc_vector(|_| {})?;
+    route_gsi(
+        gsi,
+        vector,
+        LocalApic::current().id(),
+        TriggerMode::Edge,
+        Polarity::ActiveHigh,
+    )?;
+    hpet.start_periodic_timer(gsi, HPET_TICK_INTERVAL)?;
+    info!("HPET interrupts GSI {gsi} (vector {vector:#04X}) every {HPET_TICK_INTERVAL:?}");
+    Ok(())
+}
+
+/// Switches interrupt delivery to the APICs, routes the ISA IRQs of the
+/// keyboard and COM1, then enables interrupts.
+pub fn init_interrupts(acpi: &AcpiRsdpStruct) {
+    init_apic(acpi).expect("Failed to initialize APIC");
+    for irq in [IRQ_KEYBOARD, IRQ_COM1] {
+        let vector = route_isa_irq(irq).expect("Failed to route an ISA IRQ");
+        info!("ISA IRQ {irq} is delivered to vector {vector:#04X}");
+    }
+    // SAFETY: the IDT is loaded and all the routed vectors are handled.
+    unsafe { enable_interrupts() };
+}
 
 pub fn init_allocator(memory_map: &MemoryMapHolder) {
     let mut total_memory_pages = 0;
@@ -80,6 +256,7 @@ pub fn init_allocator(memory_map: &MemoryMapHolder) {
     }
     let total_memory_size_mib = total_memory_pages * 4096 / 1024 / 1024;
     info!("Total: {total_memory_pages} pages = {total_memory_size_mib} MiB");
+    info!("Heap: {}", ALLOCATOR.stats());
 }
 
 pub fn init_display(vram: &mut VramBufferInfo) {
@@ -89,6 +266,8 @@ pub fn init_display(vram: &mut VramBufferInfo) {
     draw_test_pattern(vram);
 }
 
+/// Probes the PCI devices and attaches the drivers. This should be called
+/// before init_smp(), since the drivers change the page table.
 pub fn init_pci(acpi: &AcpiRsdpStruct) {
     if let Some(mcfg) = acpi.mcfg() {
         for i in 0..mcfg.num_of_entries() {
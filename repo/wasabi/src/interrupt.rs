@@ -0,0 +1,141 @@
+//! Handlers of external interrupts
+//!
+//! Every vector has an entrypoint in x86.rs, and the vectors from 32 are
+//! dispatched to the handlers registered here. Handlers run with
+//! interrupts disabled on the CPU, and the EOI is sent after they return.
+
+extern crate alloc;
+
+use crate::apic::LocalApic;
+use crate::apic::ISA_IRQ_BASE_VECTOR;
+use crate::apic::SPURIOUS_VECTOR;
+use crate::mutex::SpinLock;
+use crate::result::Result;
+use crate::warn;
+use crate::x86::InterruptInfo;
+use alloc::sync::Arc;
+use core::ops::Range;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+/// Vectors handed out by alloc_vector(), e.g. for MSI. The vectors below
+/// are for the exceptions and the ISA IRQs, and the ones above are kept
+/// for IPIs and the spurious interrupts.
+pub const DYNAMIC_VECTORS: Range<u8> = 0x30..0xF0;
+
+pub type InterruptHandler = Arc<dyn Fn(&InterruptInfo) + Send + Sync>;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: SpinLock<Option<InterruptHandler>> = SpinLock::new(None);
+static HANDLERS: [SpinLock<Option<InterruptHandler>>; 256] = [NO_HANDLER; 256];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_COUNT: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; 256] = [NO_COUNT; 256];
+
+fn check_vector(vector: u8) -> Result<()> {
+    if vector < ISA_IRQ_BASE_VECTOR {
+        Err("Vectors below 0x20 are reserved for exceptions")
+    } else if vector == SPURIOUS_VECTOR {
+        Err("The spurious interrupt vector can not have a handler")
+    } else {
+        Ok(())
+    }
+}
+
+/// Registers handler for vector. Fails if the vector already has one.
+pub fn register_handler(
+    vector: u8,
+    handler: impl Fn(&InterruptInfo) + Send + Sync + 'static,
+) -> Result<()> {
+    check_vector(vector)?;
+    let mut slot = HANDLERS[vector as usize].lock();
+    if slot.is_some() {
+        return Err("The vector already has a handler");
+    }
+    *slot = Some(Arc::new(handler));
+    Ok(())
+}
+
+/// Removes the handler of vector. A call of the handler running on
+/// another CPU may still finish after this returns.
+pub fn unregister_handler(vector: u8) -> Result<()> {
+    check_vector(vector)?;
+    HANDLERS[vector as usize]
+        .lock()
+        .take()
+        .map(|_| ())
+        .ok_or("The vector has no handler")
+}
+
+/// Registers handler for a free vector in DYNAMIC_VECTORS and returns the
+/// vector. Release it with unregister_handler().
+pub fn alloc_vector(handler: impl Fn(&InterruptInfo) + Send + Sync + 'static) -> Result<u8> {
+    let handler: InterruptHandler = Arc::new(handler);
+    for vector in DYNAMIC_VECTORS {
+        let mut slot = HANDLERS[vector as usize].lock();
+        if slot.is_none() {
+            *slot = Some(handler);
+            return Ok(vector);
+        }
+    }
+    Err("No free interrupt vector")
+}
+
+/// Number of interrupts delivered to the vector so far
+pub fn interrupt_count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Calls the handler of vector. Returns false if there is none.
+fn call_handler(vector: u8, info: &InterruptInfo) -> bool {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+    // Call it outside of the lock so that the handler can unregister
+    // itself.
+    let handler = HANDLERS[vector as usize].lock().clone();
+    match handler {
+        Some(handler) => {
+            handler(info);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Called from the interrupt handler for the vectors from 32.
+pub fn handle_external_interrupt(vector: u8, info: &InterruptInfo) {
+    if vector == SPURIOUS_VECTOR {
+        return;
+    }
+    if !call_handler(vector, info) {
+        warn!("No handler for interrupt vector {vector:#04X}");
+    }
+    LocalApic::current().eoi();
+}
+
+#[test_case]
+fn handlers_are_registered_and_called() {
+    use core::sync::atomic::AtomicUsize;
+    static CALLED: AtomicUsize = AtomicUsize::new(0);
+    // SAFETY: InterruptInfo consists of integers only.
+    let info: InterruptInfo = unsafe { core::mem::zeroed() };
+    assert!(register_handler(14, |_| {}).is_err());
+    let vector = alloc_vector(|info| {
+        assert_eq!(info.error_code(), 0);
+        CALLED.fetch_add(1, Ordering::SeqCst);
+    })
+    .unwrap();
+    assert!(DYNAMIC_VECTORS.contains(&vector));
+    assert!(register_handler(vector, |_| {}).is_err());
+    let count = interrupt_count(vector);
+    assert!(call_handler(vector, &info));
+    assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+    assert_eq!(interrupt_count(vector), count + 1);
+    let other = alloc_vector(|_| {}).unwrap();
+    assert_ne!(other, vector);
+    unregister_handler(vector).unwrap();
+    unregister_handler(other).unwrap();
+    assert!(!call_handler(vector, &info));
+    assert!(unregister_handler(vector).is_err());
+    assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+}
@@ -2,15 +2,17 @@ extern crate alloc;
 
 use crate::executor::spawn_global;
 use crate::info;
+use crate::mutex::Mutex;
 use crate::result::Result;
 use crate::usb::*;
 use crate::xhci::CommandRing;
 use crate::xhci::Controller;
 use alloc::collections::BTreeSet;
+use alloc::collections::VecDeque;
 use alloc::rc::Rc;
 use alloc::vec::Vec;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KeyEvent {
     None,
     Char(char),
@@ -43,6 +45,22 @@ impl KeyEvent {
     }
 }
 
+/// Oldest events are dropped when more than this are pending.
+const MAX_PENDING_KEY_EVENTS: usize = 64;
+static PENDING_KEY_EVENTS: Mutex<VecDeque<KeyEvent>> = Mutex::new(VecDeque::new());
+
+fn push_key_event(e: KeyEvent) {
+    let mut events = PENDING_KEY_EVENTS.lock();
+    if events.len() >= MAX_PENDING_KEY_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(e);
+}
+/// Takes the oldest key press that is not taken yet.
+pub fn pop_key_event() -> Option<KeyEvent> {
+    PENDING_KEY_EVENTS.lock().pop_front()
+}
+
 pub struct UsbKeyboardDriver;
 impl UsbKeyboardDriver {
     async fn run(
@@ -80,6 +98,7 @@ impl UsbKeyboardDriver {
                 let e = KeyEvent::from_usb_key_id(*id);
                 if pressed.contains(id) {
                     info!("usb_keyboard: key down: {id} = {e:?}");
+                    push_key_event(e);
                 } else {
                     info!("usb_keyboard: key up  : {id} = {e:?}");
                 }
@@ -0,0 +1,219 @@
+//! Virtual memory regions backed on demand
+//!
+//! A LazyRegion reserves a range of kernel virtual addresses without any
+//! memory behind it. The first access to each page raises #PF, and the
+//! handler maps a zeroed frame there and returns to the faulting
+//! instruction. This is for large areas that are mostly untouched, such
+//! as per-task stacks and views of files.
+//!
+//! The handler takes the heap lock to allocate frames, so lazy regions
+//! should not be touched while the heap is locked. For the same reason,
+//! they can not back the kernel heap itself.
+//!
+//! There is no TLB shootdown yet, so the pages of a region dropped after
+//! the other CPUs start are unmapped but never freed. The other CPUs may
+//! still have them in their TLBs, and the addresses are never reused.
+
+extern crate alloc;
+
+use crate::error;
+use crate::frame::FrameConstraints;
+use crate::frame::PhysFrames;
+use crate::mutex::SpinLock;
+use crate::result::Result;
+use crate::smp::are_aps_started;
+use crate::warn;
+use crate::x86::available_attr;
+use crate::x86::take_current_page_table;
+use crate::x86::with_current_page_table;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PF_ERROR_INSTRUCTION_FETCH;
+use crate::x86::PF_ERROR_PRESENT;
+use crate::x86::PF_ERROR_USER;
+use crate::x86::PF_ERROR_WRITE;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Virtual addresses for lazy regions, between the direct map and the
+/// kernel image. Addresses are never reused.
+const LAZY_REGION_AREA: Range<u64> = 0xFFFF_C000_0000_0000..0xFFFF_E000_0000_0000;
+
+struct RegionInfo {
+    name: &'static str,
+    range: Range<u64>,
+    attr: PageAttr,
+    num_populated_pages: usize,
+}
+
+struct Regions {
+    next_start: u64,
+    regions: Vec<RegionInfo>,
+}
+impl Regions {
+    fn find_mut(&mut self, addr: u64) -> Option<&mut RegionInfo> {
+        self.regions.iter_mut().find(|r| r.range.contains(&addr))
+    }
+}
+
+static REGIONS: SpinLock<Regions> = SpinLock::new(Regions {
+    next_start: LAZY_REGION_AREA.start,
+    regions: Vec::new(),
+});
+
+/// A reserved range of virtual addresses whose pages are allocated on the
+/// first access. The pages are freed when this is dropped, unless the
+/// other CPUs have been started.
+#[derive(Debug)]
+pub struct LazyRegion {
+    range: Range<u64>,
+}
+impl LazyRegion {
+    /// Reserves size bytes (rounded up to pages) mapped with attr once
+    /// touched. An unmapped page is left after each region to catch
+    /// overruns.
+    pub fn reserve(name: &'static str, size: usize, attr: PageAttr) -> Result<Self> {
+        if !attr.contains(PageAttr::PRESENT) {
+            return Err("Lazy regions should be mapped as present");
+        }
+        let size = (size.div_ceil(PAGE_SIZE) * PAGE_SIZE) as u64;
+        if size == 0 {
+            return Err("Lazy region is empty");
+        }
+        let mut regions = REGIONS.lock();
+        let start = regions.next_start;
+        let end = start
+            .checked_add(size)
+            .filter(|end| *end < LAZY_REGION_AREA.end)
+            .ok_or("No virtual address space left for lazy regions")?;
+        regions.next_start = end + PAGE_SIZE as u64;
+        regions.regions.push(RegionInfo {
+            name,
+            range: start..end,
+            attr: available_attr(attr),
+            num_populated_pages: 0,
+        });
+        Ok(Self { range: start..end })
+    }
+    pub fn start(&self) -> u64 {
+        self.range.start
+    }
+    pub fn end(&self) -> u64 {
+        self.range.end
+    }
+    pub fn size(&self) -> usize {
+        (self.range.end - self.range.start) as usize
+    }
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.range.start as *mut T
+    }
+    /// Number of pages that have been touched so far
+    pub fn num_populated_pages(&self) -> usize {
+        REGIONS
+            .lock()
+            .find_mut(self.range.start)
+            .map(|r| r.num_populated_pages)
+            .unwrap_or(0)
+    }
+}
+impl Drop for LazyRegion {
+    fn drop(&mut self) {
+        let mut regions = REGIONS.lock();
+        regions.regions.retain(|r| r.range != self.range);
+        let range = self.range.clone();
+        // Only the TLB of this CPU is flushed, so a frame could still be
+        // reached from another CPU if it were reused.
+        let free_frames = !are_aps_started();
+        let mut num_leaked_pages = 0;
+        // SAFETY: the region is owned by self and no longer registered, so
+        // no one maps pages there anymore.
+        unsafe {
+            with_current_page_table(|table| {
+                for page in range.clone().step_by(PAGE_SIZE) {
+                    match table.virt_to_phys(page) {
+                        Some(phys) if free_frames => drop(PhysFrames::from_raw(phys, 1)),
+                        Some(_) => num_leaked_pages += 1,
+                        None => {}
+                    }
+                }
+                if let Err(e) = table.unmap(range.start, range.end) {
+                    error!("Failed to unmap a lazy region: {e}");
+                }
+            })
+        }
+        if num_leaked_pages > 0 {
+            warn!("{num_leaked_pages} pages of a lazy region are not freed since the other CPUs may use them");
+        }
+    }
+}
+
+/// Maps a zeroed frame at addr if it is in a lazy region and the access is
+/// allowed there. Returns true if the faulting access can be retried.
+/// This is called from the #PF handler with CR2 and the error code.
+pub fn handle_page_fault(addr: u64, error_code: u64) -> bool {
+    if error_code & PF_ERROR_PRESENT != 0 {
+        // Protection violation, which a new frame does not fix.
+        return false;
+    }
+    let mut regions = REGIONS.lock();
+    let Some(region) = regions.find_mut(addr) else {
+        return false;
+    };
+    let attr = region.attr;
+    if (error_code & PF_ERROR_WRITE != 0 && !attr.contains(PageAttr::WRITABLE))
+        || (error_code & PF_ERROR_USER != 0 && !attr.contains(PageAttr::USER))
+        || (error_code & PF_ERROR_INSTRUCTION_FETCH != 0 && attr.contains(PageAttr::NO_EXECUTE))
+    {
+        return false;
+    }
+    let page = addr & !(PAGE_SIZE as u64 - 1);
+    // SAFETY: only the pages in the registered lazy regions are touched,
+    // and they are serialized by the lock of REGIONS. The kernel space is
+    // pinned in init_paging(), so the mapping is seen from every table,
+    // including the ones for user mode.
+    let table = unsafe { take_current_page_table() };
+    if table.translate(page).is_some() {
+        // Already populated by another CPU.
+        return true;
+    }
+    let frame = match PhysFrames::alloc_zeroed(1, FrameConstraints::ANY) {
+        Ok(frame) => frame,
+        Err(e) => {
+            error!("Failed to populate lazy region {}: {e}", region.name);
+            return false;
+        }
+    };
+    if let Err(e) = table.create_mapping(page, page + PAGE_SIZE as u64, frame.phys_addr(), attr) {
+        error!("Failed to map a page in lazy region {}: {e}", region.name);
+        return false;
+    }
+    frame.leak();
+    region.num_populated_pages += 1;
+    // The TLB does not hold translations that were not present, so no flush
+    // is needed here.
+    true
+}
+
+#[test_case]
+fn lazy_region_is_populated_on_fault() {
+    let attr = PageAttr::READ_WRITE_KERNEL | PageAttr::NO_EXECUTE;
+    let region = LazyRegion::reserve("test", 3 * PAGE_SIZE - 1, attr).unwrap();
+    assert_eq!(region.size(), 3 * PAGE_SIZE);
+    assert_eq!(region.num_populated_pages(), 0);
+    let addr = region.start() + PAGE_SIZE as u64 + 8;
+    // The test runner has no IDT for #PF, so emulate the fault.
+    assert!(handle_page_fault(addr, PF_ERROR_WRITE));
+    assert!(!handle_page_fault(addr, PF_ERROR_PRESENT | PF_ERROR_WRITE));
+    assert!(!handle_page_fault(addr, PF_ERROR_INSTRUCTION_FETCH));
+    assert!(!handle_page_fault(region.end(), PF_ERROR_WRITE));
+    assert_eq!(region.num_populated_pages(), 1);
+    let p = addr as *mut u64;
+    // SAFETY: the page is mapped above.
+    unsafe {
+        assert_eq!(p.read_volatile(), 0);
+        p.write_volatile(42);
+        assert_eq!(p.read_volatile(), 42);
+    }
+    let other = LazyRegion::reserve("test2", PAGE_SIZE, attr).unwrap();
+    assert!(other.start() > region.end());
+}
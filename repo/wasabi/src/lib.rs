@@ -1,34 +1,59 @@
 #![no_std]
 #![feature(offset_of)]
+#![feature(asm_const)]
 #![feature(custom_test_frameworks)]
 #![feature(sync_unsafe_cell)]
 #![feature(const_caller_location)]
 #![feature(const_location_fields)]
 #![feature(option_get_or_insert_default)]
 #![feature(iter_advance_by)]
+#![feature(alloc_error_handler)]
+#![feature(panic_info_message)]
 #![test_runner(crate::test_runner::test_runner)]
 #![reexport_test_harness_main = "run_unit_tests"]
 #![no_main]
 pub mod acpi;
+pub mod addr;
+pub mod alloc_tracker;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
 pub mod bits;
+pub mod boot_info;
+pub mod dma;
+pub mod elf;
 pub mod executor;
+pub mod frame;
 pub mod graphics;
 pub mod hpet;
 pub mod init;
+pub mod interrupt;
 pub mod keyboard;
+pub mod lazy_region;
+pub mod loader;
+pub mod lock_order;
 pub mod mmio;
 pub mod mutex;
+pub mod once;
+pub mod panic_screen;
 pub mod pci;
+pub mod pe;
+pub mod percpu;
 pub mod print;
+pub mod process;
 pub mod qemu;
 pub mod range;
 pub mod result;
 pub mod serial;
 pub mod slice;
+pub mod smp;
+pub mod stack;
+pub mod syscall;
+pub mod syscall_abi;
 pub mod tablet;
 pub mod uefi;
 pub mod usb;
+pub mod user;
 pub mod volatile;
 pub mod x86;
 pub mod xhci;
@@ -0,0 +1,288 @@
+//! The loader stage
+//!
+//! BOOTX64.EFI reads the kernel ELF from the boot volume, copies its
+//! segments to LOADER_DATA frames, builds a page table that maps them at
+//! their link addresses together with the direct map, exits the boot
+//! services and jumps to the kernel with BootInfo. See boot_info.rs for
+//! what is handed over. The user programs in APPS_DIR are read as they
+//! are, and loaded by the kernel.
+
+extern crate alloc;
+
+use crate::addr::PhysAddr;
+use crate::addr::VirtAddr;
+use crate::allocator::ALLOCATOR;
+use crate::boot_info::BootApp;
+use crate::boot_info::BootInfo;
+use crate::boot_info::KernelSegment;
+use crate::boot_info::KERNEL_IMAGE_BASE;
+use crate::boot_info::KERNEL_PHYS_MAP_OFFSET;
+use crate::boot_info::MAX_BOOT_APPS;
+use crate::boot_info::MAX_KERNEL_SEGMENTS;
+use crate::elf::ElfImage;
+use crate::elf::R_X86_64_RELATIVE;
+use crate::frame::num_pages_for;
+use crate::frame::FrameConstraints;
+use crate::frame::PhysFrames;
+use crate::info;
+use crate::init::end_of_memory;
+use crate::result::Result;
+use crate::uefi::for_each_file_on_boot_volume;
+use crate::uefi::read_file_from_boot_volume;
+use crate::uefi::EfiHandle;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::EfiSystemTable;
+use crate::uefi::MemoryMapHolder;
+use crate::uefi::VramBufferInfo;
+use crate::warn;
+use crate::x86::available_attr;
+use crate::x86::init_page_attributes;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::mem::size_of;
+use core::slice;
+
+pub const KERNEL_PATH: &str = "\\EFI\\wasabi\\kernel.elf";
+pub const CMDLINE_PATH: &str = "\\EFI\\wasabi\\cmdline.txt";
+pub const APPS_DIR: &str = "\\EFI\\wasabi\\apps";
+const KERNEL_STACK_SIZE: usize = 256 * 1024;
+// For the page tables, the stack and the boot info, in addition to the
+// kernel image itself.
+const LOADER_HEAP_EXTRA_SIZE: usize = 8 * 1024 * 1024;
+
+fn page_start(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE as u64 - 1)
+}
+fn page_end(addr: u64) -> u64 {
+    page_start(addr + PAGE_SIZE as u64 - 1)
+}
+
+/// Allocates LOADER_DATA pages from the firmware and gives them to the
+/// allocator, so that the loader can use the heap and frames before
+/// exiting the boot services. The kernel never reuses LOADER_DATA, so
+/// everything allocated here stays alive after the handover.
+pub fn init_loader_heap(efi_system_table: &EfiSystemTable, elf: &ElfImage) -> Result<()> {
+    let image_size: u64 = elf
+        .load_segments()
+        .map(|s| {
+            let range = s.virt_range();
+            page_end(range.end) - page_start(range.start)
+        })
+        .sum();
+    let num_pages = num_pages_for(image_size as usize + LOADER_HEAP_EXTRA_SIZE);
+    let phys_addr = efi_system_table
+        .boot_services()
+        .allocate_pages(EfiMemoryType::LOADER_DATA, num_pages)?;
+    // SAFETY: the pages are allocated for us above.
+    unsafe { ALLOCATOR.add_free_region(phys_addr, num_pages) };
+    Ok(())
+}
+
+pub struct LoadedKernel {
+    entry: u64,
+    segments: [KernelSegment; MAX_KERNEL_SEGMENTS],
+    num_segments: usize,
+}
+impl LoadedKernel {
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+    pub fn segments(&self) -> &[KernelSegment] {
+        &self.segments[..self.num_segments]
+    }
+    fn write_u64(&self, virt: u64, value: u64) -> Result<()> {
+        let segment = self
+            .segments()
+            .iter()
+            .find(|s| s.virt <= virt && virt + 8 <= s.virt + s.size)
+            .ok_or("Relocation target is not in the kernel image")?;
+        let phys = segment.phys + (virt - segment.virt);
+        // SAFETY: the frames of the segment are owned by the kernel image.
+        unsafe { phys.as_mut_ptr::<u64>().write_unaligned(value) };
+        Ok(())
+    }
+}
+
+/// Copies the segments of the kernel to frames and applies relocations.
+/// The kernel is placed at its link address, so relocations are applied
+/// with no bias.
+pub fn load_kernel(elf: &ElfImage) -> Result<LoadedKernel> {
+    let mut kernel = LoadedKernel {
+        entry: elf.entry(),
+        segments: [KernelSegment::default(); MAX_KERNEL_SEGMENTS],
+        num_segments: 0,
+    };
+    for s in elf.load_segments() {
+        let range = s.virt_range();
+        if range.start < KERNEL_IMAGE_BASE {
+            return Err("Kernel is not linked in the higher half");
+        }
+        if kernel.num_segments >= MAX_KERNEL_SEGMENTS {
+            return Err("Too many segments in the kernel");
+        }
+        let virt = page_start(range.start);
+        if kernel
+            .segments()
+            .last()
+            .is_some_and(|prev| virt < prev.virt + prev.size)
+        {
+            return Err("Kernel segments overlap or share a page");
+        }
+        let size = page_end(range.end) - virt;
+        let frames = PhysFrames::alloc_zeroed(size as usize / PAGE_SIZE, FrameConstraints::ANY)?;
+        let data = elf.segment_data(&s);
+        let offset = (range.start - virt) as usize;
+        // SAFETY: the frames are allocated above and not shared yet.
+        let dst = unsafe { slice::from_raw_parts_mut(frames.as_mut_ptr::<u8>(), frames.size()) };
+        dst[offset..offset + data.len()].copy_from_slice(data);
+        kernel.segments[kernel.num_segments] = KernelSegment {
+            virt,
+            phys: frames.leak(),
+            size,
+            writable: s.is_writable(),
+            executable: s.is_executable(),
+        };
+        kernel.num_segments += 1;
+    }
+    for r in elf.relocations()? {
+        if r.reloc_type() != R_X86_64_RELATIVE {
+            return Err("Unsupported relocation type");
+        }
+        kernel.write_u64(r.offset(), r.addend() as u64)?;
+    }
+    Ok(kernel)
+}
+
+/// Reads the files in APPS_DIR into LOADER_DATA, which the kernel never
+/// reuses. Files that can not be read or handed over are skipped. This
+/// should be called after init_loader_heap().
+pub fn read_apps(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) -> Vec<BootApp> {
+    let mut names = Vec::new();
+    if let Err(e) = for_each_file_on_boot_volume(image_handle, efi_system_table, APPS_DIR, |name| {
+        names.push(String::from(name))
+    }) {
+        info!("No apps are loaded from {APPS_DIR}: {e}");
+    }
+    let mut apps = Vec::new();
+    for name in names {
+        if apps.len() >= MAX_BOOT_APPS {
+            warn!("Too many apps. {name} and the rest are skipped");
+            break;
+        }
+        let path = format!("{APPS_DIR}\\{name}");
+        let app = read_file_from_boot_volume(
+            image_handle,
+            efi_system_table,
+            &path,
+            EfiMemoryType::LOADER_DATA,
+        )
+        .and_then(|file| {
+            BootApp::new(
+                &name,
+                VirtAddr::from_ptr(file.as_ptr()).to_phys(),
+                file.len(),
+            )
+        });
+        match app {
+            Ok(app) => apps.push(app),
+            Err(e) => warn!("Failed to read {path}: {e}"),
+        }
+    }
+    apps
+}
+
+/// Builds the page table for the handover. The identity mapping keeps the
+/// loader running until it jumps to the kernel, which drops it in
+/// init_paging().
+fn build_page_table(
+    memory_map: &MemoryMapHolder,
+    vram: &VramBufferInfo,
+    kernel: &LoadedKernel,
+) -> Result<&'static mut PML4> {
+    let table = PML4::new()?;
+    let end_of_mem = end_of_memory(memory_map);
+    table.create_mapping(0, end_of_mem, PhysAddr::new(0), PageAttr::READ_WRITE_KERNEL)?;
+    table.create_mapping(
+        KERNEL_PHYS_MAP_OFFSET,
+        KERNEL_PHYS_MAP_OFFSET + end_of_mem,
+        PhysAddr::new(0),
+        available_attr(PageAttr::READ_WRITE_KERNEL | PageAttr::NO_EXECUTE),
+    )?;
+    let vram = vram.phys_range();
+    let vram_start = page_start(vram.start);
+    table.create_mapping(
+        KERNEL_PHYS_MAP_OFFSET + vram_start,
+        KERNEL_PHYS_MAP_OFFSET + page_end(vram.end),
+        PhysAddr::new(vram_start),
+        available_attr(
+            PageAttr::READ_WRITE_KERNEL | PageAttr::WRITE_COMBINING | PageAttr::NO_EXECUTE,
+        ),
+    )?;
+    for s in kernel.segments() {
+        table.create_mapping(s.virt, s.virt + s.size, s.phys, available_attr(s.attr()?))?;
+    }
+    Ok(table)
+}
+
+/// Hands over the machine to the kernel.
+/// This should be called after exiting the boot services.
+pub fn start_kernel(
+    memory_map: &'static MemoryMapHolder,
+    vram: VramBufferInfo,
+    acpi_rsdp: PhysAddr,
+    kernel: &LoadedKernel,
+    cmdline: &str,
+    apps: &[BootApp],
+) -> ! {
+    init_page_attributes();
+    let table = build_page_table(memory_map, &vram, kernel).expect("Failed to build page table");
+    let stack = PhysFrames::alloc_zeroed(KERNEL_STACK_SIZE / PAGE_SIZE, FrameConstraints::ANY)
+        .expect("Failed to allocate the kernel stack");
+    let stack_top = KERNEL_PHYS_MAP_OFFSET + stack.end_phys_addr().as_u64();
+    let boot_info_frames =
+        PhysFrames::alloc(num_pages_for(size_of::<BootInfo>()), FrameConstraints::ANY)
+            .expect("Failed to allocate the boot info");
+    let boot_info = BootInfo::new(
+        VirtAddr::from_ptr(memory_map).to_phys(),
+        vram,
+        acpi_rsdp,
+        kernel.segments(),
+        cmdline,
+        apps,
+    )
+    .expect("Failed to create the boot info");
+    // SAFETY: the frames are allocated for BootInfo above.
+    unsafe { boot_info_frames.as_mut_ptr::<BootInfo>().write(boot_info) };
+    let boot_info = KERNEL_PHYS_MAP_OFFSET + boot_info_frames.leak().as_u64();
+    stack.leak();
+    let table = VirtAddr::from_ptr(table).to_phys();
+    info!("Jumping to the kernel at {:#018X}", kernel.entry());
+    // SAFETY: the table maps the loader itself, the kernel, the stack and
+    // the boot info. Nothing in the loader is used after this.
+    unsafe { jump_to_kernel(table, stack_top, kernel.entry(), boot_info) }
+}
+
+/// # Safety
+/// The page table should map the code of this function at the same
+/// address, and the entry, the stack and the boot info at the given
+/// addresses.
+unsafe fn jump_to_kernel(table: PhysAddr, stack_top: u64, entry: u64, boot_info: u64) -> ! {
+    asm!(
+        "cli",
+        "mov cr3, {table}",
+        "mov rsp, {stack_top}",
+        "xor ebp, ebp",
+        "call {entry}",
+        "ud2",
+        table = in(reg) table.as_u64(),
+        stack_top = in(reg) stack_top,
+        entry = in(reg) entry,
+        in("rdi") boot_info,
+        options(noreturn)
+    )
+}
@@ -0,0 +1,244 @@
+//! Debug mode of the locks to find deadlocks
+//!
+//! While the check is enabled, every lock taken is recorded together with
+//! the locks already held by the CPU. Locks are grouped into classes by
+//! the Location where they were created, and each order seen (held class,
+//! then taken class) becomes an edge of a graph. Taking a lock that would
+//! close a cycle in the graph is reported, since two CPUs taking the locks
+//! in the different orders can deadlock, as is taking a lock that the CPU
+//! already holds.
+//!
+//! This is called from the locks themselves, so it can not use any locks
+//! or the heap. Everything is in fixed-size tables, and reports go to the
+//! serial port directly.
+
+use crate::cpu_local;
+use crate::serial::SerialPort;
+use crate::x86::busy_loop_hint;
+use core::cell::SyncUnsafeCell;
+use core::fmt::Write;
+use core::panic::Location;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+const MAX_HELD_LOCKS: usize = 16;
+const MAX_EDGES: usize = 256;
+
+type LockClass = &'static Location<'static>;
+
+#[derive(Clone, Copy)]
+struct HeldLock {
+    lock: usize,
+    class: LockClass,
+    taken_at: &'static Location<'static>,
+}
+
+#[derive(Clone, Copy)]
+struct Edge {
+    from: LockClass,
+    to: LockClass,
+    /// Where the lock of class `to` was taken while holding `from`
+    taken_at: &'static Location<'static>,
+}
+
+struct Graph {
+    edges: [Option<Edge>; MAX_EDGES],
+}
+impl Graph {
+    fn edges(&self) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().flatten()
+    }
+    fn has_edge(&self, from: LockClass, to: LockClass) -> bool {
+        self.edges().any(|e| e.from == from && e.to == to)
+    }
+    /// Returns the first edge of a path from `from` to `to`, if any.
+    fn find_path(&self, from: LockClass, to: LockClass) -> Option<Edge> {
+        // For each edge reachable from `from`, the index of the first edge
+        // of the path to it.
+        let mut first: [Option<u16>; MAX_EDGES] = [None; MAX_EDGES];
+        let mut is_updated = true;
+        while is_updated {
+            is_updated = false;
+            for (i, e) in self.edges.iter().enumerate() {
+                let Some(e) = e else {
+                    continue;
+                };
+                if first[i].is_some() {
+                    continue;
+                }
+                let path = if e.from == from {
+                    Some(i as u16)
+                } else {
+                    self.edges
+                        .iter()
+                        .zip(first.iter())
+                        .find_map(|(prev, f)| f.filter(|_| prev.is_some_and(|p| p.to == e.from)))
+                };
+                if let Some(path) = path {
+                    if e.to == to {
+                        return self.edges[path as usize];
+                    }
+                    first[i] = Some(path);
+                    is_updated = true;
+                }
+            }
+        }
+        None
+    }
+    /// The edge is dropped if the table is full, and cycles through it are
+    /// not detected.
+    fn add_edge(&mut self, edge: Edge) {
+        if let Some(slot) = self.edges.iter_mut().find(|e| e.is_none()) {
+            *slot = Some(edge)
+        }
+    }
+}
+
+static IS_CHECKING: AtomicBool = AtomicBool::new(false);
+static NUM_VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+static GRAPH_IS_TAKEN: AtomicBool = AtomicBool::new(false);
+static GRAPH: SyncUnsafeCell<Graph> = SyncUnsafeCell::new(Graph {
+    edges: [None; MAX_EDGES],
+});
+cpu_local! {
+    static HELD_LOCKS: SyncUnsafeCell<[Option<HeldLock>; MAX_HELD_LOCKS]> =
+        SyncUnsafeCell::new([None; MAX_HELD_LOCKS]);
+}
+
+/// Starts checking the order of locking. Only the locks taken after this
+/// are considered as held.
+pub fn start_lock_order_check() {
+    IS_CHECKING.store(true, Ordering::SeqCst);
+}
+pub fn stop_lock_order_check() {
+    IS_CHECKING.store(false, Ordering::SeqCst);
+}
+pub fn is_checking_lock_order() -> bool {
+    IS_CHECKING.load(Ordering::Relaxed)
+}
+/// Number of violations reported so far
+pub fn num_lock_order_violations() -> usize {
+    NUM_VIOLATIONS.load(Ordering::SeqCst)
+}
+
+/// Returns the locks held by the current CPU. Interrupts should be
+/// disabled while using it, which is the case while a lock is held.
+fn held_locks() -> &'static mut [Option<HeldLock>; MAX_HELD_LOCKS] {
+    // SAFETY: each CPU touches its own entry only, with interrupts disabled.
+    unsafe { &mut *HELD_LOCKS.get().get() }
+}
+
+fn with_graph<R>(f: impl FnOnce(&mut Graph) -> R) -> R {
+    while GRAPH_IS_TAKEN
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        busy_loop_hint();
+    }
+    // SAFETY: the access is unique while GRAPH_IS_TAKEN is true.
+    let result = f(unsafe { &mut *GRAPH.get() });
+    GRAPH_IS_TAKEN.store(false, Ordering::Release);
+    result
+}
+
+fn report(args: core::fmt::Arguments) {
+    NUM_VIOLATIONS.fetch_add(1, Ordering::SeqCst);
+    let _ = writeln!(SerialPort::default(), "[LOCK]  {args}");
+}
+
+/// Called before waiting for the lock at address lock, with interrupts
+/// disabled.
+pub(crate) fn check_lock(lock: usize, class: LockClass, taken_at: &'static Location<'static>) {
+    if !is_checking_lock_order() {
+        return;
+    }
+    let held = held_locks();
+    for h in held.iter().flatten() {
+        if h.lock == lock {
+            report(format_args!(
+                "lock created at {class} is taken at {taken_at} while it is held since {}",
+                h.taken_at
+            ));
+            continue;
+        }
+        if h.class == class {
+            // The order between the locks of the same class is unknown.
+            continue;
+        }
+        with_graph(|graph| {
+            if graph.has_edge(h.class, class) {
+                return;
+            }
+            if let Some(e) = graph.find_path(class, h.class) {
+                report(format_args!(
+                    "lock created at {class} is taken at {taken_at} while holding the lock \
+                     created at {} (taken at {}), but the reverse order was seen at {}",
+                    h.class, h.taken_at, e.taken_at
+                ));
+            }
+            graph.add_edge(Edge {
+                from: h.class,
+                to: class,
+                taken_at,
+            });
+        });
+    }
+}
+
+/// Called after the lock is taken, with interrupts disabled.
+pub(crate) fn on_locked(lock: usize, class: LockClass, taken_at: &'static Location<'static>) {
+    if !is_checking_lock_order() {
+        return;
+    }
+    let held = held_locks();
+    match held.iter_mut().find(|h| h.is_none()) {
+        Some(slot) => {
+            *slot = Some(HeldLock {
+                lock,
+                class,
+                taken_at,
+            })
+        }
+        None => report(format_args!("too many locks are held, at {taken_at}")),
+    }
+}
+
+/// Called before the lock is released, with interrupts disabled.
+pub(crate) fn on_unlocked(lock: usize) {
+    if !is_checking_lock_order() {
+        return;
+    }
+    let held = held_locks();
+    if let Some(slot) = held
+        .iter_mut()
+        .rev()
+        .find(|h| h.is_some_and(|h| h.lock == lock))
+    {
+        *slot = None;
+    }
+}
+
+#[test_case]
+fn lock_order_violation_is_reported() {
+    use crate::mutex::SpinLock;
+    let a = SpinLock::new(0);
+    let b = SpinLock::new(0);
+    let num_violations = num_lock_order_violations();
+    start_lock_order_check();
+    {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+    {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+    assert_eq!(num_lock_order_violations(), num_violations);
+    {
+        let _b = b.lock();
+        let _a = a.lock();
+    }
+    stop_lock_order_check();
+    assert_eq!(num_lock_order_violations(), num_violations + 1);
+}
@@ -2,96 +2,87 @@
 #![no_main]
 #![feature(offset_of)]
 
+extern crate alloc;
+
+use alloc::boxed::Box;
 use core::panic::PanicInfo;
-use core::time::Duration;
+use core::str;
+use wasabi::addr::VirtAddr;
+use wasabi::backtrace::print_backtrace;
+use wasabi::backtrace::set_image_base;
+use wasabi::elf::ElfImage;
 use wasabi::error;
-use wasabi::executor::sleep;
-use wasabi::executor::spawn_global;
-use wasabi::executor::start_global_executor;
-use wasabi::hpet::global_timestamp;
 use wasabi::info;
-use wasabi::init::init_allocator;
-use wasabi::init::init_basic_runtime;
-use wasabi::init::init_display;
-use wasabi::init::init_hpet;
-use wasabi::init::init_paging;
-use wasabi::init::init_pci;
-use wasabi::print::hexdump_struct;
-use wasabi::print::set_global_vram;
+use wasabi::loader::init_loader_heap;
+use wasabi::loader::load_kernel;
+use wasabi::loader::read_apps;
+use wasabi::loader::start_kernel;
+use wasabi::loader::CMDLINE_PATH;
+use wasabi::loader::KERNEL_PATH;
 use wasabi::println;
 use wasabi::qemu::exit_qemu;
 use wasabi::qemu::QemuExitCode;
-use wasabi::serial::SerialPort;
+use wasabi::uefi::exit_from_efi_boot_services;
 use wasabi::uefi::init_vram;
 use wasabi::uefi::locate_loaded_image_protocol;
+use wasabi::uefi::read_file_from_boot_volume;
 use wasabi::uefi::EfiHandle;
+use wasabi::uefi::EfiMemoryType;
 use wasabi::uefi::EfiSystemTable;
-use wasabi::warn;
-use wasabi::x86::init_exceptions;
+use wasabi::uefi::MemoryMapHolder;
 
 #[no_mangle]
 fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
-    println!("Booting WasabiOS...");
+    println!("Booting WasabiOS loader...");
     println!("image_handle: {:#018X}", image_handle);
     println!("efi_system_table: {:#p}", efi_system_table);
     let loaded_image_protocol = locate_loaded_image_protocol(image_handle, efi_system_table)
         .expect("Failed to get LoadedImageProtocol");
     println!("image_base: {:#018X}", loaded_image_protocol.image_base);
+    set_image_base(loaded_image_protocol.image_base);
     println!("image_size: {:#018X}", loaded_image_protocol.image_size);
-    info!("info");
-    warn!("warn");
-    error!("error");
-    hexdump_struct(efi_system_table);
-    let mut vram = init_vram(efi_system_table).expect("init_vram failed");
-    init_display(&mut vram);
-    set_global_vram(vram);
+    let vram = init_vram(efi_system_table).expect("init_vram failed");
     let acpi = efi_system_table.acpi_table().expect("ACPI table not found");
-
-    let memory_map = init_basic_runtime(image_handle, efi_system_table);
-    info!("Hello, Non-UEFI world!");
-    init_allocator(&memory_map);
-    let (_gdt, _idt) = init_exceptions();
-    init_paging(&memory_map);
-    init_hpet(acpi);
-    init_pci(acpi);
-    let t0 = global_timestamp();
-    let task1 = async move {
-        for i in 100..=103 {
-            info!("{i} hpet.main_counter = {:?}", global_timestamp() - t0);
-            sleep(Duration::from_secs(1)).await;
-        }
-        Ok(())
-    };
-    let task2 = async move {
-        for i in 200..=203 {
-            info!("{i} hpet.main_counter = {:?}", global_timestamp() - t0);
-            sleep(Duration::from_secs(2)).await;
-        }
-        Ok(())
-    };
-    let serial_task = async {
-        let sp = SerialPort::default();
-        if let Err(e) = sp.loopback_test() {
-            error!("{e:?}");
-            return Err("serial: loopback test failed");
-        }
-        info!("Started to monitor serial port");
-        loop {
-            if let Some(v) = sp.try_read() {
-                let c = char::from_u32(v as u32);
-                info!("serial input: {v:#04X} = {c:?}");
-            }
-            sleep(Duration::from_millis(20)).await;
-        }
-    };
-    spawn_global(task1);
-    spawn_global(task2);
-    spawn_global(serial_task);
-    start_global_executor()
+    // The files are read into BOOT_SERVICES_DATA, which the kernel reclaims
+    // after everything needed is copied out.
+    let kernel_file = read_file_from_boot_volume(
+        image_handle,
+        efi_system_table,
+        KERNEL_PATH,
+        EfiMemoryType::BOOT_SERVICES_DATA,
+    )
+    .expect("Failed to read the kernel");
+    let cmdline = read_file_from_boot_volume(
+        image_handle,
+        efi_system_table,
+        CMDLINE_PATH,
+        EfiMemoryType::BOOT_SERVICES_DATA,
+    )
+    .map(|file| str::from_utf8(file).unwrap_or("").trim())
+    .unwrap_or("");
+    let elf = ElfImage::parse(kernel_file).expect("Failed to parse the kernel");
+    init_loader_heap(efi_system_table, &elf).expect("Failed to allocate the loader heap");
+    let kernel = load_kernel(&elf).expect("Failed to load the kernel");
+    info!("Loaded the kernel: entry = {:#018X}", kernel.entry());
+    let apps = read_apps(image_handle, efi_system_table);
+    for app in &apps {
+        info!("Read the app {}", app.name());
+    }
+    let memory_map = Box::leak(Box::new(MemoryMapHolder::new()));
+    exit_from_efi_boot_services(image_handle, efi_system_table, memory_map);
+    start_kernel(
+        memory_map,
+        vram,
+        VirtAddr::from_ptr(acpi).to_phys(),
+        &kernel,
+        cmdline,
+        &apps,
+    )
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     error!("PANIC: {info:?}");
+    print_backtrace();
     exit_qemu(QemuExitCode::Fail);
 }
@@ -1,10 +1,8 @@
 extern crate alloc;
 
-use crate::x86::disable_cache;
+use crate::addr::PhysAddr;
 use alloc::boxed::Box;
-use core::marker::PhantomPinned;
 use core::mem::ManuallyDrop;
-use core::mem::MaybeUninit;
 use core::pin::Pin;
 
 pub struct Mmio<T: Sized> {
@@ -22,6 +20,11 @@ impl<T: Sized> Mmio<T> {
         }
     }
     /// # Safety
+    /// Same as from_raw(). The registers are accessed through the direct map.
+    pub unsafe fn from_phys(phys_addr: PhysAddr) -> Self {
+        Self::from_raw(phys_addr.as_mut_ptr())
+    }
+    /// # Safety
     /// Same rules as Pin::get_unchecked_mut() applies.
     pub unsafe fn get_unchecked_mut(&mut self) -> &mut T {
         self.inner.as_mut().get_unchecked_mut()
@@ -32,51 +35,3 @@ impl<T> AsRef<T> for Mmio<T> {
         self.inner.as_ref().get_ref()
     }
 }
-
-#[repr(align(4096))]
-pub struct IoBoxInner<T: Sized> {
-    data: T,
-    _pinned: PhantomPinned,
-}
-impl<T: Sized> IoBoxInner<T> {
-    pub fn new(data: T) -> Self {
-        Self {
-            data,
-            _pinned: PhantomPinned,
-        }
-    }
-}
-
-pub struct IoBox<T: Sized> {
-    inner: Pin<Box<IoBoxInner<T>>>,
-}
-impl<T: Sized> IoBox<T> {
-    pub fn new() -> Self {
-        let inner = Box::pin(IoBoxInner::new(unsafe {
-            MaybeUninit::<T>::zeroed().assume_init()
-        }));
-        let this = Self { inner };
-        disable_cache(&this);
-        this
-    }
-    /// # Safety
-    /// Same rules as Pin::get_unchecked_mut() applies.
-    pub unsafe fn get_unchecked_mut(&mut self) -> &mut T {
-        &mut self.inner.as_mut().get_unchecked_mut().data
-    }
-}
-impl<T> AsRef<T> for IoBox<T> {
-    fn as_ref(&self) -> &T {
-        &self.inner.as_ref().get_ref().data
-    }
-}
-impl<T: Sized> Default for IoBox<T> {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-#[test_case]
-fn io_box_new() {
-    IoBox::<u64>::new();
-}
@@ -1,120 +1,185 @@
-//! Simple thread-safe mutex
+//! Locks
 //!
-//! As the doc of SyncUnsafeCell says,
-//! `SyncUnsafeCell::get()` can be used to get
-//! `*mut T` from `&SyncUnsafeCell<T>` but we must
-//! ensure that the access to the object pointed
-//! is unique before dereferencing it.
+//! SpinLock is the basic lock. Interrupts are disabled on the CPU while it
+//! is held, so it can be taken from interrupt handlers as well, and a
+//! waiter spins until the holder (which may be another CPU) releases it.
+//! RwLock is the same but allows multiple readers at a time.
 //!
-//! This mutex protects the data with AtomicBool
-//! to ensure that the access to the contents
-//! is unique so taking a mutable reference
-//! to it will be safe.
+//! Mutex is a SpinLock that can be shared even if the data is not Send,
+//! e.g. raw pointers to MMIO registers. Prefer SpinLock in new code.
+//!
+//! Every lock remembers where it was created and where it was taken last,
+//! and tells lock_order about locking so that the order can be checked.
 
+use crate::lock_order::check_lock;
+use crate::lock_order::on_locked;
+use crate::lock_order::on_unlocked;
 use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::InterruptGuard;
 use core::cell::SyncUnsafeCell;
 use core::fmt::Debug;
 use core::ops::Deref;
 use core::ops::DerefMut;
 use core::panic::Location;
+use core::ptr::null_mut;
 use core::sync::atomic::AtomicBool;
-use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering;
 
-pub struct MutexGuard<'a, T> {
-    mutex: &'a Mutex<T>,
-    data: &'a mut T,
-    location: Location<'a>,
+/// Where a lock is created and where it was taken last
+struct LockInfo {
+    created_at: &'static Location<'static>,
+    taken_at: AtomicPtr<Location<'static>>,
 }
-impl<'a, T> MutexGuard<'a, T> {
+impl LockInfo {
     #[track_caller]
-    unsafe fn new(mutex: &'a Mutex<T>, data: &SyncUnsafeCell<T>) -> Self {
+    const fn new() -> Self {
         Self {
-            mutex,
-            data: &mut *data.get(),
-            location: *Location::caller(),
+            created_at: Location::caller(),
+            taken_at: AtomicPtr::new(null_mut()),
         }
     }
+    fn set_taken_at(&self, taken_at: &'static Location<'static>) {
+        self.taken_at
+            .store(taken_at as *const _ as *mut _, Ordering::Relaxed)
+    }
+    fn taken_at(&self) -> Option<&'static Location<'static>> {
+        // SAFETY: only 'static Locations are stored.
+        unsafe { self.taken_at.load(Ordering::Relaxed).as_ref() }
+    }
 }
-unsafe impl<'a, T> Sync for MutexGuard<'a, T> {}
-impl<'a, T> Deref for MutexGuard<'a, T> {
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    data: &'a mut T,
+    // Dropped after the lock is released.
+    _interrupts: InterruptGuard,
+}
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
         self.data
     }
 }
-impl<'a, T> DerefMut for MutexGuard<'a, T> {
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.data
     }
 }
-impl<'a, T> Drop for MutexGuard<'a, T> {
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
     fn drop(&mut self) {
-        self.mutex.is_taken.store(false, Ordering::SeqCst)
+        on_unlocked(self.lock.addr());
+        self.lock.is_taken.store(false, Ordering::Release)
     }
 }
-impl<'a, T> Debug for MutexGuard<'a, T> {
+impl<'a, T> Debug for SpinLockGuard<'a, T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "MutexGuard {{ location: {:?} }}", self.location)
+        write!(
+            f,
+            "SpinLockGuard {{ taken_at: {:?} }}",
+            self.lock.taken_at()
+        )
     }
 }
 
-pub struct Mutex<T> {
+/// A lock that can be taken from interrupt handlers.
+///
+/// Interrupts are disabled on the CPU while the lock is held, so a handler
+/// can never interrupt the holder on the same CPU. This spins until the
+/// lock is available since the holder may be another CPU. Keep the
+/// critical sections short.
+pub struct SpinLock<T> {
     data: SyncUnsafeCell<T>,
     is_taken: AtomicBool,
-    taker_line_num: AtomicU32,
-    created_at_file: &'static str,
-    created_at_line: u32,
-}
-impl<T: Sized> Debug for Mutex<T> {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(
-            f,
-            "Mutex @ {}:{}",
-            self.created_at_file, self.created_at_line
-        )
-    }
+    info: LockInfo,
 }
-impl<T: Sized> Mutex<T> {
+impl<T> SpinLock<T> {
     #[track_caller]
     pub const fn new(data: T) -> Self {
         Self {
             data: SyncUnsafeCell::new(data),
             is_taken: AtomicBool::new(false),
-            taker_line_num: AtomicU32::new(0),
-            created_at_file: Location::caller().file(),
-            created_at_line: Location::caller().line(),
+            info: LockInfo::new(),
         }
     }
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+    fn try_lock_at(
+        &self,
+        interrupts: InterruptGuard,
+        taken_at: &'static Location<'static>,
+    ) -> Option<SpinLockGuard<T>> {
+        self.is_taken
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.info.set_taken_at(taken_at);
+        on_locked(self.addr(), self.info.created_at, taken_at);
+        Some(SpinLockGuard {
+            lock: self,
+            // SAFETY: the access is unique while is_taken is true.
+            data: unsafe { &mut *self.data.get() },
+            _interrupts: interrupts,
+        })
+    }
     #[track_caller]
-    fn try_lock(&self) -> Result<MutexGuard<T>> {
-        if self
-            .is_taken
-            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-            .is_ok()
-        {
-            self.taker_line_num
-                .store(Location::caller().line(), Ordering::SeqCst);
-            Ok(unsafe { MutexGuard::new(self, &self.data) })
-        } else {
-            Err("Lock failed")
-        }
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        self.try_lock_at(InterruptGuard::new(), Location::caller())
     }
     #[track_caller]
-    pub fn lock(&self) -> MutexGuard<T> {
-        for _ in 0..10000 {
-            if let Ok(locked) = self.try_lock() {
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let taken_at = Location::caller();
+        {
+            let _interrupts = InterruptGuard::new();
+            check_lock(self.addr(), self.info.created_at, taken_at);
+        }
+        loop {
+            if let Some(locked) = self.try_lock_at(InterruptGuard::new(), taken_at) {
                 return locked;
             }
+            while self.is_taken.load(Ordering::Relaxed) {
+                busy_loop_hint();
+            }
         }
-        panic!(
-            "Failed to lock Mutex at {}:{}, caller: {:?}, taker_line_num: {}",
-            self.created_at_file,
-            self.created_at_line,
-            Location::caller(),
-            self.taker_line_num.load(Ordering::SeqCst),
-        )
+    }
+    pub fn created_at(&self) -> &'static Location<'static> {
+        self.info.created_at
+    }
+    /// Where the lock was taken last, for debugging
+    pub fn taken_at(&self) -> Option<&'static Location<'static>> {
+        self.info.taken_at()
+    }
+}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+impl<T> Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "SpinLock @ {}", self.info.created_at)
+    }
+}
+
+pub type MutexGuard<'a, T> = SpinLockGuard<'a, T>;
+
+/// A SpinLock that is Sync regardless of T. See the module doc.
+pub struct Mutex<T> {
+    lock: SpinLock<T>,
+}
+impl<T> Mutex<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            lock: SpinLock::new(data),
+        }
+    }
+    #[track_caller]
+    pub fn try_lock(&self) -> Result<MutexGuard<T>> {
+        self.lock.try_lock().ok_or("Lock failed")
+    }
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.lock.lock()
     }
     pub fn under_locked<R: Sized>(&self, f: &dyn Fn(&mut T) -> Result<R>) -> Result<R> {
         let mut locked = self.lock();
@@ -122,9 +187,201 @@ impl<T: Sized> Mutex<T> {
     }
 }
 unsafe impl<T> Sync for Mutex<T> {}
+impl<T> Debug for Mutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Mutex @ {}", self.lock.info.created_at)
+    }
+}
 impl<T: Default> Default for Mutex<T> {
     #[track_caller]
     fn default() -> Self {
         Self::new(T::default())
     }
 }
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    data: &'a T,
+    _interrupts: InterruptGuard,
+}
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        on_unlocked(self.lock.addr());
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    data: &'a mut T,
+    _interrupts: InterruptGuard,
+}
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.data
+    }
+}
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        on_unlocked(self.lock.addr());
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
+
+const RW_LOCK_WRITER: usize = 1 << (usize::BITS - 1);
+
+/// A SpinLock that allows multiple readers, or one writer.
+///
+/// Writers are not prioritized, so a writer can wait as long as readers
+/// keep coming. This is for data that is read often and rarely updated.
+pub struct RwLock<T> {
+    data: SyncUnsafeCell<T>,
+    /// RW_LOCK_WRITER if a writer holds the lock, or the number of readers
+    state: AtomicUsize,
+    info: LockInfo,
+}
+impl<T> RwLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            data: SyncUnsafeCell::new(data),
+            state: AtomicUsize::new(0),
+            info: LockInfo::new(),
+        }
+    }
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+    fn try_read_at(
+        &self,
+        interrupts: InterruptGuard,
+        taken_at: &'static Location<'static>,
+    ) -> Option<RwLockReadGuard<T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & RW_LOCK_WRITER != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.info.set_taken_at(taken_at);
+        on_locked(self.addr(), self.info.created_at, taken_at);
+        Some(RwLockReadGuard {
+            lock: self,
+            // SAFETY: no one writes while the readers are counted.
+            data: unsafe { &*self.data.get() },
+            _interrupts: interrupts,
+        })
+    }
+    fn try_write_at(
+        &self,
+        interrupts: InterruptGuard,
+        taken_at: &'static Location<'static>,
+    ) -> Option<RwLockWriteGuard<T>> {
+        self.state
+            .compare_exchange(0, RW_LOCK_WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.info.set_taken_at(taken_at);
+        on_locked(self.addr(), self.info.created_at, taken_at);
+        Some(RwLockWriteGuard {
+            lock: self,
+            // SAFETY: the access is unique while RW_LOCK_WRITER is set.
+            data: unsafe { &mut *self.data.get() },
+            _interrupts: interrupts,
+        })
+    }
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        self.try_read_at(InterruptGuard::new(), Location::caller())
+    }
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        self.try_write_at(InterruptGuard::new(), Location::caller())
+    }
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        let taken_at = Location::caller();
+        {
+            let _interrupts = InterruptGuard::new();
+            check_lock(self.addr(), self.info.created_at, taken_at);
+        }
+        loop {
+            if let Some(locked) = self.try_read_at(InterruptGuard::new(), taken_at) {
+                return locked;
+            }
+            busy_loop_hint();
+        }
+    }
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        let taken_at = Location::caller();
+        {
+            let _interrupts = InterruptGuard::new();
+            check_lock(self.addr(), self.info.created_at, taken_at);
+        }
+        loop {
+            if let Some(locked) = self.try_write_at(InterruptGuard::new(), taken_at) {
+                return locked;
+            }
+            busy_loop_hint();
+        }
+    }
+    /// Where the lock was taken last, for debugging
+    pub fn taken_at(&self) -> Option<&'static Location<'static>> {
+        self.info.taken_at()
+    }
+}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+impl<T> Debug for RwLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "RwLock @ {}", self.info.created_at)
+    }
+}
+
+#[test_case]
+fn spin_lock_disables_interrupts() {
+    use crate::x86::are_interrupts_enabled;
+    let lock = SpinLock::new(1);
+    let was_enabled = are_interrupts_enabled();
+    {
+        let mut locked = lock.lock();
+        assert!(!are_interrupts_enabled());
+        assert!(lock.try_lock().is_none());
+        *locked += 1;
+    }
+    assert_eq!(are_interrupts_enabled(), was_enabled);
+    assert_eq!(*lock.lock(), 2);
+    assert_eq!(lock.taken_at().unwrap().line(), line!() - 1);
+}
+
+#[test_case]
+fn rw_lock_allows_readers_or_a_writer() {
+    let lock = RwLock::new(1);
+    {
+        let r1 = lock.read();
+        let r2 = lock.read();
+        assert_eq!(*r1 + *r2, 2);
+        assert!(lock.try_write().is_none());
+    }
+    {
+        let mut w = lock.write();
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        *w = 3;
+    }
+    assert_eq!(*lock.read(), 3);
+}
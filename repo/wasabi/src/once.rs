@@ -0,0 +1,147 @@
+//! One-time initialization for global singletons
+//!
+//! Once holds a value that is set only once, and can be read without any
+//! locks after that. Lazy is a Once that is initialized on the first
+//! access with the function given at compile time.
+//!
+//! A CPU that finds the value being initialized by another CPU spins until
+//! it is done, so the initialization should not be done from interrupt
+//! handlers, nor depend on the same Once.
+
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use core::cell::SyncUnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+const ONCE_INCOMPLETE: u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_COMPLETE: u8 = 2;
+
+pub struct Once<T> {
+    state: AtomicU8,
+    value: SyncUnsafeCell<MaybeUninit<T>>,
+}
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(ONCE_INCOMPLETE),
+            value: SyncUnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == ONCE_COMPLETE
+    }
+    /// Returns the value if it is initialized already.
+    pub fn get(&self) -> Option<&T> {
+        // SAFETY: the value is never modified once completed.
+        self.is_completed()
+            .then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+    fn begin(&self) -> bool {
+        self.state
+            .compare_exchange(
+                ONCE_INCOMPLETE,
+                ONCE_RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+    fn complete(&self, value: T) -> &T {
+        // SAFETY: only the caller that made the state RUNNING reaches here.
+        let value = unsafe { (*self.value.get()).write(value) };
+        self.state.store(ONCE_COMPLETE, Ordering::Release);
+        value
+    }
+    /// Sets the value. Fails if it is set or being set already.
+    pub fn set(&self, value: T) -> Result<()> {
+        if !self.begin() {
+            return Err("Once is already initialized");
+        }
+        self.complete(value);
+        Ok(())
+    }
+    /// Returns the value, initializing it with f if no one has done it.
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        if self.begin() {
+            return self.complete(f());
+        }
+        loop {
+            if let Some(value) = self.get() {
+                return value;
+            }
+            busy_loop_hint();
+        }
+    }
+}
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == ONCE_COMPLETE {
+            // SAFETY: the value is initialized when completed.
+            unsafe { self.value.get_mut().assume_init_drop() }
+        }
+    }
+}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+/// A value initialized by init on the first access
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: SyncUnsafeCell<Option<F>>,
+}
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: SyncUnsafeCell::new(Some(init)),
+        }
+    }
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            // SAFETY: only the caller that initializes the value reaches
+            // here.
+            let init = unsafe { (*this.init.get()).take() };
+            init.expect("Lazy has been poisoned by a panic during initialization")()
+        })
+    }
+}
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+#[test_case]
+fn once_is_set_only_once() {
+    let once = Once::new();
+    assert!(once.get().is_none());
+    assert_eq!(*once.call_once(|| 1), 1);
+    assert_eq!(*once.call_once(|| 2), 1);
+    assert!(once.set(3).is_err());
+    assert_eq!(once.get(), Some(&1));
+}
+
+#[test_case]
+fn lazy_is_initialized_on_first_access() {
+    use core::sync::atomic::AtomicUsize;
+    static NUM_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static VALUE: Lazy<usize> = Lazy::new(|| NUM_CALLS.fetch_add(1, Ordering::SeqCst) + 10);
+    assert_eq!(NUM_CALLS.load(Ordering::SeqCst), 0);
+    assert_eq!(*VALUE, 10);
+    assert_eq!(*VALUE, 10);
+    assert_eq!(NUM_CALLS.load(Ordering::SeqCst), 1);
+}
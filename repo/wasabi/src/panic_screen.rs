@@ -0,0 +1,186 @@
+//! The screen shown when the kernel panics
+//!
+//! A panic can happen anywhere, e.g. while the VRAM writer is locked, so
+//! this draws on the framebuffer directly without taking any locks. The
+//! global prints stop drawing on the VRAM once this takes it over, and go
+//! to the serial port only.
+
+use crate::backtrace::for_each_frame;
+use crate::graphics::draw_font_fg;
+use crate::graphics::fill_rect;
+use crate::graphics::Bitmap;
+use crate::hpet::global_timestamp;
+use crate::percpu::this_cpu;
+use crate::print::take_over_global_vram;
+use crate::x86::exception_name;
+use crate::x86::read_rbp;
+use crate::x86::InterruptInfo;
+use core::cell::SyncUnsafeCell;
+use core::fmt;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+const BACKGROUND_COLOR: u32 = 0x000080;
+const TITLE_COLOR: u32 = 0xffff00;
+const TEXT_COLOR: u32 = 0xffffff;
+const MARGIN: i64 = 16;
+const CHAR_WIDTH: i64 = 8;
+const LINE_HEIGHT: i64 = 16;
+
+static IS_RECORDING_EXCEPTION: AtomicBool = AtomicBool::new(false);
+static HAS_EXCEPTION: AtomicBool = AtomicBool::new(false);
+static EXCEPTION: SyncUnsafeCell<Option<(usize, InterruptInfo)>> = SyncUnsafeCell::new(None);
+static IS_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Records the exception that is going to cause a panic, so that the
+/// screen can show its registers. Only the first one is kept.
+pub fn record_exception(vector: usize, info: &InterruptInfo) {
+    if IS_RECORDING_EXCEPTION.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // SAFETY: only the first caller reaches here, and EXCEPTION is not read
+    // until HAS_EXCEPTION is set.
+    unsafe { *EXCEPTION.get() = Some((vector, *info)) };
+    HAS_EXCEPTION.store(true, Ordering::SeqCst);
+}
+fn recorded_exception() -> Option<(usize, InterruptInfo)> {
+    if HAS_EXCEPTION.load(Ordering::SeqCst) {
+        // SAFETY: EXCEPTION is never modified once HAS_EXCEPTION is set.
+        unsafe { *EXCEPTION.get() }
+    } else {
+        None
+    }
+}
+
+/// Writes text on a bitmap from the top left, wrapping long lines. Lines
+/// that do not fit in the bitmap are dropped.
+struct ScreenWriter<'a, T> {
+    buf: &'a mut T,
+    x: i64,
+    y: i64,
+    color: u32,
+}
+impl<'a, T: Bitmap> ScreenWriter<'a, T> {
+    fn new(buf: &'a mut T) -> Self {
+        Self {
+            buf,
+            x: MARGIN,
+            y: MARGIN,
+            color: TEXT_COLOR,
+        }
+    }
+    fn new_line(&mut self) {
+        self.x = MARGIN;
+        self.y += LINE_HEIGHT;
+    }
+}
+impl<'a, T: Bitmap> fmt::Write for ScreenWriter<'a, T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if c == '\n' {
+                self.new_line();
+                continue;
+            }
+            if self.x + CHAR_WIDTH > self.buf.width() - MARGIN {
+                self.new_line();
+            }
+            if self.y + LINE_HEIGHT > self.buf.height() - MARGIN {
+                break;
+            }
+            draw_font_fg(self.buf, self.x, self.y, self.color, c);
+            self.x += CHAR_WIDTH;
+        }
+        Ok(())
+    }
+}
+
+fn draw_panic_info<T: Bitmap>(w: &mut ScreenWriter<T>, info: &PanicInfo) -> fmt::Result {
+    w.color = TITLE_COLOR;
+    writeln!(w, "WasabiOS kernel panic")?;
+    w.color = TEXT_COLOR;
+    writeln!(w)?;
+    match info.message() {
+        Some(message) => writeln!(w, "{message}")?,
+        None => writeln!(w, "(no message)")?,
+    }
+    if let Some(location) = info.location() {
+        writeln!(w, "at {location}")?;
+    }
+    writeln!(w, "uptime: {:?}", global_timestamp())?;
+    let cpu = this_cpu();
+    write!(w, "CPU {} (APIC ID {})", cpu.id(), cpu.apic_id())?;
+    match cpu.current_task() {
+        Some(spawned_at) => writeln!(w, ", in the task spawned at {spawned_at}")?,
+        None => writeln!(w)?,
+    }
+    let exception = recorded_exception();
+    if let Some((vector, info)) = &exception {
+        writeln!(w)?;
+        writeln!(w, "Exception {vector:#04X}: {}", exception_name(*vector))?;
+        // The dump starts with a new line.
+        write!(w, "Registers:{info:?}")?;
+        writeln!(w)?;
+    }
+    writeln!(w)?;
+    writeln!(w, "Backtrace:")?;
+    let (rip, rbp) = match &exception {
+        Some((_, info)) => (Some(info.rip()), info.rbp()),
+        None => (None, read_rbp()),
+    };
+    for_each_frame(rip, rbp, |frame| {
+        let _ = writeln!(w, "  {frame}");
+    });
+    Ok(())
+}
+
+/// Takes over the framebuffer and draws the details of the panic on it.
+/// This does nothing if there is no framebuffer, or if the screen is
+/// already shown, e.g. when panicked again while drawing it.
+pub fn show_panic_screen(info: &PanicInfo) {
+    if IS_SHOWN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let Some(mut vram) = take_over_global_vram() else {
+        return;
+    };
+    let (width, height) = (vram.width(), vram.height());
+    let _ = fill_rect(&mut vram, BACKGROUND_COLOR, 0, 0, width, height);
+    let _ = draw_panic_info(&mut ScreenWriter::new(&mut vram), info);
+}
+
+#[test_case]
+fn screen_writer_wraps_and_clips_lines() {
+    struct TestBitmap {
+        pixels: [u32; 64 * 64],
+    }
+    impl Bitmap for TestBitmap {
+        fn bytes_per_pixel(&self) -> i64 {
+            4
+        }
+        fn pixels_per_line(&self) -> i64 {
+            64
+        }
+        fn width(&self) -> i64 {
+            64
+        }
+        fn height(&self) -> i64 {
+            64
+        }
+        fn buf_mut(&mut self) -> *mut u8 {
+            self.pixels.as_mut_ptr() as *mut u8
+        }
+    }
+    let mut bitmap = TestBitmap {
+        pixels: [0; 64 * 64],
+    };
+    let mut w = ScreenWriter::new(&mut bitmap);
+    // 4 characters fit in a line between the margins.
+    write!(w, "ABCDE").unwrap();
+    assert_eq!((w.x, w.y), (MARGIN + CHAR_WIDTH, MARGIN + LINE_HEIGHT));
+    // Only 2 lines fit, so the rest is dropped.
+    write!(w, "\nFGH\nIJK").unwrap();
+    assert_eq!(w.y, MARGIN + 2 * LINE_HEIGHT);
+    assert!(bitmap.pixels.iter().any(|p| *p == TEXT_COLOR));
+}
@@ -1,9 +1,13 @@
 use crate::acpi::AcpiMcfgDescriptor;
+use crate::addr::PhysAddr;
 use crate::error;
 use crate::info;
 use crate::result::Result;
-use crate::x86::with_current_page_table;
+use crate::x86::available_attr;
+use crate::x86::invalidate_page;
+use crate::x86::take_current_page_table;
 use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
 use crate::xhci::PciXhciDriver;
 use core::fmt;
 use core::marker::PhantomData;
@@ -129,21 +133,21 @@ impl<T> ConfigRegisters<T> {
 }
 
 pub struct Pci {
-    ecm_range: Range<usize>,
+    ecm_range: Range<PhysAddr>,
 }
 impl Pci {
     pub fn new(mcfg: &AcpiMcfgDescriptor) -> Self {
         // To simplify, assume that there is one mcfg entry that maps all the
         // pci configuration spaces.
         assert!(mcfg.num_of_entries() == 1);
-        let pci_config_space_base = mcfg.entry(0).expect("Out of range").base_address() as usize;
+        let pci_config_space_base = mcfg.entry(0).expect("Out of range").base_address();
         let pci_config_space_end = pci_config_space_base + (1 << 24);
         Self {
             ecm_range: pci_config_space_base..pci_config_space_end,
         }
     }
     pub fn ecm_base<T>(&self, id: BusDeviceFunction) -> *mut T {
-        (self.ecm_range.start + ((id.id as usize) << 12)) as *mut T
+        (self.ecm_range.start + ((id.id as u64) << 12)).as_mut_ptr()
     }
     pub fn read_register_u16(&self, bdf: BusDeviceFunction, byte_offset: usize) -> Result<u16> {
         ConfigRegisters::read(self.ecm_base(bdf), byte_offset)
@@ -205,13 +209,13 @@ impl Pci {
         if bar0 & 0b0111 == 0b0100
         /* Memory, 64bit, Non-prefetchable */
         {
-            let addr = (bar0 & !0b1111) as *mut u8;
+            let phys_addr = PhysAddr::new(bar0 & !0b1111);
             // Write all-1s to get the size of the region
             self.write_register_u64(bdf, 0x10, !0u64)?;
             let size = 1 + !(self.read_register_u64(bdf, 0x10)? & !0b1111);
             // Restore the original value
             self.write_register_u64(bdf, 0x10, bar0)?;
-            Ok(BarMem64 { addr, size })
+            Ok(BarMem64 { phys_addr, size })
         } else {
             Err("Unexpected BAR0 Type")
         }
@@ -232,25 +236,42 @@ impl Pci {
     }
 }
 pub struct BarMem64 {
-    addr: *mut u8,
+    phys_addr: PhysAddr,
     size: u64,
 }
 impl BarMem64 {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+    /// Returns a pointer to the region through the direct map.
     pub fn addr(&self) -> *mut u8 {
-        self.addr
+        self.phys_addr.as_mut_ptr()
     }
     pub fn size(&self) -> u64 {
         self.size
     }
-    pub fn disable_cache(&self) {
-        let vstart = self.addr() as u64;
-        let vend = self.addr() as u64 + self.size();
-        unsafe {
-            with_current_page_table(|pt| {
-                pt.create_mapping(vstart, vend, vstart, PageAttr::ReadWriteIo)
-                    .expect("Failed to create mapping")
-            })
+    /// Maps the region as uncacheable in the direct map, which may not
+    /// cover it if it is above the RAM. This should be called before the
+    /// other CPUs are started, since only the TLB of the current CPU is
+    /// flushed.
+    pub fn disable_cache(&self) -> Result<()> {
+        let vstart = self.phys_addr.to_virt().as_u64();
+        let vend = vstart + self.size();
+        // SAFETY: only the mapping of the region is changed.
+        let table = unsafe { take_current_page_table() };
+        table.create_mapping(
+            vstart,
+            vend,
+            self.phys_addr,
+            available_attr(PageAttr::READ_WRITE_IO | PageAttr::NO_EXECUTE | PageAttr::GLOBAL),
+        )?;
+        // Global entries survive CR3 reloads, so drop them one by one.
+        let page_start = vstart & !(PAGE_SIZE as u64 - 1);
+        for virt in (page_start..vend).step_by(PAGE_SIZE) {
+            // SAFETY: virt is in the direct map.
+            unsafe { invalidate_page(virt) };
         }
+        Ok(())
     }
 }
 impl fmt::Debug for BarMem64 {
@@ -258,8 +279,8 @@ impl fmt::Debug for BarMem64 {
         write!(
             f,
             "BarMem64[{:#018X}..{:#018X}]",
-            self.addr as u64,
-            self.addr as u64 + self.size()
+            self.phys_addr,
+            self.phys_addr + self.size()
         )
     }
 }
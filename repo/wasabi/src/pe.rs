@@ -0,0 +1,196 @@
+extern crate alloc;
+
+use crate::result::Result;
+use crate::slice::Sliceable;
+use core::mem::size_of;
+use core::ops::Range;
+
+const PE_SIGNATURE: &[u8; 4] = b"PE\0\0";
+const PE32_PLUS_MAGIC: u16 = 0x20B;
+const SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const SCN_MEM_READ: u32 = 0x4000_0000;
+const SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+#[derive(Debug, Copy, Clone, Default)]
+#[allow(unused)]
+#[repr(packed)]
+struct CoffFileHeader {
+    machine: u16,
+    num_of_sections: u16,
+    time_date_stamp: u32,
+    pointer_to_symbol_table: u32,
+    num_of_symbols: u32,
+    size_of_optional_header: u16,
+    characteristics: u16,
+}
+const _: () = assert!(size_of::<CoffFileHeader>() == 20);
+unsafe impl Sliceable for CoffFileHeader {}
+
+/// The first part of the PE32+ optional header, which is enough for us.
+#[derive(Debug, Copy, Clone, Default)]
+#[allow(unused)]
+#[repr(packed)]
+struct OptionalHeader64Head {
+    magic: u16,
+    major_linker_version: u8,
+    minor_linker_version: u8,
+    size_of_code: u32,
+    size_of_initialized_data: u32,
+    size_of_uninitialized_data: u32,
+    address_of_entry_point: u32,
+    base_of_code: u32,
+    image_base: u64,
+    section_alignment: u32,
+    file_alignment: u32,
+}
+const _: () = assert!(size_of::<OptionalHeader64Head>() == 40);
+unsafe impl Sliceable for OptionalHeader64Head {}
+
+#[derive(Debug, Copy, Clone, Default)]
+#[allow(unused)]
+#[repr(packed)]
+pub struct SectionHeader {
+    name: [u8; 8],
+    virtual_size: u32,
+    virtual_address: u32,
+    size_of_raw_data: u32,
+    pointer_to_raw_data: u32,
+    pointer_to_relocations: u32,
+    pointer_to_line_numbers: u32,
+    num_of_relocations: u16,
+    num_of_line_numbers: u16,
+    characteristics: u32,
+}
+const _: () = assert!(size_of::<SectionHeader>() == 40);
+unsafe impl Sliceable for SectionHeader {}
+impl SectionHeader {
+    pub fn name(&self) -> &str {
+        let name = &self.name;
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        core::str::from_utf8(&name[..len]).unwrap_or("?")
+    }
+    /// Range of the section relative to the image base
+    pub fn virt_range(&self) -> Range<u64> {
+        let start = self.virtual_address as u64;
+        start..start + self.virtual_size as u64
+    }
+    pub fn is_readable(&self) -> bool {
+        self.characteristics & SCN_MEM_READ != 0
+    }
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & SCN_MEM_WRITE != 0
+    }
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & SCN_MEM_EXECUTE != 0
+    }
+}
+
+/// A PE/COFF image loaded in memory (so that sections are placed at
+/// their virtual addresses relative to the start of the image)
+pub struct PeImage<'a> {
+    image: &'a [u8],
+    section_alignment: u32,
+    section_headers_offset: usize,
+    num_of_sections: usize,
+}
+impl<'a> PeImage<'a> {
+    pub fn parse(image: &'a [u8]) -> Result<Self> {
+        let pe_offset = image
+            .get(0x3C..0x40)
+            .and_then(|b| b.try_into().ok())
+            .map(u32::from_le_bytes)
+            .ok_or("Image is too small for a DOS header")? as usize;
+        if image.get(pe_offset..pe_offset + 4) != Some(PE_SIGNATURE) {
+            return Err("PE signature not found");
+        }
+        let coff_offset = pe_offset + 4;
+        let coff = CoffFileHeader::copy_from_slice(image.get(coff_offset..).ok_or("Truncated")?)?;
+        let optional_header_offset = coff_offset + size_of::<CoffFileHeader>();
+        let optional_header = OptionalHeader64Head::copy_from_slice(
+            image.get(optional_header_offset..).ok_or("Truncated")?,
+        )?;
+        if optional_header.magic != PE32_PLUS_MAGIC {
+            return Err("Not a PE32+ image");
+        }
+        let section_headers_offset = optional_header_offset + coff.size_of_optional_header as usize;
+        let num_of_sections = coff.num_of_sections as usize;
+        if image.len() < section_headers_offset + num_of_sections * size_of::<SectionHeader>() {
+            return Err("Section headers are truncated");
+        }
+        Ok(Self {
+            image,
+            section_alignment: optional_header.section_alignment,
+            section_headers_offset,
+            num_of_sections,
+        })
+    }
+    pub fn section_alignment(&self) -> u32 {
+        self.section_alignment
+    }
+    pub fn sections(&self) -> impl Iterator<Item = SectionHeader> + '_ {
+        (0..self.num_of_sections).filter_map(|i| {
+            let offset = self.section_headers_offset + i * size_of::<SectionHeader>();
+            SectionHeader::copy_from_slice(&self.image[offset..]).ok()
+        })
+    }
+}
+
+#[cfg(test)]
+fn build_test_image() -> [u8; 0x400] {
+    fn put(image: &mut [u8], offset: usize, data: &[u8]) {
+        image[offset..offset + data.len()].copy_from_slice(data)
+    }
+    let mut image = [0u8; 0x400];
+    put(&mut image, 0, b"MZ");
+    put(&mut image, 0x3C, &0x80u32.to_le_bytes());
+    put(&mut image, 0x80, PE_SIGNATURE);
+    // COFF header: 2 sections, optional header of 0xF0 bytes
+    put(&mut image, 0x84, &0x8664u16.to_le_bytes());
+    put(&mut image, 0x86, &2u16.to_le_bytes());
+    put(&mut image, 0x94, &0xF0u16.to_le_bytes());
+    // Optional header
+    put(&mut image, 0x98, &PE32_PLUS_MAGIC.to_le_bytes());
+    put(&mut image, 0x98 + 32, &0x1000u32.to_le_bytes());
+    // Section headers
+    let text = 0x98 + 0xF0;
+    put(&mut image, text, b".text");
+    put(&mut image, text + 8, &0x1234u32.to_le_bytes());
+    put(&mut image, text + 12, &0x1000u32.to_le_bytes());
+    put(
+        &mut image,
+        text + 36,
+        &(SCN_MEM_EXECUTE | SCN_MEM_READ).to_le_bytes(),
+    );
+    let data = text + 40;
+    put(&mut image, data, b".data");
+    put(&mut image, data + 8, &0x20u32.to_le_bytes());
+    put(&mut image, data + 12, &0x3000u32.to_le_bytes());
+    put(
+        &mut image,
+        data + 36,
+        &(SCN_MEM_READ | SCN_MEM_WRITE).to_le_bytes(),
+    );
+    image
+}
+
+#[test_case]
+fn parse_pe_sections() {
+    let image = build_test_image();
+    let pe = PeImage::parse(&image).unwrap();
+    assert_eq!(pe.section_alignment(), 0x1000);
+    let sections: alloc::vec::Vec<SectionHeader> = pe.sections().collect();
+    assert_eq!(sections.len(), 2);
+    assert_eq!(sections[0].name(), ".text");
+    assert_eq!(sections[0].virt_range(), 0x1000..0x2234);
+    assert!(sections[0].is_executable() && !sections[0].is_writable());
+    assert_eq!(sections[1].name(), ".data");
+    assert_eq!(sections[1].virt_range(), 0x3000..0x3020);
+    assert!(!sections[1].is_executable() && sections[1].is_writable());
+}
+#[test_case]
+fn parse_broken_pe() {
+    let mut image = build_test_image();
+    assert!(PeImage::parse(&image[..0x100]).is_err());
+    image[0x80] = b'X';
+    assert!(PeImage::parse(&image).is_err());
+}
@@ -0,0 +1,226 @@
+//! Data owned by each CPU
+//!
+//! Each CPU has a CpuData, and its GS base points to it. The first field
+//! of CpuData points to itself, so that it can be found with a single load
+//! from gs:0 instead of reading the MSR. Until the boot CPU sets its GS
+//! base, it is the only CPU running and this_cpu() returns its CpuData
+//! without looking at GS. The other CPUs set theirs before anything else.
+//!
+//! Code entered from user mode should swapgs before touching any of this.
+//!
+//! Statics declared with cpu_local! have a value for each CPU, indexed by
+//! the id in CpuData.
+
+use crate::allocator::CpuCache;
+use crate::x86::write_gs_base;
+use core::arch::asm;
+use core::cell::SyncUnsafeCell;
+use core::marker::PhantomData;
+use core::mem::offset_of;
+use core::panic::Location;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+pub const MAX_CPUS: usize = 64;
+
+#[repr(C)]
+pub struct CpuData {
+    this: *const CpuData,
+    id: usize,
+    apic_id: AtomicUsize,
+    interrupt_depth: AtomicUsize,
+    /// Where the task being polled was spawned, or null.
+    current_task: AtomicPtr<Location<'static>>,
+    allocator_cache: SyncUnsafeCell<CpuCache>,
+    /// Top of the stack used on syscall, which is rsp0 of the TSS.
+    kernel_stack: AtomicU64,
+    /// Scratch slots for the user mode entry and exit code
+    user_rsp: AtomicU64,
+    kernel_rsp: AtomicU64,
+}
+const _: () = assert!(offset_of!(CpuData, this) == 0);
+pub(crate) const CPU_DATA_KERNEL_STACK: usize = offset_of!(CpuData, kernel_stack);
+pub(crate) const CPU_DATA_USER_RSP: usize = offset_of!(CpuData, user_rsp);
+pub(crate) const CPU_DATA_KERNEL_RSP: usize = offset_of!(CpuData, kernel_rsp);
+// SAFETY: the allocator cache is only used by the CPU that owns it, with
+// interrupts disabled, and the other fields are immutable or atomic.
+unsafe impl Sync for CpuData {}
+impl CpuData {
+    /// Returns a CpuData whose `this` is not set yet.
+    pub fn new(id: usize, apic_id: u32) -> Self {
+        Self {
+            this: core::ptr::null(),
+            id,
+            apic_id: AtomicUsize::new(apic_id as usize),
+            interrupt_depth: AtomicUsize::new(0),
+            current_task: AtomicPtr::new(null_mut()),
+            allocator_cache: SyncUnsafeCell::new(CpuCache::new()),
+            kernel_stack: AtomicU64::new(0),
+            user_rsp: AtomicU64::new(0),
+            kernel_rsp: AtomicU64::new(0),
+        }
+    }
+    /// Sequential number of the CPU. The boot CPU is 0, and the others are
+    /// numbered in the order they are started.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed) as u32
+    }
+    /// Number of interrupt handlers running on this CPU, including nested
+    /// ones.
+    pub fn interrupt_depth(&self) -> usize {
+        self.interrupt_depth.load(Ordering::Relaxed)
+    }
+    pub fn current_task(&self) -> Option<&'static Location<'static>> {
+        // SAFETY: the pointer is made from a &'static in set_current_task().
+        unsafe { self.current_task.load(Ordering::Relaxed).as_ref() }
+    }
+    pub fn set_current_task(&self, spawned_at: Option<&'static Location<'static>>) {
+        let ptr = spawned_at.map_or(null_mut(), |l| l as *const Location as *mut Location);
+        self.current_task.store(ptr, Ordering::Relaxed);
+    }
+    pub(crate) fn allocator_cache(&self) -> *mut CpuCache {
+        self.allocator_cache.get()
+    }
+    pub fn kernel_stack(&self) -> u64 {
+        self.kernel_stack.load(Ordering::Relaxed)
+    }
+    pub fn set_kernel_stack(&self, top: u64) {
+        self.kernel_stack.store(top, Ordering::Relaxed);
+    }
+}
+
+static BSP_CPU_DATA: CpuData = CpuData {
+    this: &BSP_CPU_DATA,
+    id: 0,
+    apic_id: AtomicUsize::new(0),
+    interrupt_depth: AtomicUsize::new(0),
+    current_task: AtomicPtr::new(null_mut()),
+    allocator_cache: SyncUnsafeCell::new(CpuCache::new()),
+    kernel_stack: AtomicU64::new(0),
+    user_rsp: AtomicU64::new(0),
+    kernel_rsp: AtomicU64::new(0),
+};
+static IS_GS_BASE_SET: AtomicBool = AtomicBool::new(false);
+
+/// Points the GS base of the boot CPU to its CpuData.
+pub fn init_bsp_cpu_data(apic_id: u32) {
+    BSP_CPU_DATA
+        .apic_id
+        .store(apic_id as usize, Ordering::Relaxed);
+    // SAFETY: GS is not used for anything else.
+    unsafe { write_gs_base(&BSP_CPU_DATA as *const CpuData as u64) };
+    IS_GS_BASE_SET.store(true, Ordering::SeqCst);
+}
+
+/// Points the GS base of the calling AP to data, which should be made with
+/// CpuData::new() and never freed.
+///
+/// # Safety
+/// This should be called on each AP before anything that uses this_cpu(),
+/// including the heap.
+pub unsafe fn init_ap_cpu_data(data: *mut CpuData) {
+    (*data).this = data;
+    write_gs_base(data as u64);
+}
+
+/// Returns true if the GS base points to the CpuData of the calling CPU,
+/// which is required to enter user mode.
+pub fn is_gs_base_set() -> bool {
+    IS_GS_BASE_SET.load(Ordering::Relaxed)
+}
+
+/// Returns the CpuData of the calling CPU.
+pub fn this_cpu() -> &'static CpuData {
+    if !IS_GS_BASE_SET.load(Ordering::Relaxed) {
+        return &BSP_CPU_DATA;
+    }
+    let data: *const CpuData;
+    // SAFETY: gs:0 has the pointer to the CpuData that is never freed.
+    unsafe {
+        asm!("mov {}, gs:[0]",
+            out(reg) data,
+            options(nostack, readonly, preserves_flags))
+    }
+    // SAFETY: same as above.
+    unsafe { &*data }
+}
+
+/// Counts the interrupt handlers running on the current CPU while alive.
+pub struct InterruptNesting {
+    // The count belongs to the CPU that created this.
+    _not_send: PhantomData<*const ()>,
+}
+impl InterruptNesting {
+    #[allow(clippy::new_without_default)]
+    pub fn enter() -> Self {
+        this_cpu().interrupt_depth.fetch_add(1, Ordering::Relaxed);
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+impl Drop for InterruptNesting {
+    fn drop(&mut self) {
+        this_cpu().interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A static with a value for each CPU. Declare it with cpu_local!.
+pub struct CpuLocal<T> {
+    values: [T; MAX_CPUS],
+}
+// SAFETY: each CPU only gets a reference to its own value, which can not
+// be passed to the other CPUs unless T is Sync.
+unsafe impl<T> Sync for CpuLocal<T> {}
+impl<T> CpuLocal<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        Self { values }
+    }
+    /// Returns the value of the calling CPU.
+    pub fn get(&self) -> &T {
+        &self.values[this_cpu().id()]
+    }
+}
+
+/// Declares statics that have a value for each CPU, initialized with a
+/// constant expression, like:
+/// `cpu_local! { static COUNT: Cell<usize> = Cell::new(0); }`
+/// The value of the calling CPU is returned by `COUNT.get()`.
+#[macro_export]
+macro_rules! cpu_local {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])* $vis static $name: $crate::percpu::CpuLocal<$t> = {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const INIT: $t = $init;
+                $crate::percpu::CpuLocal::new([INIT; $crate::percpu::MAX_CPUS])
+            };
+        )*
+    };
+}
+
+#[test_case]
+fn cpu_local_values_are_per_cpu() {
+    use core::cell::Cell;
+    cpu_local! {
+        static COUNT: Cell<usize> = Cell::new(0);
+    }
+    COUNT.get().set(COUNT.get().get() + 1);
+    assert_eq!(COUNT.get().get(), 1);
+    assert_eq!(COUNT.values[this_cpu().id()].get(), 1);
+    assert_eq!(COUNT.values.iter().map(Cell::get).sum::<usize>(), 1);
+    let depth = this_cpu().interrupt_depth();
+    {
+        let _nesting = InterruptNesting::enter();
+        assert_eq!(this_cpu().interrupt_depth(), depth + 1);
+    }
+    assert_eq!(this_cpu().interrupt_depth(), depth);
+}
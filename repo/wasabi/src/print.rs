@@ -1,28 +1,40 @@
 use crate::graphics::Bitmap;
 use crate::graphics::BitmapTextWriter;
-use crate::mutex::Mutex;
+use crate::mutex::SpinLock;
+use crate::once::Once;
 use crate::serial::SerialPort;
 use crate::uefi::VramBufferInfo;
 use core::fmt;
 use core::mem::size_of;
 use core::slice;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
 
-static GLOBAL_VRAM_WRITER: Mutex<Option<BitmapTextWriter<VramBufferInfo>>> = Mutex::new(None);
+static GLOBAL_VRAM: Once<VramBufferInfo> = Once::new();
+static GLOBAL_VRAM_WRITER: Once<SpinLock<BitmapTextWriter<VramBufferInfo>>> = Once::new();
+static IS_VRAM_TAKEN_OVER: AtomicBool = AtomicBool::new(false);
 pub fn set_global_vram(vram: VramBufferInfo) {
-    assert!(GLOBAL_VRAM_WRITER.lock().is_none());
-    let w = BitmapTextWriter::new(vram);
-    *GLOBAL_VRAM_WRITER.lock() = Some(w);
+    GLOBAL_VRAM.set(vram).expect("Global VRAM is already set");
+    GLOBAL_VRAM_WRITER.call_once(|| SpinLock::new(BitmapTextWriter::new(vram)));
+}
+/// Stops drawing the global prints on the VRAM, and returns the VRAM to be
+/// drawn by the caller instead. This does not take any locks, so it works
+/// even if the code that has panicked holds the writer.
+pub fn take_over_global_vram() -> Option<VramBufferInfo> {
+    IS_VRAM_TAKEN_OVER.store(true, Ordering::SeqCst);
+    GLOBAL_VRAM.get().copied()
 }
 pub fn get_global_vram_resolutions() -> Option<(i64, i64)> {
-    (GLOBAL_VRAM_WRITER.lock())
-        .as_ref()
-        .map(|vram| (vram.buf().width(), vram.buf().height()))
+    GLOBAL_VRAM.get().map(|vram| (vram.width(), vram.height()))
 }
 pub fn global_print(args: fmt::Arguments) {
     let mut writer = SerialPort::default();
     fmt::write(&mut writer, args).unwrap();
-    if let Some(w) = &mut *GLOBAL_VRAM_WRITER.lock() {
-        fmt::write(w, args).expect("Failed to write to GLOBAL_VRAM_WRITER");
+    if IS_VRAM_TAKEN_OVER.load(Ordering::SeqCst) {
+        return;
+    }
+    if let Some(w) = GLOBAL_VRAM_WRITER.get() {
+        fmt::write(&mut *w.lock(), args).expect("Failed to write to GLOBAL_VRAM_WRITER");
     }
 }
 
@@ -0,0 +1,262 @@
+//! User programs loaded from ELF64 executables
+//!
+//! Each Process has its own AddressSpace. The PT_LOAD segments are mapped
+//! with the permissions in their flags, and the stack is set up as the
+//! System V ABI describes: rsp points to argc, followed by the argv and
+//! envp pointers and an empty auxiliary vector. Position independent
+//! executables are loaded at USER_PIE_BASE and relocated.
+//!
+//! The stack pointer is also passed in rdi, so the entry point can be a
+//! function that takes it after fixing up the alignment.
+
+extern crate alloc;
+
+use crate::elf::ElfImage;
+use crate::elf::ProgramHeader;
+use crate::elf::R_X86_64_RELATIVE;
+use crate::result::Result;
+use crate::user::AddressSpace;
+use crate::user::UserExit;
+use crate::user::USER_CODE_BASE;
+use crate::user::USER_HEAP_BASE;
+use crate::user::USER_STACK_SIZE;
+use crate::user::USER_STACK_TOP;
+use crate::x86::is_no_execute_enabled;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+/// Where ET_DYN executables, which are usually linked at 0, are placed
+pub const USER_PIE_BASE: u64 = USER_CODE_BASE;
+/// Segments should be below this, so that they do not overlap the heap
+/// and the stack.
+const USER_IMAGE_END: u64 = USER_HEAP_BASE;
+/// Limit of the strings and pointers put on the stack by the kernel
+const MAX_INITIAL_STACK_SIZE: usize = USER_STACK_SIZE / 4;
+const AT_NULL: u64 = 0;
+
+fn page_start(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE as u64 - 1)
+}
+fn page_end(addr: u64) -> u64 {
+    page_start(addr + PAGE_SIZE as u64 - 1)
+}
+
+fn segment_attr(segment: &ProgramHeader) -> PageAttr {
+    let attr = if segment.is_writable() {
+        PageAttr::READ_WRITE_USER
+    } else {
+        PageAttr::READ_ONLY_USER
+    };
+    if !segment.is_executable() && is_no_execute_enabled() {
+        attr | PageAttr::NO_EXECUTE
+    } else {
+        attr
+    }
+}
+
+/// Lays out argc, argv and envp for a stack that ends at stack_top.
+/// Returns the contents to be placed at the returned stack pointer, which
+/// is 16 byte aligned.
+pub fn build_initial_stack(stack_top: u64, argv: &[&str], envp: &[&str]) -> Result<(Vec<u8>, u64)> {
+    let strings = argv.iter().chain(envp);
+    if strings.clone().any(|s| s.contains('\0')) {
+        return Err("Arguments should not contain NUL");
+    }
+    // argc, argv, NULL, envp, NULL and an AT_NULL entry of auxv
+    let num_words = 1 + argv.len() + 1 + envp.len() + 1 + 2;
+    let strings_size: usize = strings.clone().map(|s| s.len() + 1).sum();
+    let size = num_words * size_of::<u64>() + strings_size;
+    if size > MAX_INITIAL_STACK_SIZE {
+        return Err("Arguments are too long");
+    }
+    let rsp = (stack_top - size as u64) & !0xF;
+    let mut stack = vec![0u8; (stack_top - rsp) as usize];
+    let mut addrs = Vec::with_capacity(argv.len() + envp.len());
+    let mut offset = num_words * size_of::<u64>();
+    for s in strings {
+        addrs.push(rsp + offset as u64);
+        stack[offset..offset + s.len()].copy_from_slice(s.as_bytes());
+        offset += s.len() + 1;
+    }
+    let (argv_addrs, envp_addrs) = addrs.split_at(argv.len());
+    let mut words = Vec::with_capacity(num_words);
+    words.push(argv.len() as u64);
+    words.extend(argv_addrs);
+    words.push(0);
+    words.extend(envp_addrs);
+    words.extend([0, AT_NULL, 0]);
+    for (dst, word) in stack.chunks_exact_mut(size_of::<u64>()).zip(&words) {
+        dst.copy_from_slice(&word.to_le_bytes());
+    }
+    Ok((stack, rsp))
+}
+
+pub struct Process {
+    name: String,
+    space: AddressSpace,
+    entry: u64,
+    stack_pointer: u64,
+}
+impl Process {
+    /// Maps the executable in file to a new address space, with argv and
+    /// envp on its stack. Nothing runs until run() is called.
+    pub fn load(name: &str, file: &[u8], argv: &[&str], envp: &[&str]) -> Result<Self> {
+        let elf = ElfImage::parse(file)?;
+        let bias = if elf.is_position_independent() {
+            USER_PIE_BASE
+        } else {
+            0
+        };
+        let mut space = AddressSpace::new()?;
+        // Keep the null page unmapped.
+        let mut mapped_end = PAGE_SIZE as u64;
+        for s in elf.load_segments() {
+            if s.is_writable() && s.is_executable() {
+                return Err("Segment is writable and executable");
+            }
+            let range = s.virt_range();
+            if range.is_empty() {
+                continue;
+            }
+            let start = range.start.checked_add(bias).ok_or("Segment overflows")?;
+            let end = range.end.checked_add(bias).ok_or("Segment overflows")?;
+            if end > USER_IMAGE_END {
+                return Err("Segment is not in the user space");
+            }
+            let virt = page_start(start);
+            if virt < mapped_end {
+                return Err("Segments overlap, share a page or map the null page");
+            }
+            mapped_end = page_end(end);
+            space.alloc(virt, (mapped_end - virt) as usize, segment_attr(&s))?;
+            space.write(start, elf.segment_data(&s))?;
+        }
+        for r in elf.relocations()? {
+            if r.reloc_type() != R_X86_64_RELATIVE {
+                return Err("Unsupported relocation type");
+            }
+            let target = r.offset().checked_add(bias).ok_or("Bad relocation")?;
+            if target.saturating_add(8) > mapped_end {
+                return Err("Relocation target is not in the image");
+            }
+            let value = bias.wrapping_add(r.addend() as u64);
+            space.write(target, &value.to_le_bytes())?;
+        }
+        let entry = elf.entry().checked_add(bias).ok_or("Bad entry point")?;
+        let is_executable = space.table().effective_attr(entry).is_some_and(|attr| {
+            attr.contains(PageAttr::READ_ONLY_USER) && !attr.contains(PageAttr::NO_EXECUTE)
+        });
+        if !is_executable {
+            return Err("Entry point is not in an executable segment");
+        }
+        let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE as u64;
+        let stack_attr = if is_no_execute_enabled() {
+            PageAttr::READ_WRITE_USER | PageAttr::NO_EXECUTE
+        } else {
+            PageAttr::READ_WRITE_USER
+        };
+        space.alloc(stack_bottom, USER_STACK_SIZE, stack_attr)?;
+        let (stack, stack_pointer) = build_initial_stack(USER_STACK_TOP, argv, envp)?;
+        space.write(stack_pointer, &stack)?;
+        Ok(Self {
+            name: String::from(name),
+            space,
+            entry,
+            stack_pointer,
+        })
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+    /// Runs the process until it exits or causes an exception. This should
+    /// be called only once, from a task.
+    pub async fn run(&mut self) -> Result<UserExit> {
+        self.space
+            .run(self.entry, self.stack_pointer, self.stack_pointer)
+            .await
+    }
+}
+
+#[test_case]
+fn initial_stack_has_argv_and_envp() {
+    let top = 0x10_0000;
+    let (stack, rsp) = build_initial_stack(top, &["app", "-v"], &["A=1"]).unwrap();
+    assert_eq!(rsp % 16, 0);
+    assert_eq!(rsp + stack.len() as u64, top);
+    let word = |i: usize| u64::from_le_bytes(stack[i * 8..i * 8 + 8].try_into().unwrap());
+    let string = |addr: u64| {
+        let s = &stack[(addr - rsp) as usize..];
+        core::str::from_utf8(&s[..s.iter().position(|c| *c == 0).unwrap()]).unwrap()
+    };
+    assert_eq!(word(0), 2);
+    assert_eq!(string(word(1)), "app");
+    assert_eq!(string(word(2)), "-v");
+    assert_eq!(word(3), 0);
+    assert_eq!(string(word(4)), "A=1");
+    assert_eq!([word(5), word(6), word(7)], [0, AT_NULL, 0]);
+    let (_, rsp) = build_initial_stack(top, &[], &[]).unwrap();
+    assert_eq!(top - rsp, 48);
+    assert!(build_initial_stack(top, &["a\0b"], &[]).is_err());
+}
+
+#[test_case]
+fn load_maps_segments_with_their_permissions() {
+    use crate::elf::build_test_elf;
+    use crate::elf::PF_R;
+    use crate::elf::PF_W;
+    use crate::elf::PF_X;
+    fn set_flags(image: &mut [u8], index: usize, flags: u32) {
+        let offset = 0x40 + index * size_of::<ProgramHeader>() + 4;
+        image[offset..offset + 4].copy_from_slice(&flags.to_le_bytes());
+    }
+    let no_execute = |attr: PageAttr| {
+        if is_no_execute_enabled() {
+            attr | PageAttr::NO_EXECUTE
+        } else {
+            attr
+        }
+    };
+    let mut image = build_test_elf(0);
+    let process = Process::load("test", &image, &["test"], &[]).unwrap();
+    let table = process.space.table();
+    assert_eq!(process.entry(), USER_PIE_BASE + 0x1000);
+    assert_eq!(
+        table.effective_attr(USER_PIE_BASE + 0x1000),
+        Some(PageAttr::READ_ONLY_USER)
+    );
+    assert_eq!(
+        table.effective_attr(USER_PIE_BASE + 0x4000),
+        Some(no_execute(PageAttr::READ_WRITE_USER))
+    );
+    assert_eq!(table.effective_attr(USER_PIE_BASE), None);
+    let phys = table.virt_to_phys(USER_PIE_BASE + 0x2300).unwrap();
+    assert_eq!(unsafe { *phys.as_mut_ptr::<u64>() }, USER_PIE_BASE + 0x1010);
+    drop(process);
+
+    set_flags(&mut image, 1, PF_R);
+    let process = Process::load("test", &image, &[], &[]).unwrap();
+    assert_eq!(
+        process.space.table().effective_attr(USER_PIE_BASE + 0x2000),
+        Some(no_execute(PageAttr::READ_ONLY_USER))
+    );
+    drop(process);
+
+    set_flags(&mut image, 0, PF_R | PF_W | PF_X);
+    assert_eq!(
+        Process::load("test", &image, &[], &[]).err(),
+        Some("Segment is writable and executable")
+    );
+    // The data segment ends above USER_IMAGE_END.
+    let image = build_test_elf(USER_IMAGE_END - USER_PIE_BASE - 0x2000);
+    assert_eq!(
+        Process::load("test", &image, &[], &[]).err(),
+        Some("Segment is not in the user space")
+    );
+}
@@ -0,0 +1,380 @@
+//! Startup of the application processors (APs)
+//!
+//! The boot CPU starts each enabled CPU in the MADT with the INIT-SIPI-SIPI
+//! sequence, one by one. An AP starts in real mode at the page given by
+//! the SIPI, so the trampoline below is copied to a page below 1MiB. It
+//! goes through protected mode to long mode with a temporary GDT and page
+//! table given in ApBootParams, then jumps to ap_main() on its own kernel
+//! stack. Each AP loads its own GDT, TSS and IDT there, and runs the tasks
+//! spawned with spawn_shared().
+//! c.f. SDM Vol.3: 9.4 Multiple-Processor (MP) Initialization
+
+extern crate alloc;
+
+use crate::acpi::AcpiRsdpStruct;
+use crate::acpi::MadtEntry;
+use crate::addr::PhysAddr;
+use crate::addr::VirtAddr;
+use crate::apic::init_local_apic;
+use crate::apic::LocalApic;
+use crate::executor::run_shared_tasks;
+use crate::frame::FrameConstraints;
+use crate::frame::PhysFrames;
+use crate::hpet::global_timestamp;
+use crate::info;
+use crate::percpu::init_ap_cpu_data;
+use crate::percpu::init_bsp_cpu_data;
+use crate::percpu::this_cpu;
+use crate::percpu::CpuData;
+use crate::percpu::MAX_CPUS;
+use crate::result::Result;
+use crate::stack::KernelStack;
+use crate::user::init_syscall;
+use crate::warn;
+use crate::x86::available_attr;
+use crate::x86::busy_loop_hint;
+use crate::x86::enable_interrupts;
+use crate::x86::init_exceptions;
+use crate::x86::init_page_attributes;
+use crate::x86::read_cr0;
+use crate::x86::read_cr3;
+use crate::x86::read_cr4;
+use crate::x86::read_msr;
+use crate::x86::take_current_page_table;
+use crate::x86::write_cr3;
+use crate::x86::PageAttr;
+use crate::x86::MSR_EFER;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::mem::offset_of;
+use core::mem::size_of;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+const AP_STACK_SIZE: usize = 256 * 1024;
+const AP_START_TIMEOUT: Duration = Duration::from_secs(1);
+/// Where ApBootParams is placed in the trampoline page
+const PARAMS_OFFSET: usize = 0x800;
+
+static APS_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true once init_smp() starts the other CPUs. Their TLBs are not
+/// flushed by the current CPU after that.
+pub fn are_aps_started() -> bool {
+    APS_STARTED.load(Ordering::SeqCst)
+}
+
+const CR4_PCIDE: u64 = 1 << 17;
+const EFER_LMA: u64 = 1 << 10;
+
+// Selectors of the temporary GDT
+const BOOT_CS32: u16 = 1 << 3;
+const BOOT_DS32: u16 = 2 << 3;
+const BOOT_CS64: u16 = 3 << 3;
+// Flat 4GiB code and data segments for the 32-bit stage, and a code
+// segment for long mode.
+const BOOT_GDT: [u64; 4] = [
+    0,
+    0x00CF_9A00_0000_FFFF,
+    0x00CF_9200_0000_FFFF,
+    0x00AF_9A00_0000_FFFF,
+];
+
+/// Operand of a far jump through memory (m16:32)
+#[repr(C)]
+struct FarPointer {
+    offset: u32,
+    selector: u16,
+}
+
+/// Values for the trampoline, filled by the boot CPU
+#[repr(C)]
+struct ApBootParams {
+    gdt: [u64; 4],
+    /// Operand of lgdt: the limit and the 32-bit base split into two
+    gdtr: [u16; 4],
+    entry32: FarPointer,
+    entry64: FarPointer,
+    cr0: u64,
+    /// The temporary page table below 4GiB
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    stack_top: u64,
+    ap_main: u64,
+    /// The first argument of ap_main()
+    arg: u64,
+}
+const _: () = assert!(PARAMS_OFFSET + size_of::<ApBootParams>() <= PAGE_SIZE);
+
+// The trampoline. CS:IP is (page >> 4):0000 when it starts, and EBX holds
+// the physical address of the page after the 16-bit stage. Everything
+// here is relative to the page, as it runs at a copy of this code.
+global_asm!(
+    r#"
+.global ap_trampoline_start
+.global ap_trampoline32
+.global ap_trampoline64
+.global ap_trampoline_end
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    xor ebx, ebx
+    mov bx, ax
+    shl ebx, 4
+    lgdt [{gdtr}]
+    mov eax, cr0
+    or eax, 1 // PE
+    mov cr0, eax
+    jmp fword ptr [{entry32}]
+.code32
+ap_trampoline32:
+    mov ax, {ds32}
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov eax, [ebx + {cr4}]
+    mov cr4, eax
+    mov eax, [ebx + {cr3}]
+    mov cr3, eax
+    mov ecx, {msr_efer}
+    mov eax, [ebx + {efer}]
+    xor edx, edx
+    wrmsr
+    // Enables paging, and long mode with it.
+    mov eax, [ebx + {cr0}]
+    mov cr0, eax
+    jmp fword ptr [ebx + {entry64}]
+.code64
+ap_trampoline64:
+    // The upper halves of the registers are undefined here.
+    mov ebx, ebx
+    mov rsp, [rbx + {stack_top}]
+    mov rdi, [rbx + {arg}]
+    mov rax, [rbx + {ap_main}]
+    // Terminate the chain of frame pointers, with a null return address.
+    xor ebp, ebp
+    push 0
+    jmp rax
+ap_trampoline_end:
+"#,
+    gdtr = const PARAMS_OFFSET + offset_of!(ApBootParams, gdtr),
+    entry32 = const PARAMS_OFFSET + offset_of!(ApBootParams, entry32),
+    ds32 = const BOOT_DS32,
+    cr4 = const PARAMS_OFFSET + offset_of!(ApBootParams, cr4),
+    cr3 = const PARAMS_OFFSET + offset_of!(ApBootParams, cr3),
+    msr_efer = const MSR_EFER,
+    efer = const PARAMS_OFFSET + offset_of!(ApBootParams, efer),
+    cr0 = const PARAMS_OFFSET + offset_of!(ApBootParams, cr0),
+    entry64 = const PARAMS_OFFSET + offset_of!(ApBootParams, entry64),
+    stack_top = const PARAMS_OFFSET + offset_of!(ApBootParams, stack_top),
+    arg = const PARAMS_OFFSET + offset_of!(ApBootParams, arg),
+    ap_main = const PARAMS_OFFSET + offset_of!(ApBootParams, ap_main),
+);
+
+extern "sysv64" {
+    fn ap_trampoline_start();
+    fn ap_trampoline32();
+    fn ap_trampoline64();
+    fn ap_trampoline_end();
+}
+
+fn trampoline_code() -> &'static [u8] {
+    let start = ap_trampoline_start as usize;
+    // SAFETY: the code is between the labels in the same section.
+    unsafe { core::slice::from_raw_parts(start as *const u8, ap_trampoline_end as usize - start) }
+}
+
+/// What an AP needs after leaving the trampoline
+struct ApStartInfo {
+    cpu_data: *mut CpuData,
+    kernel_cr3: PhysAddr,
+    is_started: AtomicBool,
+}
+
+extern "sysv64" fn ap_main(info: &'static ApStartInfo) -> ! {
+    // SAFETY: nothing has used this_cpu() on this CPU yet.
+    unsafe { init_ap_cpu_data(info.cpu_data) };
+    // SAFETY: the kernel page table maps everything that the temporary one
+    // does, except for the trampoline page that is not used anymore.
+    unsafe { write_cr3(info.kernel_cr3) };
+    init_page_attributes();
+    // The temporary GDT is not mapped anymore, but no segment is loaded
+    // until the new one is loaded here.
+    let (gdt, idt) = init_exceptions();
+    init_syscall(&gdt);
+    // They are used until the CPU stops.
+    core::mem::forget((gdt, idt));
+    init_local_apic();
+    let cpu = this_cpu();
+    info!("CPU {} (APIC ID {}) is up", cpu.id(), cpu.apic_id());
+    info.is_started.store(true, Ordering::SeqCst);
+    // SAFETY: the IDT is loaded, and no IRQ is routed to this CPU.
+    unsafe { enable_interrupts() };
+    run_shared_tasks()
+}
+
+/// The page that APs start from. The page and its page table are never
+/// freed, since an AP that failed to start in time may still run them.
+struct Trampoline {
+    page: PhysAddr,
+}
+impl Trampoline {
+    fn new() -> Result<Self> {
+        let code = trampoline_code();
+        if code.len() > PARAMS_OFFSET {
+            return Err("The trampoline code overlaps with its parameters");
+        }
+        let page = PhysFrames::alloc_zeroed(1, FrameConstraints::ANY.below(0x10_0000))?.leak();
+        // CR3 is loaded in 32-bit mode, so only the PML4 should be below
+        // 4GiB.
+        let table = PML4::new_with(FrameConstraints::BELOW_4G)?;
+        // SAFETY: the current table is only read.
+        table.share_kernel_space(unsafe { take_current_page_table() });
+        table.create_mapping(
+            page.as_u64(),
+            page.as_u64() + PAGE_SIZE as u64,
+            page,
+            available_attr(PageAttr::READ_WRITE_KERNEL),
+        )?;
+        let base = page.as_u64() as u32;
+        let gdtr_base = base + (PARAMS_OFFSET + offset_of!(ApBootParams, gdt)) as u32;
+        let offset_of_label = |label: unsafe extern "sysv64" fn()| {
+            (label as usize - ap_trampoline_start as usize) as u32
+        };
+        let params = ApBootParams {
+            gdt: BOOT_GDT,
+            gdtr: [
+                (size_of::<[u64; 4]>() - 1) as u16,
+                gdtr_base as u16,
+                (gdtr_base >> 16) as u16,
+                0,
+            ],
+            entry32: FarPointer {
+                offset: base + offset_of_label(ap_trampoline32),
+                selector: BOOT_CS32,
+            },
+            entry64: FarPointer {
+                offset: base + offset_of_label(ap_trampoline64),
+                selector: BOOT_CS64,
+            },
+            cr0: read_cr0(),
+            cr3: VirtAddr::from_ptr(table).to_phys().as_u64(),
+            // PCIDE can not be set outside of long mode, and LMA is set by
+            // the CPU.
+            cr4: read_cr4() & !CR4_PCIDE,
+            efer: read_msr(MSR_EFER) & !EFER_LMA,
+            stack_top: 0,
+            ap_main: ap_main as usize as u64,
+            arg: 0,
+        };
+        // SAFETY: the page is allocated above, and large enough for both.
+        unsafe {
+            core::ptr::copy_nonoverlapping(code.as_ptr(), page.as_mut_ptr::<u8>(), code.len());
+            (page + PARAMS_OFFSET as u64)
+                .as_mut_ptr::<ApBootParams>()
+                .write(params);
+        }
+        Ok(Self { page })
+    }
+    /// The vector of the startup IPI
+    fn vector(&self) -> u8 {
+        (self.page.as_u64() >> 12) as u8
+    }
+    /// # Safety
+    /// No AP should be running the trampoline.
+    unsafe fn prepare(&self, stack_top: u64, info: &'static ApStartInfo) {
+        let params = &mut *(self.page + PARAMS_OFFSET as u64).as_mut_ptr::<ApBootParams>();
+        params.stack_top = stack_top;
+        params.arg = info as *const ApStartInfo as u64;
+    }
+}
+
+fn busy_wait(duration: Duration) {
+    let end = global_timestamp() + duration;
+    while global_timestamp() < end {
+        busy_loop_hint();
+    }
+}
+
+fn start_ap(trampoline: &Trampoline, id: usize, apic_id: u32) -> Result<()> {
+    let stack = KernelStack::alloc("ap", AP_STACK_SIZE)?;
+    let info = Box::leak(Box::new(ApStartInfo {
+        cpu_data: Box::leak(Box::new(CpuData::new(id, apic_id))),
+        kernel_cr3: read_cr3(),
+        is_started: AtomicBool::new(false),
+    }));
+    let lapic = LocalApic::current();
+    // SAFETY: APs are started one by one, and the CPU of apic_id is not
+    // running anything yet.
+    unsafe {
+        trampoline.prepare(stack.top(), info);
+        lapic.send_init(apic_id);
+        busy_wait(Duration::from_millis(10));
+        // The second SIPI is ignored if the first one worked.
+        for _ in 0..2 {
+            lapic.send_startup(apic_id, trampoline.vector());
+            busy_wait(Duration::from_micros(200));
+        }
+    }
+    let deadline = global_timestamp() + AP_START_TIMEOUT;
+    while !info.is_started.load(Ordering::SeqCst) {
+        if global_timestamp() > deadline {
+            return Err("The CPU did not start in time");
+        }
+        busy_loop_hint();
+    }
+    Ok(())
+}
+
+/// Sets up the per-CPU data of the boot CPU, then starts the other CPUs
+/// enabled in the MADT. Returns the number of CPUs running. This should be
+/// called after the HPET and the local APIC are initialized.
+pub fn init_smp(acpi: &AcpiRsdpStruct) -> Result<usize> {
+    let bsp_apic_id = LocalApic::current().id();
+    init_bsp_cpu_data(bsp_apic_id);
+    let madt = acpi.madt().ok_or("MADT not found")?;
+    let ap_apic_ids: Vec<u32> = madt
+        .entries()
+        .filter_map(|e| match e {
+            MadtEntry::LocalApic {
+                apic_id,
+                enabled: true,
+                ..
+            } if apic_id != bsp_apic_id => Some(apic_id),
+            _ => None,
+        })
+        .collect();
+    if ap_apic_ids.is_empty() {
+        return Ok(1);
+    }
+    let trampoline = Trampoline::new()?;
+    APS_STARTED.store(true, Ordering::SeqCst);
+    let mut num_cpus = 1;
+    for apic_id in ap_apic_ids {
+        if num_cpus >= MAX_CPUS {
+            warn!("Only {MAX_CPUS} CPUs are supported, others are not started");
+            break;
+        }
+        match start_ap(&trampoline, num_cpus, apic_id) {
+            Ok(()) => num_cpus += 1,
+            Err(e) => warn!("Failed to start the CPU of APIC ID {apic_id}: {e}"),
+        }
+    }
+    info!("{num_cpus} CPUs are running");
+    Ok(num_cpus)
+}
+
+#[test_case]
+fn trampoline_fits_before_params() {
+    assert!(trampoline_code().len() <= PARAMS_OFFSET);
+    assert_eq!(trampoline_code().first(), Some(&0xFA)); // cli
+    assert_eq!(size_of::<FarPointer>(), 8);
+}
@@ -0,0 +1,160 @@
+//! Kernel stacks with guard pages
+//!
+//! Every kernel stack has an unmapped page right below it so that an
+//! overflow faults instead of corrupting the memory next to it.
+//! Stacks are registered here so that the fault handlers can tell
+//! which stack has overflowed, without taking any locks.
+
+extern crate alloc;
+
+use crate::frame::FrameConstraints;
+use crate::frame::PhysFrames;
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::x86::with_current_page_table;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::cell::SyncUnsafeCell;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+const MAX_KERNEL_STACKS: usize = 1024;
+
+/// A kernel stack. Stacks are never freed once allocated.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    name: &'static str,
+    guard_page: u64,
+    top: u64,
+}
+impl KernelStack {
+    /// Allocates a stack of size bytes (rounded up to pages) with a guard
+    /// page below it. The guard page is unmapped right away if paging is
+    /// already initialized, or by init_paging() otherwise.
+    pub fn alloc(name: &'static str, size: usize) -> Result<Self> {
+        let num_pages = size.div_ceil(PAGE_SIZE);
+        let frames = PhysFrames::alloc_zeroed(num_pages + 1, FrameConstraints::ANY)?;
+        let guard_page = frames.leak().to_virt().as_u64();
+        let stack = Self {
+            name,
+            guard_page,
+            top: guard_page + ((num_pages + 1) * PAGE_SIZE) as u64,
+        };
+        register(stack)?;
+        if GUARD_PAGES_ENABLED.load(Ordering::SeqCst) {
+            // SAFETY: the guard page is owned by this stack and no one uses it.
+            unsafe {
+                with_current_page_table(|pt| {
+                    pt.unmap(guard_page, guard_page + PAGE_SIZE as u64)
+                        .expect("Failed to unmap a guard page")
+                })
+            }
+        }
+        Ok(stack)
+    }
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    /// The initial value of RSP for this stack
+    pub fn top(&self) -> u64 {
+        self.top
+    }
+    pub fn bottom(&self) -> u64 {
+        self.guard_page + PAGE_SIZE as u64
+    }
+    pub fn guard_page(&self) -> u64 {
+        self.guard_page
+    }
+    /// Switches to this stack and calls f on it. The current stack will not
+    /// be used anymore (but not freed either). f should not return.
+    pub fn switch_to(&self, f: impl FnOnce() + 'static) -> ! {
+        extern "sysv64" fn trampoline(f: *mut Box<dyn FnOnce()>) -> ! {
+            // SAFETY: f was made from Box::into_raw() in switch_to().
+            let f = unsafe { Box::from_raw(f) };
+            f();
+            panic!("Returned to the bottom of a kernel stack");
+        }
+        let f: Box<Box<dyn FnOnce()>> = Box::new(Box::new(f));
+        // SAFETY: the stack is valid and 16-byte aligned.
+        // RBP is cleared to terminate the chain of frame pointers.
+        unsafe {
+            asm!(
+                "mov rsp, {top}",
+                "xor ebp, ebp",
+                "call {trampoline}",
+                "ud2",
+                top = in(reg) self.top,
+                trampoline = sym trampoline,
+                in("rdi") Box::into_raw(f),
+                options(noreturn)
+            )
+        }
+    }
+}
+
+static STACKS: SyncUnsafeCell<[Option<KernelStack>; MAX_KERNEL_STACKS]> =
+    SyncUnsafeCell::new([None; MAX_KERNEL_STACKS]);
+static NUM_STACKS: AtomicUsize = AtomicUsize::new(0);
+static REGISTER_LOCK: Mutex<()> = Mutex::new(());
+static GUARD_PAGES_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn register(stack: KernelStack) -> Result<()> {
+    let _lock = REGISTER_LOCK.lock();
+    let index = NUM_STACKS.load(Ordering::SeqCst);
+    if index >= MAX_KERNEL_STACKS {
+        return Err("Too many kernel stacks");
+    }
+    // SAFETY: slots at index and above are not visible to readers
+    // until NUM_STACKS is updated, and writers are serialized by the lock.
+    unsafe { (*STACKS.get())[index] = Some(stack) };
+    NUM_STACKS.store(index + 1, Ordering::SeqCst);
+    Ok(())
+}
+
+fn stacks() -> impl Iterator<Item = KernelStack> {
+    let num_stacks = NUM_STACKS.load(Ordering::SeqCst);
+    // SAFETY: slots below NUM_STACKS are never modified again.
+    let stacks = unsafe { &*STACKS.get() };
+    stacks[..num_stacks].iter().flatten().copied()
+}
+
+/// Returns the stack whose guard page contains addr.
+/// This does not take any locks so it is safe to call from fault handlers.
+pub fn find_stack_by_guard_page(addr: u64) -> Option<KernelStack> {
+    stacks().find(|s| (s.guard_page..s.bottom()).contains(&addr))
+}
+
+/// Returns the stack that contains addr, excluding its guard page.
+/// This does not take any locks so it is safe to call from fault handlers.
+pub fn find_stack(addr: u64) -> Option<KernelStack> {
+    stacks().find(|s| (s.bottom()..s.top()).contains(&addr))
+}
+
+/// Unmaps the guard pages of all the stacks in the table, which will
+/// become the current page table. Stacks allocated after this call get
+/// their guard pages unmapped from the current page table.
+pub fn enable_guard_pages(table: &mut PML4) -> Result<()> {
+    let _lock = REGISTER_LOCK.lock();
+    for s in stacks() {
+        table.unmap(s.guard_page, s.bottom())?;
+    }
+    GUARD_PAGES_ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+#[test_case]
+fn kernel_stack_is_registered() {
+    let stack = KernelStack::alloc("test", 3 * PAGE_SIZE + 1).unwrap();
+    assert_eq!(stack.top() % 16, 0);
+    assert_eq!(stack.top() - stack.bottom(), 4 * PAGE_SIZE as u64);
+    assert_eq!(stack.bottom() - stack.guard_page(), PAGE_SIZE as u64);
+    let found = find_stack_by_guard_page(stack.guard_page() + 8).unwrap();
+    assert_eq!(found.name(), "test");
+    assert_eq!(found.top(), stack.top());
+    assert!(find_stack_by_guard_page(stack.bottom()).is_none());
+    assert_eq!(find_stack(stack.bottom()).unwrap().top(), stack.top());
+    assert!(find_stack(stack.guard_page()).is_none());
+}
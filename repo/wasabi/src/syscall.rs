@@ -0,0 +1,321 @@
+//! The system call interface for user mode
+//!
+//! See syscall_abi.rs for the numbers and the calling convention. The
+//! handlers are looked up in SYSCALL_TABLE by the number.
+//!
+//! Pointers given by the user are checked against the page table before
+//! being accessed, and the data is copied via the direct map.
+
+extern crate alloc;
+
+use crate::hpet::global_timestamp;
+use crate::keyboard::pop_key_event;
+use crate::keyboard::KeyEvent;
+use crate::print;
+use crate::syscall_abi::encode_result;
+use crate::syscall_abi::SyscallError;
+use crate::syscall_abi::SyscallResult;
+use crate::syscall_abi::KEY_EVENT_NOT_CHAR;
+use crate::syscall_abi::SYSCALL_ALLOC_PAGES;
+use crate::syscall_abi::SYSCALL_EXIT;
+use crate::syscall_abi::SYSCALL_GET_TIME;
+use crate::syscall_abi::SYSCALL_READ_KEY_EVENTS;
+use crate::syscall_abi::SYSCALL_SLEEP;
+use crate::syscall_abi::SYSCALL_WRITE_CONSOLE;
+use crate::user::exit_user;
+use crate::user::sleep_after_syscall;
+use crate::user::with_running_space;
+use crate::user::SyscallFrame;
+use crate::user::USER_SPACE_END;
+use crate::warn;
+use crate::x86::is_no_execute_enabled;
+use crate::x86::read_cr3;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use alloc::vec;
+use core::time::Duration;
+
+const MAX_WRITE_CONSOLE_LEN: usize = 4096;
+const MAX_READ_KEY_EVENTS: usize = 256;
+const MAX_ALLOC_PAGES: usize = 4096;
+
+/// Encodes a key event for read_key_events: a Unicode scalar value for
+/// characters (0x0A for Enter), or 0x8000_0000 | usage ID for other keys.
+pub fn encode_key_event(e: KeyEvent) -> u32 {
+    match e {
+        KeyEvent::Char(c) => c as u32,
+        KeyEvent::Enter => '\n' as u32,
+        KeyEvent::Unknown(usage_id) => KEY_EVENT_NOT_CHAR | usage_id as u32,
+        KeyEvent::None => KEY_EVENT_NOT_CHAR,
+    }
+}
+
+/// Checks that the user can access every page of [virt, virt + len) with
+/// required, in the page table.
+pub fn check_user_buffer(
+    table: &PML4,
+    virt: u64,
+    len: usize,
+    required: PageAttr,
+) -> SyscallResult<()> {
+    let end = virt
+        .checked_add(len as u64)
+        .filter(|end| *end <= USER_SPACE_END)
+        .ok_or(SyscallError::BadAddress)?;
+    let required = required | PageAttr::PRESENT | PageAttr::USER;
+    let mut page = virt & !(PAGE_SIZE as u64 - 1);
+    while page < end {
+        match table.effective_attr(page) {
+            Some(attr) if attr.contains(required) => {}
+            _ => return Err(SyscallError::BadAddress),
+        }
+        page += PAGE_SIZE as u64;
+    }
+    Ok(())
+}
+/// Calls f with the pieces of [virt, virt + len), split at page boundaries,
+/// as pointers in the direct map and the offsets from virt. All the pages
+/// should be mapped.
+fn for_each_user_chunk(
+    table: &PML4,
+    virt: u64,
+    len: usize,
+    mut f: impl FnMut(*mut u8, usize, usize),
+) {
+    let mut done = 0;
+    while done < len {
+        let addr = virt + done as u64;
+        let phys = table
+            .virt_to_phys(addr)
+            .expect("The buffer should be checked already");
+        let chunk_len = (PAGE_SIZE - (addr as usize % PAGE_SIZE)).min(len - done);
+        f(phys.as_mut_ptr::<u8>(), done, chunk_len);
+        done += chunk_len;
+    }
+}
+/// Copies dst.len() bytes at src in the user space of table to dst.
+pub fn copy_from_user(table: &PML4, src: u64, dst: &mut [u8]) -> SyscallResult<()> {
+    check_user_buffer(table, src, dst.len(), PageAttr::READ_ONLY_USER)?;
+    for_each_user_chunk(table, src, dst.len(), |p, offset, len| {
+        // SAFETY: the page is mapped, and dst does not overlap with user
+        // pages since it is not in the user space.
+        unsafe { core::ptr::copy_nonoverlapping(p, dst[offset..].as_mut_ptr(), len) }
+    });
+    Ok(())
+}
+/// Copies src to dst in the user space of table.
+pub fn copy_to_user(table: &PML4, dst: u64, src: &[u8]) -> SyscallResult<()> {
+    check_user_buffer(table, dst, src.len(), PageAttr::READ_WRITE_USER)?;
+    for_each_user_chunk(table, dst, src.len(), |p, offset, len| {
+        // SAFETY: same as copy_from_user().
+        unsafe { core::ptr::copy_nonoverlapping(src[offset..].as_ptr(), p, len) }
+    });
+    Ok(())
+}
+
+/// Arguments of a syscall in the order of the ABI
+pub struct SyscallArgs([u64; 6]);
+impl SyscallArgs {
+    fn from_frame(frame: &SyscallFrame) -> Self {
+        Self([
+            frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+        ])
+    }
+    fn usize(&self, index: usize) -> SyscallResult<usize> {
+        usize::try_from(self.0[index]).map_err(|_| SyscallError::InvalidArgument)
+    }
+}
+
+type SyscallHandler = fn(&SyscallArgs) -> SyscallResult;
+struct SyscallEntry {
+    number: u64,
+    name: &'static str,
+    handler: SyscallHandler,
+}
+/// Indexed by the syscall number
+static SYSCALL_TABLE: [SyscallEntry; 6] = [
+    SyscallEntry {
+        number: SYSCALL_EXIT,
+        name: "exit",
+        handler: sys_exit,
+    },
+    SyscallEntry {
+        number: SYSCALL_WRITE_CONSOLE,
+        name: "write_console",
+        handler: sys_write_console,
+    },
+    SyscallEntry {
+        number: SYSCALL_READ_KEY_EVENTS,
+        name: "read_key_events",
+        handler: sys_read_key_events,
+    },
+    SyscallEntry {
+        number: SYSCALL_SLEEP,
+        name: "sleep",
+        handler: sys_sleep,
+    },
+    SyscallEntry {
+        number: SYSCALL_GET_TIME,
+        name: "get_time",
+        handler: sys_get_time,
+    },
+    SyscallEntry {
+        number: SYSCALL_ALLOC_PAGES,
+        name: "alloc_pages",
+        handler: sys_alloc_pages,
+    },
+];
+
+/// Handles the syscall made by the user code, and returns the value of rax
+/// for the user.
+pub(crate) fn dispatch_syscall(frame: &SyscallFrame) -> u64 {
+    let Some(entry) = usize::try_from(frame.rax)
+        .ok()
+        .and_then(|n| SYSCALL_TABLE.get(n))
+    else {
+        warn!("Unknown syscall {:#X} at {:#018X}", frame.rax, frame.rip());
+        return encode_result(Err(SyscallError::NoSuchSyscall));
+    };
+    let result = (entry.handler)(&SyscallArgs::from_frame(frame));
+    if let Err(e) = result {
+        warn!(
+            "syscall {} ({}): {e:?} at {:#018X}",
+            entry.number,
+            entry.name,
+            frame.rip()
+        );
+    }
+    encode_result(result)
+}
+
+/// The page table of the user code that made the syscall
+fn user_table() -> &'static PML4 {
+    // SAFETY: the table is not modified while the syscall is handled,
+    // except by the handler itself.
+    unsafe { &*read_cr3().as_mut_ptr::<PML4>() }
+}
+
+fn sys_exit(args: &SyscallArgs) -> SyscallResult {
+    exit_user(args.0[0])
+}
+fn sys_write_console(args: &SyscallArgs) -> SyscallResult {
+    let len = args.usize(1)?;
+    if len > MAX_WRITE_CONSOLE_LEN {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let mut buf = vec![0; len];
+    copy_from_user(user_table(), args.0[0], &mut buf)?;
+    let text = core::str::from_utf8(&buf).map_err(|_| SyscallError::InvalidArgument)?;
+    print!("{text}");
+    Ok(len as u64)
+}
+fn sys_read_key_events(args: &SyscallArgs) -> SyscallResult {
+    let buf = args.0[0];
+    let max = args.usize(1)?;
+    if max > MAX_READ_KEY_EVENTS {
+        return Err(SyscallError::InvalidArgument);
+    }
+    // Check before taking the events, so that they are not lost.
+    check_user_buffer(user_table(), buf, max * 4, PageAttr::READ_WRITE_USER)?;
+    let mut count = 0;
+    while count < max {
+        let Some(e) = pop_key_event() else {
+            break;
+        };
+        let offset = buf + (count * 4) as u64;
+        copy_to_user(user_table(), offset, &encode_key_event(e).to_le_bytes())?;
+        count += 1;
+    }
+    Ok(count as u64)
+}
+fn sys_sleep(args: &SyscallArgs) -> SyscallResult {
+    let ms = args.0[0];
+    if global_timestamp() == Duration::ZERO {
+        // The time never advances without the HPET.
+        return Err(SyscallError::NotReady);
+    }
+    // The task running the user code waits with executor::sleep(), so that
+    // the CPU runs other tasks meanwhile.
+    sleep_after_syscall(Duration::from_millis(ms)).map_err(|_| SyscallError::NotReady)?;
+    Ok(0)
+}
+fn sys_get_time(_: &SyscallArgs) -> SyscallResult {
+    Ok(global_timestamp().as_nanos() as u64)
+}
+fn sys_alloc_pages(args: &SyscallArgs) -> SyscallResult {
+    let num_pages = args.usize(0)?;
+    if num_pages == 0 || num_pages > MAX_ALLOC_PAGES {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let attr = if is_no_execute_enabled() {
+        PageAttr::READ_WRITE_USER | PageAttr::NO_EXECUTE
+    } else {
+        PageAttr::READ_WRITE_USER
+    };
+    with_running_space(|space| space.alloc_pages(num_pages, attr))
+        .ok_or(SyscallError::NotReady)?
+        .map_err(|_| SyscallError::OutOfMemory)
+}
+
+#[test_case]
+fn syscall_table_is_indexed_by_number() {
+    for (i, entry) in SYSCALL_TABLE.iter().enumerate() {
+        assert_eq!(entry.number, i as u64, "{}", entry.name);
+    }
+}
+
+#[test_case]
+fn syscall_results_round_trip() {
+    use crate::syscall_abi::decode_result;
+    for result in [
+        Ok(0),
+        Ok(i64::MAX as u64),
+        Err(SyscallError::NoSuchSyscall),
+        Err(SyscallError::BadAddress),
+        Err(SyscallError::NotReady),
+    ] {
+        assert_eq!(decode_result(encode_result(result)), result);
+    }
+    assert_eq!(
+        encode_result(Err(SyscallError::InvalidArgument)),
+        -2i64 as u64
+    );
+}
+
+#[test_case]
+fn user_buffers_are_checked_against_the_page_table() {
+    use crate::user::AddressSpace;
+    use crate::user::USER_CODE_BASE;
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .alloc(USER_CODE_BASE, PAGE_SIZE, PageAttr::READ_ONLY_USER)
+        .unwrap();
+    let data = space.alloc_pages(2, PageAttr::READ_WRITE_USER).unwrap();
+    let table = space.table();
+    let straddling = data + PAGE_SIZE as u64 - 2;
+    copy_to_user(table, straddling, b"wasabi").unwrap();
+    let mut buf = [0; 6];
+    copy_from_user(table, straddling, &mut buf).unwrap();
+    assert_eq!(&buf, b"wasabi");
+    copy_from_user(table, USER_CODE_BASE, &mut buf).unwrap();
+    assert_eq!(
+        copy_to_user(table, USER_CODE_BASE, b"x"),
+        Err(SyscallError::BadAddress)
+    );
+    // The page after the allocation is not mapped.
+    assert_eq!(
+        copy_from_user(table, data + 2 * PAGE_SIZE as u64 - 1, &mut buf),
+        Err(SyscallError::BadAddress)
+    );
+    // Addresses outside of the user pages are rejected.
+    let kernel_addr = &buf as *const [u8; 6] as u64;
+    assert_eq!(
+        copy_from_user(table, kernel_addr, &mut buf),
+        Err(SyscallError::BadAddress)
+    );
+    assert_eq!(
+        check_user_buffer(table, u64::MAX, 2, PageAttr::READ_ONLY_USER),
+        Err(SyscallError::BadAddress)
+    );
+}
@@ -0,0 +1,82 @@
+//! The numbers and the calling convention of the system calls
+//!
+//! User code calls `syscall` with the number in rax and the arguments in
+//! rdi, rsi, rdx, r10, r8 and r9. The result comes back in rax, which is a
+//! non-negative value on success, or a negated SyscallError code. The other
+//! registers are preserved, except rcx and r11 that `syscall` overwrites.
+//!
+//! This file depends on nothing else in the crate, so that user programs
+//! can include it with `#[path]` instead of linking the kernel.
+
+/// exit(code: u64) -> !
+pub const SYSCALL_EXIT: u64 = 0;
+/// write_console(buf: *const u8, len: usize) -> usize
+/// Prints UTF-8 text, and returns the number of bytes written.
+pub const SYSCALL_WRITE_CONSOLE: u64 = 1;
+/// read_key_events(buf: *mut u32, max: usize) -> usize
+/// Takes pending key presses without waiting, and returns the number of
+/// events stored in buf. Characters are stored as Unicode scalar values
+/// (0x0A for Enter), and the other keys with KEY_EVENT_NOT_CHAR.
+pub const SYSCALL_READ_KEY_EVENTS: u64 = 2;
+/// sleep(ms: u64) -> 0
+/// Suspends the caller for at least ms milliseconds.
+pub const SYSCALL_SLEEP: u64 = 3;
+/// get_time() -> u64
+/// Returns the time since boot in nanoseconds.
+pub const SYSCALL_GET_TIME: u64 = 4;
+/// alloc_pages(num_pages: usize) -> *mut u8
+/// Maps zeroed, writable and non-executable pages, and returns the address.
+pub const SYSCALL_ALLOC_PAGES: u64 = 5;
+
+/// Bit set in the key events of read_key_events that are not characters.
+/// The lower bits have the USB HID usage ID of the key.
+pub const KEY_EVENT_NOT_CHAR: u32 = 0x8000_0000;
+
+/// Error codes returned to the user, negated
+#[repr(i64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// The syscall number is not in the table
+    NoSuchSyscall = 1,
+    /// An argument is out of the range that the syscall accepts
+    InvalidArgument = 2,
+    /// A pointer to a buffer that the user can not access as required
+    BadAddress = 3,
+    OutOfMemory = 4,
+    /// The syscall is not usable in the current state of the kernel
+    NotReady = 5,
+}
+impl SyscallError {
+    pub fn from_code(code: i64) -> Option<Self> {
+        Some(match code {
+            1 => Self::NoSuchSyscall,
+            2 => Self::InvalidArgument,
+            3 => Self::BadAddress,
+            4 => Self::OutOfMemory,
+            5 => Self::NotReady,
+            _ => return None,
+        })
+    }
+}
+
+pub type SyscallResult<T = u64> = core::result::Result<T, SyscallError>;
+
+/// Makes the value of rax returned to the user.
+pub fn encode_result(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => {
+            debug_assert!(value as i64 >= 0);
+            value
+        }
+        Err(e) => (-(e as i64)) as u64,
+    }
+}
+/// Reads the value of rax returned from a syscall.
+pub fn decode_result(rax: u64) -> SyscallResult {
+    let value = rax as i64;
+    if value >= 0 {
+        Ok(rax)
+    } else {
+        Err(SyscallError::from_code(-value).unwrap_or(SyscallError::NoSuchSyscall))
+    }
+}
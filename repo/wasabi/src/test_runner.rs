@@ -1,3 +1,6 @@
+use crate::alloc_tracker::dump_outstanding_allocations;
+use crate::alloc_tracker::start_tracking_allocations;
+use crate::alloc_tracker::stop_tracking_allocations;
 use crate::qemu::*;
 use crate::serial::SerialPort;
 use core::any::type_name;
@@ -15,7 +18,16 @@ where
 {
     fn run(&self, writer: &mut SerialPort) {
         writeln!(writer, "[RUNNING] >>> {}", type_name::<T>()).unwrap();
-        self();
+        // Enable the "alloc-tracking" feature to see what each test leaves
+        // allocated.
+        if cfg!(feature = "alloc-tracking") {
+            start_tracking_allocations();
+            self();
+            stop_tracking_allocations();
+            dump_outstanding_allocations();
+        } else {
+            self();
+        }
         writeln!(writer, "[PASS   ] <<< {}", type_name::<T>()).unwrap();
     }
 }
@@ -1,9 +1,14 @@
 use crate::acpi::AcpiRsdpStruct;
+use crate::addr::PhysAddr;
 use crate::graphics::Bitmap;
 use crate::result::Result;
+use core::cmp::max;
 use core::mem::offset_of;
 use core::mem::size_of;
+use core::mem::size_of_val;
+use core::ops::Range;
 use core::ptr::null_mut;
+use core::slice;
 
 type EfiVoid = u8;
 pub type EfiHandle = u64;
@@ -31,6 +36,13 @@ const EFI_LOADED_IMAGE_PROTOCOL_GUID: EfiGuid = EfiGuid {
     data3: [0x8E, 0x3F, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
 };
 
+const EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID: EfiGuid = EfiGuid {
+    data0: 0x964e5b22,
+    data1: 0x6459,
+    data2: 0x11d2,
+    data3: [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+};
+
 const EFI_ACPI_TABLE_GUID: EfiGuid = EfiGuid {
     data0: 0x8868e871,
     data1: 0xe4f1,
@@ -89,6 +101,7 @@ impl EfiMemoryDescriptor {
 
 const MEMORY_MAP_BUFFER_SIZE: usize = 0x8000;
 
+#[repr(C)]
 pub struct MemoryMapHolder {
     memory_map_buffer: [u8; MEMORY_MAP_BUFFER_SIZE],
     memory_map_size: usize,
@@ -137,7 +150,14 @@ impl<'a> Iterator for MemoryMapIterator<'a> {
 
 #[repr(C)]
 pub struct EfiBootServicesTable {
-    _reserved0: [u64; 7],
+    _reserved0: [u64; 5],
+    allocate_pages: extern "win64" fn(
+        allocate_type: u32,
+        memory_type: EfiMemoryType,
+        num_pages: usize,
+        memory: *mut u64,
+    ) -> EfiStatus,
+    _free_pages: u64,
     get_memory_map: extern "win64" fn(
         memory_map_size: *mut usize,
         memory_map: *mut u8,
@@ -171,7 +191,18 @@ impl EfiBootServicesTable {
             &mut map.descriptor_version,
         )
     }
+    /// Allocates num_pages pages of the memory_type anywhere in memory.
+    pub fn allocate_pages(&self, memory_type: EfiMemoryType, num_pages: usize) -> Result<PhysAddr> {
+        const ALLOCATE_ANY_PAGES: u32 = 0;
+        let mut memory = 0;
+        let status = (self.allocate_pages)(ALLOCATE_ANY_PAGES, memory_type, num_pages, &mut memory);
+        if status != EfiStatus::Success {
+            return Err("Failed to allocate pages");
+        }
+        Ok(PhysAddr::new(memory))
+    }
 }
+const _: () = assert!(offset_of!(EfiBootServicesTable, allocate_pages) == 40);
 const _: () = assert!(offset_of!(EfiBootServicesTable, get_memory_map) == 56);
 const _: () = assert!(offset_of!(EfiBootServicesTable, exit_boot_services) == 232);
 const _: () = assert!(offset_of!(EfiBootServicesTable, locate_protocol) == 320);
@@ -253,11 +284,15 @@ fn locate_graphic_protocol<'a>(
     Ok(unsafe { &*graphic_output_protocol })
 }
 
+#[repr(C)]
 pub struct EfiLoadedImageProtocol {
-    _reserved: [u64; 8],
+    _reserved0: [u64; 3],
+    device_handle: EfiHandle,
+    _reserved1: [u64; 4],
     pub image_base: u64,
     pub image_size: u64,
 }
+const _: () = assert!(offset_of!(EfiLoadedImageProtocol, image_base) == 64);
 
 pub fn locate_loaded_image_protocol(
     image_handle: EfiHandle,
@@ -275,13 +310,206 @@ pub fn locate_loaded_image_protocol(
     Ok(unsafe { &*graphic_output_protocol })
 }
 
+#[repr(C)]
+struct EfiSimpleFileSystemProtocol {
+    revision: u64,
+    open_volume: extern "win64" fn(
+        this: *mut EfiSimpleFileSystemProtocol,
+        root: *mut *mut EfiFileProtocol,
+    ) -> EfiStatus,
+}
+
+#[repr(C)]
+struct EfiFileProtocol {
+    revision: u64,
+    open: extern "win64" fn(
+        this: *mut EfiFileProtocol,
+        new_handle: *mut *mut EfiFileProtocol,
+        file_name: *const u16,
+        open_mode: u64,
+        attributes: u64,
+    ) -> EfiStatus,
+    close: extern "win64" fn(this: *mut EfiFileProtocol) -> EfiStatus,
+    _delete: u64,
+    read: extern "win64" fn(
+        this: *mut EfiFileProtocol,
+        buffer_size: *mut usize,
+        buffer: *mut u8,
+    ) -> EfiStatus,
+    _write: u64,
+    get_position: extern "win64" fn(this: *mut EfiFileProtocol, position: *mut u64) -> EfiStatus,
+    set_position: extern "win64" fn(this: *mut EfiFileProtocol, position: u64) -> EfiStatus,
+}
+const _: () = assert!(offset_of!(EfiFileProtocol, set_position) == 56);
+
+const EFI_FILE_MODE_READ: u64 = 1;
+
+/// Opens the root directory of the volume that the image was loaded from.
+/// The caller should close it.
+fn open_boot_volume(
+    image_handle: EfiHandle,
+    efi_system_table: &EfiSystemTable,
+) -> Result<*mut EfiFileProtocol> {
+    let loaded_image = locate_loaded_image_protocol(image_handle, efi_system_table)?;
+    let mut fs = null_mut::<EfiSimpleFileSystemProtocol>();
+    let status = (efi_system_table.boot_services.handle_protocol)(
+        loaded_image.device_handle,
+        &EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID,
+        &mut fs as *mut *mut EfiSimpleFileSystemProtocol as *mut *mut EfiVoid,
+    );
+    if status != EfiStatus::Success {
+        return Err("Failed to locate the file system of the boot volume");
+    }
+    let mut root = null_mut::<EfiFileProtocol>();
+    // SAFETY: the protocol is given by the firmware.
+    if unsafe { ((*fs).open_volume)(fs, &mut root) } != EfiStatus::Success {
+        return Err("Failed to open the boot volume");
+    }
+    Ok(root)
+}
+
+/// Opens the file or directory at path on the boot volume. The caller
+/// should close it.
+fn open_on_boot_volume(
+    image_handle: EfiHandle,
+    efi_system_table: &EfiSystemTable,
+    path: &str,
+) -> Result<*mut EfiFileProtocol> {
+    let mut file_name = [0u16; 256];
+    if path.encode_utf16().count() >= file_name.len() {
+        return Err("Path is too long");
+    }
+    for (dst, c) in file_name.iter_mut().zip(path.encode_utf16()) {
+        *dst = c;
+    }
+    let root = open_boot_volume(image_handle, efi_system_table)?;
+    let mut file = null_mut::<EfiFileProtocol>();
+    // SAFETY: root is opened above, and closed here.
+    unsafe {
+        let status = ((*root).open)(root, &mut file, file_name.as_ptr(), EFI_FILE_MODE_READ, 0);
+        let _ = ((*root).close)(root);
+        if status != EfiStatus::Success {
+            return Err("File not found");
+        }
+    }
+    Ok(file)
+}
+
+/// Reads the file at path (e.g. `\EFI\BOOT\BOOTX64.EFI`) on the volume
+/// that the image was loaded from. The contents are placed in pages of
+/// memory_type.
+pub fn read_file_from_boot_volume(
+    image_handle: EfiHandle,
+    efi_system_table: &EfiSystemTable,
+    path: &str,
+    memory_type: EfiMemoryType,
+) -> Result<&'static mut [u8]> {
+    let file = open_on_boot_volume(image_handle, efi_system_table, path)?;
+    // SAFETY: the file is opened above and closed here.
+    unsafe {
+        let contents = read_whole_file(efi_system_table, file, memory_type);
+        let _ = ((*file).close)(file);
+        contents
+    }
+}
+
+const EFI_FILE_DIRECTORY: u64 = 0x10;
+// Offsets in EFI_FILE_INFO
+const FILE_INFO_ATTRIBUTE_OFFSET: usize = 72;
+const FILE_INFO_FILE_NAME_OFFSET: usize = 80;
+
+/// Calls f with the name of each file in the directory at path on the boot
+/// volume. Subdirectories are skipped.
+pub fn for_each_file_on_boot_volume(
+    image_handle: EfiHandle,
+    efi_system_table: &EfiSystemTable,
+    path: &str,
+    mut f: impl FnMut(&str),
+) -> Result<()> {
+    let dir = open_on_boot_volume(image_handle, efi_system_table, path)?;
+    // EFI_FILE_INFO is 8-byte aligned, and file names are up to 255 chars.
+    let mut buf = [0u64; 128];
+    let result = loop {
+        let mut size = size_of_val(&buf);
+        // SAFETY: dir is opened above, and reading a directory returns an
+        // EFI_FILE_INFO of an entry, or nothing at the end.
+        if unsafe { ((*dir).read)(dir, &mut size, buf.as_mut_ptr() as *mut u8) }
+            != EfiStatus::Success
+        {
+            break Err("Failed to read the directory");
+        }
+        if size == 0 {
+            break Ok(());
+        }
+        if size <= FILE_INFO_FILE_NAME_OFFSET {
+            break Err("Directory entry is truncated");
+        }
+        if buf[FILE_INFO_ATTRIBUTE_OFFSET / 8] & EFI_FILE_DIRECTORY != 0 {
+            continue;
+        }
+        // SAFETY: the name is a null terminated UTF-16 string in buf.
+        let name = unsafe {
+            slice::from_raw_parts(
+                (buf.as_ptr() as *const u8).add(FILE_INFO_FILE_NAME_OFFSET) as *const u16,
+                (size - FILE_INFO_FILE_NAME_OFFSET) / 2,
+            )
+        };
+        let mut utf8 = [0u8; 256 * 3];
+        let mut len = 0;
+        for c in char::decode_utf16(name.iter().copied().take_while(|c| *c != 0)) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            if len + c.len_utf8() > utf8.len() {
+                break;
+            }
+            len += c.encode_utf8(&mut utf8[len..]).len();
+        }
+        // SAFETY: only whole chars are written above.
+        f(unsafe { core::str::from_utf8_unchecked(&utf8[..len]) });
+    };
+    // SAFETY: dir is opened above.
+    let _ = unsafe { ((*dir).close)(dir) };
+    result
+}
+
+unsafe fn read_whole_file(
+    efi_system_table: &EfiSystemTable,
+    file: *mut EfiFileProtocol,
+    memory_type: EfiMemoryType,
+) -> Result<&'static mut [u8]> {
+    // Seeking to u64::MAX moves the position to the end of the file.
+    let mut size = 0;
+    if ((*file).set_position)(file, u64::MAX) != EfiStatus::Success
+        || ((*file).get_position)(file, &mut size) != EfiStatus::Success
+        || ((*file).set_position)(file, 0) != EfiStatus::Success
+    {
+        return Err("Failed to get the file size");
+    }
+    let size = size as usize;
+    let buf = efi_system_table
+        .boot_services
+        .allocate_pages(memory_type, max(size.div_ceil(4096), 1))?
+        .as_mut_ptr::<u8>();
+    let mut read_size = size;
+    if ((*file).read)(file, &mut read_size, buf) != EfiStatus::Success || read_size != size {
+        return Err("Failed to read the file");
+    }
+    Ok(slice::from_raw_parts_mut(buf, size))
+}
+
+#[repr(C)]
 #[derive(Clone, Copy)]
 pub struct VramBufferInfo {
-    buf: *mut u8,
+    phys_addr: PhysAddr,
     width: i64,
     height: i64,
     pixels_per_line: i64,
 }
+impl VramBufferInfo {
+    pub fn phys_range(&self) -> Range<u64> {
+        let start = self.phys_addr.as_u64();
+        start..start + (self.pixels_per_line * self.height * self.bytes_per_pixel()) as u64
+    }
+}
 impl Bitmap for VramBufferInfo {
     fn bytes_per_pixel(&self) -> i64 {
         4
@@ -296,14 +524,14 @@ impl Bitmap for VramBufferInfo {
         self.height
     }
     fn buf_mut(&mut self) -> *mut u8 {
-        self.buf
+        self.phys_addr.as_mut_ptr()
     }
 }
 
 pub fn init_vram(efi_system_table: &EfiSystemTable) -> Result<VramBufferInfo> {
     let gp = locate_graphic_protocol(efi_system_table)?;
     Ok(VramBufferInfo {
-        buf: gp.mode.frame_buffer_base as *mut u8,
+        phys_addr: PhysAddr::new(gp.mode.frame_buffer_base as u64),
         width: gp.mode.info.horizontal_resolution as i64,
         height: gp.mode.info.vertical_resolution as i64,
         pixels_per_line: gp.mode.info.pixels_per_scan_line as i64,
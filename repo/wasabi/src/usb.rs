@@ -1,14 +1,13 @@
 extern crate alloc;
 
+use crate::dma::DmaSlice;
 use crate::result::Result;
 use crate::slice::Sliceable;
 use crate::xhci::CommandRing;
 use crate::xhci::Controller;
-use alloc::boxed::Box;
 use alloc::rc::Rc;
 use alloc::string::String;
 use alloc::string::ToString;
-use alloc::vec;
 use alloc::vec::Vec;
 use core::marker::PhantomPinned;
 use core::mem::size_of;
@@ -193,8 +192,7 @@ pub async fn request_device_descriptor(
     slot: u8,
     ctrl_ep_ring: &mut CommandRing,
 ) -> Result<UsbDeviceDescriptor> {
-    let buf = vec![0; size_of::<UsbDeviceDescriptor>()];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = DmaSlice::<u8>::new(size_of::<UsbDeviceDescriptor>())?;
     xhc.request_descriptor(
         slot,
         ctrl_ep_ring,
@@ -204,7 +202,7 @@ pub async fn request_device_descriptor(
         &mut buf,
     )
     .await?;
-    UsbDeviceDescriptor::copy_from_slice(buf.as_ref().get_ref())
+    UsbDeviceDescriptor::copy_from_slice(&buf)
 }
 pub async fn request_string_descriptor(
     xhc: &Rc<Controller>,
@@ -213,8 +211,7 @@ pub async fn request_string_descriptor(
     lang_id: u16,
     index: u8,
 ) -> Result<String> {
-    let buf = vec![0; 128];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = DmaSlice::<u8>::new(128)?;
     xhc.request_descriptor(
         slot,
         ctrl_ep_ring,
@@ -234,8 +231,7 @@ pub async fn request_string_descriptor_zero(
     slot: u8,
     ctrl_ep_ring: &mut CommandRing,
 ) -> Result<Vec<u8>> {
-    let buf = vec![0; 8];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = DmaSlice::<u8>::new(8)?;
     xhc.request_descriptor(
         slot,
         ctrl_ep_ring,
@@ -245,15 +241,14 @@ pub async fn request_string_descriptor_zero(
         &mut buf,
     )
     .await?;
-    Ok(buf.as_ref().get_ref().to_vec())
+    Ok(buf.to_vec())
 }
 pub async fn request_config_descriptor_and_rest(
     xhc: &Rc<Controller>,
     slot: u8,
     ctrl_ep_ring: &mut CommandRing,
 ) -> Result<Vec<UsbDescriptor>> {
-    let buf = vec![0u8; size_of::<ConfigDescriptor>()];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = DmaSlice::<u8>::new(size_of::<ConfigDescriptor>())?;
     xhc.request_descriptor(
         slot,
         ctrl_ep_ring,
@@ -263,9 +258,8 @@ pub async fn request_config_descriptor_and_rest(
         &mut buf,
     )
     .await?;
-    let config_descriptor = ConfigDescriptor::copy_from_slice(buf.as_ref().get_ref())?;
-    let buf = vec![0; config_descriptor.total_length()];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let config_descriptor = ConfigDescriptor::copy_from_slice(&buf)?;
+    let mut buf = DmaSlice::<u8>::new(config_descriptor.total_length())?;
     xhc.request_descriptor(
         slot,
         ctrl_ep_ring,
@@ -284,8 +278,7 @@ pub async fn request_hid_report(
     slot: u8,
     ctrl_ep_ring: &mut CommandRing,
 ) -> Result<Vec<u8>> {
-    let buf = vec![0u8; 8];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = DmaSlice::<u8>::new(8)?;
     xhc.request_report_bytes(slot, ctrl_ep_ring, &mut buf)
         .await?;
     Ok(buf.to_vec())
@@ -333,8 +326,7 @@ pub async fn request_hid_report_descriptor(
     desc_size: usize,
 ) -> Result<Vec<u8>> {
     // 7.1.1 Get_Descriptor Request
-    let buf = vec![0u8; desc_size];
-    let mut buf = Box::into_pin(buf.into_boxed_slice());
+    let mut buf = DmaSlice::<u8>::new(desc_size)?;
     xhc.request_descriptor_for_interface(
         slot,
         ctrl_ep_ring,
@@ -0,0 +1,542 @@
+//! Running code in user mode (ring 3)
+//!
+//! User code enters the kernel with `syscall`, which jumps to syscall_entry
+//! with the user stack still loaded. The entry swaps GS to find the per-CPU
+//! data, switches to the kernel stack of the TSS (rsp0), and goes back with
+//! `sysret`. Exceptions in user mode go through the usual interrupt entry,
+//! which swaps GS as well.
+//!
+//! AddressSpace::run() enters user mode from a kernel task, and returns
+//! when the user code exits with the exit syscall or causes an exception,
+//! by going back to the kernel stack saved on entry. Syscalls that wait,
+//! like sleep, suspend the user code in the same way after saving its
+//! registers, and run() resumes it once the task is woken up, possibly on
+//! another CPU.
+
+extern crate alloc;
+
+use crate::addr::VirtAddr;
+use crate::cpu_local;
+use crate::executor::sleep;
+use crate::frame::num_pages_for;
+use crate::frame::FrameConstraints;
+use crate::frame::PhysFrames;
+use crate::percpu::is_gs_base_set;
+use crate::percpu::this_cpu;
+use crate::percpu::CPU_DATA_KERNEL_RSP;
+use crate::percpu::CPU_DATA_KERNEL_STACK;
+use crate::percpu::CPU_DATA_USER_RSP;
+use crate::result::Result;
+use crate::syscall::dispatch_syscall;
+use crate::warn;
+use crate::x86::exception_name;
+use crate::x86::is_no_execute_enabled;
+use crate::x86::read_cr3;
+use crate::x86::read_msr;
+use crate::x86::write_cr3;
+use crate::x86::write_msr;
+use crate::x86::GdtWrapper;
+use crate::x86::InterruptInfo;
+use crate::x86::PageAttr;
+use crate::x86::EFER_SCE;
+use crate::x86::KERNEL_CS;
+use crate::x86::MSR_EFER;
+use crate::x86::MSR_FMASK;
+use crate::x86::MSR_LSTAR;
+use crate::x86::MSR_STAR;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use crate::x86::USER_DS;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::arch::global_asm;
+use core::cell::Cell;
+use core::mem::offset_of;
+use core::ptr::null_mut;
+use core::time::Duration;
+
+/// The lower half of the address space is for the user.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+/// Where run_user_code() places the code
+pub const USER_CODE_BASE: u64 = 0x0040_0000;
+/// run_user_code() places the stack below this, leaving a page unmapped
+/// at the end of the user space.
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE as u64;
+pub const USER_STACK_SIZE: usize = 64 * 1024;
+/// Pages allocated by alloc_pages() are placed from here.
+pub const USER_HEAP_BASE: u64 = 0x0000_1000_0000_0000;
+const USER_HEAP_END: u64 = 0x0000_7000_0000_0000;
+
+const RFLAGS_IF: u64 = 1 << 9;
+/// IF and the reserved bit 1, which is always set
+const USER_RFLAGS: u64 = RFLAGS_IF | 1 << 1;
+/// TF, IF, DF, NT and AC are cleared on syscall.
+const SYSCALL_RFLAGS_MASK: u64 = 1 << 8 | RFLAGS_IF | 1 << 10 | 1 << 14 | 1 << 18;
+
+/// Value of MSR_STAR. syscall loads CS and SS from the kernel selectors,
+/// and sysret loads SS from base + 8 and CS from base + 16, with RPL 3.
+pub const fn star_value() -> u64 {
+    let sysret_base = (USER_DS & !3) - 8;
+    (sysret_base as u64) << 48 | (KERNEL_CS as u64) << 32
+}
+
+/// Enables syscall on the calling CPU, entering the kernel on the rsp0
+/// stack of gdt. This should be called on each CPU after
+/// init_exceptions().
+pub fn init_syscall(gdt: &GdtWrapper) {
+    this_cpu().set_kernel_stack(gdt.kernel_stack());
+    // SAFETY: syscall_entry is ready to be used once the kernel stack is set.
+    unsafe {
+        write_msr(MSR_STAR, star_value());
+        write_msr(MSR_LSTAR, syscall_entry as usize as u64);
+        write_msr(MSR_FMASK, SYSCALL_RFLAGS_MASK);
+        write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_SCE);
+    }
+}
+
+/// Registers of the user code saved on syscall. rax has the syscall number,
+/// and the arguments are in rdi, rsi, rdx, r10, r8 and r9. The value of rax
+/// is returned to the user.
+#[repr(C)]
+#[derive(Debug, Default, Clone)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+}
+impl SyscallFrame {
+    pub fn rip(&self) -> u64 {
+        self.rip
+    }
+    pub fn rsp(&self) -> u64 {
+        self.rsp
+    }
+}
+
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    // Interrupts are disabled by SFMASK until the kernel stack is loaded.
+    // NMI and #MC are not, but they check the GS base itself and run on
+    // their own stacks (see inthandler_common).
+    swapgs
+    mov gs:[{user_rsp}], rsp
+    mov rsp, gs:[{kernel_stack}]
+    // SyscallFrame, from the last field
+    push qword ptr gs:[{user_rsp}]
+    push rcx // RIP
+    push r11 // RFLAGS
+    push r15
+    push r14
+    push r13
+    push r12
+    push rbp
+    push rbx
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    mov rdi, rsp
+    sti
+    call {handler}
+    cli
+// Returns to the user with the SyscallFrame at rsp.
+syscall_return:
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop rbx
+    pop rbp
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+
+// fn resume_user(context: &UserContext) -> RawUserExit
+.global resume_user
+resume_user:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    sub rsp, 8
+    stmxcsr [rsp]
+    fnstcw [rsp + 4]
+    pushfq
+    cli
+    mov gs:[{kernel_rsp}], rsp
+    // Every register is loaded from the context, so no kernel values are
+    // leaked to the user.
+    fxrstor64 [rdi + {context_fpu}]
+    lea rsp, [rdi + {context_frame}]
+    jmp syscall_return
+
+// fn return_to_kernel(kind: u64, value: u64) -> !
+// Returns from resume_user() with (kind, value).
+.global return_to_kernel
+return_to_kernel:
+    cli
+    mov rsp, gs:[{kernel_rsp}]
+    mov rax, rdi
+    mov rdx, rsi
+    popfq
+    ldmxcsr [rsp]
+    fldcw [rsp + 4]
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#,
+    user_rsp = const CPU_DATA_USER_RSP,
+    kernel_stack = const CPU_DATA_KERNEL_STACK,
+    kernel_rsp = const CPU_DATA_KERNEL_RSP,
+    context_fpu = const offset_of!(UserContext, fpu),
+    context_frame = const offset_of!(UserContext, frame),
+    handler = sym handle_syscall,
+);
+
+extern "sysv64" {
+    fn syscall_entry();
+    fn resume_user(context: &UserContext) -> RawUserExit;
+    fn return_to_kernel(kind: u64, value: u64) -> !;
+}
+
+const EXIT_KIND_EXITED: u64 = 0;
+const EXIT_KIND_EXCEPTION: u64 = 1;
+const EXIT_KIND_SLEEP: u64 = 2;
+
+/// Registers of the user code while it is not running. The FPU and SSE
+/// registers are in the format of fxsave.
+#[repr(C, align(16))]
+struct UserContext {
+    fpu: [u8; 512],
+    frame: SyscallFrame,
+}
+impl UserContext {
+    fn new(rip: u64, rsp: u64, arg: u64) -> Self {
+        let mut fpu = [0; 512];
+        // The initial values of FCW and MXCSR, which mask all exceptions
+        fpu[0..2].copy_from_slice(&0x037Fu16.to_le_bytes());
+        fpu[24..28].copy_from_slice(&0x1F80u32.to_le_bytes());
+        Self {
+            fpu,
+            frame: SyscallFrame {
+                rdi: arg,
+                rflags: USER_RFLAGS,
+                rip,
+                rsp,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[repr(C)]
+struct RawUserExit {
+    kind: u64,
+    value: u64,
+}
+
+/// How the user code stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// Called the exit syscall with the code
+    Exited(u64),
+    /// Caused an exception at rip
+    Exception { vector: usize, rip: u64 },
+}
+impl From<RawUserExit> for UserExit {
+    fn from(raw: RawUserExit) -> Self {
+        match raw.kind & 0xFF {
+            EXIT_KIND_EXITED => Self::Exited(raw.value),
+            _ => Self::Exception {
+                vector: (raw.kind >> 8) as usize,
+                rip: raw.value,
+            },
+        }
+    }
+}
+
+extern "sysv64" fn handle_syscall(frame: &mut SyscallFrame) {
+    frame.rax = dispatch_syscall(frame);
+    if let Some(duration) = SLEEP_REQUEST.get().take() {
+        // SAFETY: the context is borrowed mutably by resume(), which does
+        // not touch it until the user code returns.
+        let context = unsafe { &mut *RUNNING_CONTEXT.get().get() };
+        context.frame = frame.clone();
+        // SAFETY: the kernel does not use the FPU and SSE registers (it is
+        // built with soft-float), so they still have the user values.
+        unsafe {
+            asm!("fxsave64 [{}]",
+                in(reg) context.fpu.as_mut_ptr(),
+                options(nostack))
+        };
+        let nanos = duration.as_nanos().min(u64::MAX as u128) as u64;
+        // SAFETY: syscalls only come from the user code entered by run().
+        unsafe { return_to_kernel(EXIT_KIND_SLEEP, nanos) }
+    }
+}
+
+/// Makes the user code that made the syscall being handled sleep for
+/// duration after the syscall returns. Its task waits meanwhile with
+/// executor::sleep(), so the CPU runs other tasks.
+pub(crate) fn sleep_after_syscall(duration: Duration) -> Result<()> {
+    if RUNNING_CONTEXT.get().get().is_null() {
+        return Err("No user code is running");
+    }
+    SLEEP_REQUEST.get().set(Some(duration));
+    Ok(())
+}
+
+/// Stops the user code that made the syscall, and makes run() return.
+pub(crate) fn exit_user(code: u64) -> ! {
+    // SAFETY: syscalls only come from the user code entered by run().
+    unsafe { return_to_kernel(EXIT_KIND_EXITED, code) }
+}
+
+/// Called by the interrupt handler for exceptions in user mode. The user
+/// code is abandoned, and run() returns.
+pub(crate) fn exit_user_on_exception(vector: usize, info: &InterruptInfo) -> ! {
+    warn!(
+        "User code caused exception {vector:#04X} ({}) at {:#018X}",
+        exception_name(vector),
+        info.rip()
+    );
+    // SAFETY: user mode is only entered by run().
+    unsafe { return_to_kernel(EXIT_KIND_EXCEPTION | (vector as u64) << 8, info.rip()) }
+}
+
+/// A page table for user mode. The lower half is for the user, and the
+/// upper half is shared with the kernel page table that was active when
+/// this was created.
+pub struct AddressSpace {
+    table: &'static mut PML4,
+    frames: Vec<PhysFrames>,
+    heap_next: u64,
+}
+
+cpu_local! {
+    /// The AddressSpace that is running user code on each CPU
+    static RUNNING_SPACE: Cell<*mut AddressSpace> = Cell::new(null_mut());
+    /// The UserContext of the user code running on each CPU
+    static RUNNING_CONTEXT: Cell<*mut UserContext> = Cell::new(null_mut());
+    /// Set by sleep_after_syscall() to suspend the user code
+    static SLEEP_REQUEST: Cell<Option<Duration>> = Cell::new(None);
+}
+/// Calls f with the AddressSpace whose user code made the syscall being
+/// handled on this CPU.
+pub(crate) fn with_running_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    // SAFETY: the space is borrowed mutably by run(), which does not touch
+    // it until the user code returns.
+    unsafe { RUNNING_SPACE.get().get().as_mut() }.map(f)
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self> {
+        let table = PML4::new()?;
+        // SAFETY: the current table is only read here.
+        table.share_kernel_space(unsafe { &*read_cr3().as_mut_ptr::<PML4>() });
+        Ok(Self {
+            table,
+            frames: Vec::new(),
+            heap_next: USER_HEAP_BASE,
+        })
+    }
+    pub fn table(&self) -> &PML4 {
+        self.table
+    }
+    /// Maps zeroed pages to [virt, virt + size) with attr for the user.
+    pub fn alloc(&mut self, virt: u64, size: usize, attr: PageAttr) -> Result<()> {
+        if virt.checked_add(size as u64).ok_or("Range overflows")? > USER_SPACE_END {
+            return Err("Range is not in the user space");
+        }
+        let frames = PhysFrames::alloc_zeroed(num_pages_for(size), FrameConstraints::ANY)?;
+        self.table.create_mapping(
+            virt,
+            virt + frames.size() as u64,
+            frames.phys_addr(),
+            attr | PageAttr::USER,
+        )?;
+        self.frames.push(frames);
+        Ok(())
+    }
+    /// Maps zeroed pages with attr somewhere above USER_HEAP_BASE, and
+    /// returns the address.
+    pub fn alloc_pages(&mut self, num_pages: usize, attr: PageAttr) -> Result<u64> {
+        let size = num_pages.checked_mul(PAGE_SIZE).ok_or("Too many pages")?;
+        let virt = self.heap_next;
+        // Leave an unmapped page after each allocation.
+        let next = virt
+            .checked_add(size as u64 + PAGE_SIZE as u64)
+            .filter(|next| *next <= USER_HEAP_END)
+            .ok_or("User heap is exhausted")?;
+        self.alloc(virt, size, attr)?;
+        self.heap_next = next;
+        Ok(virt)
+    }
+    /// Copies data to virt, which should be mapped by alloc().
+    pub fn write(&mut self, virt: u64, data: &[u8]) -> Result<()> {
+        let mut written = 0;
+        while written < data.len() {
+            let addr = virt + written as u64;
+            if addr >= USER_SPACE_END {
+                return Err("Range is not in the user space");
+            }
+            let phys = self.table.virt_to_phys(addr).ok_or("Page is not mapped")?;
+            let offset_in_page = (addr % PAGE_SIZE as u64) as usize;
+            let len = (PAGE_SIZE - offset_in_page).min(data.len() - written);
+            // SAFETY: the page is one of self.frames, which is accessible
+            // via the direct map.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    phys.as_mut_ptr::<u8>(),
+                    len,
+                )
+            };
+            written += len;
+        }
+        Ok(())
+    }
+    /// Runs the user code at rip with the stack at rsp and arg in rdi,
+    /// until it exits or causes an exception. This should be awaited in a
+    /// task, not in an interrupt handler.
+    pub async fn run(&mut self, rip: u64, rsp: u64, arg: u64) -> Result<UserExit> {
+        if rip >= USER_SPACE_END || rsp > USER_SPACE_END {
+            // sysret to a non-canonical address faults in the kernel.
+            return Err("Entry point or stack is not in the user space");
+        }
+        let mut context = UserContext::new(rip, rsp, arg);
+        loop {
+            let exit = self.resume(&mut context)?;
+            if exit.kind != EXIT_KIND_SLEEP {
+                return Ok(exit.into());
+            }
+            sleep(Duration::from_nanos(exit.value)).await;
+        }
+    }
+    /// Runs the user code from context until it exits, causes an exception
+    /// or is suspended by a syscall.
+    fn resume(&mut self, context: &mut UserContext) -> Result<RawUserExit> {
+        if !is_gs_base_set() || this_cpu().kernel_stack() == 0 {
+            return Err("syscall is not initialized on this CPU");
+        }
+        let kernel_cr3 = read_cr3();
+        let table = VirtAddr::from_ptr(self.table).to_phys();
+        let running_space = RUNNING_SPACE.get().replace(self);
+        let running_context = RUNNING_CONTEXT.get().replace(context);
+        // SAFETY: the kernel space is shared, and the user code can only
+        // touch the pages of this table, which stays alive until it returns.
+        let exit = unsafe {
+            write_cr3(table);
+            let exit = resume_user(context);
+            write_cr3(kernel_cr3);
+            exit
+        };
+        RUNNING_CONTEXT.get().set(running_context);
+        RUNNING_SPACE.get().set(running_space);
+        Ok(exit)
+    }
+}
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Only the tables for the user half are freed. The frames mapped
+        // there are freed with self.frames.
+        self.table
+            .unmap(0, USER_SPACE_END)
+            .expect("Failed to unmap the user space");
+        let phys = VirtAddr::from_ptr(self.table).to_phys();
+        // SAFETY: the table was leaked by PML4::new() and is not loaded.
+        drop(unsafe { PhysFrames::from_raw(phys, 1) });
+    }
+}
+
+/// Runs code as a user mode function that takes arg in rdi, on its own
+/// address space and stack. The code should exit with the exit syscall.
+pub async fn run_user_code(code: &[u8], arg: u64) -> Result<UserExit> {
+    let mut space = AddressSpace::new()?;
+    space.alloc(USER_CODE_BASE, code.len(), PageAttr::READ_ONLY_USER)?;
+    space.write(USER_CODE_BASE, code)?;
+    let stack_attr = if is_no_execute_enabled() {
+        PageAttr::READ_WRITE_USER | PageAttr::NO_EXECUTE
+    } else {
+        PageAttr::READ_WRITE_USER
+    };
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE as u64;
+    space.alloc(stack_bottom, USER_STACK_SIZE, stack_attr)?;
+    space.run(USER_CODE_BASE, USER_STACK_TOP, arg).await
+}
+
+#[test_case]
+fn star_selects_user_and_kernel_segments() {
+    use crate::x86::KERNEL_DS;
+    use crate::x86::USER_CS;
+    let star = star_value();
+    assert_eq!((star >> 32) as u16, KERNEL_CS);
+    assert_eq!((star >> 32) as u16 + 8, KERNEL_DS);
+    let sysret_base = (star >> 48) as u16;
+    assert_eq!((sysret_base + 8) | 3, USER_DS);
+    assert_eq!((sysret_base + 16) | 3, USER_CS);
+}
+
+#[test_case]
+fn address_space_maps_user_pages() {
+    let mut space = AddressSpace::new().unwrap();
+    assert!(space
+        .alloc(USER_SPACE_END - 0x1000, 0x2000, PageAttr::READ_WRITE_USER)
+        .is_err());
+    space
+        .alloc(USER_CODE_BASE, 0x1800, PageAttr::READ_ONLY_USER)
+        .unwrap();
+    space.write(USER_CODE_BASE + 0xFFE, &[1, 2, 3, 4]).unwrap();
+    assert!(space.write(USER_CODE_BASE + 0x1FFF, &[1, 2]).is_err());
+    let phys = space.table().virt_to_phys(USER_CODE_BASE + 0x1000).unwrap();
+    assert_eq!(unsafe { *phys.as_mut_ptr::<[u8; 2]>() }, [3, 4]);
+    let region = space.table().mapped_regions().next().unwrap();
+    assert_eq!(region.virt, USER_CODE_BASE);
+    assert_eq!(region.size, 0x2000);
+    assert_eq!(region.attr, PageAttr::READ_ONLY_USER);
+    let a = space.alloc_pages(2, PageAttr::READ_WRITE_USER).unwrap();
+    let b = space.alloc_pages(1, PageAttr::READ_WRITE_USER).unwrap();
+    assert_eq!(a, USER_HEAP_BASE);
+    assert_eq!(b, a + 3 * PAGE_SIZE as u64);
+    assert!(space.table().translate(a + 2 * PAGE_SIZE as u64).is_none());
+}
+
+#[test_case]
+fn sleep_is_only_requested_by_user_code() {
+    assert!(sleep_after_syscall(Duration::from_millis(1)).is_err());
+    assert_eq!(SLEEP_REQUEST.get().get(), None);
+}
@@ -1,19 +1,35 @@
 extern crate alloc;
 
+use crate::addr::PhysAddr;
+use crate::addr::VirtAddr;
+use crate::backtrace::print_backtrace_from;
 use crate::error;
+use crate::frame::is_managed;
+use crate::frame::FrameConstraints;
+use crate::frame::PhysFrames;
 use crate::info;
-use crate::mmio::IoBox;
+use crate::interrupt::handle_external_interrupt;
+use crate::lazy_region::handle_page_fault as handle_lazy_page_fault;
+use crate::panic_screen::record_exception;
+use crate::percpu::InterruptNesting;
 use crate::result::Result;
+use crate::stack::find_stack_by_guard_page;
+use crate::stack::KernelStack;
+use crate::user::exit_user_on_exception;
 use alloc::boxed::Box;
 use core::arch::asm;
 use core::arch::global_asm;
+use core::arch::x86_64::__cpuid;
+use core::arch::x86_64::__cpuid_count;
+use core::cmp::max;
+use core::cmp::min;
 use core::fmt;
 use core::marker::PhantomData;
 use core::mem::offset_of;
 use core::mem::size_of;
 use core::mem::size_of_val;
-use core::mem::ManuallyDrop;
-use core::mem::MaybeUninit;
+use core::ops::BitOr;
+use core::ops::BitOrAssign;
 use core::pin::Pin;
 
 pub fn hlt() {
@@ -24,6 +40,70 @@ pub fn busy_loop_hint() {
     unsafe { asm!("pause") }
 }
 
+pub fn read_rbp() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp",
+            out(reg) rbp)
+    }
+    rbp
+}
+
+pub const RFLAGS_IF: u64 = 1 << 9;
+
+pub fn read_rflags() -> u64 {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq",
+            "pop {}",
+            out(reg) rflags)
+    }
+    rflags
+}
+
+pub fn are_interrupts_enabled() -> bool {
+    read_rflags() & RFLAGS_IF != 0
+}
+
+/// # Safety
+/// Interrupt handlers can run at any point after this.
+pub unsafe fn enable_interrupts() {
+    asm!("sti")
+}
+
+/// Keeps the interrupts disabled on the current CPU until dropped, then
+/// restores the interrupt flag to the state before new() was called.
+/// Guards can be nested.
+pub struct InterruptGuard {
+    was_enabled: bool,
+    // The flag belongs to the CPU that created the guard.
+    _not_send: PhantomData<*const ()>,
+}
+impl InterruptGuard {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let was_enabled = are_interrupts_enabled();
+        unsafe { asm!("cli") }
+        Self {
+            was_enabled,
+            _not_send: PhantomData,
+        }
+    }
+}
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            unsafe { asm!("sti") }
+        }
+    }
+}
+
+/// Returns the initial APIC ID of the current CPU, which is unique per CPU.
+pub fn cpu_index() -> usize {
+    // SAFETY: CPUID leaf 1 is always available on x86_64.
+    (unsafe { __cpuid(1) }.ebx >> 24) as usize
+}
+
 pub fn read_io_port_u8(port: u16) -> u8 {
     let mut data: u8;
     unsafe {
@@ -41,34 +121,385 @@ pub fn write_io_port_u8(port: u16, data: u8) {
     }
 }
 
-pub fn read_cr3() -> *mut PML4 {
-    let mut cr3: *mut PML4;
+pub fn read_cr3() -> PhysAddr {
+    let mut cr3: u64;
     unsafe {
         asm!("mov rax, cr3",
             out("rax") cr3)
     }
-    cr3
+    PhysAddr::new(cr3 & ADDR_MASK)
+}
+
+pub fn supports_1g_pages() -> bool {
+    // SAFETY: CPUID is always available on x86_64.
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0 }
+}
+
+pub fn supports_no_execute() -> bool {
+    // SAFETY: CPUID is always available on x86_64.
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 20) != 0 }
+}
+
+pub const MSR_EFER: u32 = 0xC000_0080;
+pub const MSR_PAT: u32 = 0x277;
+const EFER_NXE: u64 = 1 << 11;
+/// Enables syscall and sysret
+pub const EFER_SCE: u64 = 1 << 0;
+const CR4_PGE: u64 = 1 << 7;
+const PAT_TYPE_WC: u64 = 0x01;
+
+pub fn read_msr(msr: u32) -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe {
+        asm!("rdmsr",
+            in("ecx") msr,
+            out("eax") lo,
+            out("edx") hi)
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+/// # Safety
+/// Writing to MSRs can change the behavior of the CPU in any way.
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    asm!("wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32)
+}
+
+/// Segment selectors loaded by syscall and sysret
+pub const MSR_STAR: u32 = 0xC000_0081;
+/// Entry point of syscall
+pub const MSR_LSTAR: u32 = 0xC000_0082;
+/// RFLAGS bits cleared by syscall
+pub const MSR_FMASK: u32 = 0xC000_0084;
+
+pub const MSR_FS_BASE: u32 = 0xC000_0100;
+pub const MSR_GS_BASE: u32 = 0xC000_0101;
+/// Swapped with the GS base by swapgs
+pub const MSR_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+pub fn read_fs_base() -> u64 {
+    read_msr(MSR_FS_BASE)
+}
+/// # Safety
+/// Anything that accesses memory via FS will use the new base.
+pub unsafe fn write_fs_base(base: u64) {
+    write_msr(MSR_FS_BASE, base)
+}
+pub fn read_gs_base() -> u64 {
+    read_msr(MSR_GS_BASE)
+}
+/// # Safety
+/// Anything that accesses memory via GS will use the new base, including
+/// the per-CPU data.
+pub unsafe fn write_gs_base(base: u64) {
+    write_msr(MSR_GS_BASE, base)
+}
+pub fn read_kernel_gs_base() -> u64 {
+    read_msr(MSR_KERNEL_GS_BASE)
+}
+/// # Safety
+/// The value becomes the GS base after the next swapgs.
+pub unsafe fn write_kernel_gs_base(base: u64) {
+    write_msr(MSR_KERNEL_GS_BASE, base)
+}
+/// Exchanges the GS base and the value of MSR_KERNEL_GS_BASE.
+///
+/// # Safety
+/// The per-CPU data can not be reached until swapgs is done again.
+pub unsafe fn swapgs() {
+    asm!("swapgs")
+}
+
+pub fn read_cr4() -> u64 {
+    let mut cr4: u64;
+    unsafe {
+        asm!("mov rax, cr4",
+            out("rax") cr4)
+    }
+    cr4
+}
+/// # Safety
+/// Writing to CR4 can change the behavior of the CPU in any way.
+pub unsafe fn write_cr4(cr4: u64) {
+    asm!("mov cr4, rax",
+        in("rax") cr4)
+}
+
+pub fn read_cr0() -> u64 {
+    let mut cr0: u64;
+    unsafe {
+        asm!("mov rax, cr0",
+            out("rax") cr0)
+    }
+    cr0
+}
+/// # Safety
+/// Writing to CR0 can change the behavior of the CPU in any way.
+pub unsafe fn write_cr0(cr0: u64) {
+    asm!("mov cr0, rax",
+        in("rax") cr0)
+}
+
+const CR0_WP: u64 = 1 << 16;
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+fn structured_extended_features() -> u32 {
+    // SAFETY: CPUID is always available on x86_64.
+    unsafe {
+        if __cpuid(0).eax >= 7 {
+            __cpuid_count(7, 0).ebx
+        } else {
+            0
+        }
+    }
+}
+pub fn supports_smep() -> bool {
+    structured_extended_features() & (1 << 7) != 0
+}
+pub fn supports_smap() -> bool {
+    structured_extended_features() & (1 << 20) != 0
+}
+
+/// Makes read-only pages read-only for the kernel as well (CR0.WP),
+/// and prevents the kernel from executing (SMEP) or accessing (SMAP)
+/// user pages where supported.
+pub fn init_kernel_protections() {
+    unsafe {
+        write_cr0(read_cr0() | CR0_WP);
+        let mut cr4 = read_cr4();
+        if supports_smep() {
+            cr4 |= CR4_SMEP;
+        }
+        if supports_smap() {
+            cr4 |= CR4_SMAP;
+        }
+        write_cr4(cr4);
+    }
+    info!(
+        "CR0.WP enabled, SMEP: {}, SMAP: {}",
+        read_cr4() & CR4_SMEP != 0,
+        read_cr4() & CR4_SMAP != 0
+    );
+}
+
+pub fn is_no_execute_enabled() -> bool {
+    read_msr(MSR_EFER) & EFER_NXE != 0
+}
+
+/// Drops NO_EXECUTE if the CPU does not support it.
+pub fn available_attr(attr: PageAttr) -> PageAttr {
+    if is_no_execute_enabled() {
+        attr
+    } else {
+        attr.without(PageAttr::NO_EXECUTE)
+    }
+}
+
+/// Writes back and invalidates all the CPU caches.
+pub fn flush_cpu_caches() {
+    unsafe { asm!("wbinvd") }
+}
+
+/// Enables the CPU features that PageAttr relies on:
+/// EFER.NXE for NO_EXECUTE, PAT entry 4 for WRITE_COMBINING and
+/// CR4.PGE for GLOBAL.
+pub fn init_page_attributes() {
+    unsafe {
+        if supports_no_execute() {
+            write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_NXE);
+        }
+        let pat = read_msr(MSR_PAT) & !(0xFF << 32);
+        write_msr(MSR_PAT, pat | (PAT_TYPE_WC << 32));
+        write_cr4(read_cr4() | CR4_PGE);
+    }
+    // Cached translations may use the old PAT entries.
+    flush_tlb();
+}
+
+fn check_attr(attr: PageAttr) -> Result<()> {
+    if attr.contains(PageAttr::NO_EXECUTE) && !is_no_execute_enabled() {
+        Err("NO_EXECUTE is used but EFER.NXE is not set")
+    } else {
+        Ok(())
+    }
 }
 
 pub const PAGE_SIZE: usize = 4096;
 const ATTR_MASK: u64 = 0xFFF;
 const ATTR_PRESENT: u64 = 1 << 0;
 const ATTR_WRITABLE: u64 = 1 << 1;
+const ATTR_USER: u64 = 1 << 2;
 const ATTR_WRITE_THROUGH: u64 = 1 << 3;
 const ATTR_CACHE_DISABLE: u64 = 1 << 4;
+const ATTR_PAGE_SIZE: u64 = 1 << 7;
+const ATTR_PAT_4K: u64 = 1 << 7;
+const ATTR_PAT_HUGE: u64 = 1 << 12;
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
 
-#[derive(Debug, Copy, Clone)]
-#[repr(u64)]
-pub enum PageAttr {
-    NotPresent = 0,
-    ReadWriteKernel = ATTR_PRESENT | ATTR_WRITABLE,
-    ReadWriteIo = ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH | ATTR_CACHE_DISABLE,
+const ATTR_GLOBAL: u64 = 1 << 8;
+/// Ignored by the CPU. Entries with this bit are not freed by unmap().
+const ATTR_PINNED: u64 = 1 << 9;
+const ATTR_NO_EXECUTE: u64 = 1 << 63;
+
+/// Attributes of page mappings, which can be combined with `|` like
+/// `PageAttr::READ_WRITE_KERNEL | PageAttr::NO_EXECUTE`.
+/// The bits are kept in the format of 4KiB page entries.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct PageAttr {
+    bits: u64,
+}
+impl PageAttr {
+    pub const NOT_PRESENT: Self = Self::from_bits(0);
+    pub const PRESENT: Self = Self::from_bits(ATTR_PRESENT);
+    pub const WRITABLE: Self = Self::from_bits(ATTR_WRITABLE);
+    pub const USER: Self = Self::from_bits(ATTR_USER);
+    pub const WRITE_THROUGH: Self = Self::from_bits(ATTR_WRITE_THROUGH);
+    pub const CACHE_DISABLE: Self = Self::from_bits(ATTR_CACHE_DISABLE);
+    /// Selects PAT entry 4, which init_page_attributes() sets to WC.
+    pub const WRITE_COMBINING: Self = Self::from_bits(ATTR_PAT_4K);
+    /// Requires CR4.PGE to take effect.
+    pub const GLOBAL: Self = Self::from_bits(ATTR_GLOBAL);
+    /// Requires EFER.NXE to be set.
+    pub const NO_EXECUTE: Self = Self::from_bits(ATTR_NO_EXECUTE);
+
+    pub const READ_ONLY_KERNEL: Self = Self::PRESENT;
+    pub const READ_WRITE_KERNEL: Self = Self::PRESENT.with(Self::WRITABLE);
+    pub const READ_WRITE_IO: Self = Self::READ_WRITE_KERNEL
+        .with(Self::WRITE_THROUGH)
+        .with(Self::CACHE_DISABLE);
+    pub const READ_ONLY_USER: Self = Self::PRESENT.with(Self::USER);
+    pub const READ_WRITE_USER: Self = Self::READ_WRITE_KERNEL.with(Self::USER);
+    const ALL: Self = Self::from_bits(
+        ATTR_PRESENT
+            | ATTR_WRITABLE
+            | ATTR_USER
+            | ATTR_WRITE_THROUGH
+            | ATTR_CACHE_DISABLE
+            | ATTR_PAT_4K
+            | ATTR_GLOBAL
+            | ATTR_NO_EXECUTE,
+    );
+
+    const fn from_bits(bits: u64) -> Self {
+        Self { bits }
+    }
+    pub const fn bits(self) -> u64 {
+        self.bits
+    }
+    pub const fn with(self, other: Self) -> Self {
+        Self::from_bits(self.bits | other.bits)
+    }
+    pub const fn without(self, other: Self) -> Self {
+        Self::from_bits(self.bits & !other.bits)
+    }
+    pub const fn contains(self, other: Self) -> bool {
+        self.bits & other.bits == other.bits
+    }
+}
+impl BitOr for PageAttr {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        self.with(rhs)
+    }
+}
+impl BitOrAssign for PageAttr {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = self.with(rhs)
+    }
+}
+impl fmt::Debug for PageAttr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.contains(Self::PRESENT) {
+            return write!(f, "PageAttr(NotPresent)");
+        }
+        write!(
+            f,
+            "PageAttr({}{}{}{}",
+            if self.contains(Self::WRITABLE) {
+                "RW"
+            } else {
+                "RO"
+            },
+            if self.contains(Self::USER) {
+                " User"
+            } else {
+                " Kernel"
+            },
+            if self.contains(Self::NO_EXECUTE) {
+                " NX"
+            } else {
+                ""
+            },
+            if self.contains(Self::GLOBAL) {
+                " Global"
+            } else {
+                ""
+            },
+        )?;
+        match self.bits & (ATTR_PAT_4K | ATTR_CACHE_DISABLE | ATTR_WRITE_THROUGH) {
+            0 => write!(f, ")"),
+            ATTR_PAT_4K => write!(f, " WC)"),
+            ATTR_WRITE_THROUGH => write!(f, " WT)"),
+            _ => write!(f, " UC)"),
+        }
+    }
 }
 #[derive(Debug, Eq, PartialEq)]
 pub enum TranslationResult {
-    PageMapped4K { phys: u64 },
-    PageMapped2M { phys: u64 },
-    PageMapped1G { phys: u64 },
+    PageMapped4K { phys: PhysAddr },
+    PageMapped2M { phys: PhysAddr },
+    PageMapped1G { phys: PhysAddr },
+}
+impl TranslationResult {
+    fn new(level: usize, phys: PhysAddr) -> Self {
+        match level {
+            1 => Self::PageMapped4K { phys },
+            2 => Self::PageMapped2M { phys },
+            3 => Self::PageMapped1G { phys },
+            _ => unreachable!("Leaf at level {level}"),
+        }
+    }
+    /// Physical address of the start of the page
+    pub fn phys(&self) -> PhysAddr {
+        match *self {
+            Self::PageMapped4K { phys } => phys,
+            Self::PageMapped2M { phys } => phys,
+            Self::PageMapped1G { phys } => phys,
+        }
+    }
+    pub fn page_size(&self) -> u64 {
+        match self {
+            Self::PageMapped4K { .. } => 1 << 12,
+            Self::PageMapped2M { .. } => 1 << 21,
+            Self::PageMapped1G { .. } => 1 << 30,
+        }
+    }
+}
+
+/// A virtually contiguous range mapped to a physically contiguous range
+/// with the same attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRegion {
+    pub virt: u64,
+    pub phys: PhysAddr,
+    pub size: u64,
+    pub attr: PageAttr,
+}
+impl MappedRegion {
+    fn last(&self) -> u64 {
+        self.virt + (self.size - 1)
+    }
+}
+
+fn canonicalize(addr: u64) -> u64 {
+    if addr & (1 << 47) != 0 {
+        addr | 0xFFFF_0000_0000_0000
+    } else {
+        addr & 0x0000_FFFF_FFFF_FFFF
+    }
 }
 
 #[repr(transparent)]
@@ -77,6 +508,7 @@ pub struct Entry<const LEVEL: usize, NEXT> {
     next_type: PhantomData<NEXT>,
 }
 impl<const LEVEL: usize, NEXT> Entry<LEVEL, NEXT> {
+    const SPAN: u64 = 1 << ((LEVEL - 1) * 9 + 12);
     fn read_value(&self) -> u64 {
         self.value
     }
@@ -89,6 +521,29 @@ impl<const LEVEL: usize, NEXT> Entry<LEVEL, NEXT> {
     fn is_user(&self) -> bool {
         (self.read_value() & (1 << 2)) != 0
     }
+    /// Returns true if this entry maps a page rather than pointing a table.
+    fn is_leaf(&self) -> bool {
+        LEVEL == 1 || ((LEVEL == 2 || LEVEL == 3) && self.read_value() & ATTR_PAGE_SIZE != 0)
+    }
+    fn leaf_phys(&self) -> PhysAddr {
+        PhysAddr::new(self.read_value() & ADDR_MASK & !(Self::SPAN - 1))
+    }
+    /// Physical address of the next level table. The table is accessed
+    /// through the direct map since the value is not a pointer.
+    fn table_phys(&self) -> PhysAddr {
+        PhysAddr::new(self.read_value() & ADDR_MASK)
+    }
+    fn leaf_attr(&self) -> PageAttr {
+        let value = self.read_value();
+        let attr = value & PageAttr::ALL.bits;
+        PageAttr::from_bits(if LEVEL == 1 {
+            attr
+        } else if value & ATTR_PAT_HUGE != 0 {
+            (attr & !ATTR_PAGE_SIZE) | ATTR_PAT_4K
+        } else {
+            attr & !ATTR_PAGE_SIZE
+        })
+    }
     fn format(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
@@ -103,42 +558,94 @@ impl<const LEVEL: usize, NEXT> Entry<LEVEL, NEXT> {
         write!(f, " }}")
     }
     fn table(&self) -> Result<&NEXT> {
-        if self.is_present() {
-            Ok(unsafe { &*((self.value & !ATTR_MASK) as *const NEXT) })
+        if self.is_present() && !self.is_leaf() {
+            Ok(unsafe { &*self.table_phys().as_mut_ptr::<NEXT>() })
         } else {
             Err("Page Not Found")
         }
     }
     fn table_mut(&mut self) -> Result<&mut NEXT> {
-        if self.is_present() {
-            Ok(unsafe { &mut *((self.value & !ATTR_MASK) as *mut NEXT) })
+        if self.is_present() && !self.is_leaf() {
+            Ok(unsafe { &mut *self.table_phys().as_mut_ptr::<NEXT>() })
         } else {
             Err("Page Not Found")
         }
     }
-    fn set_page(&mut self, phys: u64, attr: PageAttr) -> Result<()> {
-        if phys & ATTR_MASK != 0 {
-            Err("phys is not aligned")
+    /// Makes this entry a leaf. The PAT bit is moved for huge pages here.
+    fn set_leaf(&mut self, phys: PhysAddr, attr: PageAttr) {
+        let phys = phys.as_u64();
+        debug_assert!(phys & (Self::SPAN - 1) == 0);
+        let attr_bits = attr.bits();
+        self.value = if LEVEL == 1 {
+            phys | attr_bits
+        } else if attr_bits & ATTR_PAT_4K != 0 {
+            phys | (attr_bits & !ATTR_PAT_4K) | ATTR_PAT_HUGE | ATTR_PAGE_SIZE
         } else {
-            self.value = phys | attr as u64;
-            Ok(())
+            phys | attr_bits | ATTR_PAGE_SIZE
+        }
+    }
+    /// Pages under this entry are writable or accessible from user mode
+    /// only if this entry allows it too, and not executable if this
+    /// entry says so.
+    fn restrict(&self, attr: PageAttr) -> PageAttr {
+        let value = self.read_value();
+        let denied = (ATTR_WRITABLE | ATTR_USER) & !value;
+        PageAttr::from_bits((attr.bits() & !denied) | (value & ATTR_NO_EXECUTE))
+    }
+    /// Intermediate entries should also have the USER bit to make the
+    /// leaves under them accessible from user mode.
+    fn allow_user_if(&mut self, attr: PageAttr) {
+        if attr.contains(PageAttr::USER) {
+            self.value |= ATTR_USER;
         }
     }
+    fn is_pinned(&self) -> bool {
+        self.read_value() & ATTR_PINNED != 0
+    }
     fn populate(&mut self) -> Result<&mut Self> {
         if self.is_present() {
             Err("Page is already populated")
         } else {
-            let next: Box<NEXT> = Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
-            self.value = Box::into_raw(next) as u64 | PageAttr::ReadWriteKernel as u64;
+            // Entries filled with 0 are valid (not present).
+            let next = PhysFrames::alloc_zeroed(1, FrameConstraints::ANY)?.leak();
+            self.value = next.as_u64() | PageAttr::READ_WRITE_KERNEL.bits();
             Ok(self)
         }
     }
+}
+impl<const LEVEL: usize, NEXT: PageTableNode> Entry<LEVEL, NEXT> {
     fn ensure_populated(&mut self) -> Result<&mut Self> {
-        if self.is_present() {
+        if !self.is_present() {
+            self.populate()
+        } else if self.is_leaf() {
+            self.split()?;
             Ok(self)
         } else {
-            self.populate()
+            Ok(self)
+        }
+    }
+    /// Replaces a huge page with a table of smaller pages that maps
+    /// the same range with the same attributes. The permissions are kept
+    /// in the new leaves only, so that they can be widened one by one.
+    fn split(&mut self) -> Result<()> {
+        if LEVEL == 1 || !self.is_present() || !self.is_leaf() {
+            return Err("Not a huge page");
+        }
+        let phys = self.leaf_phys();
+        let attr = self.leaf_attr();
+        let next = PhysFrames::alloc_zeroed(1, FrameConstraints::ANY)?.leak();
+        unsafe { &mut *next.as_mut_ptr::<NEXT>() }.fill_with_leaves(phys, attr);
+        self.value = next.as_u64() | PageAttr::READ_WRITE_KERNEL.bits();
+        self.allow_user_if(attr);
+        Ok(())
+    }
+    /// Clears this entry and frees the tables under it.
+    fn clear(&mut self) {
+        if let Ok(table) = self.table_mut() {
+            table.free_subtables();
+            free_table_frame(self.table_phys());
         }
+        self.value = 0;
     }
 }
 impl<const LEVEL: usize, NEXT> fmt::Display for Entry<LEVEL, NEXT> {
@@ -152,6 +659,216 @@ impl<const LEVEL: usize, NEXT> fmt::Debug for Entry<LEVEL, NEXT> {
     }
 }
 
+fn free_table_frame(phys: PhysAddr) {
+    // Tables made by the firmware are not ours to free.
+    if is_managed(phys) {
+        // SAFETY: the table is unlinked and was allocated with PhysFrames.
+        drop(unsafe { PhysFrames::from_raw(phys, 1) })
+    }
+}
+
+/// Walks below a page table entry. The ranges given are inclusive
+/// ([start, last]) so that the end of the address space can be expressed.
+pub trait PageTableNode {
+    fn translate(&self, virt: u64) -> Option<TranslationResult>;
+    fn effective_attr(&self, virt: u64) -> Option<PageAttr>;
+    /// Returns true if every page in [start, last] is mapped.
+    fn is_range_mapped(&self, start: u64, last: u64) -> bool;
+    fn unmap_range(&mut self, start: u64, last: u64) -> Result<()>;
+    fn change_attr_range(&mut self, start: u64, last: u64, attr: PageAttr) -> Result<()>;
+    /// Maps [start, last] to phys. Leaves are placed at levels up to
+    /// max_leaf_level.
+    fn map_range(
+        &mut self,
+        start: u64,
+        last: u64,
+        phys: PhysAddr,
+        attr: PageAttr,
+        max_leaf_level: usize,
+    ) -> Result<()>;
+    /// Returns the first leaf that ends at or after from
+    fn next_mapped(&self, from: u64) -> Option<MappedRegion>;
+    fn fill_with_leaves(&mut self, phys: PhysAddr, attr: PageAttr);
+    fn free_subtables(&mut self);
+    fn is_empty(&self) -> bool;
+}
+/// Pages are the leaves of the walk, so none of these will be called.
+impl PageTableNode for [u8; PAGE_SIZE] {
+    fn translate(&self, _: u64) -> Option<TranslationResult> {
+        unreachable!()
+    }
+    fn effective_attr(&self, _: u64) -> Option<PageAttr> {
+        unreachable!()
+    }
+    fn is_range_mapped(&self, _: u64, _: u64) -> bool {
+        unreachable!()
+    }
+    fn unmap_range(&mut self, _: u64, _: u64) -> Result<()> {
+        unreachable!()
+    }
+    fn change_attr_range(&mut self, _: u64, _: u64, _: PageAttr) -> Result<()> {
+        unreachable!()
+    }
+    fn map_range(&mut self, _: u64, _: u64, _: PhysAddr, _: PageAttr, _: usize) -> Result<()> {
+        unreachable!()
+    }
+    fn next_mapped(&self, _: u64) -> Option<MappedRegion> {
+        unreachable!()
+    }
+    fn fill_with_leaves(&mut self, _: PhysAddr, _: PageAttr) {
+        unreachable!()
+    }
+    fn free_subtables(&mut self) {
+        unreachable!()
+    }
+    fn is_empty(&self) -> bool {
+        unreachable!()
+    }
+}
+impl<const LEVEL: usize, NEXT: PageTableNode> PageTableNode for Table<LEVEL, NEXT> {
+    fn translate(&self, virt: u64) -> Option<TranslationResult> {
+        let entry = &self.entry[self.calc_index(virt)];
+        if !entry.is_present() {
+            None
+        } else if entry.is_leaf() {
+            Some(TranslationResult::new(LEVEL, entry.leaf_phys()))
+        } else {
+            entry.table().ok()?.translate(virt)
+        }
+    }
+    fn effective_attr(&self, virt: u64) -> Option<PageAttr> {
+        let entry = &self.entry[self.calc_index(virt)];
+        if !entry.is_present() {
+            None
+        } else if entry.is_leaf() {
+            Some(entry.leaf_attr())
+        } else {
+            Some(entry.restrict(entry.table().ok()?.effective_attr(virt)?))
+        }
+    }
+    fn is_range_mapped(&self, start: u64, last: u64) -> bool {
+        self.entries(start, last).all(|(entry_start, entry)| {
+            let entry_last = entry_start + (Entry::<LEVEL, NEXT>::SPAN - 1);
+            entry.is_present()
+                && (entry.is_leaf()
+                    || entry.table().is_ok_and(|table| {
+                        table.is_range_mapped(max(start, entry_start), min(last, entry_last))
+                    }))
+        })
+    }
+    fn unmap_range(&mut self, start: u64, last: u64) -> Result<()> {
+        for (entry_start, entry) in self.entries_mut(start, last) {
+            if !entry.is_present() {
+                continue;
+            }
+            let entry_last = entry_start + (Entry::<LEVEL, NEXT>::SPAN - 1);
+            if start <= entry_start && entry_last <= last && !entry.is_pinned() {
+                entry.clear();
+                continue;
+            }
+            if entry.is_leaf() {
+                entry.split()?;
+            }
+            let table = entry.table_mut()?;
+            table.unmap_range(max(start, entry_start), min(last, entry_last))?;
+            if table.is_empty() && !entry.is_pinned() {
+                entry.clear();
+            }
+        }
+        Ok(())
+    }
+    fn change_attr_range(&mut self, start: u64, last: u64, attr: PageAttr) -> Result<()> {
+        for (entry_start, entry) in self.entries_mut(start, last) {
+            if !entry.is_present() {
+                return Err("Page Not Found");
+            }
+            let entry_last = entry_start + (Entry::<LEVEL, NEXT>::SPAN - 1);
+            if entry.is_leaf() {
+                if start <= entry_start && entry_last <= last {
+                    entry.set_leaf(entry.leaf_phys(), attr);
+                    continue;
+                }
+                entry.split()?;
+            }
+            entry.allow_user_if(attr);
+            entry.table_mut()?.change_attr_range(
+                max(start, entry_start),
+                min(last, entry_last),
+                attr,
+            )?;
+        }
+        Ok(())
+    }
+    fn map_range(
+        &mut self,
+        start: u64,
+        last: u64,
+        phys: PhysAddr,
+        attr: PageAttr,
+        max_leaf_level: usize,
+    ) -> Result<()> {
+        for (entry_start, entry) in self.entries_mut(start, last) {
+            let entry_last = entry_start + (Entry::<LEVEL, NEXT>::SPAN - 1);
+            let sub_start = max(start, entry_start);
+            let sub_phys = phys + (sub_start - start);
+            if LEVEL == 1
+                || (LEVEL <= max_leaf_level
+                    && start <= entry_start
+                    && entry_last <= last
+                    && sub_phys.as_u64() & (Entry::<LEVEL, NEXT>::SPAN - 1) == 0)
+            {
+                entry.clear();
+                entry.set_leaf(sub_phys, attr);
+                continue;
+            }
+            let entry = entry.ensure_populated()?;
+            entry.allow_user_if(attr);
+            entry.table_mut()?.map_range(
+                sub_start,
+                min(last, entry_last),
+                sub_phys,
+                attr,
+                max_leaf_level,
+            )?;
+        }
+        Ok(())
+    }
+    fn next_mapped(&self, from: u64) -> Option<MappedRegion> {
+        let table_base = from & !(Entry::<LEVEL, NEXT>::SPAN * 512 - 1);
+        for (i, entry) in self.entry.iter().enumerate().skip(self.calc_index(from)) {
+            if !entry.is_present() {
+                continue;
+            }
+            let entry_start = canonicalize(table_base + i as u64 * Entry::<LEVEL, NEXT>::SPAN);
+            if entry.is_leaf() {
+                return Some(MappedRegion {
+                    virt: entry_start,
+                    phys: entry.leaf_phys(),
+                    size: Entry::<LEVEL, NEXT>::SPAN,
+                    attr: entry.leaf_attr(),
+                });
+            }
+            if let Some(region) = entry.table().ok()?.next_mapped(max(from, entry_start)) {
+                return Some(region);
+            }
+        }
+        None
+    }
+    fn fill_with_leaves(&mut self, phys: PhysAddr, attr: PageAttr) {
+        for (i, entry) in self.entry.iter_mut().enumerate() {
+            entry.set_leaf(phys + i as u64 * Entry::<LEVEL, NEXT>::SPAN, attr);
+        }
+    }
+    fn free_subtables(&mut self) {
+        for entry in self.entry.iter_mut() {
+            entry.clear();
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.entry.iter().all(|e| !e.is_present())
+    }
+}
+
 #[repr(align(4096))]
 pub struct Table<const LEVEL: usize, NEXT> {
     entry: [Entry<LEVEL, NEXT>; 512],
@@ -168,15 +885,45 @@ impl<const LEVEL: usize, NEXT: core::fmt::Debug> Table<LEVEL, NEXT> {
         }
         writeln!(f, "}}")
     }
-    const fn index_shift() -> usize {
-        (LEVEL - 1) * 9 + 12
-    }
     pub fn next_level(&self, index: usize) -> Option<&NEXT> {
         self.entry.get(index).and_then(|e| e.table().ok())
     }
+}
+impl<const LEVEL: usize, NEXT> Table<LEVEL, NEXT> {
+    const fn index_shift() -> usize {
+        (LEVEL - 1) * 9 + 12
+    }
     fn calc_index(&self, addr: u64) -> usize {
         ((addr >> Self::index_shift()) & 0b1_1111_1111) as usize
     }
+    /// Iterates over the entries that overlap with [start, last] along with
+    /// the start address of the range each of them covers.
+    fn entries(&self, start: u64, last: u64) -> impl Iterator<Item = (u64, &Entry<LEVEL, NEXT>)> {
+        let span = Entry::<LEVEL, NEXT>::SPAN;
+        let table_base = start & !(span * 512 - 1);
+        let first = self.calc_index(start);
+        self.entry
+            .iter()
+            .enumerate()
+            .skip(first)
+            .map(move |(i, e)| (table_base + i as u64 * span, e))
+            .take_while(move |(entry_start, _)| *entry_start <= last)
+    }
+    fn entries_mut(
+        &mut self,
+        start: u64,
+        last: u64,
+    ) -> impl Iterator<Item = (u64, &mut Entry<LEVEL, NEXT>)> {
+        let span = Entry::<LEVEL, NEXT>::SPAN;
+        let table_base = start & !(span * 512 - 1);
+        let first = self.calc_index(start);
+        self.entry
+            .iter_mut()
+            .enumerate()
+            .skip(first)
+            .map(move |(i, e)| (table_base + i as u64 * span, e))
+            .take_while(move |(entry_start, _)| *entry_start <= last)
+    }
 }
 impl<const LEVEL: usize, NEXT: fmt::Debug> fmt::Debug for Table<LEVEL, NEXT> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -190,55 +937,551 @@ pub type PDPT = Table<3, PD>;
 pub type PML4 = Table<4, PDPT>;
 
 impl PML4 {
-    pub fn new() -> Box<Self> {
-        Box::new(Self::default())
+    pub fn new() -> Result<&'static mut Self> {
+        Self::new_with(FrameConstraints::ANY)
     }
-    fn default() -> Self {
-        // This is safe since entries filled with 0 is valid.
-        unsafe { MaybeUninit::zeroed().assume_init() }
+    /// Allocates an empty table placed as constraints says, e.g. below
+    /// 4GiB to be loaded into CR3 in 32-bit mode.
+    pub fn new_with(constraints: FrameConstraints) -> Result<&'static mut Self> {
+        // Entries filled with 0 are valid (not present).
+        let table = PhysFrames::alloc_zeroed(1, constraints)?.leak();
+        Ok(unsafe { &mut *table.as_mut_ptr::<Self>() })
+    }
+    /// Populates all the entries of the upper half (the kernel space) and
+    /// keeps them forever, so that the tables made by share_kernel_space()
+    /// see every mapping made in the kernel space later, from any table.
+    pub fn pin_kernel_space(&mut self) -> Result<()> {
+        for e in &mut self.entry[256..] {
+            if !e.is_present() {
+                e.populate()?;
+            }
+            e.value |= ATTR_PINNED;
+        }
+        Ok(())
+    }
+    /// Makes the upper half (the kernel space) of this table point to the
+    /// same tables as kernel, so that the mappings there are shared.
+    /// Entries that kernel populates after this are not shared, unless
+    /// kernel is pinned with pin_kernel_space().
+    pub fn share_kernel_space(&mut self, kernel: &PML4) {
+        for (e, k) in self.entry[256..].iter_mut().zip(&kernel.entry[256..]) {
+            e.value = k.value;
+        }
     }
+    /// Maps [virt_start, virt_end) to the physical range starting at phys.
+    /// 2MiB and 1GiB pages are used where the alignment allows, and huge
+    /// pages that are partially remapped are split.
     pub fn create_mapping(
         &mut self,
         virt_start: u64,
         virt_end: u64,
-        phys: u64,
+        phys: PhysAddr,
         attr: PageAttr,
     ) -> Result<()> {
-        let table = self;
-        let mut addr = virt_start;
-        loop {
-            let index = table.calc_index(addr);
-            let table = table.entry[index].ensure_populated()?.table_mut()?;
-            loop {
-                let index = table.calc_index(addr);
-                let table = table.entry[index].ensure_populated()?.table_mut()?;
-                loop {
-                    let index = table.calc_index(addr);
-                    let table = table.entry[index].ensure_populated()?.table_mut()?;
-                    loop {
-                        let index = table.calc_index(addr);
-                        let pte = &mut table.entry[index];
-                        let phys_addr = phys + addr - virt_start;
-                        pte.set_page(phys_addr, attr)?;
-                        addr = addr.wrapping_add(PAGE_SIZE as u64);
-                        if index + 1 >= (1 << 9) || addr >= virt_end {
-                            break;
-                        }
-                    }
-                    if index + 1 >= (1 << 9) || addr >= virt_end {
-                        break;
-                    }
-                }
-                if index + 1 >= (1 << 9) || addr >= virt_end {
-                    break;
+        if virt_start & ATTR_MASK != 0 || phys.as_u64() & ATTR_MASK != 0 {
+            return Err("Mapping is not page aligned");
+        }
+        if virt_end <= virt_start {
+            return Err("Range is empty");
+        }
+        check_attr(attr)?;
+        let max_leaf_level = if supports_1g_pages() { 3 } else { 2 };
+        self.map_range(
+            virt_start,
+            (virt_end - 1) | ATTR_MASK,
+            phys,
+            attr,
+            max_leaf_level,
+        )
+    }
+    pub fn translate(&self, virt: u64) -> Option<TranslationResult> {
+        if canonicalize(virt) != virt {
+            return None;
+        }
+        PageTableNode::translate(self, virt)
+    }
+    /// Returns the attributes that apply to accesses to virt, taking the
+    /// entries of all the levels into account.
+    pub fn effective_attr(&self, virt: u64) -> Option<PageAttr> {
+        if canonicalize(virt) != virt {
+            return None;
+        }
+        PageTableNode::effective_attr(self, virt)
+    }
+    /// Returns the physical address that virt is mapped to.
+    pub fn virt_to_phys(&self, virt: u64) -> Option<PhysAddr> {
+        self.translate(virt)
+            .map(|t| t.phys() + (virt & (t.page_size() - 1)))
+    }
+    fn check_range(virt_start: u64, virt_end: u64) -> Result<u64> {
+        if virt_start & ATTR_MASK != 0 || virt_end & ATTR_MASK != 0 {
+            Err("Range is not page aligned")
+        } else if virt_end <= virt_start {
+            Err("Range is empty")
+        } else {
+            Ok(virt_end - 1)
+        }
+    }
+    /// Unmaps [virt_start, virt_end). Huge pages that are partially in
+    /// the range are split, and tables that become empty are freed.
+    /// TLB is not flushed here.
+    pub fn unmap(&mut self, virt_start: u64, virt_end: u64) -> Result<()> {
+        let last = Self::check_range(virt_start, virt_end)?;
+        self.unmap_range(virt_start, last)
+    }
+    /// Changes the attributes of the pages in [virt_start, virt_end),
+    /// keeping the physical addresses. All the pages should be mapped,
+    /// otherwise nothing is changed. TLB is not flushed here.
+    pub fn change_attr(&mut self, virt_start: u64, virt_end: u64, attr: PageAttr) -> Result<()> {
+        let last = Self::check_range(virt_start, virt_end)?;
+        check_attr(attr)?;
+        if !self.is_range_mapped(virt_start, last) {
+            return Err("Page Not Found");
+        }
+        self.change_attr_range(virt_start, last, attr)
+    }
+    /// Iterates over the mapped regions in the ascending order of
+    /// virtual addresses. Adjacent leaves are merged if they are
+    /// contiguous both virtually and physically and have the same attributes.
+    pub fn mapped_regions(&self) -> MappedRegionIterator {
+        MappedRegionIterator {
+            table: self,
+            next: Some(0),
+        }
+    }
+}
+
+pub struct MappedRegionIterator<'a> {
+    table: &'a PML4,
+    next: Option<u64>,
+}
+impl<'a> Iterator for MappedRegionIterator<'a> {
+    type Item = MappedRegion;
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut region = self.table.next_mapped(self.next?)?;
+        self.next = loop {
+            let Some(next) = region.last().checked_add(1) else {
+                break None;
+            };
+            match self.table.next_mapped(next) {
+                Some(r)
+                    if r.virt == next
+                        && r.phys == region.phys + region.size
+                        && r.attr == region.attr =>
+                {
+                    region.size += r.size
                 }
+                _ => break Some(next),
             }
-            if index + 1 >= (1 << 9) || addr >= virt_end {
-                break;
-            }
-        }
-        Ok(())
+        };
+        Some(region)
+    }
+}
+
+#[test_case]
+fn page_table_translate_and_unmap() {
+    let free_pages_before = crate::allocator::ALLOCATOR.num_free_pages();
+    let table = PML4::new().unwrap();
+    let free_pages_empty = crate::allocator::ALLOCATOR.num_free_pages();
+    assert_eq!(table.translate(0x1000), None);
+    table
+        .create_mapping(
+            0x1000,
+            0x5000,
+            PhysAddr::new(0x8000_0000),
+            PageAttr::READ_WRITE_KERNEL,
+        )
+        .unwrap();
+    assert_eq!(
+        table.translate(0x2000),
+        Some(TranslationResult::PageMapped4K {
+            phys: PhysAddr::new(0x8000_1000)
+        })
+    );
+    assert_eq!(table.virt_to_phys(0x4ABC), Some(PhysAddr::new(0x8000_3ABC)));
+    assert_eq!(table.translate(0x5000), None);
+    assert_eq!(table.translate(0x8000_0000_0000), None);
+    table.unmap(0x2000, 0x3000).unwrap();
+    assert_eq!(table.translate(0x2000), None);
+    assert_eq!(table.virt_to_phys(0x3000), Some(PhysAddr::new(0x8000_2000)));
+    table.unmap(0, 0x10000).unwrap();
+    assert!(table.is_empty());
+    assert_eq!(
+        crate::allocator::ALLOCATOR.num_free_pages(),
+        free_pages_empty
+    );
+    free_table_frame(VirtAddr::from_ptr(table).to_phys());
+    assert_eq!(
+        crate::allocator::ALLOCATOR.num_free_pages(),
+        free_pages_before
+    );
+}
+#[test_case]
+fn page_table_splits_huge_pages() {
+    let table = PML4::new().unwrap();
+    let pdpt = table.entry[0].populate().unwrap().table_mut().unwrap();
+    pdpt.entry[1].set_leaf(PhysAddr::new(0x4000_0000), PageAttr::READ_WRITE_KERNEL);
+    let pd = pdpt.entry[0].populate().unwrap().table_mut().unwrap();
+    pd.entry[1].set_leaf(PhysAddr::new(0x20_0000), PageAttr::READ_WRITE_KERNEL);
+    assert_eq!(
+        table.translate(0x20_1000),
+        Some(TranslationResult::PageMapped2M {
+            phys: PhysAddr::new(0x20_0000)
+        })
+    );
+    assert_eq!(
+        table.virt_to_phys(0x4123_4567),
+        Some(PhysAddr::new(0x4123_4567))
+    );
+    table.unmap(0x20_1000, 0x20_2000).unwrap();
+    assert_eq!(
+        table.translate(0x20_0000),
+        Some(TranslationResult::PageMapped4K {
+            phys: PhysAddr::new(0x20_0000)
+        })
+    );
+    assert_eq!(table.translate(0x20_1000), None);
+    table
+        .change_attr(0x4000_0000, 0x4020_0000, PageAttr::READ_WRITE_IO)
+        .unwrap();
+    assert_eq!(
+        table.translate(0x4000_0000),
+        Some(TranslationResult::PageMapped2M {
+            phys: PhysAddr::new(0x4000_0000)
+        })
+    );
+    assert_eq!(
+        table.translate(0x4020_0000),
+        Some(TranslationResult::PageMapped2M {
+            phys: PhysAddr::new(0x4020_0000)
+        })
+    );
+    // Nothing is changed if a part of the range is not mapped.
+    assert!(table
+        .change_attr(0x20_0000, 0x20_3000, PageAttr::READ_WRITE_IO)
+        .is_err());
+    let regions: alloc::vec::Vec<MappedRegion> = table.mapped_regions().collect();
+    assert_eq!(
+        regions,
+        [
+            MappedRegion {
+                virt: 0x20_0000,
+                phys: PhysAddr::new(0x20_0000),
+                size: 0x1000,
+                attr: PageAttr::READ_WRITE_KERNEL,
+            },
+            MappedRegion {
+                virt: 0x20_2000,
+                phys: PhysAddr::new(0x20_2000),
+                size: 0x1F_E000,
+                attr: PageAttr::READ_WRITE_KERNEL,
+            },
+            MappedRegion {
+                virt: 0x4000_0000,
+                phys: PhysAddr::new(0x4000_0000),
+                size: 0x20_0000,
+                attr: PageAttr::READ_WRITE_IO,
+            },
+            MappedRegion {
+                virt: 0x4020_0000,
+                phys: PhysAddr::new(0x4020_0000),
+                size: 0x3FE0_0000,
+                attr: PageAttr::READ_WRITE_KERNEL,
+            },
+        ]
+    );
+    table.unmap(0, 0x8000_0000).unwrap();
+    assert!(table.is_empty());
+    free_table_frame(VirtAddr::from_ptr(table).to_phys());
+}
+#[test_case]
+fn create_mapping_uses_huge_pages() {
+    let table = PML4::new().unwrap();
+    table
+        .create_mapping(
+            0,
+            0x8040_1000,
+            PhysAddr::new(0),
+            PageAttr::READ_WRITE_KERNEL,
+        )
+        .unwrap();
+    let expected_page_size = if supports_1g_pages() {
+        1 << 30
+    } else {
+        1 << 21
+    };
+    assert_eq!(
+        table.translate(0x4000_1000).map(|t| t.page_size()),
+        Some(expected_page_size)
+    );
+    assert_eq!(
+        table.translate(0x8020_0000),
+        Some(TranslationResult::PageMapped2M {
+            phys: PhysAddr::new(0x8020_0000)
+        })
+    );
+    assert_eq!(
+        table.translate(0x8040_0000),
+        Some(TranslationResult::PageMapped4K {
+            phys: PhysAddr::new(0x8040_0000)
+        })
+    );
+    table
+        .create_mapping(
+            0x4000_3000,
+            0x4000_3800,
+            PhysAddr::new(0x4000_3000),
+            PageAttr::READ_WRITE_IO,
+        )
+        .unwrap();
+    assert_eq!(
+        table.translate(0x4000_2000),
+        Some(TranslationResult::PageMapped4K {
+            phys: PhysAddr::new(0x4000_2000)
+        })
+    );
+    assert_eq!(
+        table.translate(0x4020_0000),
+        Some(TranslationResult::PageMapped2M {
+            phys: PhysAddr::new(0x4020_0000)
+        })
+    );
+    let io_regions: alloc::vec::Vec<MappedRegion> = table
+        .mapped_regions()
+        .filter(|r| r.attr == PageAttr::READ_WRITE_IO)
+        .collect();
+    assert_eq!(
+        io_regions,
+        [MappedRegion {
+            virt: 0x4000_3000,
+            phys: PhysAddr::new(0x4000_3000),
+            size: 0x1000,
+            attr: PageAttr::READ_WRITE_IO,
+        }]
+    );
+    // Huge pages can not be used if phys is not aligned to it
+    table
+        .create_mapping(
+            0x20_0000,
+            0x40_0000,
+            PhysAddr::new(0x1000),
+            PageAttr::READ_WRITE_KERNEL,
+        )
+        .unwrap();
+    assert_eq!(
+        table.translate(0x20_0000),
+        Some(TranslationResult::PageMapped4K {
+            phys: PhysAddr::new(0x1000)
+        })
+    );
+    table.unmap(0, 0x8040_1000).unwrap();
+    assert!(table.is_empty());
+    free_table_frame(VirtAddr::from_ptr(table).to_phys());
+}
+#[test_case]
+fn effective_attr_is_restricted_by_upper_levels() {
+    let table = PML4::new().unwrap();
+    table
+        .create_mapping(
+            0x1000,
+            0x2000,
+            PhysAddr::new(0x8000_0000),
+            PageAttr::READ_WRITE_USER,
+        )
+        .unwrap();
+    table
+        .create_mapping(
+            0x2000,
+            0x3000,
+            PhysAddr::new(0x8000_1000),
+            PageAttr::READ_WRITE_KERNEL,
+        )
+        .unwrap();
+    assert_eq!(table.effective_attr(0x0000), None);
+    assert_eq!(
+        table.effective_attr(0x1234),
+        Some(PageAttr::READ_WRITE_USER)
+    );
+    assert_eq!(
+        table.effective_attr(0x2000),
+        Some(PageAttr::READ_WRITE_KERNEL)
+    );
+    table.entry[0].value &= !(ATTR_USER | ATTR_WRITABLE);
+    assert_eq!(
+        table.effective_attr(0x1000),
+        Some(PageAttr::READ_ONLY_KERNEL)
+    );
+    table.unmap(0, 0x3000).unwrap();
+    assert!(table.is_empty());
+    // A page made writable in a split read-only huge page is writable.
+    table
+        .create_mapping(
+            0x20_0000,
+            0x40_0000,
+            PhysAddr::new(0x20_0000),
+            PageAttr::READ_ONLY_USER,
+        )
+        .unwrap();
+    table
+        .change_attr(0x20_1000, 0x20_2000, PageAttr::READ_WRITE_USER)
+        .unwrap();
+    assert_eq!(
+        table.effective_attr(0x20_1000),
+        Some(PageAttr::READ_WRITE_USER)
+    );
+    assert_eq!(
+        table.effective_attr(0x20_2000),
+        Some(PageAttr::READ_ONLY_USER)
+    );
+    table.unmap(0x20_0000, 0x40_0000).unwrap();
+    assert!(table.is_empty());
+    free_table_frame(VirtAddr::from_ptr(table).to_phys());
+}
+#[test_case]
+fn page_attr_combination() {
+    let attr = PageAttr::READ_ONLY_KERNEL | PageAttr::NO_EXECUTE;
+    assert!(attr.contains(PageAttr::PRESENT));
+    assert!(attr.contains(PageAttr::NO_EXECUTE));
+    assert!(!attr.contains(PageAttr::WRITABLE));
+    assert!(!attr.contains(PageAttr::READ_WRITE_KERNEL));
+    let mut attr = PageAttr::READ_WRITE_KERNEL;
+    attr |= PageAttr::USER;
+    assert_eq!(attr, PageAttr::READ_WRITE_USER);
+    assert_eq!(attr.without(PageAttr::WRITABLE), PageAttr::READ_ONLY_USER);
+}
+#[test_case]
+fn page_attr_is_kept_in_huge_pages() {
+    let table = PML4::new().unwrap();
+    let attr = PageAttr::READ_ONLY_USER | PageAttr::WRITE_COMBINING | PageAttr::GLOBAL;
+    table
+        .create_mapping(0x20_0000, 0x40_0000, PhysAddr::new(0x20_0000), attr)
+        .unwrap();
+    assert_eq!(
+        table.translate(0x20_0000),
+        Some(TranslationResult::PageMapped2M {
+            phys: PhysAddr::new(0x20_0000)
+        })
+    );
+    assert!(table.entry[0].is_user());
+    assert!(table.entry[0].table().unwrap().entry[0].is_user());
+    table.unmap(0x20_0000, 0x20_1000).unwrap();
+    let regions: alloc::vec::Vec<MappedRegion> = table.mapped_regions().collect();
+    assert_eq!(
+        regions,
+        [MappedRegion {
+            virt: 0x20_1000,
+            phys: PhysAddr::new(0x20_1000),
+            size: 0x1F_F000,
+            attr,
+        }]
+    );
+    table.unmap(0x20_0000, 0x40_0000).unwrap();
+    assert!(table.is_empty());
+    free_table_frame(VirtAddr::from_ptr(table).to_phys());
+}
+#[test_case]
+fn mapped_regions_cover_high_half() {
+    let table = PML4::new().unwrap();
+    table
+        .create_mapping(
+            0xFFFF_FFFF_FFFF_E000,
+            0xFFFF_FFFF_FFFF_F000,
+            PhysAddr::new(0x1000),
+            PageAttr::READ_WRITE_KERNEL,
+        )
+        .unwrap();
+    let regions: alloc::vec::Vec<MappedRegion> = table.mapped_regions().collect();
+    assert_eq!(regions.len(), 1);
+    assert_eq!(regions[0].virt, 0xFFFF_FFFF_FFFF_E000);
+    assert_eq!(
+        table.virt_to_phys(0xFFFF_FFFF_FFFF_E010),
+        Some(PhysAddr::new(0x1010))
+    );
+    table
+        .unmap(0xFFFF_FFFF_FFFF_E000, 0xFFFF_FFFF_FFFF_F000)
+        .unwrap();
+    assert!(table.is_empty());
+    free_table_frame(VirtAddr::from_ptr(table).to_phys());
+}
+
+#[test_case]
+fn kernel_space_is_shared() {
+    let kernel = PML4::new().unwrap();
+    let user = PML4::new_with(FrameConstraints::BELOW_4G).unwrap();
+    assert!(VirtAddr::from_ptr(user).to_phys().as_u64() < 1 << 32);
+    kernel
+        .create_mapping(
+            0xFFFF_8000_0000_0000,
+            0xFFFF_8000_0000_1000,
+            PhysAddr::new(0x1000),
+            PageAttr::READ_WRITE_KERNEL,
+        )
+        .unwrap();
+    user.share_kernel_space(kernel);
+    // Mappings made later under the shared entries are seen by both.
+    kernel
+        .create_mapping(
+            0xFFFF_8000_0000_1000,
+            0xFFFF_8000_0000_2000,
+            PhysAddr::new(0x2000),
+            PageAttr::READ_WRITE_KERNEL,
+        )
+        .unwrap();
+    assert_eq!(
+        user.virt_to_phys(0xFFFF_8000_0000_1008),
+        Some(PhysAddr::new(0x2008))
+    );
+    assert!(user.translate(0x1000).is_none());
+    kernel
+        .unmap(0xFFFF_8000_0000_0000, 0xFFFF_8000_0000_2000)
+        .unwrap();
+    assert!(kernel.is_empty());
+    free_table_frame(VirtAddr::from_ptr(kernel).to_phys());
+    free_table_frame(VirtAddr::from_ptr(user).to_phys());
+}
+#[test_case]
+fn pinned_kernel_space_is_shared() {
+    let kernel = PML4::new().unwrap();
+    kernel.pin_kernel_space().unwrap();
+    let user = PML4::new().unwrap();
+    user.share_kernel_space(kernel);
+    // Mappings made in any table are seen by both, even in the ranges
+    // that were empty when the table was made.
+    kernel
+        .create_mapping(
+            0xFFFF_C000_0000_0000,
+            0xFFFF_C000_0000_1000,
+            PhysAddr::new(0x1000),
+            PageAttr::READ_WRITE_KERNEL,
+        )
+        .unwrap();
+    user.create_mapping(
+        0xFFFF_C000_0000_1000,
+        0xFFFF_C000_0000_2000,
+        PhysAddr::new(0x2000),
+        PageAttr::READ_WRITE_KERNEL,
+    )
+    .unwrap();
+    assert_eq!(
+        user.virt_to_phys(0xFFFF_C000_0000_0008),
+        Some(PhysAddr::new(0x1008))
+    );
+    assert_eq!(
+        kernel.virt_to_phys(0xFFFF_C000_0000_1008),
+        Some(PhysAddr::new(0x2008))
+    );
+    // The pinned entries are kept after unmapping everything.
+    kernel
+        .unmap(0xFFFF_8000_0000_0000, 0xFFFF_FFFF_FFFF_F000)
+        .unwrap();
+    assert!(user.virt_to_phys(0xFFFF_C000_0000_0008).is_none());
+    assert!(kernel.entry[256..].iter().all(|e| e.is_present()));
+    for e in &mut kernel.entry[256..] {
+        e.clear();
     }
+    assert!(kernel.is_empty());
+    free_table_frame(VirtAddr::from_ptr(kernel).to_phys());
+    free_table_frame(VirtAddr::from_ptr(user).to_phys());
 }
 
 /// # Safety
@@ -297,6 +1540,18 @@ pub unsafe fn write_gs(selector: u16) {
 struct FPUContenxt {
     data: [u8; 512],
 }
+// SDM Vol.1: 10.5.1 FXSAVE Area
+impl FPUContenxt {
+    fn fcw(&self) -> u16 {
+        u16::from_le_bytes([self.data[0], self.data[1]])
+    }
+    fn fsw(&self) -> u16 {
+        u16::from_le_bytes([self.data[2], self.data[3]])
+    }
+    fn mxcsr(&self) -> u32 {
+        u32::from_le_bytes([self.data[24], self.data[25], self.data[26], self.data[27]])
+    }
+}
 #[allow(dead_code)]
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -332,7 +1587,7 @@ const _: () = assert!(size_of::<InterruptContext>() == 8 * 5);
 #[allow(dead_code)]
 #[repr(C)]
 #[derive(Clone, Copy)]
-struct InterruptInfo {
+pub struct InterruptInfo {
     // This struct is placed at top of the interrupt stack.
     // Should be aligned on 16-byte boundaries to pass the
     // alignment checks done by FXSAVE / FXRSTOR
@@ -343,6 +1598,27 @@ struct InterruptInfo {
     ctx: InterruptContext,
 }
 const _: () = assert!(size_of::<InterruptInfo>() == (16 + 4 + 1) * 8 + 8 + 512);
+impl InterruptInfo {
+    /// The error code pushed by the CPU, or 0 for the vectors without one
+    pub fn error_code(&self) -> u64 {
+        self.error_code
+    }
+    pub fn rip(&self) -> u64 {
+        self.ctx.rip
+    }
+    pub fn rsp(&self) -> u64 {
+        self.ctx.rsp
+    }
+    pub fn rbp(&self) -> u64 {
+        self.greg.rbp
+    }
+    pub fn cs(&self) -> u64 {
+        self.ctx.cs
+    }
+    pub fn rflags(&self) -> u64 {
+        self.ctx.rflags
+    }
+}
 impl fmt::Debug for InterruptInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -396,66 +1672,45 @@ impl fmt::Debug for InterruptInfo {
 // In IA-32e mode, the RSP is aligned to a 16-byte boundary
 // before pushing the stack frame
 
-/// This generates interrupt_entrypointN()
-/// Generated asm will be looks like this:
-/// ```
-/// .global interrupt_entrypointN
-///    interrupt_entrypointN:
-///    push 0 // No error code
-///    push rcx // Save rcx first to reuse
-///    mov rcx, N // INT#
-///    jmp inthandler_common
-/// ```
-macro_rules! interrupt_entrypoint {
-    ($index:literal) => {
-        global_asm!(concat!(
-            ".global interrupt_entrypoint",
-            stringify!($index),
-            "\n",
-            "interrupt_entrypoint",
-            stringify!($index),
-            ":\n",
-            "push 0 // No error code\n",
-            "push rcx // Save rcx first to reuse\n",
-            "mov rcx, ",
-            stringify!($index),
-            "\n",
-            "jmp inthandler_common"
-        ));
-    };
-}
-macro_rules! interrupt_entrypoint_with_ecode {
-    ($index:literal) => {
-        global_asm!(concat!(
-            ".global interrupt_entrypoint",
-            stringify!($index),
-            "\n",
-            "interrupt_entrypoint",
-            stringify!($index),
-            ":\n",
-            "push rcx // Save rcx first to reuse\n",
-            "mov rcx, ",
-            stringify!($index),
-            "\n",
-            "jmp inthandler_common"
-        ));
-    };
-}
+/// Size of each entrypoint in interrupt_entrypoints
+const INTERRUPT_ENTRYPOINT_SIZE: usize = 16;
 
-interrupt_entrypoint!(3);
-interrupt_entrypoint!(6);
-interrupt_entrypoint_with_ecode!(8);
-interrupt_entrypoint_with_ecode!(13);
-interrupt_entrypoint_with_ecode!(14);
-interrupt_entrypoint!(32);
+// This generates an entrypoint for every vector at
+// interrupt_entrypoints + vector * INTERRUPT_ENTRYPOINT_SIZE.
+// Each of them looks like this:
+// ```
+//    push 0 // No error code (omitted for the vectors with one)
+//    push rcx // Save rcx first to reuse
+//    mov ecx, N // INT#
+//    jmp inthandler_common
+// ```
+// Vectors with an error code: SDM Vol.3: Table 6-1
+global_asm!(
+    r#"
+.global interrupt_entrypoints
+.balign 16
+interrupt_entrypoints:
+.set vector, 0
+.rept 256
+.balign 16
+.if vector == 8 || (vector >= 10 && vector <= 14) || vector == 17 || vector == 21 || vector == 29 || vector == 30
+.else
+    push 0
+.endif
+    push rcx
+    mov ecx, vector
+    jmp inthandler_common
+.set vector, vector + 1
+.endr
+"#
+);
 
 extern "sysv64" {
-    fn interrupt_entrypoint3();
-    fn interrupt_entrypoint6();
-    fn interrupt_entrypoint8();
-    fn interrupt_entrypoint13();
-    fn interrupt_entrypoint14();
-    fn interrupt_entrypoint32();
+    fn interrupt_entrypoints();
+}
+
+fn interrupt_entrypoint(vector: u8) -> u64 {
+    interrupt_entrypoints as usize as u64 + (vector as usize * INTERRUPT_ENTRYPOINT_SIZE) as u64
 }
 
 global_asm!(
@@ -477,6 +1732,32 @@ inthandler_common:
     push rbx
     push rdx
     push rax
+    // Switch to the kernel GS base if it is not active. rbx remembers it
+    // to switch back on return, since it is preserved by inthandler.
+    xor ebx, ebx
+    cmp ecx, 2 // NMI
+    je 3f
+    cmp ecx, 18 // #MC
+    je 3f
+    // Others never come in kernel mode with the user GS base, since
+    // interrupts are disabled around swapgs. So CS.RPL=3 tells it.
+    test byte ptr [rsp + 136], 3
+    jz 1f
+    jmp 2f
+3:
+    // NMI and #MC can come just after the entry to or just before the
+    // exit from the kernel, where CS does not match the GS base. Read the
+    // base itself instead. The kernel one is in the upper half.
+    mov r12, rcx
+    mov ecx, {msr_gs_base}
+    rdmsr
+    mov rcx, r12
+    test edx, edx
+    js 1f
+2:
+    swapgs
+    mov ebx, 1
+1:
     // FPU State
     sub rsp, 512 + 8
     fxsave64[rsp]
@@ -495,6 +1776,10 @@ inthandler_common:
     fxrstor64[rsp]
     add rsp, 512 + 8
     //
+    test ebx, ebx
+    jz 4f
+    swapgs
+4:
     pop rax
     pop rdx
     pop rbx
@@ -513,9 +1798,17 @@ inthandler_common:
     pop rcx
     add rsp, 8 // for Error Code
     iretq
-"#
+"#,
+    msr_gs_base = const MSR_GS_BASE,
 );
 
+// Bits of the error code of #PF (SDM Vol.3: 4.7 Page-Fault Exceptions)
+pub const PF_ERROR_PRESENT: u64 = 1 << 0;
+pub const PF_ERROR_WRITE: u64 = 1 << 1;
+pub const PF_ERROR_USER: u64 = 1 << 2;
+pub const PF_ERROR_RESERVED_BIT: u64 = 1 << 3;
+pub const PF_ERROR_INSTRUCTION_FETCH: u64 = 1 << 4;
+
 pub fn read_cr2() -> u64 {
     let mut cr2: u64;
     unsafe {
@@ -525,23 +1818,165 @@ pub fn read_cr2() -> u64 {
     cr2
 }
 
+fn report_stack_overflow(info: &InterruptInfo) {
+    // A #DF caused by an overflow may not have CR2 pointing the guard page
+    // (e.g. when pushing an exception frame failed), so check RSP as well.
+    if let Some(stack) = find_stack_by_guard_page(read_cr2())
+        .or_else(|| find_stack_by_guard_page(info.ctx.rsp))
+        .or_else(|| find_stack_by_guard_page(info.ctx.rsp.wrapping_sub(8)))
+    {
+        error!("stack overflow on stack {}", stack.name());
+    }
+}
+
+// SDM Vol.3: Table 6-1. Protected-Mode Exceptions and Interrupts
+const EXCEPTION_NAMES: [&str; 32] = [
+    "Divide Error (#DE)",
+    "Debug Exception (#DB)",
+    "NMI Interrupt",
+    "Breakpoint (#BP)",
+    "Overflow (#OF)",
+    "BOUND Range Exceeded (#BR)",
+    "Invalid Opcode (#UD)",
+    "Device Not Available (#NM)",
+    "Double Fault (#DF)",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS (#TS)",
+    "Segment Not Present (#NP)",
+    "Stack-Segment Fault (#SS)",
+    "General Protection Fault (#GP)",
+    "Page Fault (#PF)",
+    "Reserved",
+    "x87 FPU Floating-Point Error (#MF)",
+    "Alignment Check (#AC)",
+    "Machine Check (#MC)",
+    "SIMD Floating-Point Exception (#XM)",
+    "Virtualization Exception (#VE)",
+    "Control Protection Exception (#CP)",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception (#HV)",
+    "VMM Communication Exception (#VC)",
+    "Security Exception (#SX)",
+    "Reserved",
+];
+
+pub fn exception_name(vector: usize) -> &'static str {
+    EXCEPTION_NAMES
+        .get(vector)
+        .copied()
+        .unwrap_or("Not an exception")
+}
+
+/// Error code of the exceptions related to a segment selector
+/// (#TS, #NP, #SS and #GP). SDM Vol.3: 6.13 Error Code
+pub struct SelectorErrorCode(pub u64);
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        if code == 0 {
+            return write!(f, "not related to a segment");
+        }
+        let table = if code & 0b010 != 0 {
+            "IDT"
+        } else if code & 0b100 != 0 {
+            "LDT"
+        } else {
+            "GDT"
+        };
+        write!(f, "{table}[{}]", (code >> 3) & 0x1FFF)?;
+        if code & 0b001 != 0 {
+            write!(f, " (external event)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Exception flags of x87 FSW and MXCSR, which share the bit layout.
+pub struct FloatExceptionFlags(pub u32);
+impl fmt::Display for FloatExceptionFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: [&str; 6] = [
+            "invalid operation",
+            "denormal operand",
+            "divide-by-zero",
+            "overflow",
+            "underflow",
+            "precision",
+        ];
+        let mut flags = NAMES
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.0 & (1 << i) != 0)
+            .map(|(_, name)| name);
+        match flags.next() {
+            Some(first) => {
+                write!(f, "{first}")?;
+                for name in flags {
+                    write!(f, ", {name}")?;
+                }
+                Ok(())
+            }
+            None => write!(f, "none"),
+        }
+    }
+}
+
+const MSR_MCG_CAP: u32 = 0x179;
+const MSR_MCG_STATUS: u32 = 0x17A;
+const MSR_MC0_STATUS: u32 = 0x401;
+const MSR_MC0_ADDR: u32 = 0x402;
+const MCI_STATUS_VALID: u64 = 1 << 63;
+const MCI_STATUS_ADDR_VALID: u64 = 1 << 58;
+
+fn report_machine_check() {
+    // #MC is only raised if the CPU supports the machine check
+    // architecture, which has these MSRs.
+    let num_banks = read_msr(MSR_MCG_CAP) & 0xFF;
+    error!("MCG_STATUS={:#018X}", read_msr(MSR_MCG_STATUS));
+    for i in 0..num_banks as u32 {
+        let status = read_msr(MSR_MC0_STATUS + i * 4);
+        if status & MCI_STATUS_VALID == 0 {
+            continue;
+        }
+        error!("MC{i}_STATUS={status:#018X}");
+        if status & MCI_STATUS_ADDR_VALID != 0 {
+            error!("MC{i}_ADDR={:#018X}", read_msr(MSR_MC0_ADDR + i * 4));
+        }
+    }
+}
+
 #[no_mangle]
 extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
+    if index < 32 && !matches!(index, 2 | 3) && info.cs() & 3 == 3 {
+        exit_user_on_exception(index, info);
+    }
+    let _nesting = InterruptNesting::enter();
+    if index == 14 && handle_lazy_page_fault(read_cr2(), info.error_code) {
+        return;
+    }
+    if index >= 32 {
+        handle_external_interrupt(index as u8, info);
+        return;
+    }
     error!("Interrupt Info: {:?}", info);
-    error!("Exception {index:#04X}: ");
+    error!("Exception {index:#04X}: {}", exception_name(index));
     match index {
         3 => {
-            error!("Breakpoint");
             return;
         }
-        6 => {
-            error!("Invalid Opcode");
-        }
         8 => {
-            error!("Double Fault");
+            report_stack_overflow(info);
+        }
+        10..=12 => {
+            error!("Selector: {}", SelectorErrorCode(info.error_code));
         }
         13 => {
-            error!("General Protection Fault");
+            error!("Selector: {}", SelectorErrorCode(info.error_code));
             let rip = info.ctx.rip;
             error!("Bytes @ RIP({rip:#018X}):");
             let rip = rip as *const u8;
@@ -549,44 +1984,82 @@ extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
             error!("  = {bytes:02X?}");
         }
         14 => {
-            error!("Page Fault");
             error!("CR2={:#018X}", read_cr2());
+            report_stack_overflow(info);
             error!(
                 "Caused by: A {} mode {} on a {} page, page structures are {}",
-                if info.error_code & 0b0000_0100 != 0 {
+                if info.error_code & PF_ERROR_USER != 0 {
                     "user"
                 } else {
                     "supervisor"
                 },
-                if info.error_code & 0b0001_0000 != 0 {
+                if info.error_code & PF_ERROR_INSTRUCTION_FETCH != 0 {
                     "instruction fetch"
-                } else if info.error_code & 0b0010 != 0 {
+                } else if info.error_code & PF_ERROR_WRITE != 0 {
                     "data write"
                 } else {
                     "data read"
                 },
-                if info.error_code & 0b0001 != 0 {
+                if info.error_code & PF_ERROR_PRESENT != 0 {
                     "present"
                 } else {
                     "non-present"
                 },
-                if info.error_code & 0b1000 != 0 {
+                if info.error_code & PF_ERROR_RESERVED_BIT != 0 {
                     "invalid"
                 } else {
                     "valid"
                 },
             );
         }
-        _ => {
-            error!("Not handled");
+        16 => {
+            let fsw = info.fpu_context.fsw();
+            error!("FCW={:#06X} FSW={fsw:#06X}", info.fpu_context.fcw());
+            error!("x87 exceptions: {}", FloatExceptionFlags(fsw as u32));
+        }
+        17 => {
+            error!("Unaligned access with CPL=3 and AC=1");
         }
+        18 => {
+            report_machine_check();
+        }
+        19 => {
+            let mxcsr = info.fpu_context.mxcsr();
+            error!("MXCSR={mxcsr:#010X}");
+            // Unmasked ones raise #XM. The mask bits are at 7-12.
+            error!(
+                "SIMD exceptions: {}, unmasked: {}",
+                FloatExceptionFlags(mxcsr),
+                FloatExceptionFlags(mxcsr & !(mxcsr >> 7))
+            );
+        }
+        _ => {}
     }
+    print_backtrace_from(info.ctx.rip, info.greg.rbp);
+    record_exception(index, info);
     panic!("fatal exception");
 }
 
-#[no_mangle]
-extern "sysv64" fn int_handler_unimplemented() {
-    panic!("unexpected interrupt!");
+#[test_case]
+fn exception_details_are_decoded() {
+    use alloc::format;
+    assert_eq!(exception_name(13), "General Protection Fault (#GP)");
+    assert_eq!(exception_name(32), "Not an exception");
+    assert_eq!(
+        format!("{}", SelectorErrorCode(0)),
+        "not related to a segment"
+    );
+    assert_eq!(format!("{}", SelectorErrorCode(0x18)), "GDT[3]");
+    assert_eq!(
+        format!("{}", SelectorErrorCode(14 << 3 | 0b011)),
+        "IDT[14] (external event)"
+    );
+    assert_eq!(format!("{}", SelectorErrorCode(0x0C)), "LDT[1]");
+    assert_eq!(format!("{}", FloatExceptionFlags(0)), "none");
+    assert_eq!(
+        format!("{}", FloatExceptionFlags(0b100100)),
+        "divide-by-zero, precision"
+    );
 }
 
 // PDDRTTTT (TTTT: type, R: reserved, D: DPL, P: present)
@@ -619,13 +2092,7 @@ pub struct IdtDescriptor {
 }
 const _: () = assert!(size_of::<IdtDescriptor>() == 16);
 impl IdtDescriptor {
-    fn new(
-        segment_selector: u16,
-        ist_index: u8,
-        attr: IdtAttr,
-        f: unsafe extern "sysv64" fn(),
-    ) -> Self {
-        let handler_addr = f as *const unsafe extern "sysv64" fn() as usize;
+    fn new(segment_selector: u16, ist_index: u8, attr: IdtAttr, handler_addr: u64) -> Self {
         Self {
             offset_low: handler_addr as u16,
             offset_mid: (handler_addr >> 16) as u16,
@@ -648,56 +2115,39 @@ struct IdtrParameters {
 const _: () = assert!(size_of::<IdtrParameters>() == 10);
 const _: () = assert!(offset_of!(IdtrParameters, base) == 2);
 
+// Indexes of the interrupt stacks in the TSS (0 means no stack switch)
+const IST_NMI: u8 = 1;
+const IST_DOUBLE_FAULT: u8 = 2;
+const IST_MACHINE_CHECK: u8 = 3;
+
 pub struct Idt {
     #[allow(dead_code)]
     entries: Pin<Box<[IdtDescriptor; 0x100]>>,
 }
 impl Idt {
     pub fn new(segment_selector: u16) -> Self {
-        let mut entries = [IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            int_handler_unimplemented,
-        ); 0x100];
-        entries[3] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            // Set DPL=3 to allow user land to make this interrupt (e.g. via
-            // int3 op)
-            IdtAttr::IntGateDPL3,
-            interrupt_entrypoint3,
-        );
-        entries[6] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint6,
-        );
-        entries[8] = IdtDescriptor::new(
-            segment_selector,
-            2,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint8,
-        );
-        entries[13] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint13,
-        );
-        entries[14] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint14,
-        );
-        entries[32] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint32,
-        );
+        let entries: [IdtDescriptor; 0x100] = core::array::from_fn(|vector| {
+            let (ist_index, attr) = match vector {
+                // NMI and #MC can nest in any handler, so they have their
+                // own stacks not to overwrite the interrupted frame.
+                2 => (IST_NMI, IdtAttr::IntGateDPL0),
+                18 => (IST_MACHINE_CHECK, IdtAttr::IntGateDPL0),
+                // Set DPL=3 to allow user land to make this interrupt (e.g.
+                // via int3 op)
+                3 => (0, IdtAttr::IntGateDPL3),
+                // #DF uses its own stack to report kernel stack overflows.
+                8 => (IST_DOUBLE_FAULT, IdtAttr::IntGateDPL0),
+                // Others run on the current stack, or on rsp0 if they come
+                // from user mode.
+                _ => (0, IdtAttr::IntGateDPL0),
+            };
+            IdtDescriptor::new(
+                segment_selector,
+                ist_index,
+                attr,
+                interrupt_entrypoint(vector as u8),
+            )
+        });
         let limit = size_of_val(&entries) as u16;
         let entries = Box::pin(entries);
         let params = IdtrParameters {
@@ -732,21 +2182,22 @@ impl TaskStateSegment64 {
     pub fn phys_addr(&self) -> u64 {
         self.inner.as_ref().get_ref() as *const TaskStateSegment64Inner as u64
     }
-    unsafe fn alloc_interrupt_stack() -> u64 {
+    pub fn rsp0(&self) -> u64 {
+        let rsp = self.inner._rsp;
+        rsp[0]
+    }
+    fn alloc_interrupt_stack(name: &'static str) -> u64 {
         const HANDLER_STACK_SIZE: usize = 64 * 1024;
-        let stack = Box::new([0u8; HANDLER_STACK_SIZE]);
-        let rsp = unsafe { stack.as_ptr().add(HANDLER_STACK_SIZE) as u64 };
-        core::mem::forget(stack);
-        // now, no one except us own the region since it is forgotten by the
-        // allocator ;)
-        rsp
+        KernelStack::alloc(name, HANDLER_STACK_SIZE)
+            .expect("Failed to allocate an interrupt stack")
+            .top()
     }
     pub fn new() -> Self {
-        let rsp0 = unsafe { Self::alloc_interrupt_stack() };
+        let rsp0 = Self::alloc_interrupt_stack("rsp0");
         let mut ist = [0u64; 8];
-        for ist in ist[1..=7].iter_mut() {
-            *ist = unsafe { Self::alloc_interrupt_stack() };
-        }
+        ist[IST_NMI as usize] = Self::alloc_interrupt_stack("nmi");
+        ist[IST_DOUBLE_FAULT as usize] = Self::alloc_interrupt_stack("double_fault");
+        ist[IST_MACHINE_CHECK as usize] = Self::alloc_interrupt_stack("machine_check");
         let tss64 = TaskStateSegment64Inner {
             _reserved0: 0,
             _rsp: [rsp0, 0, 0],
@@ -761,6 +2212,11 @@ impl TaskStateSegment64 {
         this
     }
 }
+impl Default for TaskStateSegment64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Drop for TaskStateSegment64 {
     fn drop(&mut self) {
         panic!("TSS64 being dropped!");
@@ -770,6 +2226,9 @@ impl Drop for TaskStateSegment64 {
 pub fn init_exceptions() -> (GdtWrapper, Idt) {
     let gdt = GdtWrapper::default();
     gdt.load();
+    // Loading the selectors clears the bases, which may point to the
+    // per-CPU data already.
+    let (fs_base, gs_base) = (read_fs_base(), read_gs_base());
     unsafe {
         write_cs(KERNEL_CS);
         write_ss(KERNEL_DS);
@@ -777,6 +2236,8 @@ pub fn init_exceptions() -> (GdtWrapper, Idt) {
         write_ds(KERNEL_DS);
         write_fs(KERNEL_DS);
         write_gs(KERNEL_DS);
+        write_fs_base(fs_base);
+        write_gs_base(gs_base);
     }
     let idt = Idt::new(KERNEL_CS);
     (gdt, idt)
@@ -796,6 +2257,8 @@ pub const BIT_DPL3: u64 = 3u64 << 45;
 enum GdtAttr {
     KernelCode = BIT_TYPE_CODE | BIT_PRESENT | BIT_CS_LONG_MODE | BIT_CS_READABLE,
     KernelData = BIT_TYPE_DATA | BIT_PRESENT | BIT_DS_WRITABLE,
+    UserCode = BIT_TYPE_CODE | BIT_PRESENT | BIT_CS_LONG_MODE | BIT_DPL3,
+    UserData = BIT_TYPE_DATA | BIT_PRESENT | BIT_DS_WRITABLE | BIT_DPL3,
 }
 
 #[allow(dead_code)]
@@ -807,7 +2270,10 @@ struct GdtrParameters {
 
 pub const KERNEL_CS: u16 = 1 << 3;
 pub const KERNEL_DS: u16 = 2 << 3;
-pub const TSS64_SEL: u16 = 3 << 3;
+// sysret requires the user data segment to be followed by the user code.
+pub const USER_DS: u16 = 3 << 3 | 3;
+pub const USER_CS: u16 = 4 << 3 | 3;
+pub const TSS64_SEL: u16 = 5 << 3;
 
 #[allow(dead_code)]
 #[repr(C, packed)]
@@ -815,9 +2281,11 @@ pub struct Gdt {
     null_segment: GdtSegmentDescriptor,
     kernel_code_segment: GdtSegmentDescriptor,
     kernel_data_segment: GdtSegmentDescriptor,
+    user_data_segment: GdtSegmentDescriptor,
+    user_code_segment: GdtSegmentDescriptor,
     task_state_segment: TaskStateSegment64Descriptor,
 }
-const _: () = assert!(size_of::<Gdt>() == 40);
+const _: () = assert!(size_of::<Gdt>() == 56);
 
 #[allow(dead_code)]
 pub struct GdtWrapper {
@@ -844,6 +2312,11 @@ impl GdtWrapper {
                 in("cx") TSS64_SEL);
         }
     }
+    /// The stack that the CPU switches to when entering the kernel from
+    /// user mode (rsp0 of the TSS)
+    pub fn kernel_stack(&self) -> u64 {
+        self.tss64.rsp0()
+    }
 }
 impl Default for GdtWrapper {
     fn default() -> Self {
@@ -852,6 +2325,8 @@ impl Default for GdtWrapper {
             null_segment: GdtSegmentDescriptor::null(),
             kernel_code_segment: GdtSegmentDescriptor::new(GdtAttr::KernelCode),
             kernel_data_segment: GdtSegmentDescriptor::new(GdtAttr::KernelData),
+            user_data_segment: GdtSegmentDescriptor::new(GdtAttr::UserData),
+            user_code_segment: GdtSegmentDescriptor::new(GdtAttr::UserCode),
             task_state_segment: TaskStateSegment64Descriptor::new(tss64.phys_addr()),
         };
         let gdt = Box::pin(gdt);
@@ -910,14 +2385,32 @@ pub fn trigger_debug_interrupt() {
 /// Writing to CR3 can causes any exceptions so it is
 /// programmer's responsibility to setup correct page tables.
 #[no_mangle]
-pub unsafe fn write_cr3(table: *const PML4) {
+pub unsafe fn write_cr3(table: PhysAddr) {
     asm!("mov cr3, rax",
-            in("rax") table)
+            in("rax") table.as_u64())
+}
+
+/// Drops the TLB entries for the page that has virt, including global and
+/// huge page ones, on the current CPU.
+///
+/// # Safety
+/// virt should be canonical.
+pub unsafe fn invalidate_page(virt: u64) {
+    asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags))
 }
 
 pub fn flush_tlb() {
     unsafe {
         write_cr3(read_cr3());
+        flush_global_pages();
+    }
+}
+/// Global pages survive CR3 writes, so toggle CR4.PGE to drop them too.
+unsafe fn flush_global_pages() {
+    let cr4 = read_cr4();
+    if cr4 & CR4_PGE != 0 {
+        write_cr4(cr4 & !CR4_PGE);
+        write_cr4(cr4);
     }
 }
 
@@ -925,14 +2418,15 @@ pub fn flush_tlb() {
 /// This will create a mutable reference to the page table structure
 /// So is is programmer's responsibility to ensure that at most one
 /// instance of the reference exist at every moment.
-pub unsafe fn take_current_page_table() -> ManuallyDrop<Box<PML4>> {
-    ManuallyDrop::new(Box::from_raw(read_cr3()))
+pub unsafe fn take_current_page_table() -> &'static mut PML4 {
+    &mut *read_cr3().as_mut_ptr::<PML4>()
 }
 /// # Safety
 /// This function sets the CR3 value so that anything bad can happen.
-pub unsafe fn put_current_page_table(mut table: ManuallyDrop<Box<PML4>>) {
+pub unsafe fn put_current_page_table(table: &'static mut PML4) {
     // Set CR3 to reflect the updates and drop TLB caches.
-    write_cr3(Box::into_raw(ManuallyDrop::take(&mut table)))
+    write_cr3(VirtAddr::from_ptr(table).to_phys());
+    flush_global_pages();
 }
 /// # Safety
 /// This function modifies the page table as callback does, so
@@ -941,19 +2435,7 @@ pub unsafe fn with_current_page_table<F>(callback: F)
 where
     F: FnOnce(&mut PML4),
 {
-    let mut table = take_current_page_table();
-    callback(&mut table);
+    let table = take_current_page_table();
+    callback(table);
     put_current_page_table(table)
 }
-
-pub fn disable_cache<T: Sized>(io_box: &IoBox<T>) {
-    let region = io_box.as_ref();
-    let vstart = region as *const T as u64;
-    let vend = vstart + size_of_val(region) as u64;
-    unsafe {
-        with_current_page_table(|pt| {
-            pt.create_mapping(vstart, vend, vstart, PageAttr::ReadWriteIo)
-                .expect("Failed to create mapping")
-        })
-    }
-}
@@ -1,12 +1,16 @@
 extern crate alloc;
 
-use crate::allocator::ALLOCATOR;
+use crate::addr::PhysAddr;
 use crate::bits::extract_bits;
+use crate::dma::DmaBox;
+use crate::dma::DmaSlice;
 use crate::executor::spawn_global;
 use crate::executor::yield_execution;
+use crate::frame::num_pages_for;
+use crate::frame::FrameConstraints;
+use crate::frame::PhysFrames;
 use crate::info;
 use crate::keyboard::UsbKeyboardDriver;
-use crate::mmio::IoBox;
 use crate::mmio::Mmio;
 use crate::mutex::Mutex;
 use crate::pci::BarMem64;
@@ -21,12 +25,10 @@ use crate::usb::UsbDeviceDescriptor;
 use crate::usb::UsbDeviceDriver;
 use crate::volatile::Volatile;
 use crate::x86::busy_loop_hint;
-use alloc::boxed::Box;
 use alloc::collections::VecDeque;
 use alloc::rc::Rc;
 use alloc::rc::Weak;
 use alloc::vec::Vec;
-use core::alloc::Layout;
 use core::cmp::max;
 use core::future::Future;
 use core::marker::PhantomPinned;
@@ -69,14 +71,16 @@ impl PciXhciDriver {
         VDI_LIST.contains(&vp)
     }
     fn setup_xhc_registers(bar0: &BarMem64) -> Result<XhcRegisters> {
-        let cap_regs = unsafe { Mmio::from_raw(bar0.addr() as *mut CapabilityRegisters) };
+        let cap_regs = unsafe { Mmio::<CapabilityRegisters>::from_phys(bar0.phys_addr()) };
         let op_regs = unsafe {
-            Mmio::from_raw(
-                bar0.addr().add(cap_regs.as_ref().caplength()) as *mut OperationalRegisters
+            Mmio::<OperationalRegisters>::from_phys(
+                bar0.phys_addr() + cap_regs.as_ref().caplength() as u64,
             )
         };
         let rt_regs = unsafe {
-            Mmio::from_raw(bar0.addr().add(cap_regs.as_ref().rtsoff()) as *mut RuntimeRegisters)
+            Mmio::<RuntimeRegisters>::from_phys(
+                bar0.phys_addr() + cap_regs.as_ref().rtsoff() as u64,
+            )
         };
         let portsc = PortSc::new(bar0, cap_regs.as_ref());
         let num_slots = cap_regs.as_ref().num_of_ports();
@@ -101,7 +105,7 @@ impl PciXhciDriver {
         pci.disable_interrupt(bdf)?;
         pci.enable_bus_master(bdf)?;
         let bar0 = pci.try_bar0_mem64(bdf)?;
-        bar0.disable_cache();
+        bar0.disable_cache()?;
         let regs = Self::setup_xhc_registers(&bar0)?;
         let xhc = Controller::new(regs)?;
         spawn_global(Self::run(xhc));
@@ -242,30 +246,34 @@ impl PciXhciDriver {
     async fn address_device(xhc: &Rc<Controller>, port: usize, slot: u8) -> Result<CommandRing> {
         // Setup an input context and send AddressDevice command.
         // 4.3.3 Device Slot Initialization
-        let output_context = Box::pin(OutputContext::default());
+        let output_context = DmaBox::new(OutputContext::default())?;
         xhc.set_output_context_for_slot(slot, output_context);
         let mut input_ctrl_ctx = InputControlContext::default();
         input_ctrl_ctx.add_context(0)?;
         input_ctrl_ctx.add_context(1)?;
-        let mut input_context = Box::pin(InputContext::default());
-        input_context.as_mut().set_input_ctrl_ctx(input_ctrl_ctx)?;
+        let mut input_context = DmaBox::new(InputContext::default())?;
+        input_context
+            .as_pin_mut()
+            .set_input_ctrl_ctx(input_ctrl_ctx)?;
         // 3. Initialize the Input Slot Context data structure (6.2.2)
-        input_context.as_mut().set_root_hub_port_number(port)?;
-        input_context.as_mut().set_last_valid_dci(1)?;
+        input_context.as_pin_mut().set_root_hub_port_number(port)?;
+        input_context.as_pin_mut().set_last_valid_dci(1)?;
         // 4. Initialize the Transfer Ring for the Default Control Endpoint
         // 5. Initialize the Input default control Endpoint 0 Context (6.2.3)
         let portsc = xhc.regs.portsc.get(port).ok_or("PORTSC was invalid")?;
-        input_context.as_mut().set_port_speed(portsc.port_speed())?;
-        let ctrl_ep_ring = CommandRing::default();
-        input_context.as_mut().set_ep_ctx(
+        input_context
+            .as_pin_mut()
+            .set_port_speed(portsc.port_speed())?;
+        let ctrl_ep_ring = CommandRing::new()?;
+        input_context.as_pin_mut().set_ep_ctx(
             1,
             EndpointContext::new_control_endpoint(
                 portsc.max_packet_size()?,
-                ctrl_ep_ring.ring_phys_addr(),
+                ctrl_ep_ring.ring_phys_addr().as_u64(),
             )?,
         )?;
         // 8. Issue an Address Device Command for the Device Slot
-        let cmd = GenericTrbEntry::cmd_address_device(input_context.as_ref(), slot);
+        let cmd = GenericTrbEntry::cmd_address_device(input_context.phys_addr(), slot);
         xhc.send_command(cmd).await?.cmd_result_ok()?;
         Ok(ctrl_ep_ring)
     }
@@ -315,7 +323,8 @@ impl CapabilityRegisters {
 // Address Array is utilized by the xHCI Scratchpad mechanism.
 #[repr(C, align(64))]
 struct RawDeviceContextBaseAddressArray {
-    scratchpad_table_ptr: *const *const u8,
+    /// Physical address of the scratchpad buffer array
+    scratchpad_table_ptr: u64,
     context: [u64; 255],
     _pinned: PhantomPinned,
 }
@@ -335,7 +344,7 @@ struct OperationalRegisters {
     dnctrl: Volatile<u32>,
     crcr: Volatile<u64>,
     rsvdz2: [u64; 2],
-    dcbaap: Volatile<*const RawDeviceContextBaseAddressArray>,
+    dcbaap: Volatile<u64>,
     config: Volatile<u64>,
 }
 const _: () = assert!(size_of::<OperationalRegisters>() == 0x40);
@@ -373,11 +382,11 @@ impl OperationalRegisters {
     }
     fn set_cmd_ring_ctrl(&mut self, ring: &CommandRing) {
         self.crcr.write(
-            ring.ring_phys_addr() | 1, /* Consumer Ring Cycle State */
+            ring.ring_phys_addr().as_u64() | 1, /* Consumer Ring Cycle State */
         )
     }
     fn set_dcbaa_ptr(&mut self, dcbaa: &mut DeviceContextBaseAddressArray) -> Result<()> {
-        self.dcbaap.write(dcbaa.inner_mut_ptr());
+        self.dcbaap.write(dcbaa.phys_addr().as_u64());
         Ok(())
     }
     fn set_num_device_slots(&mut self, num: usize) -> Result<()> {
@@ -420,8 +429,8 @@ impl RuntimeRegisters {
     fn init_irs(&mut self, index: usize, ring: &mut EventRing) -> Result<()> {
         let irs = self.irs.get_mut(index).ok_or("Index out of range")?;
         irs.erst_size = 1;
-        irs.erdp = ring.ring_phys_addr();
-        irs.erst_base = ring.erst_phys_addr();
+        irs.erdp = ring.ring_phys_addr().as_u64();
+        irs.erst_base = ring.erst_phys_addr().as_u64();
         irs.management = 0;
         ring.set_erdp(&mut irs.erdp as *mut u64);
         Ok(())
@@ -429,8 +438,8 @@ impl RuntimeRegisters {
 }
 
 struct ScratchpadBuffers {
-    table: Pin<Box<[*const u8]>>,
-    _bufs: Vec<Pin<Box<[u8]>>>,
+    table: PhysFrames,
+    _bufs: Vec<PhysFrames>,
 }
 impl ScratchpadBuffers {
     fn alloc(cap_regs: &CapabilityRegisters, op_regs: &OperationalRegisters) -> Result<Self> {
@@ -440,21 +449,17 @@ impl ScratchpadBuffers {
         info!("xhci: original num_scratchpad_bufs = {num_scratchpad_bufs}");
 
         let num_scratchpad_bufs = max(cap_regs.num_scratchpad_bufs(), 1);
-        let table = ALLOCATOR.alloc_with_options(
-            Layout::from_size_align(size_of::<usize>() * num_scratchpad_bufs, page_size)
-                .map_err(|_| "could not allocate scratchpad buffer table")?,
-        );
-        let table = unsafe { slice::from_raw_parts(table as *mut *const u8, num_scratchpad_bufs) };
-        let mut table = Pin::new(Box::<[*const u8]>::from(table));
+        let constraints = FrameConstraints::ANY.aligned(page_size);
+        let table = PhysFrames::alloc_zeroed(
+            num_pages_for(size_of::<u64>() * num_scratchpad_bufs),
+            constraints,
+        )?;
+        let entries =
+            unsafe { slice::from_raw_parts_mut(table.as_mut_ptr::<u64>(), num_scratchpad_bufs) };
         let mut bufs = Vec::new();
-        for sb in table.iter_mut() {
-            let buf = ALLOCATOR.alloc_with_options(
-                Layout::from_size_align(page_size, page_size)
-                    .map_err(|_| "could not allocated a scratchpad buffer")?,
-            );
-            let buf = unsafe { slice::from_raw_parts(buf as *const u8, page_size) };
-            let buf = Pin::new(Box::<[u8]>::from(buf));
-            *sb = buf.as_ref().as_ptr();
+        for sb in entries.iter_mut() {
+            let buf = PhysFrames::alloc_zeroed(num_pages_for(page_size), constraints)?;
+            *sb = buf.phys_addr().as_u64();
             bufs.push(buf);
         }
         Ok(Self { table, _bufs: bufs })
@@ -576,38 +581,32 @@ struct OutputContext {
 const _: () = assert!(size_of::<OutputContext>() <= 4096);
 
 struct DeviceContextBaseAddressArray {
-    inner: Pin<Box<RawDeviceContextBaseAddressArray>>,
+    inner: DmaBox<RawDeviceContextBaseAddressArray>,
     // NB: the index of context is [slot - 1], not slot.
-    context: [Option<Pin<Box<OutputContext>>>; 255],
+    context: [Option<DmaBox<OutputContext>>; 255],
     _scratchpad_buffers: ScratchpadBuffers,
 }
 impl DeviceContextBaseAddressArray {
-    fn new(scratchpad_buffers: ScratchpadBuffers) -> Self {
+    fn new(scratchpad_buffers: ScratchpadBuffers) -> Result<Self> {
         let mut inner = RawDeviceContextBaseAddressArray::new();
-        inner.scratchpad_table_ptr = scratchpad_buffers.table.as_ref().as_ptr();
-        let inner = Box::pin(inner);
-        Self {
+        inner.scratchpad_table_ptr = scratchpad_buffers.table.phys_addr().as_u64();
+        let inner = DmaBox::new(inner)?;
+        Ok(Self {
             inner,
             context: unsafe { MaybeUninit::zeroed().assume_init() },
             _scratchpad_buffers: scratchpad_buffers,
-        }
+        })
     }
-    fn inner_mut_ptr(&mut self) -> *const RawDeviceContextBaseAddressArray {
-        self.inner.as_ref().get_ref() as *const RawDeviceContextBaseAddressArray
+    fn phys_addr(&self) -> PhysAddr {
+        self.inner.phys_addr()
     }
-    fn set_output_context(&mut self, slot: u8, output_context: Pin<Box<OutputContext>>) {
+    fn set_output_context(&mut self, slot: u8, output_context: DmaBox<OutputContext>) {
         let ctx_idx = slot as usize - 1;
-        // Own the output context here
+        // Set it in the actual pointer array...
+        unsafe { self.inner.get_unchecked_mut() }.context[ctx_idx] =
+            output_context.phys_addr().as_u64();
+        // ...and own the output context here
         self.context[ctx_idx] = Some(output_context);
-        // ...and set it in the actual pointer array
-        unsafe {
-            self.inner.as_mut().get_unchecked_mut().context[ctx_idx] =
-                self.context[ctx_idx]
-                    .as_ref()
-                    .expect("Output Context was None")
-                    .as_ref()
-                    .get_ref() as *const OutputContext as u64;
-        }
     }
 }
 
@@ -624,10 +623,10 @@ impl Controller {
         }
         let scratchpad_buffers =
             ScratchpadBuffers::alloc(regs.cap_regs.as_ref(), regs.op_regs.as_ref())?;
-        let device_context_base_array = DeviceContextBaseAddressArray::new(scratchpad_buffers);
+        let device_context_base_array = DeviceContextBaseAddressArray::new(scratchpad_buffers)?;
         let device_context_base_array = Mutex::new(device_context_base_array);
         let primary_event_ring = Mutex::new(EventRing::new()?);
-        let command_ring = Mutex::new(CommandRing::default());
+        let command_ring = Mutex::new(CommandRing::new()?);
         let mut xhc = Self {
             regs,
             device_context_base_array,
@@ -674,7 +673,7 @@ impl Controller {
         db.notify(dci, 0);
         Ok(())
     }
-    fn set_output_context_for_slot(&self, slot: u8, output_context: Pin<Box<OutputContext>>) {
+    fn set_output_context_for_slot(&self, slot: u8, output_context: DmaBox<OutputContext>) {
         self.device_context_base_array
             .lock()
             .set_output_context(slot, output_context);
@@ -686,7 +685,7 @@ impl Controller {
         desc_type: usb::UsbDescriptorType,
         desc_index: u8,
         lang_id: u16,
-        buf: &mut Pin<Box<[u8]>>,
+        buf: &mut DmaSlice<u8>,
     ) -> Result<()> {
         ctrl_ep_ring.push(
             SetupStageTrb::new(
@@ -712,7 +711,7 @@ impl Controller {
         desc_type: usb::UsbDescriptorType,
         desc_index: u8,
         w_index: u16,
-        buf: &mut Pin<Box<[u8]>>,
+        buf: &mut DmaSlice<u8>,
     ) -> Result<()> {
         ctrl_ep_ring.push(
             SetupStageTrb::new(
@@ -735,7 +734,7 @@ impl Controller {
         &self,
         slot: u8,
         ctrl_ep_ring: &mut CommandRing,
-        buf: &mut Pin<Box<[u8]>>,
+        buf: &mut DmaSlice<u8>,
     ) -> Result<()> {
         // [HID] 7.2.1 Get_Report Request
         ctrl_ep_ring.push(
@@ -831,15 +830,15 @@ impl Controller {
 }
 
 struct EventRing {
-    ring: IoBox<TrbRing>,
-    erst: IoBox<EventRingSegmentTableEntry>,
+    ring: DmaBox<TrbRing>,
+    erst: DmaBox<EventRingSegmentTableEntry>,
     cycle_state_ours: bool,
     erdp: Option<*mut u64>,
     wait_list: VecDeque<Weak<EventWaitInfo>>,
 }
 impl EventRing {
     fn new() -> Result<Self> {
-        let ring = TrbRing::new();
+        let ring = TrbRing::new()?;
         let erst = EventRingSegmentTableEntry::new(&ring)?;
         Ok(Self {
             ring,
@@ -849,14 +848,14 @@ impl EventRing {
             wait_list: Default::default(),
         })
     }
-    fn ring_phys_addr(&self) -> u64 {
-        self.ring.as_ref() as *const TrbRing as u64
+    fn ring_phys_addr(&self) -> PhysAddr {
+        self.ring.phys_addr()
     }
     fn set_erdp(&mut self, erdp: *mut u64) {
         self.erdp = Some(erdp);
     }
-    fn erst_phys_addr(&self) -> u64 {
-        self.erst.as_ref() as *const EventRingSegmentTableEntry as u64
+    fn erst_phys_addr(&self) -> PhysAddr {
+        self.erst.phys_addr()
     }
     /// Non-blocking
     fn pop(&mut self) -> Result<Option<GenericTrbEntry>> {
@@ -864,11 +863,11 @@ impl EventRing {
             return Ok(None);
         }
         let e = self.ring.as_ref().current();
-        let eptr = self.ring.as_ref().current_ptr() as u64;
+        let eptr = self.ring.phys_addr() + self.ring.as_ref().current_offset();
         unsafe { self.ring.get_unchecked_mut() }.advance_index_notoggle(self.cycle_state_ours)?;
         unsafe {
             let erdp = self.erdp.expect("erdp is not set");
-            write_volatile(erdp, eptr | (*erdp & 0b1111));
+            write_volatile(erdp, eptr.as_u64() | (*erdp & 0b1111));
         }
         if self.ring.as_ref().current_index() == 0 {
             self.cycle_state_ours = !self.cycle_state_ours;
@@ -926,21 +925,20 @@ struct EventRingSegmentTableEntry {
 }
 const _: () = assert!(size_of::<EventRingSegmentTableEntry>() == 4096);
 impl EventRingSegmentTableEntry {
-    fn new(ring: &IoBox<TrbRing>) -> Result<IoBox<Self>> {
-        let mut erst: IoBox<Self> = IoBox::new();
-        {
-            let erst = unsafe { erst.get_unchecked_mut() };
-            erst.ring_segment_base_address = ring.as_ref() as *const TrbRing as u64;
-            erst.ring_segment_size = ring
+    fn new(ring: &DmaBox<TrbRing>) -> Result<DmaBox<Self>> {
+        DmaBox::new(Self {
+            ring_segment_base_address: ring.phys_addr().as_u64(),
+            ring_segment_size: ring
                 .as_ref()
                 .num_trbs()
                 .try_into()
-                .or(Err("Too large num trbs"))?;
-        }
-        Ok(erst)
+                .or(Err("Too large num trbs"))?,
+            _rsvdz: [0; 3],
+        })
     }
 }
 #[repr(C, align(4096))]
+#[derive(Default)]
 struct TrbRing {
     trb: [GenericTrbEntry; Self::NUM_TRB],
     current_index: usize,
@@ -951,8 +949,8 @@ struct TrbRing {
 const _: () = assert!(size_of::<TrbRing>() <= 4096);
 impl TrbRing {
     const NUM_TRB: usize = 16;
-    fn new() -> IoBox<Self> {
-        IoBox::new()
+    fn new() -> Result<DmaBox<Self>> {
+        DmaBox::new(Self::default())
     }
     const fn num_trbs(&self) -> usize {
         Self::NUM_TRB
@@ -967,9 +965,6 @@ impl TrbRing {
             Err("TrbRing Out of Range")
         }
     }
-    fn phys_addr(&self) -> u64 {
-        &self.trb[0] as *const GenericTrbEntry as u64
-    }
     fn current_index(&self) -> usize {
         self.current_index
     }
@@ -986,8 +981,9 @@ impl TrbRing {
     fn trb(&self, index: usize) -> GenericTrbEntry {
         unsafe { read_volatile(&self.trb[index]) }
     }
-    fn current_ptr(&self) -> usize {
-        &self.trb[self.current_index] as *const GenericTrbEntry as usize
+    /// Offset of the current TRB from the start of the ring
+    fn current_offset(&self) -> u64 {
+        (self.current_index * size_of::<GenericTrbEntry>()) as u64
     }
     fn advance_index(&mut self, new_cycle: bool) -> Result<()> {
         if self.current().cycle_state() == new_cycle {
@@ -1038,10 +1034,10 @@ impl GenericTrbEntry {
     const CTRL_BIT_INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
     const CTRL_BIT_IMMEDIATE_DATA: u32 = 1 << 6;
     const CTRL_BIT_DATA_DIR_IN: u32 = 1 << 16;
-    fn trb_link(ring: &TrbRing) -> Self {
+    fn trb_link(ring: &DmaBox<TrbRing>) -> Self {
         let mut trb = GenericTrbEntry::default();
         trb.set_trb_type(TrbType::Link);
-        trb.data.write(ring.phys_addr());
+        trb.data.write(ring.phys_addr().as_u64());
         trb.set_toggle_cycle(true);
         trb
     }
@@ -1103,11 +1099,10 @@ impl GenericTrbEntry {
     fn set_slot_id(&mut self, slot: u8) {
         self.control.write_bits(24, 8, slot as u32).unwrap()
     }
-    fn cmd_address_device(input_context: Pin<&InputContext>, slot: u8) -> Self {
+    fn cmd_address_device(input_context_phys_addr: PhysAddr, slot: u8) -> Self {
         let mut trb = Self::default();
         trb.set_trb_type(TrbType::AddressDeviceCommand);
-        trb.data
-            .write(input_context.get_ref() as *const InputContext as u64);
+        trb.data.write(input_context_phys_addr.as_u64());
         trb.set_slot_id(slot);
         trb
     }
@@ -1131,14 +1126,24 @@ impl From<StatusStageTrb> for GenericTrbEntry {
 }
 
 pub struct CommandRing {
-    ring: IoBox<TrbRing>,
+    ring: DmaBox<TrbRing>,
     cycle_state_ours: bool,
 }
 impl CommandRing {
-    fn ring_phys_addr(&self) -> u64 {
-        self.ring.as_ref() as *const TrbRing as u64
+    fn new() -> Result<Self> {
+        let mut this = Self {
+            ring: TrbRing::new()?,
+            cycle_state_ours: false,
+        };
+        let link_trb = GenericTrbEntry::trb_link(&this.ring);
+        unsafe { this.ring.get_unchecked_mut() }.write(TrbRing::NUM_TRB - 1, link_trb)?;
+        Ok(this)
     }
-    fn push(&mut self, mut src: GenericTrbEntry) -> Result<u64> {
+    fn ring_phys_addr(&self) -> PhysAddr {
+        self.ring.phys_addr()
+    }
+    fn push(&mut self, mut src: GenericTrbEntry) -> Result<PhysAddr> {
+        let ring_phys_addr = self.ring.phys_addr();
         // Calling get_unchecked_mut() here is safe
         // as far as this function does not move the ring out.
         let ring = unsafe { self.ring.get_unchecked_mut() };
@@ -1146,7 +1151,7 @@ impl CommandRing {
             return Err("Command Ring is Full");
         }
         src.set_cycle_state(self.cycle_state_ours);
-        let dst_ptr = ring.current_ptr();
+        let dst_ptr = ring_phys_addr + ring.current_offset();
         ring.write_current(src);
         ring.advance_index(!self.cycle_state_ours)?;
         if ring.current().trb_type() == TrbType::Link as u32 {
@@ -1156,20 +1161,7 @@ impl CommandRing {
         }
         // The returned ptr will be used for waiting on command completion
         // events.
-        Ok(dst_ptr as u64)
-    }
-}
-impl Default for CommandRing {
-    fn default() -> Self {
-        let mut this = Self {
-            ring: TrbRing::new(),
-            cycle_state_ours: false,
-        };
-        let link_trb = GenericTrbEntry::trb_link(this.ring.as_ref());
-        unsafe { this.ring.get_unchecked_mut() }
-            .write(TrbRing::NUM_TRB - 1, link_trb)
-            .expect("failed to write a link trb");
-        this
+        Ok(dst_ptr)
     }
 }
 
@@ -1369,8 +1361,8 @@ impl EventFuture {
             _pinned: PhantomPinned,
         }
     }
-    fn new_for_trb(event_ring: &Mutex<EventRing>, trb_addr: u64) -> Self {
-        let trb_addr = Some(trb_addr);
+    fn new_for_trb(event_ring: &Mutex<EventRing>, trb_addr: PhysAddr) -> Self {
+        let trb_addr = Some(trb_addr.as_u64());
         Self::new(
             event_ring,
             EventWaitCond {
@@ -1563,9 +1555,9 @@ pub struct DataStageTrb {
 }
 const _: () = assert!(size_of::<DataStageTrb>() == 16);
 impl DataStageTrb {
-    pub fn new_in(buf: &mut Pin<Box<[u8]>>) -> Self {
+    pub fn new_in(buf: &mut DmaSlice<u8>) -> Self {
         Self {
-            buf: buf.as_ptr() as u64,
+            buf: buf.phys_addr().as_u64(),
             option: buf.len() as u32,
             control: (TrbType::DataStage as u32) << 10
                 | GenericTrbEntry::CTRL_BIT_DATA_DIR_IN